@@ -1,11 +1,57 @@
-pub type StmtBlock = Vec<AstStmtNode>;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::frontend::span::Span;
+
+/// AST node with the source location it was parsed from
+#[derive(Debug, PartialEq, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+}
+
+pub type AstExpr = Spanned<AstExprNode>;
+pub type AstStmt = Spanned<AstStmtNode>;
+
+pub type StmtBlock = Vec<AstStmt>;
 
 #[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum AstStmtNode {
-    ExprStmt(Box<AstExprNode>),
-    RetStmt(Option<Box<AstExprNode>>),
-    VarStmt(String, Option<TypeInfo>, bool, Box<AstExprNode>),
-    WhileStmt(Box<AstExprNode>, StmtBlock)
+    ExprStmt(Box<AstExpr>),
+    RetStmt(Option<Box<AstExpr>>),
+    /// Name, type annotation, whether declared by `val`, initializer
+    VarStmt(String, Option<TypeInfo>, bool, Box<AstExpr>),
+    /// `val (a, b) = tuple`, names are `None` for `_`
+    TupleVarStmt(Vec<Option<String>>, Option<TypeInfo>, bool, Box<AstExpr>),
+    /// Label, condition, body
+    WhileStmt(Option<String>, Box<AstExpr>, StmtBlock),
+    /// Label, loop variable, what it iterates over, body
+    ForStmt(Option<String>, String, ForIter, StmtBlock),
+    /// Leave the innermost loop or the loop with the label
+    Break(Option<String>),
+    /// Go to next iteration of the innermost loop or the loop with the label
+    Continue(Option<String>),
+    /// Raise an `Error`, it unwinds to the innermost `try` block catching it
+    Throw(Box<AstExpr>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ForIter {
+    /// `start..end`, `start..=end` with optional `step`
+    Range {
+        start: Box<AstExpr>,
+        end: Box<AstExpr>,
+        inclusive: bool,
+        step: Option<Box<AstExpr>>,
+    },
+    Iterable(Box<AstExpr>),
 }
 
 pub type AccessedIdent = Vec<String>;
@@ -16,13 +62,74 @@ pub enum AstExprNode {
     Float(f64),
     Bool(bool),
     String(String),
+    /// `none`, the optional without value
+    None,
+    /// `[a, b, c]`
+    List(Vec<AstExpr>),
+    /// `{key: value, ...}`
+    Map(Vec<(Box<AstExpr>, Box<AstExpr>)>),
     Ident(AccessedIdent),
-    Op(Box<AstExprNode>, Op, Box<AstExprNode>),
-    FnCall(AccessedIdent, Option<Vec<Box<AstExprNode>>>),
-    UnaryOp(UnaryOp, Box<AstExprNode>),
+    Op(Box<AstExpr>, Op, Box<AstExpr>),
+    FnCall(AccessedIdent, Option<Vec<AstExpr>>),
+    /// Field of the value of an expression which is not a plain identifier, like `f().x`
+    FieldAccess(Box<AstExpr>, String),
+    /// Method called on the value of an expression which is not a plain identifier, like `"a".len()`
+    MethodCall(Box<AstExpr>, String, Option<Vec<AstExpr>>),
+    /// `(a, b)` with at least two elements
+    Tuple(Vec<AstExpr>),
+    /// `xs[i]` or `map[key]`
+    Index(Box<AstExpr>, Box<AstExpr>),
+    UnaryOp(UnaryOp, Box<AstExpr>),
     BlockExpr(StmtBlock),
-    AssignExpr(String, Box<AstExprNode>),
-    IfExpr(Box<AstExprNode>, Box<AstExprNode>, Option<Box<AstExprNode>>),//last stmt is return value
+    /// `target = value` or `target op= value`, target is a variable, a field or an element like `xs[i]`
+    AssignExpr(Box<AstExpr>, Option<Op>, Box<AstExpr>),
+    IfExpr(Box<AstExpr>, Box<AstExpr>, Option<Box<AstExpr>>),//last stmt is return value
+    /// Value matched against patterns of arms in order
+    Match(Box<AstExpr>, Vec<MatchArm>),
+    /// `val x = maybe` as condition of `if`, it holds if the optional has a value,
+    /// which is bound to `x` in the `then` branch
+    OptionalBinding(String, Box<AstExpr>),
+    /// `maybe?.field` or `maybe?.method(args)`, `none` if the optional has no value
+    SafeAccess(Box<AstExpr>, String, Option<Option<Vec<AstExpr>>>),
+    /// `maybe ?? default`
+    Coalesce(Box<AstExpr>, Box<AstExpr>),
+    /// `try { .. } catch e { .. } finally { .. }`, at least one of `catch` and `finally` is present
+    Try(Box<AstExpr>, Option<CatchClause>, Option<Box<AstExpr>>),
+    /// `|x: int| -> int { .. }` or `|x: int| expr`, the return type is inferred from the body if left out
+    Lambda(Vec<(String, TypeInfo)>, Option<TypeInfo>, Box<AstExpr>),
+}
+
+/// `pattern if guard => body`
+#[derive(Debug, PartialEq, Clone)]
+pub struct MatchArm {
+    pub pattern: AstPattern,
+    pub guard: Option<Box<AstExpr>>,
+    pub body: Box<AstExpr>,
+}
+
+/// `catch e { .. }`, the error may be left unnamed
+#[derive(Debug, PartialEq, Clone)]
+pub struct CatchClause {
+    pub binding: Option<String>,
+    pub body: Box<AstExpr>,
+}
+
+pub type AstPattern = Spanned<PatternNode>;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatternNode {
+    /// `_` matches anything
+    Wildcard,
+    /// Name bound to the matched value
+    Bind(String),
+    /// Integer, bool or string literal, compared by `==`
+    Literal(Box<AstExpr>),
+    /// `start..end` or `start..=end` of integers
+    Range(i64, i64, bool),
+    /// `Enum.Variant` or `Enum.Variant(patterns of payload)`
+    Variant(String, String, Vec<AstPattern>),
+    /// `(a, b, ...)`
+    Tuple(Vec<AstPattern>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -43,6 +150,27 @@ pub enum Op {
     InfixFn(String),
 }
 
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Add => write!(f, "+"),
+            Op::Sub => write!(f, "-"),
+            Op::Mul => write!(f, "*"),
+            Op::Div => write!(f, "/"),
+            Op::Rem => write!(f, "%"),
+            Op::Lt => write!(f, "<"),
+            Op::Le => write!(f, "<="),
+            Op::Eq => write!(f, "=="),
+            Op::Ne => write!(f, "!="),
+            Op::Gt => write!(f, ">"),
+            Op::Ge => write!(f, ">="),
+            Op::And => write!(f, "&&"),
+            Op::Or => write!(f, "||"),
+            Op::InfixFn(name) => write!(f, "`{}`", name),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum UnaryOp {
     Plus,
@@ -50,6 +178,16 @@ pub enum UnaryOp {
     Not,
 }
 
+impl Display for UnaryOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnaryOp::Plus => write!(f, "+"),
+            UnaryOp::Minus => write!(f, "-"),
+            UnaryOp::Not => write!(f, "!"),
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum TypeInfo {
     Int,
@@ -57,27 +195,244 @@ pub enum TypeInfo {
     Bool,
     Unit,
     Any,
-    TypeSym(String),
+    String,
+    /// Value thrown by `throw` and caught by `catch`
+    Error,
+    /// `List<T>`, the empty list literal is `List<any>` until it meets an expected type
+    List(Box<TypeInfo>),
+    /// `Map<K, V>`, the empty map literal is `Map<any, any>` until it meets an expected type
+    Map(Box<TypeInfo>, Box<TypeInfo>),
+    /// `(A, B, ...)` with at least two elements
+    Tuple(Vec<TypeInfo>),
+    /// `T?`, value of `T` or `none`, the `none` literal is `any?` until it meets an expected type
+    Optional(Box<TypeInfo>),
+    /// `fn(A, B) -> R` of lambdas, the return type is `unit` if left out
+    Function(Vec<TypeInfo>, Box<TypeInfo>),
+    /// Type parameter of a generic function or class, like `T` of `fn first<T>(xs: List<T>) -> T`
+    Param(String),
+    /// Class or enum by name, with type arguments if it is a generic class, like `Stack<int>`
+    TypeSym(String, Vec<TypeInfo>),
 }
 
-impl ToString for TypeInfo {
-    fn to_string(&self) -> String {
+impl Display for TypeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            TypeInfo::Int => String::from("int"),
-            TypeInfo::Float => String::from("float"),
-            TypeInfo::Bool => String::from("bool"),
-            TypeInfo::Unit => String::from("unit"),
-            TypeInfo::Any => String::from("any"),
-            TypeInfo::TypeSym(sym) => format!(".{}", sym)
+            TypeInfo::Int => write!(f, "int"),
+            TypeInfo::Float => write!(f, "float"),
+            TypeInfo::Bool => write!(f, "bool"),
+            TypeInfo::Unit => write!(f, "unit"),
+            TypeInfo::Any => write!(f, "any"),
+            TypeInfo::String => write!(f, "String"),
+            TypeInfo::Error => write!(f, "Error"),
+            TypeInfo::List(elem) => write!(f, "List<{}>", elem),
+            TypeInfo::Map(key, value) => write!(f, "Map<{},{}>", key, value),
+            TypeInfo::Tuple(items) => write!(f, "({})", items.iter().map(TypeInfo::to_string).collect::<Vec<String>>().join(",")),
+            TypeInfo::Optional(inner) => write!(f, "{}?", inner),
+            TypeInfo::Function(params, ret) => {
+                write!(f, "fn({})->{}", params.iter().map(TypeInfo::to_string).collect::<Vec<String>>().join(","), ret)
+            }
+            TypeInfo::Param(name) => f.write_str(name),
+            TypeInfo::TypeSym(sym, args) if args.is_empty() => write!(f, ".{}", sym),
+            TypeInfo::TypeSym(sym, args) => {
+                write!(f, ".{}<{}>", sym, args.iter().map(TypeInfo::to_string).collect::<Vec<String>>().join(","))
+            }
         }
     }
 }
 
 impl TypeInfo {
+    /// Name of type shown to user in diagnostics
+    pub fn display_name(&self) -> String {
+        match self {
+            TypeInfo::TypeSym(sym, args) if args.is_empty() => sym.clone(),
+            TypeInfo::TypeSym(sym, args) => {
+                format!("{}<{}>", sym, args.iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", "))
+            }
+            TypeInfo::List(elem) => format!("List<{}>", elem.display_name()),
+            TypeInfo::Map(key, value) => format!("Map<{}, {}>", key.display_name(), value.display_name()),
+            TypeInfo::Tuple(items) => {
+                format!("({})", items.iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", "))
+            }
+            _ if self.is_none_literal() => String::from("none"),
+            TypeInfo::Optional(inner) => format!("{}?", inner.display_name()),
+            TypeInfo::Function(params, ret) => {
+                let params = params.iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", ");
+                match &**ret {
+                    TypeInfo::Unit => format!("fn({})", params),
+                    ret => format!("fn({}) -> {}", params, ret.display_name()),
+                }
+            }
+            ty => ty.to_string(),
+        }
+    }
+
     pub fn is_can_convert_to(&self, target: &TypeInfo) -> bool {
         self == target
             || (self == &TypeInfo::Int && target == & TypeInfo::Float)
             || target == &TypeInfo::Any
+            || self.fills_unknown_elem(target)
+            || matches!(target, TypeInfo::Optional(inner) if !matches!(self, TypeInfo::Optional(_)) && self.is_can_convert_to(inner))
+    }
+
+    /// Type of `[]`, `{}` or `none`, whose element types are unknown
+    pub fn is_empty_literal(&self) -> bool {
+        match self {
+            TypeInfo::List(elem) | TypeInfo::Optional(elem) => **elem == TypeInfo::Any,
+            TypeInfo::Map(key, value) => **key == TypeInfo::Any && **value == TypeInfo::Any,
+            _ => false,
+        }
+    }
+
+    /// Whether it is the type of a literal with empty collections inside,
+    /// whose unknown element types can be taken from `target`
+    pub fn fills_unknown_elem(&self, target: &TypeInfo) -> bool {
+        let fits = |elem: &TypeInfo, target: &TypeInfo| elem == target || elem.fills_unknown_elem(target);
+        match (self, target) {
+            _ if self.is_empty_literal() => std::mem::discriminant(self) == std::mem::discriminant(target),
+            (TypeInfo::List(elem), TypeInfo::List(target_elem)) => fits(elem, target_elem),
+            (TypeInfo::Optional(inner), TypeInfo::Optional(target_inner)) => fits(inner, target_inner),
+            (TypeInfo::Map(key, value), TypeInfo::Map(target_key, target_value)) => {
+                fits(key, target_key) && fits(value, target_value)
+            }
+            (TypeInfo::Tuple(items), TypeInfo::Tuple(target_items)) => {
+                items.len() == target_items.len() && items.iter().zip(target_items).all(|(item, target)| fits(item, target))
+            }
+            // instance of generic class whose type arguments are not inferred, like `Stack()`
+            (TypeInfo::TypeSym(name, args), TypeInfo::TypeSym(target_name, target_args)) => {
+                name == target_name
+                    && args.len() == target_args.len()
+                    && args.iter().zip(target_args).all(|(arg, target)| arg == &TypeInfo::Any || fits(arg, target))
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether an empty literal is part of the type, so it can't be the type of a variable
+    pub fn has_unknown_elem(&self) -> bool {
+        match self {
+            TypeInfo::List(elem) | TypeInfo::Optional(elem) => **elem == TypeInfo::Any || elem.has_unknown_elem(),
+            TypeInfo::Map(key, value) => {
+                **key == TypeInfo::Any || **value == TypeInfo::Any || key.has_unknown_elem() || value.has_unknown_elem()
+            }
+            TypeInfo::Tuple(items) => items.iter().any(TypeInfo::has_unknown_elem),
+            TypeInfo::TypeSym(_, args) => args.iter().any(|arg| arg == &TypeInfo::Any || arg.has_unknown_elem()),
+            _ => false,
+        }
+    }
+
+    /// Type of `none` literal
+    pub fn is_none_literal(&self) -> bool {
+        matches!(self, TypeInfo::Optional(inner) if **inner == TypeInfo::Any)
+    }
+
+    /// Type both branches of `if` can be converted to if one of them is optional, like `int?` for `int` and `none`,
+    /// or has empty literals inside, like `List<int>` for `List<int>` and `[]`
+    pub fn join(&self, other: &TypeInfo) -> Option<TypeInfo> {
+        if self.fills_unknown_elem(other) {
+            return Some(other.clone());
+        } else if other.fills_unknown_elem(self) {
+            return Some(self.clone());
+        }
+        match (self, other) {
+            (TypeInfo::Optional(_), TypeInfo::Optional(_)) if self.is_none_literal() => Some(other.clone()),
+            (TypeInfo::Optional(_), TypeInfo::Optional(_)) => other.is_none_literal().then(|| self.clone()),
+            (TypeInfo::Optional(inner), ty) | (ty, TypeInfo::Optional(inner)) if self.is_none_literal() || other.is_none_literal() || **inner == *ty => {
+                Some(ty.clone().optional())
+            }
+            _ => None,
+        }
+    }
+
+    /// Make the type optional, an optional type stays the same
+    pub fn optional(self) -> TypeInfo {
+        match self {
+            TypeInfo::Optional(_) => self,
+            ty => TypeInfo::Optional(Box::new(ty)),
+        }
+    }
+
+    /// Whether values of the type can be keys of a map
+    pub fn is_hashable(&self) -> bool {
+        matches!(self, TypeInfo::Int | TypeInfo::Bool | TypeInfo::String)
+    }
+
+    /// Replace type parameters by the types bound to them
+    pub fn substitute(&self, bindings: &HashMap<String, TypeInfo>) -> TypeInfo {
+        match self {
+            TypeInfo::Param(name) => bindings.get(name).cloned().unwrap_or_else(|| self.clone()),
+            TypeInfo::List(elem) => TypeInfo::List(Box::new(elem.substitute(bindings))),
+            TypeInfo::Map(key, value) => TypeInfo::Map(Box::new(key.substitute(bindings)), Box::new(value.substitute(bindings))),
+            TypeInfo::Tuple(items) => TypeInfo::Tuple(items.iter().map(|item| item.substitute(bindings)).collect()),
+            TypeInfo::Optional(inner) => TypeInfo::Optional(Box::new(inner.substitute(bindings))),
+            TypeInfo::Function(params, ret) => {
+                TypeInfo::Function(params.iter().map(|param| param.substitute(bindings)).collect(), Box::new(ret.substitute(bindings)))
+            }
+            TypeInfo::TypeSym(name, args) => TypeInfo::TypeSym(name.clone(), args.iter().map(|arg| arg.substitute(bindings)).collect()),
+            ty => ty.clone(),
+        }
+    }
+
+    /// Turn names of type parameters in `names` into `Param`, parser reads them as class names
+    pub fn resolve_params(&self, names: &[String]) -> TypeInfo {
+        let resolve = |ty: &TypeInfo| ty.resolve_params(names);
+        match self {
+            TypeInfo::TypeSym(name, args) if args.is_empty() && names.contains(name) => TypeInfo::Param(name.clone()),
+            TypeInfo::TypeSym(name, args) => TypeInfo::TypeSym(name.clone(), args.iter().map(resolve).collect()),
+            TypeInfo::List(elem) => TypeInfo::List(Box::new(resolve(elem))),
+            TypeInfo::Map(key, value) => TypeInfo::Map(Box::new(resolve(key)), Box::new(resolve(value))),
+            TypeInfo::Tuple(items) => TypeInfo::Tuple(items.iter().map(resolve).collect()),
+            TypeInfo::Optional(inner) => TypeInfo::Optional(Box::new(resolve(inner))),
+            TypeInfo::Function(params, ret) => TypeInfo::Function(params.iter().map(resolve).collect(), Box::new(resolve(ret))),
+            ty => ty.clone(),
+        }
+    }
+
+    /// Add names of type parameters in the type to `names`, in order of appearance
+    pub fn collect_params(&self, names: &mut Vec<String>) {
+        match self {
+            TypeInfo::Param(name) if !names.contains(name) => names.push(name.clone()),
+            TypeInfo::List(inner) | TypeInfo::Optional(inner) => inner.collect_params(names),
+            TypeInfo::Map(key, value) => {
+                key.collect_params(names);
+                value.collect_params(names);
+            }
+            TypeInfo::Tuple(items) | TypeInfo::TypeSym(_, items) => items.iter().for_each(|item| item.collect_params(names)),
+            TypeInfo::Function(params, ret) => {
+                params.iter().for_each(|param| param.collect_params(names));
+                ret.collect_params(names);
+            }
+            _ => {}
+        }
+    }
+
+    /// Bind type parameters of `self` not bound yet to the parts of `actual` in their place,
+    /// like `U` to `String` for `fn(T) -> U` and `fn(int) -> String`
+    pub fn bind_params(&self, actual: &TypeInfo, bindings: &mut HashMap<String, TypeInfo>) {
+        match (self, actual) {
+            (TypeInfo::Param(name), ty) => {
+                // a type known from a later argument wins over an empty literal, like `[]` before `1`
+                let bound = bindings.entry(name.clone()).or_insert_with(|| ty.clone());
+                if *bound == TypeInfo::Any || bound.fills_unknown_elem(ty) {
+                    *bound = ty.clone();
+                }
+            }
+            (TypeInfo::List(elem), TypeInfo::List(actual_elem)) | (TypeInfo::Optional(elem), TypeInfo::Optional(actual_elem)) => {
+                elem.bind_params(actual_elem, bindings)
+            }
+            (TypeInfo::Optional(inner), ty) => inner.bind_params(ty, bindings),
+            (TypeInfo::Map(key, value), TypeInfo::Map(actual_key, actual_value)) => {
+                key.bind_params(actual_key, bindings);
+                value.bind_params(actual_value, bindings);
+            }
+            (TypeInfo::Tuple(items), TypeInfo::Tuple(actual_items)) | (TypeInfo::TypeSym(_, items), TypeInfo::TypeSym(_, actual_items)) => {
+                items.iter().zip(actual_items).for_each(|(item, actual)| item.bind_params(actual, bindings))
+            }
+            (TypeInfo::Function(params, ret), TypeInfo::Function(actual_params, actual_ret)) => {
+                params.iter().zip(actual_params).for_each(|(param, actual)| param.bind_params(actual, bindings));
+                ret.bind_params(actual_ret, bindings);
+            }
+            _ => {}
+        }
     }
 }
 
@@ -88,7 +443,9 @@ impl From<String> for TypeInfo {
             "float" => TypeInfo::Float,
             "bool" => TypeInfo::Bool,
             "unit" => TypeInfo::Unit,
-            _ => TypeInfo::TypeSym(tok)
+            "String" => TypeInfo::String,
+            "Error" => TypeInfo::Error,
+            _ => TypeInfo::TypeSym(tok, Vec::new())
         }
     }
 }
@@ -100,7 +457,9 @@ impl From<&str> for TypeInfo {
             "float" => TypeInfo::Float,
             "bool" => TypeInfo::Bool,
             "unit" => TypeInfo::Unit,
-            oth => TypeInfo::TypeSym(String::from(oth))
+            "String" => TypeInfo::String,
+            "Error" => TypeInfo::Error,
+            oth => TypeInfo::TypeSym(String::from(oth), Vec::new())
         }
     }
 }
@@ -1,26 +1,57 @@
-use crate::frontend::ast::basic::{StmtBlock, TypeInfo};
+use std::collections::HashMap;
+
+use crate::frontend::ast::basic::{AstExpr, StmtBlock, TypeInfo};
 use crate::frontend::ast::func::{FunctionBasicInfo, FunctionMatcher};
+use crate::frontend::span::Span;
 
 #[derive(Debug, PartialEq)]
 pub enum ProgramElement {
-    Import(String),
+    Import(String, Span),
     Function(AstProgramFunctionImplElement),
-    Class(ProgramClassElement)
+    Class(ProgramClassElement),
+    Enum(ProgramEnumElement),
+    Interface(ProgramInterfaceElement),
+    Impl(ProgramImplElement),
+    Global(ProgramGlobalElement),
 }
 
 impl ProgramElement {
     pub fn set_module(self, module_name: String) -> Self {
         match self {
-            ProgramElement::Import(_) => self,
+            ProgramElement::Import(..) => self,
             ProgramElement::Function(mut e) => {
                 e.header.module = Some(module_name);
                 ProgramElement::Function(e)
             }
 
             ProgramElement::Class(mut e) => {
+                for method in &mut e.methods {
+                    method.header.module = Some(module_name.clone());
+                }
                 e.module = module_name;
                 ProgramElement::Class(e)
             }
+            ProgramElement::Enum(mut e) => {
+                e.module = module_name;
+                ProgramElement::Enum(e)
+            }
+            ProgramElement::Interface(mut e) => {
+                for method in &mut e.methods {
+                    method.module = Some(module_name.clone());
+                }
+                e.module = module_name;
+                ProgramElement::Interface(e)
+            }
+            ProgramElement::Impl(mut e) => {
+                for method in &mut e.methods {
+                    method.header.module = Some(module_name.clone());
+                }
+                ProgramElement::Impl(e)
+            }
+            ProgramElement::Global(mut e) => {
+                e.module = module_name;
+                ProgramElement::Global(e)
+            }
         }
     }
 }
@@ -29,6 +60,8 @@ impl ProgramElement {
 /// Parser will save source info to this struct
 /// This struct wouldn't exists long, instr will be generate in
 pub struct AstProgramFunctionImplElement {
+    /// Text of doc comments before the function, one line per `///`
+    pub doc: Option<String>,
     pub header: FunctionBasicInfo,
     pub block: StmtBlock,
 }
@@ -41,6 +74,138 @@ impl FunctionMatcher for AstProgramFunctionImplElement {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ProgramClassElement {
+    /// Text of doc comments before the class, one line per `///`
+    pub doc: Option<String>,
+    pub name: String,
+    /// Names of type parameters of a generic class, `Param` types of its fields and methods refer to them
+    pub type_params: Vec<String>,
+    pub module: String,
+    pub fields: Vec<ClassField>,
+    /// Methods are named `Class.method`, those taking `self` first are called on instances.
+    /// Method `new` is the constructor. Methods of `impl` blocks are named `Class.Interface.method`
+    /// to stay apart from methods of the same name in other interfaces
+    pub methods: Vec<AstProgramFunctionImplElement>,
+    /// Interfaces implemented by `impl` blocks, with span of the block
+    pub interfaces: Vec<(String, Span)>,
+    pub span: Span,
+}
+
+impl ProgramClassElement {
+    pub const CTOR: &'static str = "new";
+
+    /// Type of `self`, type arguments are its own type parameters
+    pub fn self_type(&self) -> TypeInfo {
+        TypeInfo::TypeSym(self.name.clone(), self.type_params.iter().cloned().map(TypeInfo::Param).collect())
+    }
+
+    /// Type arguments of instance type `ty` bound to type parameters of the class
+    pub fn bindings(&self, ty: &TypeInfo) -> HashMap<String, TypeInfo> {
+        match ty {
+            TypeInfo::TypeSym(_, args) => self.type_params.iter().cloned().zip(args.iter().cloned()).collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    pub fn method_name(&self, name: &str) -> String {
+        format!("{}.{}", self.name, name)
+    }
+
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field.name == name)
+    }
+
+    /// Methods called `name`, including those of `impl` blocks
+    pub fn methods_named(&self, name: &str) -> impl Iterator<Item = &AstProgramFunctionImplElement> {
+        let name = name.to_string();
+        self.methods.iter().filter(move |method| self.short_name(&method.header) == name)
+    }
+
+    /// `method` of `Class.method` or `Class.Interface.method`
+    pub fn short_name<'a>(&self, header: &'a FunctionBasicInfo) -> &'a str {
+        let name = &header.name[self.name.len() + 1..];
+        name.split_once('.').map_or(name, |(_, name)| name)
+    }
+}
+
+/// Methods a class must have to be used as the interface, values of the interface type call them by dynamic dispatch
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramInterfaceElement {
+    /// Text of doc comments before the interface, one line per `///`
+    pub doc: Option<String>,
     pub name: String,
-    pub module: String
-}
\ No newline at end of file
+    pub module: String,
+    /// Methods are named `Interface.method` and take `self` of the interface type first
+    pub methods: Vec<FunctionBasicInfo>,
+    /// `None` for interfaces of prelude
+    pub span: Option<Span>,
+}
+
+impl ProgramInterfaceElement {
+    pub fn method(&self, name: &str) -> Option<&FunctionBasicInfo> {
+        let name = format!("{}.{}", self.name, name);
+        self.methods.iter().find(|method| method.name == name)
+    }
+}
+
+/// `impl Interface for Class { methods }`, its methods are added to the class
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramImplElement {
+    pub interface: String,
+    pub class: String,
+    pub methods: Vec<AstProgramFunctionImplElement>,
+    pub span: Span,
+}
+
+/// Module level `val` or `var`, initializers run once before `main` in order of their dependencies
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramGlobalElement {
+    pub name: String,
+    pub module: String,
+    /// Type of the initializer if it is not given
+    pub ty: Option<TypeInfo>,
+    pub is_val: bool,
+    pub value: Box<AstExpr>,
+    pub span: Span,
+}
+
+/// Field of class, `val` fields can only be assigned by constructor
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassField {
+    pub name: String,
+    pub ty: TypeInfo,
+    pub is_val: bool,
+    /// Set before constructor runs
+    pub default: Option<Box<AstExpr>>,
+    pub span: Span,
+}
+
+/// Enum whose values are one of its variants, each variant may carry a payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramEnumElement {
+    /// Text of doc comments before the enum, one line per `///`
+    pub doc: Option<String>,
+    pub name: String,
+    pub module: String,
+    /// Tag of a variant is its position
+    pub variants: Vec<EnumVariant>,
+    pub span: Span,
+}
+
+impl ProgramEnumElement {
+    pub fn variant_index(&self, name: &str) -> Option<usize> {
+        self.variants.iter().position(|variant| variant.name == name)
+    }
+
+    /// `Enum.Variant`, as written by user
+    pub fn variant_path(&self, tag: usize) -> String {
+        format!("{}.{}", self.name, self.variants[tag].name)
+    }
+}
+
+/// Variant of enum with types of its payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumVariant {
+    pub name: String,
+    pub fields: Vec<TypeInfo>,
+    pub span: Span,
+}
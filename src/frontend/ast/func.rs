@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
 use crate::frontend::ast::basic::TypeInfo;
+use crate::frontend::span::Span;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct FunctionBasicInfo {
@@ -6,6 +10,8 @@ pub struct FunctionBasicInfo {
     pub module: Option<String>,
     pub param: Option<Vec<(String, TypeInfo)>>,
     pub ret: Option<TypeInfo>,
+    /// Location of function header, `None` for vm builtin functions
+    pub span: Option<Span>,
 }
 
 pub trait FunctionMatcher {
@@ -14,7 +20,7 @@ pub trait FunctionMatcher {
 
 impl FunctionMatcher for FunctionBasicInfo {
     fn is_executable_by(&self, name: &str, param: Option<&Vec<TypeInfo>>) -> bool {
-        if &self.name != name {
+        if self.name != name {
             false // Name is not matched!!!
         } else if let Some(ref self_param) = self.param {
             if let Some(param) = param {
@@ -22,8 +28,10 @@ impl FunctionMatcher for FunctionBasicInfo {
                     // Arguments number is not matched
                     false
                 } else {
+                    // type parameters of generic function take types of arguments
+                    let bindings = self.infer(param);
                     for i in 0..self_param.len() {
-                        if !param[i].is_can_convert_to(&self_param[i].1) {
+                        if !param[i].is_can_convert_to(&self_param[i].1.substitute(&bindings)) {
                             // A param can't be converted as requirement
                             return false;
                         }
@@ -35,19 +43,86 @@ impl FunctionMatcher for FunctionBasicInfo {
                 false
             }
         } else {
-            param == None
+            param.is_none()
         }
     }
 }
 
 
 impl FunctionBasicInfo {
+    /// Header of generic function with type parameters replaced by `bindings`
+    pub fn instantiate(&self, bindings: &HashMap<String, TypeInfo>) -> FunctionBasicInfo {
+        FunctionBasicInfo {
+            param: self.param.as_ref().map(|params| params
+                .iter()
+                .map(|(name, ty)| (name.clone(), ty.substitute(bindings)))
+                .collect()),
+            ret: self.ret.as_ref().map(|ret| ret.substitute(bindings)),
+            ..self.clone()
+        }
+    }
+
+    /// Header with names of type parameters in parameter and return types turned into `Param`
+    pub fn resolve_params(self, names: &[String]) -> FunctionBasicInfo {
+        FunctionBasicInfo {
+            param: self.param.as_ref().map(|params| params
+                .iter()
+                .map(|(name, ty)| (name.clone(), ty.resolve_params(names)))
+                .collect()),
+            ret: self.ret.as_ref().map(|ret| ret.resolve_params(names)),
+            ..self
+        }
+    }
+
+    /// Names of type parameters of a generic function, in order of appearance in its header
+    pub fn type_params(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for ty in self.param.iter().flatten().map(|(_, ty)| ty).chain(&self.ret) {
+            ty.collect_params(&mut names);
+        }
+        names
+    }
+
+    /// Bind type parameters to the types of arguments at their places, see `TypeInfo::bind_params`
+    pub fn infer(&self, args: &[TypeInfo]) -> HashMap<String, TypeInfo> {
+        let mut bindings = HashMap::new();
+        for ((_, param), arg) in self.param.iter().flatten().zip(args) {
+            param.bind_params(arg, &mut bindings);
+        }
+        bindings
+    }
+
+    /// Whether it is a method called on an instance
+    pub fn takes_self(&self) -> bool {
+        self.param.iter().flatten().next().map(|(name, _)| name == "self").unwrap_or(false)
+    }
+
+    /// Types of parameters without their names
+    pub fn param_types(&self) -> Vec<&TypeInfo> {
+        self.param.iter().flatten().map(|(_, ty)| ty).collect()
+    }
+
     pub fn param_size(&self)->usize {
         self.param
             .as_ref()
             .map(Vec::len)
             .unwrap_or(0)
     }
+    /// Function as declared in source, like `fn inner(x: int) -> int`, shown in backtrace
+    pub fn declaration(&self) -> String {
+        let params = self.param.iter().flatten()
+            .map(|(name, ty)| match name.as_str() {
+                "self" => name.clone(),
+                _ => format!("{}: {}", name, ty.display_name()),
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        match self.ret.as_ref().unwrap_or(&TypeInfo::Unit) {
+            TypeInfo::Unit => format!("fn {}({})", self.name, params),
+            ret => format!("fn {}({}) -> {}", self.name, params, ret.display_name()),
+        }
+    }
+
     pub fn signature(&self) -> String {
         let ret = self.ret.as_ref().map(|x| x.to_string()).unwrap_or(String::from("V"));
 
@@ -74,9 +149,9 @@ impl FunctionBasicInfo {
     }
 }
 
-impl ToString for FunctionBasicInfo {
-    fn to_string(&self) -> String {
-        self.signature()
+impl Display for FunctionBasicInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.signature())
     }
 }
 
@@ -1,3 +1,4 @@
 pub mod func;
 pub mod element;
-pub mod basic;
\ No newline at end of file
+pub mod basic;
+pub mod typed;
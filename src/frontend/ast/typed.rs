@@ -0,0 +1,194 @@
+use crate::frontend::ast::basic::{Op, Spanned, TypeInfo, UnaryOp};
+use crate::frontend::ast::func::FunctionBasicInfo;
+use crate::frontend::span::Span;
+use crate::vm::builtin::ProgramVmFnElement;
+
+/// Expression checked by typeck, names are resolved and implicit conversions are explicit
+#[derive(Debug, Clone)]
+pub struct TypedExpr {
+    pub node: TypedExprNode,
+    pub ty: TypeInfo,
+    pub span: Span,
+}
+
+impl TypedExpr {
+    pub fn new(node: TypedExprNode, ty: TypeInfo, span: Span) -> Self {
+        Self { node, ty, span }
+    }
+}
+
+pub type TypedStmt = Spanned<TypedStmtNode>;
+
+#[derive(Debug, Clone)]
+pub enum TypedStmtNode {
+    Expr(TypedExpr),
+    /// Return the value after running the statements, which leave the `try` blocks being returned from
+    Return(Option<TypedExpr>, Vec<TypedStmt>),
+    /// Initialize local variable slot
+    Var(usize, TypedExpr),
+    /// Keep tuple in hidden slot and initialize variable slots by its elements, `None` skips the element
+    TupleVar {
+        tuple_slot: usize,
+        slots: Vec<Option<usize>>,
+        tuple: TypedExpr,
+    },
+    While(TypedExpr, Vec<TypedStmt>),
+    /// Counting loop over `int`, bounds are evaluated once into hidden slots
+    ForRange {
+        var_slot: usize,
+        start: Box<TypedExpr>,
+        end_slot: usize,
+        end: Box<TypedExpr>,
+        inclusive: bool,
+        /// Step is `1` if it is not given
+        step: Option<(usize, Box<TypedExpr>)>,
+        body: Vec<TypedStmt>,
+    },
+    /// Loop over elements of a list, the list and position are kept in hidden slots
+    ForEach {
+        var_slot: usize,
+        list_slot: usize,
+        index_slot: usize,
+        iterable: Box<TypedExpr>,
+        body: Vec<TypedStmt>,
+    },
+    /// Leave the loop, depth `0` is the innermost loop
+    Break(usize),
+    /// Next iteration of the loop, depth `0` is the innermost loop
+    Continue(usize),
+    /// Raise the `Error`
+    Throw(TypedExpr),
+    /// Stop catching errors by the innermost `try` block, before jumping out of it
+    LeaveTry,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypedExprNode {
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    /// Optional without value
+    None,
+    /// Value as an optional, which has the same representation
+    ToOptional(Box<TypedExpr>),
+    /// Whether the optional has no value, it has type `bool`
+    IsNone(Box<TypedExpr>),
+    /// Allocate list of the elements
+    List(Vec<TypedExpr>),
+    /// Allocate map of the entries, later entries replace earlier ones of the same key
+    Map(Vec<(TypedExpr, TypedExpr)>),
+    /// Allocate tuple of the elements
+    Tuple(Vec<TypedExpr>),
+    /// Element of tuple at the index
+    TupleGet(Box<TypedExpr>, usize),
+    /// Read local variable slot
+    Load(usize),
+    /// Read module level variable of the module by index
+    LoadGlobal(String, usize),
+    /// Put the value in a cell, which is shared by the variable and closures capturing it
+    NewCell(Box<TypedExpr>),
+    /// Read variable kept in the cell of local variable slot
+    LoadCell(usize),
+    /// Write variable kept in the cell of local variable slot
+    AssignCell(usize, Box<TypedExpr>),
+    /// Closure of lambda compiled to the function of signature, capturing the values
+    Closure(String, Vec<TypedExpr>),
+    /// Call closure with the arguments, which are converted to parameter types
+    CallClosure(Box<TypedExpr>, Vec<TypedExpr>),
+    /// Both operands have the same type
+    Op(Box<TypedExpr>, Op, Box<TypedExpr>),
+    UnaryOp(UnaryOp, Box<TypedExpr>),
+    IntToFloat(Box<TypedExpr>),
+    /// Call function of the signature, arguments are converted to parameter types
+    Call(String, Vec<TypedExpr>),
+    /// Call method of interface like `Printable.show`, implemented by class of the first argument
+    CallVirtual(String, Vec<TypedExpr>),
+    /// Statements and the expression giving value of block
+    Block(Vec<TypedStmt>, Option<Box<TypedExpr>>),
+    Assign(usize, Box<TypedExpr>),
+    /// Write module level variable of the module by index
+    AssignGlobal(String, usize, Box<TypedExpr>),
+    /// Branches may have other types than the `if` when its value is unit
+    If(Box<TypedExpr>, Box<TypedExpr>, Option<Box<TypedExpr>>),
+    /// Allocate instance and call constructor on it, value is the instance
+    New {
+        class: String,
+        fields: Vec<String>,
+        ctor: String,
+        args: Vec<TypedExpr>,
+        /// Signature of the method implementing each method of interfaces implemented by class
+        vtable: Vec<(String, String)>,
+    },
+    /// Read field of instance by index
+    GetField(Box<TypedExpr>, usize),
+    /// Write field of instance by index, it has type `unit`
+    SetField(Box<TypedExpr>, usize, Box<TypedExpr>),
+    /// Element of list at `int` index or value of map at key, checked at runtime
+    Index(Box<TypedExpr>, Box<TypedExpr>),
+    /// Replace element of list at `int` index or insert value of map at key, it has type `unit`
+    SetIndex(Box<TypedExpr>, Box<TypedExpr>, Box<TypedExpr>),
+    /// Allocate value of enum variant with the payload
+    Variant {
+        name: String,
+        tag: usize,
+        args: Vec<TypedExpr>,
+    },
+    /// Tag of enum value, it has type `int`
+    VariantTag(Box<TypedExpr>),
+    /// Payload of enum value by index, the variant is known
+    VariantField(Box<TypedExpr>, usize),
+    /// Scrutinee is kept in hidden slot, the first arm whose steps all pass is taken
+    Match {
+        slot: usize,
+        scrutinee: Box<TypedExpr>,
+        arms: Vec<TypedMatchArm>,
+    },
+    /// Run `body`, if it raises an error, keep the error in the slot and run `handler` instead
+    Try {
+        body: Box<TypedExpr>,
+        slot: usize,
+        handler: Box<TypedExpr>,
+    },
+    /// Run `finally` after `body` however it is left. An error raised by `body` is kept in the slot
+    /// and raised again after `finally`, a `return`, `break` or `continue` runs its own copy of `finally`
+    Finally {
+        body: Box<TypedExpr>,
+        slot: usize,
+        finally: Box<TypedExpr>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedMatchArm {
+    /// Tests of pattern and guard, with bindings between them
+    pub steps: Vec<PatternStep>,
+    pub body: TypedExpr,
+}
+
+/// Pattern is lowered to a sequence of steps run in order
+#[derive(Debug, Clone)]
+pub enum PatternStep {
+    /// Arm is skipped if the condition is false
+    Test(TypedExpr),
+    /// Store part of scrutinee into variable slot
+    Bind(usize, TypedExpr),
+}
+
+#[derive(Debug, Clone)]
+pub struct TypedFunction {
+    pub header: FunctionBasicInfo,
+    pub local_var_size: usize,
+    pub body: Vec<TypedStmt>,
+}
+
+/// Module whose functions are all well typed, input of codegen
+pub struct TypedModule {
+    pub name: String,
+    pub functions: Vec<TypedFunction>,
+    pub vm_functions: Vec<ProgramVmFnElement>,
+    /// Names of module level variables by index
+    pub globals: Vec<String>,
+    /// Signature of the function initializing module level variables, if there is any
+    pub init: Option<String>,
+}
@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+
+use crate::frontend::ast::basic::{AccessedIdent, AstExpr, AstExprNode, AstStmt, AstStmtNode, ForIter};
+
+/// Called with each path of names like `a.b.c` and whether it is inside a lambda,
+/// the path of a call includes the function name
+type Mention<'m> = &'m mut dyn FnMut(&AccessedIdent, bool);
+
+/// Names used inside lambdas of the statements, variables of these names may be captured.
+/// Shadowing is not taken into account, at worst a variable is put in a cell needlessly
+pub fn used_in_lambdas(block: &[AstStmt]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    visit_block(block, false, &mut |path, in_lambda| {
+        if in_lambda {
+            names.insert(path[0].clone());
+        }
+    });
+    names
+}
+
+/// Names used inside lambdas nested in the expression
+pub fn used_in_lambdas_of(expr: &AstExpr) -> HashSet<String> {
+    let mut names = HashSet::new();
+    visit_expr(expr, false, &mut |path, in_lambda| {
+        if in_lambda {
+            names.insert(path[0].clone());
+        }
+    });
+    names
+}
+
+/// Names used anywhere in the expression, including lambdas nested in it
+pub fn used_by(expr: &AstExpr) -> HashSet<String> {
+    let mut names = HashSet::new();
+    visit_expr(expr, true, &mut |path, _| {
+        names.insert(path[0].clone());
+    });
+    names
+}
+
+/// Paths like `module.NAME` used anywhere in the expression, in order of appearance
+pub fn paths_used_by(expr: &AstExpr) -> Vec<AccessedIdent> {
+    let mut paths = Vec::new();
+    visit_expr(expr, false, &mut |path, _| paths.push(path.clone()));
+    paths
+}
+
+/// Paths used anywhere in the statements, like `paths_used_by`
+pub fn paths_used_in(block: &[AstStmt]) -> Vec<AccessedIdent> {
+    let mut paths = Vec::new();
+    visit_block(block, false, &mut |path, _| paths.push(path.clone()));
+    paths
+}
+
+fn visit_block(block: &[AstStmt], in_lambda: bool, mention: Mention) {
+    for stmt in block {
+        visit_stmt(stmt, in_lambda, mention);
+    }
+}
+
+fn visit_stmt(stmt: &AstStmt, in_lambda: bool, mention: Mention) {
+    match &stmt.node {
+        AstStmtNode::ExprStmt(expr)
+        | AstStmtNode::Throw(expr)
+        | AstStmtNode::RetStmt(Some(expr))
+        | AstStmtNode::VarStmt(_, _, _, expr)
+        | AstStmtNode::TupleVarStmt(_, _, _, expr) => visit_expr(expr, in_lambda, mention),
+        AstStmtNode::RetStmt(None) | AstStmtNode::Break(_) | AstStmtNode::Continue(_) => {}
+        AstStmtNode::WhileStmt(_, cond, body) => {
+            visit_expr(cond, in_lambda, mention);
+            visit_block(body, in_lambda, mention);
+        }
+        AstStmtNode::ForStmt(_, _, iter, body) => {
+            match iter {
+                ForIter::Range { start, end, step, .. } => {
+                    visit_expr(start, in_lambda, mention);
+                    visit_expr(end, in_lambda, mention);
+                    if let Some(step) = step {
+                        visit_expr(step, in_lambda, mention);
+                    }
+                }
+                ForIter::Iterable(iterable) => visit_expr(iterable, in_lambda, mention),
+            }
+            visit_block(body, in_lambda, mention);
+        }
+    }
+}
+
+fn visit_exprs<'a>(exprs: impl IntoIterator<Item = &'a AstExpr>, in_lambda: bool, mention: Mention) {
+    for expr in exprs {
+        visit_expr(expr, in_lambda, mention);
+    }
+}
+
+fn visit_expr(expr: &AstExpr, in_lambda: bool, mention: Mention) {
+    match &expr.node {
+        AstExprNode::Integer(_) | AstExprNode::Float(_) | AstExprNode::Bool(_) | AstExprNode::String(_) | AstExprNode::None => {}
+        AstExprNode::Ident(path) => mention(path, in_lambda),
+        AstExprNode::FnCall(path, args) => {
+            mention(path, in_lambda);
+            visit_exprs(args.iter().flatten(), in_lambda, mention);
+        }
+        AstExprNode::List(items) | AstExprNode::Tuple(items) => visit_exprs(items, in_lambda, mention),
+        AstExprNode::Map(entries) => {
+            for (key, value) in entries {
+                visit_expr(key, in_lambda, mention);
+                visit_expr(value, in_lambda, mention);
+            }
+        }
+        AstExprNode::Op(lhs, _, rhs)
+        | AstExprNode::Index(lhs, rhs)
+        | AstExprNode::Coalesce(lhs, rhs)
+        | AstExprNode::AssignExpr(lhs, _, rhs) => {
+            visit_expr(lhs, in_lambda, mention);
+            visit_expr(rhs, in_lambda, mention);
+        }
+        AstExprNode::FieldAccess(obj, _) | AstExprNode::UnaryOp(_, obj) | AstExprNode::OptionalBinding(_, obj) => {
+            visit_expr(obj, in_lambda, mention)
+        }
+        AstExprNode::MethodCall(receiver, _, args) => {
+            visit_expr(receiver, in_lambda, mention);
+            visit_exprs(args.iter().flatten(), in_lambda, mention);
+        }
+        AstExprNode::SafeAccess(receiver, _, args) => {
+            visit_expr(receiver, in_lambda, mention);
+            visit_exprs(args.iter().flatten().flatten(), in_lambda, mention);
+        }
+        AstExprNode::BlockExpr(block) => visit_block(block, in_lambda, mention),
+        AstExprNode::IfExpr(cond, then_branch, else_branch) => {
+            visit_expr(cond, in_lambda, mention);
+            visit_expr(then_branch, in_lambda, mention);
+            visit_exprs(else_branch.as_deref(), in_lambda, mention);
+        }
+        AstExprNode::Match(scrutinee, arms) => {
+            visit_expr(scrutinee, in_lambda, mention);
+            for arm in arms {
+                visit_exprs(arm.guard.as_deref(), in_lambda, mention);
+                visit_expr(&arm.body, in_lambda, mention);
+            }
+        }
+        AstExprNode::Try(body, catch, finally) => {
+            visit_expr(body, in_lambda, mention);
+            if let Some(catch) = catch {
+                visit_expr(&catch.body, in_lambda, mention);
+            }
+            visit_exprs(finally.as_deref(), in_lambda, mention);
+        }
+        AstExprNode::Lambda(_, _, body) => visit_expr(body, true, mention),
+    }
+}
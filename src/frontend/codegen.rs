@@ -1,579 +1,358 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::frontend::ast::basic::{AstExprNode, AstStmtNode, Op, TypeInfo, UnaryOp};
-use crate::frontend::ast::element::AstProgramFunctionImplElement;
-use crate::frontend::ast::func::FunctionBasicInfo;
-use crate::frontend::gen_info::{Env, GenInfo, VarInfo};
-use crate::frontend::module_man::ProgramModuleDecl;
-use crate::vm::builtin::builtin_class::ObjStr;
+use crate::frontend::ast::basic::{Op, TypeInfo, UnaryOp};
+use crate::frontend::ast::typed::{PatternStep, TypedExpr, TypedExprNode, TypedFunction, TypedModule, TypedStmt, TypedStmtNode};
+use crate::vm::builtin::builtin_class::{ClassLayout, ObjStr, VariantLayout};
 use crate::vm::builtin::ProgramVmFnElement;
-use crate::vm::instr::{Instr, Instructions};
+use crate::vm::instr::{Instr, Instructions, LineTable, LoopJump};
 use crate::vm::mem::Obj;
 use crate::vm::slot::Slot;
 use crate::vm::vm::{AutoScriptFunction, AutoScriptFunctionCode, AutoScriptPrototype};
 
 use super::gen_info::ConstantPoolBuilder;
 
+/// Translate well typed modules to instructions.
+/// An expression of `unit` type leaves nothing on operand stack
 pub struct CodeGen {
-    env: Env,
-    modules: HashMap<String, ProgramModuleDecl>,
+    modules: Vec<TypedModule>,
     const_pool_builder: ConstantPoolBuilder,
 }
 
-
 impl CodeGen {
-    pub fn new(modules: HashMap<String, ProgramModuleDecl>) -> Self {
+    pub fn new(modules: Vec<TypedModule>) -> Self {
         Self {
-            env: Env::default(),
             modules,
             const_pool_builder: ConstantPoolBuilder::new(),
         }
     }
 
-    fn find_function(
-        &self,
-        name: &str,
-        cur_module: &str,
-        access_module: Option<&str>,
-        param: Option<&Vec<TypeInfo>>,
-    ) -> Option<&FunctionBasicInfo> {
-        let module_name = access_module.unwrap_or(cur_module);
-        let module = self.modules.get(module_name).unwrap();
-        let prelude = self.modules.get("prelude").unwrap();
-        if let Some(header) = module.search_function(name, param) {
-            return Some(header);
-        } else if let Some(header) = prelude.search_function(name, param) {
-            return Some(header);
-        } else {
-            None
+    fn translate_function(&mut self, func: &TypedFunction) -> AutoScriptFunction {
+        let mut instr = self.translate_block(&func.body);
+        if func.header.ret.as_ref().unwrap_or(&TypeInfo::Unit) == &TypeInfo::Unit {
+            // implicit return at the end of function body
+            let ret: Instructions = vec![Instr::Return].into();
+            instr = instr + match func.header.span {
+                Some(ref span) => ret.with_span(span),
+                None => ret,
+            };
         }
-    }
-
-    fn translate_function(
-        &mut self,
-        program: &AstProgramFunctionImplElement,
-        cur_module: &str,
-    ) -> AutoScriptFunction {
-        self.env.push_scope();
-        let arg_num = if let Some(ref param) = program.header.param {
-            for i in param {
-                let slot_id = self.env.current_val_size();
-                self.env.val_insert(
-                    i.0.clone(),
-                    VarInfo {
-                        ty: i.1.clone(),
-                        binding_slot: slot_id,
-                        is_mut: false,
-                    },
-                );
-            }
-            param.len()
-        } else {
-            0
-        };
-        let instr: Instructions = self.translate_block(&program.block, cur_module, &program.header);
-        let table_size = self.env.max_val_table_size;
-        self.env.pop_scope();
         AutoScriptFunction {
-            name: program.header.name.clone(),
-            signature: program.header.signature(),
-            local_var_size: table_size,
-            arg_num,
-            code: AutoScriptFunctionCode::Instr(Rc::new(instr))
+            name: func.header.name.clone(),
+            signature: func.header.signature(),
+            declaration: func.header.declaration(),
+            local_var_size: func.local_var_size,
+            arg_num: func.header.param_size(),
+            line_table: instr.line_table(),
+            code: AutoScriptFunctionCode::Instr(Rc::new(instr)),
         }
     }
 
-    fn translate_stmt(
-        &mut self,
-        stmt: &AstStmtNode,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> Instructions {
-        match stmt {
-            AstStmtNode::ExprStmt(expr) => {
-                let expr = self.translate_expr(expr, cur_module, header);
+    fn translate_stmt(&mut self, stmt: &TypedStmt) -> Instructions {
+        match &stmt.node {
+            TypedStmtNode::Expr(expr) => {
+                let instr = self.translate_expr(expr);
                 let clean_instr = if expr.ty != TypeInfo::Unit { vec![Instr::Pop].into() } else { vec![].into() };
-                expr.instr + clean_instr
+                instr + clean_instr
             }
-            AstStmtNode::RetStmt(expr) => match expr {
-                Some(expr) => {
-                    let expr_info = self.translate_expr(expr, cur_module, header);
-                    assert_eq!(
-                        &expr_info.ty,
-                        header.ret.as_ref().unwrap_or(&TypeInfo::Unit)
-                    );
-
-                    expr_info.instr + vec![Instr::ReturnValue].into()
-                }
-                None => {
-                    assert_eq!(
-                        &TypeInfo::Unit,
-                        header.ret.as_ref().unwrap_or(&TypeInfo::Unit)
-                    );
-                    vec![Instr::Return].into()
+            // value stays on operand stack while `try` blocks are left
+            TypedStmtNode::Return(Some(expr), leave) if expr.ty != TypeInfo::Unit => {
+                self.translate_expr(expr) + self.translate_block(leave) + vec![Instr::ReturnValue].into()
+            }
+            TypedStmtNode::Return(Some(expr), leave) => {
+                self.translate_expr(expr) + self.translate_block(leave) + vec![Instr::Return].into()
+            }
+            TypedStmtNode::Return(None, leave) => self.translate_block(leave) + vec![Instr::Return].into(),
+            TypedStmtNode::Var(slot_index, expr) => {
+                self.translate_value(expr) + vec![Instr::Store(*slot_index)].into()
+            }
+            TypedStmtNode::TupleVar { tuple_slot, slots, tuple } => {
+                let mut instr = self.translate_expr(tuple) + vec![Instr::Store(*tuple_slot)].into();
+                for (idx, slot) in slots.iter().enumerate() {
+                    if let Some(slot) = slot {
+                        instr = instr + vec![Instr::Load(*tuple_slot), Instr::TupleGet(idx), Instr::Store(*slot)].into();
+                    }
                 }
-            },
-            AstStmtNode::VarStmt(name, ty_expect, is_not_mut, expr) => {
-                let expr_ret = self.translate_expr(expr, cur_module, header);
-                let ty_expect = ty_expect.clone().map(TypeInfo::from);
-                let (convert_instr, ty) = if let Some(ty_expect) = ty_expect {
-                    let instr = self.try_convert_type(&expr_ret.ty, &ty_expect);
-                    (instr, ty_expect.clone())
-                } else {
-                    (vec![].into(), expr_ret.ty.clone())
-                };
-                let slot_index = self.env.current_val_size();
-                let var_info = VarInfo::new(ty, slot_index, !*is_not_mut);
-                self.env.val_insert(name.clone(), var_info);
-
-                expr_ret.instr + convert_instr + vec![Instr::Store(slot_index)].into()
+                instr
             }
-            AstStmtNode::WhileStmt(cond, block) => {
-                let cond = self.translate_expr(cond, cur_module, header);
-                assert_eq!(cond.ty, TypeInfo::Bool);
-
-                let instr = self.translate_block(block, cur_module, header);
+            TypedStmtNode::While(cond, block) => {
+                let cond = self.translate_expr(cond);
+                let instr = self.translate_block(block);
                 let unsatisfied_offset = instr.len() as i32;
-                let rejudge_offset = -(unsatisfied_offset + 1 + cond.instr.len() as i32);
-                cond.instr
+                let rejudge_offset = -(unsatisfied_offset + 1 + cond.len() as i32);
+                let instr = cond
                     + vec![Instr::JumpIfN(unsatisfied_offset + 1)].into()
                     + instr
-                    + vec![Instr::Jump(rejudge_offset - 1)].into()
+                    + vec![Instr::Jump(rejudge_offset - 1)].into();
+                instr.resolve_loop_jumps(0)
             }
+            TypedStmtNode::ForRange { .. } => self.translate_for_range(stmt),
+            TypedStmtNode::ForEach { .. } => self.translate_for_each(stmt),
+            TypedStmtNode::Break(depth) => Instructions::loop_jump(LoopJump::Break(*depth)),
+            TypedStmtNode::Continue(depth) => Instructions::loop_jump(LoopJump::Continue(*depth)),
+            TypedStmtNode::Throw(error) => self.translate_expr(error) + vec![Instr::Throw].into(),
+            TypedStmtNode::LeaveTry => vec![Instr::TryEnd].into(),
         }
     }
 
-    fn translate_block(
-        &mut self,
-        block: &[AstStmtNode],
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> Instructions {
-        self.env.push_scope();
-        let instr = if block.is_empty() {
-            Instructions::new()
-        } else if block.len() == 1 {
-            self.translate_stmt(block.first().unwrap(), cur_module, header)
+    /// Lower counting loop to jumps:
+    /// store bounds, test loop variable against end, run body, add step, jump back to test.
+    /// Inclusive loop stops after the body run with the end, so `..=` up to the largest int doesn't overflow
+    fn translate_for_range(&mut self, stmt: &TypedStmt) -> Instructions {
+        let TypedStmtNode::ForRange { var_slot, start, end_slot, end, inclusive, step, body } = &stmt.node else { unreachable!() };
+        let (var_slot, end_slot) = (*var_slot, *end_slot);
+        let (before_end, after_end) = if *inclusive {
+            (Instr::CmpLe, Instr::CmpGe)
         } else {
-            block
-                .iter()
-                .map(|x| self.translate_stmt(x, cur_module, header))
-                .reduce(|a, b| a + b)
-                .unwrap()
+            (Instr::CmpLt, Instr::CmpGt)
         };
-        self.env.pop_scope();
-        instr
+        let mut init = self.translate_expr(start)
+            + vec![Instr::Store(var_slot)].into()
+            + self.translate_expr(end)
+            + vec![Instr::Store(end_slot)].into();
+        let (cond, load_step): (Instructions, Instr) = match step {
+            None => (vec![Instr::Load(var_slot), Instr::Load(end_slot), before_end].into(), Instr::IPush(1)),
+            Some((step_slot, step)) => {
+                init = init + self.translate_expr(step) + vec![Instr::CheckStep, Instr::Store(*step_slot)].into();
+                // direction of loop follows sign of step
+                let cond = vec![
+                    Instr::Load(*step_slot), Instr::IPush(0), Instr::CmpGt,
+                    Instr::Load(var_slot), Instr::Load(end_slot), before_end,
+                    Instr::BAnd,
+                    Instr::Load(*step_slot), Instr::IPush(0), Instr::CmpLt,
+                    Instr::Load(var_slot), Instr::Load(end_slot), after_end,
+                    Instr::BAnd,
+                    Instr::BOr,
+                ];
+                (cond.into(), Instr::Load(*step_slot))
+            }
+        };
+        let body = self.translate_block(body);
+        let mut increment: Instructions = vec![Instr::Load(var_slot), load_step, Instr::IAdd, Instr::Store(var_slot)].into();
+        if *inclusive {
+            // jumps over increment and the jump back
+            let done_offset = (increment.len() + 1) as i32;
+            let done: Instructions = vec![Instr::Load(var_slot), Instr::Load(end_slot), Instr::CmpEq, Instr::JumpIf(done_offset)].into();
+            increment = done + increment;
+        }
+        let loop_len = (cond.len() + 1 + body.len() + increment.len() + 1) as i32;
+        let exit_offset = (body.len() + increment.len() + 1) as i32;
+        // `continue` goes to increment, jumps in `init` belong to outer loops
+        let continue_pc = cond.len() + 1 + body.len();
+        let instr = cond
+            + vec![Instr::JumpIfN(exit_offset)].into()
+            + body
+            + increment
+            + vec![Instr::Jump(-loop_len)].into();
+        init + instr.resolve_loop_jumps(continue_pc)
     }
 
-    fn try_convert_type(&self, from: &TypeInfo, target: &TypeInfo) -> Instructions {
-        if from == target || target == &TypeInfo::Any {
-            vec![].into()
-        } else if from == &TypeInfo::Int && target == &TypeInfo::Float {
-            vec![Instr::I2F].into()
-        } else {
-            panic!()
-        }
+    /// Lower loop over list to counting over its indices, length is read on every test
+    /// so the body may change the list
+    fn translate_for_each(&mut self, stmt: &TypedStmt) -> Instructions {
+        let TypedStmtNode::ForEach { var_slot, list_slot, index_slot, iterable, body } = &stmt.node else { unreachable!() };
+        let (list_slot, index_slot) = (*list_slot, *index_slot);
+        let init = self.translate_expr(iterable)
+            + vec![Instr::Store(list_slot), Instr::IPush(0), Instr::Store(index_slot)].into();
+        let cond: Instructions = vec![Instr::Load(index_slot), Instr::Load(list_slot), Instr::ListLen, Instr::CmpLt].into();
+        let load_item: Instructions = vec![Instr::Load(list_slot), Instr::Load(index_slot), Instr::ListGet, Instr::Store(*var_slot)].into();
+        let body = self.translate_block(body);
+        let increment: Instructions = vec![Instr::Load(index_slot), Instr::IPush(1), Instr::IAdd, Instr::Store(index_slot)].into();
+        let loop_len = (cond.len() + 1 + load_item.len() + body.len() + increment.len() + 1) as i32;
+        let exit_offset = (load_item.len() + body.len() + increment.len() + 1) as i32;
+        let continue_pc = cond.len() + 1 + load_item.len() + body.len();
+        let instr = cond
+            + vec![Instr::JumpIfN(exit_offset)].into()
+            + load_item
+            + body
+            + increment
+            + vec![Instr::Jump(-loop_len)].into();
+        init + instr.resolve_loop_jumps(continue_pc)
     }
 
+    fn translate_block(&mut self, block: &[TypedStmt]) -> Instructions {
+        let mut instr = Instructions::new();
+        for stmt in block {
+            instr = instr + self.translate_stmt(stmt).with_span(&stmt.span);
+        }
+        instr
+    }
 
-    fn convert_internal_function(&self, func: ProgramVmFnElement) -> AutoScriptFunction {
+    fn convert_internal_function(&self, func: &ProgramVmFnElement) -> AutoScriptFunction {
         let param_size = func.header.param_size();
         AutoScriptFunction {
             name: func.header.name.clone(),
             signature: func.header.signature(),
+            declaration: func.header.declaration(),
             local_var_size: param_size,
             arg_num: param_size,
-            code: AutoScriptFunctionCode::Binding(func.block),
+            code: AutoScriptFunctionCode::Binding(Rc::clone(&func.block)),
+            line_table: LineTable::default(),
         }
     }
 
-    fn translate_module(&mut self, name: &str, output: &mut AutoScriptPrototype) -> Result<(), ()> {
-        let src_module = self.modules.get(name).unwrap().clone();
-        for element in src_module.function {
-            for func in element.1 {
-                let prototype = self.translate_function(&func, name);
-                output.insert_function_prototype(prototype.signature.clone(), prototype);
-            }
-        }
-        for element in src_module.vm_function {
-            for func in element.1 {
-                output.insert_function_prototype(func.header.signature(), self.convert_internal_function(func));
-            }
-        }
-
-        Ok(())
-    }
-
     pub fn translate_modules(mut self) -> AutoScriptPrototype {
         let mut prototype = AutoScriptPrototype::new();
-        for name in self
-            .modules
-            .keys()
-            .map(|x| x.clone())
-            .collect::<Vec<String>>()
-        {
-            self.translate_module(name.as_str(), &mut prototype)
-                .unwrap();
+        let modules = std::mem::take(&mut self.modules);
+        for module in &modules {
+            prototype.insert_globals(module.name.clone(), module.globals.clone());
+            if let Some(init) = &module.init {
+                prototype.push_initializer(init.clone());
+            }
+            for func in &module.functions {
+                let function = self.translate_function(func);
+                prototype.insert_function_prototype(function.signature.clone(), function);
+            }
+            for func in &module.vm_functions {
+                prototype.insert_function_prototype(func.header.signature(), self.convert_internal_function(func));
+            }
         }
         prototype.replace_constant_pool(self.const_pool_builder.into());
         prototype
     }
 
-    fn translate_expr_op(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        if let AstExprNode::Op(left, op, right) = expr.as_ref() {
-            let mut left_expr = self.translate_expr(left, cur_module, header);
-            let mut right_expr = self.translate_expr(right, cur_module, header);
-            if left_expr.ty == TypeInfo::Int && right_expr.ty == TypeInfo::Int {
-                match op {
-                    Op::Add => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::IAdd].into(),
-                        TypeInfo::Int,
-                    ),
-                    Op::Sub => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::ISub].into(),
-                        TypeInfo::Int,
-                    ),
-                    Op::Mul => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::IMul].into(),
-                        TypeInfo::Int,
-                    ),
-                    Op::Div => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::IDiv].into(),
-                        TypeInfo::Int,
-                    ),
-                    Op::Rem => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::IRem].into(),
-                        TypeInfo::Int,
-                    ),
-                    Op::Ge => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpGe].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Ne => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpNe].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Gt => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpGt].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Eq => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpEq].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Lt => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpLt].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Le => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpLe].into(),
-                        TypeInfo::Bool,
-                    ),
-                    _ => panic!(),
-                }
-            } else if (left_expr.ty == TypeInfo::Int && right_expr.ty == TypeInfo::Float)
-                || (left_expr.ty == TypeInfo::Float && right_expr.ty == TypeInfo::Int)
-                || (left_expr.ty == TypeInfo::Float && right_expr.ty == TypeInfo::Float)
-            {
-                left_expr.instr =
-                    left_expr.instr + self.try_convert_type(&left_expr.ty, &TypeInfo::Float);
-                right_expr.instr =
-                    right_expr.instr + self.try_convert_type(&right_expr.ty, &TypeInfo::Float);
-                match op {
-                    Op::Add => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::FAdd].into(),
-                        TypeInfo::Float,
-                    ),
-                    Op::Sub => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::FSub].into(),
-                        TypeInfo::Float,
-                    ),
-                    Op::Mul => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::FMul].into(),
-                        TypeInfo::Float,
-                    ),
-                    Op::Div => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::FDiv].into(),
-                        TypeInfo::Float,
-                    ),
-                    Op::Rem => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::FRem].into(),
-                        TypeInfo::Float,
-                    ),
-                    Op::Ge => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpGe].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Gt => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpGt].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Ne => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpNe].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Eq => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpEq].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Lt => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpLt].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Le => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::CmpLe].into(),
-                        TypeInfo::Bool,
-                    ),
-                    _ => panic!(),
-                }
-            } else if left_expr.ty == TypeInfo::Bool && right_expr.ty == TypeInfo::Bool {
-                match op {
-                    Op::And => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::BAnd].into(),
-                        TypeInfo::Bool,
-                    ),
-                    Op::Or => GenInfo::new(
-                        left_expr.instr + right_expr.instr + vec![Instr::BOr].into(),
-                        TypeInfo::Bool,
-                    ),
-                    _ => panic!(),
-                }
-            } else {
-                panic!("Operator was not supported!")
+    fn translate_expr_op(&mut self, left: &TypedExpr, op: &Op, right: &TypedExpr) -> Instructions {
+        let instr = match (&left.ty, op) {
+            (TypeInfo::String, Op::Add) => Instr::SConcat,
+            // strings are compared by content, ordering of them is compared with 0
+            (TypeInfo::String, op) => {
+                let cmp = match op {
+                    Op::Ge => Instr::CmpGe,
+                    Op::Gt => Instr::CmpGt,
+                    Op::Le => Instr::CmpLe,
+                    Op::Lt => Instr::CmpLt,
+                    Op::Eq => Instr::CmpEq,
+                    Op::Ne => Instr::CmpNe,
+                    op => unreachable!("operator `{}` on `String` passed type checking", op),
+                };
+                return self.translate_expr(left)
+                    + self.translate_expr(right)
+                    + vec![Instr::SCmp, Instr::IPush(0), cmp].into();
             }
-        } else {
-            panic!()
-        }
+            (_, Op::And) => return self.translate_short_circuit(left, false, right),
+            (_, Op::Or) => return self.translate_short_circuit(left, true, right),
+            (TypeInfo::Int, Op::Add) => Instr::IAdd,
+            (TypeInfo::Int, Op::Sub) => Instr::ISub,
+            (TypeInfo::Int, Op::Mul) => Instr::IMul,
+            (TypeInfo::Int, Op::Div) => Instr::IDiv,
+            (TypeInfo::Int, Op::Rem) => Instr::IRem,
+            (TypeInfo::Float, Op::Add) => Instr::FAdd,
+            (TypeInfo::Float, Op::Sub) => Instr::FSub,
+            (TypeInfo::Float, Op::Mul) => Instr::FMul,
+            (TypeInfo::Float, Op::Div) => Instr::FDiv,
+            (TypeInfo::Float, Op::Rem) => Instr::FRem,
+            (_, Op::Ge) => Instr::CmpGe,
+            (_, Op::Gt) => Instr::CmpGt,
+            (_, Op::Le) => Instr::CmpLe,
+            (_, Op::Lt) => Instr::CmpLt,
+            (_, Op::Eq) => Instr::CmpEq,
+            (_, Op::Ne) => Instr::CmpNe,
+            (ty, op) => unreachable!("operator `{}` on `{}` passed type checking", op, ty.display_name()),
+        };
+        self.translate_expr(left) + self.translate_expr(right) + vec![instr].into()
     }
 
-    fn translate_expr_unary(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        if let AstExprNode::UnaryOp(op, expr) = expr.as_ref() {
-            let sub_expr = self.translate_expr(expr, cur_module, header);
-            match op {
-                UnaryOp::Plus => match sub_expr.ty {
-                    TypeInfo::Int | TypeInfo::Float => GenInfo::new(vec![].into(), sub_expr.ty),
-                    _ => panic!(),
-                },
-                UnaryOp::Minus => match sub_expr.ty {
-                    TypeInfo::Int => {
-                        GenInfo::new(sub_expr.instr + vec![Instr::INeg].into(), TypeInfo::Int)
-                    }
-                    TypeInfo::Float => {
-                        GenInfo::new(sub_expr.instr + vec![Instr::FNeg].into(), TypeInfo::Float)
-                    }
-                    _ => panic!(),
-                },
-                UnaryOp::Not => match sub_expr.ty {
-                    TypeInfo::Bool => {
-                        GenInfo::new(sub_expr.instr + vec![Instr::BNeg].into(), TypeInfo::Bool)
-                    }
-                    _ => panic!(),
-                },
-            }
+    /// Right operand only runs if left one is not `decided`, which is then the result:
+    /// `false` for `&&`, `true` for `||`
+    fn translate_short_circuit(&mut self, left: &TypedExpr, decided: bool, right: &TypedExpr) -> Instructions {
+        let left = self.translate_expr(left);
+        let right = self.translate_expr(right);
+        let skip_right = if decided {
+            Instr::JumpIf(right.len() as i32 + 1)
         } else {
-            panic!()
-        }
+            Instr::JumpIfN(right.len() as i32 + 1)
+        };
+        left + vec![skip_right].into()
+            + right
+            + vec![Instr::Jump(1), Instr::BPush(decided)].into()
     }
 
-    fn translate_expr_fncall(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        if let AstExprNode::FnCall(fn_id, param) = expr.as_ref() {
-            let args: Option<Vec<GenInfo>> = if let Some(exprs) = param {
-                Some(
-                    exprs
-                        .iter()
-                        .map(|e| self.translate_expr(e, cur_module, header))
-                        .collect(),
-                )
-            } else {
-                None
-            };
-
-            let types = args
-                .as_ref()
-                .map(|vec| vec.iter().map(|e| e.ty.clone()).collect::<Vec<TypeInfo>>());
-
-            let fn_name = fn_id.last().unwrap();
-
-            let access_module = fn_id[0..fn_id.len() - 1]
-                .iter()
-                .map(|x| x.to_string())
-                .reduce(|a, b| format!("{}.{}", a, b));
-
-            let fn_header = self
-                .find_function(
-                    fn_name,
-                    cur_module,
-                    access_module.as_deref(),
-                    types.as_ref(),
-                )
-                .expect(&format!("Can't find function: {}", fn_name));
-            let args = if let Some(param) = args {
-                let require_types = fn_header
-                    .param
-                    .as_ref()
-                    .unwrap()
-                    .iter()
-                    .map(|x| &x.1)
-                    .collect::<Vec<&TypeInfo>>();
-                let args = param
-                    .into_iter()
-                    .zip(require_types)
-                    .map(|(a, e)| {
-                        let convert_instr = self.try_convert_type(&a.ty, e);
-                        GenInfo::new(a.instr + convert_instr, e.clone())
-                    })
-                    .collect::<Vec<GenInfo>>();
-                Some(args)
-            } else {
-                None
-            };
-
-            let before_instr = if let Some(instr) = args {
-                instr
-                    .into_iter()
-                    .fold(Instructions::new(), |a, b| a + b.instr)
-            } else {
-                Vec::new().into()
-            };
-
-            // let call_instr = match &fn_header.origin {
-            //     FunctionOrigin::Source => Instr::Call(fn_header.signature()),
-            //     FunctionOrigin::FFI => Instr::Call(fn_header.signature()),
-            // };
-            let call_instr = Instr::Call(fn_header.signature());
-
-            GenInfo::new(
-                before_instr + vec![call_instr].into(),
-                fn_header.ret.clone().unwrap_or(TypeInfo::Unit),
-            )
-        } else {
-            panic!()
+    fn translate_expr_unary(&mut self, op: &UnaryOp, sub: &TypedExpr) -> Instructions {
+        let instr = self.translate_expr(sub);
+        match (op, &sub.ty) {
+            (UnaryOp::Plus, _) => instr,
+            (UnaryOp::Minus, TypeInfo::Int) => instr + vec![Instr::INeg].into(),
+            (UnaryOp::Minus, _) => instr + vec![Instr::FNeg].into(),
+            (UnaryOp::Not, _) => instr + vec![Instr::BNeg].into(),
         }
     }
 
-    fn translate_expr_ifexpr(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        if let AstExprNode::IfExpr(cond, block, else_branch) = expr.as_ref() {
-            let cond_gen = self.translate_expr(cond, cur_module, header);
-            assert_eq!(cond_gen.ty, TypeInfo::Bool);
-            self.env.push_scope();
-            let block_code = self.translate_expr(block, cur_module, header);
-            self.env.pop_scope();
-            self.env.push_scope();
-            let else_code = else_branch
-                .as_ref()
-                .map(|e| self.translate_expr(e, cur_module, header))
-                .unwrap_or(GenInfo::new(vec![].into(), TypeInfo::Unit));
-            self.env.pop_scope();
-
-            let instr = cond_gen.instr
-                + vec![Instr::JumpIf(else_code.instr.len() as i32 + 1)].into()
-                + else_code.instr
-                + vec![Instr::Jump(block_code.instr.len() as i32)].into()
-                + block_code.instr;
-            let ty = if block_code.ty == else_code.ty {
-                block_code.ty.clone()
+    fn translate_expr_if(&mut self, expr: &TypedExpr) -> Instructions {
+        let TypedExprNode::If(cond, then_branch, else_branch) = &expr.node else { unreachable!() };
+        let cond_instr = self.translate_expr(cond);
+        // value of branches is dropped if the `if` is unit
+        let mut branch = |branch: &TypedExpr| {
+            let instr = self.translate_expr(branch);
+            if expr.ty == TypeInfo::Unit && branch.ty != TypeInfo::Unit {
+                instr + vec![Instr::Pop].into()
             } else {
-                TypeInfo::Unit
-            };
-            if ty == TypeInfo::Unit {
-                GenInfo::new(instr + vec![Instr::NPush].into(), ty)
-            } else {
-                GenInfo::new(instr, ty)
+                instr
             }
-        } else {
-            panic!()
-        }
+        };
+        let block_code = branch(then_branch);
+        let else_code = else_branch.as_deref().map(&mut branch).unwrap_or_else(Instructions::new);
+
+        cond_instr
+            + vec![Instr::JumpIf(else_code.len() as i32 + 1)].into()
+            + else_code
+            + vec![Instr::Jump(block_code.len() as i32)].into()
+            + block_code
     }
 
-    fn translate_expr_blockexpr(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        if let AstExprNode::BlockExpr(block) = expr.as_ref() {
-            if block.is_empty() {
-                GenInfo::new(vec![].into(), TypeInfo::Unit)
+    /// Handler follows the body, which is skipped when the body finishes
+    fn translate_expr_try(&mut self, expr: &TypedExpr) -> Instructions {
+        let TypedExprNode::Try { body, slot, handler } = &expr.node else { unreachable!() };
+        let mut branch = |branch: &TypedExpr| {
+            let instr = self.translate_expr(branch);
+            if expr.ty == TypeInfo::Unit && branch.ty != TypeInfo::Unit {
+                instr + vec![Instr::Pop].into()
             } else {
-                let (head, last) = block.split_at(block.len() - 1);
-                let head_instr = self.translate_block(head, cur_module, header);
-                let last_stmt = last.last().unwrap();
-                match last_stmt {
-                    AstStmtNode::ExprStmt(expr) => {
-                        let last = self.translate_expr(expr, cur_module, header);
-                        GenInfo::new(head_instr + last.instr, last.ty)
-                    }
-                    _ => {
-                        let last = self.translate_stmt(last_stmt, cur_module, header);
-                        GenInfo::new(head_instr + last, TypeInfo::Unit)
-                    }
-                }
+                instr
             }
-        } else {
-            panic!()
-        }
+        };
+        let body = branch(body);
+        let handler = Instructions::from(vec![Instr::Store(*slot)]) + branch(handler);
+        Instructions::from(vec![Instr::TryBegin(body.len() as i32 + 2)])
+            + body
+            + vec![Instr::TryEnd, Instr::Jump(handler.len() as i32)].into()
+            + handler
     }
 
-    fn translate_expr_assign(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        if let AstExprNode::AssignExpr(id, expr) = expr.as_ref() {
-            let expr = self.translate_expr(expr, cur_module, header);
-            let info = self
-                .env
-                .val_lookup(id)
-                .expect(&format!("Can't find var named"));
-            assert_eq!(info.ty, expr.ty);
-            GenInfo {
-                instr: expr.instr + vec![Instr::Dup, Instr::Store(info.binding_slot)].into(),
-                ty: expr.ty,
-            }
+    /// `finally` is copied to the normal path and to the handler which raises the error again
+    fn translate_expr_finally(&mut self, expr: &TypedExpr) -> Instructions {
+        let TypedExprNode::Finally { body, slot, finally } = &expr.node else { unreachable!() };
+        let body = self.translate_expr(body);
+        let finally = self.translate_expr(finally);
+        let handler = Instructions::from(vec![Instr::Store(*slot)])
+            + finally.clone()
+            + vec![Instr::Load(*slot), Instr::Throw].into();
+        Instructions::from(vec![Instr::TryBegin(body.len() as i32 + finally.len() as i32 + 2)])
+            + body
+            + vec![Instr::TryEnd].into()
+            + finally
+            + vec![Instr::Jump(handler.len() as i32)].into()
+            + handler
+    }
+
+    /// Translate expression whose value is needed, `unit` is pushed for expression of `unit` type
+    fn translate_value(&mut self, expr: &TypedExpr) -> Instructions {
+        let instr = self.translate_expr(expr);
+        if expr.ty == TypeInfo::Unit {
+            instr + vec![Instr::NPush].into()
         } else {
-            panic!()
+            instr
         }
     }
 
-    fn translate_expr(
-        &mut self,
-        expr: &Box<AstExprNode>,
-        cur_module: &str,
-        header: &FunctionBasicInfo,
-    ) -> GenInfo {
-        match expr.as_ref() {
-            AstExprNode::Integer(integer) => {
-                GenInfo::new(vec![Instr::IPush(*integer)].into(), TypeInfo::Int)
-            }
-            AstExprNode::Float(float) => {
-                GenInfo::new(vec![Instr::FPush(*float)].into(), TypeInfo::Float)
-            }
-            AstExprNode::Bool(boolean) => {
-                GenInfo::new(vec![Instr::BPush(*boolean)].into(), TypeInfo::Bool)
-            }
-            AstExprNode::String(s) => {
+    fn translate_expr(&mut self, expr: &TypedExpr) -> Instructions {
+        self.translate_expr_node(expr).with_span(&expr.span)
+    }
+
+    fn translate_expr_node(&mut self, expr: &TypedExpr) -> Instructions {
+        match &expr.node {
+            TypedExprNode::Integer(integer) => vec![Instr::IPush(*integer)].into(),
+            TypedExprNode::Float(float) => vec![Instr::FPush(*float)].into(),
+            TypedExprNode::Bool(boolean) => vec![Instr::BPush(*boolean)].into(),
+            TypedExprNode::String(s) => {
                 let const_id = if let Some(id) = self.const_pool_builder.find(s) {
                     id
                 } else {
@@ -582,25 +361,165 @@ impl CodeGen {
                     let slot = Slot::Ref(obj);
                     self.const_pool_builder.insert(s, slot)
                 };
-                GenInfo::new(
-                    vec![Instr::CPush(const_id)].into(),
-                    TypeInfo::TypeSym(String::from("String")),
-                )
+                vec![Instr::CPush(const_id)].into()
+            }
+            TypedExprNode::None => vec![Instr::NonePush].into(),
+            TypedExprNode::ToOptional(value) => self.translate_value(value),
+            TypedExprNode::IsNone(value) => self.translate_expr(value) + vec![Instr::NonePush, Instr::CmpEq].into(),
+            TypedExprNode::List(items) => {
+                let mut instr = Instructions::new();
+                for item in items {
+                    instr = instr + self.translate_value(item);
+                }
+                instr + vec![Instr::MakeList(items.len())].into()
+            }
+            TypedExprNode::Map(entries) => {
+                let mut instr = Instructions::new();
+                for (key, value) in entries {
+                    instr = instr + self.translate_value(key) + self.translate_value(value);
+                }
+                instr + vec![Instr::MakeMap(entries.len())].into()
+            }
+            TypedExprNode::Tuple(items) => {
+                let mut instr = Instructions::new();
+                for item in items {
+                    instr = instr + self.translate_value(item);
+                }
+                instr + vec![Instr::MakeTuple(items.len())].into()
             }
-            AstExprNode::Op(_, _, _) => self.translate_expr_op(expr, cur_module, header),
-            AstExprNode::UnaryOp(_, _) => self.translate_expr_unary(expr, cur_module, header),
-            AstExprNode::Ident(id) => {
-                assert_eq!(id.len(), 1);
-                let ident_info = self.env.val_lookup(&id.first().unwrap()).unwrap();
-                GenInfo::new(
-                    vec![Instr::Load(ident_info.binding_slot)].into(),
-                    ident_info.ty.clone(),
-                )
+            TypedExprNode::TupleGet(tuple, idx) => self.translate_expr(tuple) + vec![Instr::TupleGet(*idx)].into(),
+            TypedExprNode::Load(slot) => vec![Instr::Load(*slot)].into(),
+            TypedExprNode::LoadGlobal(module, idx) => vec![Instr::GLoad(module.clone(), *idx)].into(),
+            TypedExprNode::NewCell(value) => self.translate_value(value) + vec![Instr::MakeCell].into(),
+            TypedExprNode::LoadCell(slot) => vec![Instr::Load(*slot), Instr::CellGet].into(),
+            TypedExprNode::AssignCell(slot, value) => {
+                let store: Instructions = if expr.ty == TypeInfo::Unit {
+                    vec![Instr::Load(*slot), Instr::CellSet].into()
+                } else {
+                    vec![Instr::Dup, Instr::Load(*slot), Instr::CellSet].into()
+                };
+                self.translate_value(value) + store
+            }
+            TypedExprNode::Closure(signature, captures) => {
+                let mut instr = Instructions::new();
+                for capture in captures {
+                    instr = instr + self.translate_value(capture);
+                }
+                instr + vec![Instr::MakeClosure(signature.clone(), captures.len())].into()
+            }
+            TypedExprNode::CallClosure(callee, args) => {
+                let mut instr = self.translate_expr(callee);
+                for arg in args {
+                    instr = instr + self.translate_value(arg);
+                }
+                instr + vec![Instr::CallClosure(args.len())].into()
+            }
+            TypedExprNode::Op(left, op, right) => self.translate_expr_op(left, op, right),
+            TypedExprNode::UnaryOp(op, sub) => self.translate_expr_unary(op, sub),
+            TypedExprNode::IntToFloat(sub) => self.translate_expr(sub) + vec![Instr::I2F].into(),
+            TypedExprNode::Call(signature, args) => {
+                let mut instr = Instructions::new();
+                for arg in args {
+                    instr = instr + self.translate_value(arg);
+                }
+                instr + vec![Instr::Call(signature.clone())].into()
+            }
+            TypedExprNode::CallVirtual(method, args) => {
+                let mut instr = Instructions::new();
+                for arg in args {
+                    instr = instr + self.translate_value(arg);
+                }
+                instr + vec![Instr::CallVirtual(method.clone(), args.len())].into()
+            }
+            TypedExprNode::Block(stmts, tail) => {
+                let instr = self.translate_block(stmts);
+                match tail {
+                    Some(tail) => instr + self.translate_expr(tail),
+                    None => instr,
+                }
+            }
+            TypedExprNode::Assign(slot, value) => {
+                let store: Instructions = if expr.ty == TypeInfo::Unit {
+                    vec![Instr::Store(*slot)].into()
+                } else {
+                    vec![Instr::Dup, Instr::Store(*slot)].into()
+                };
+                self.translate_value(value) + store
+            }
+            TypedExprNode::AssignGlobal(module, idx, value) => {
+                let store: Instructions = if expr.ty == TypeInfo::Unit {
+                    vec![Instr::GStore(module.clone(), *idx)].into()
+                } else {
+                    vec![Instr::Dup, Instr::GStore(module.clone(), *idx)].into()
+                };
+                self.translate_value(value) + store
+            }
+            TypedExprNode::If(..) => self.translate_expr_if(expr),
+            TypedExprNode::Try { .. } => self.translate_expr_try(expr),
+            TypedExprNode::Finally { .. } => self.translate_expr_finally(expr),
+            TypedExprNode::New { class, fields, ctor, args, vtable } => {
+                let layout = Rc::new(ClassLayout {
+                    name: class.clone(),
+                    fields: fields.clone(),
+                    vtable: vtable.iter().cloned().collect(),
+                });
+                // constructor takes the instance as `self` and returns nothing
+                let mut instr: Instructions = vec![Instr::New(layout), Instr::Dup].into();
+                for arg in args {
+                    instr = instr + self.translate_value(arg);
+                }
+                instr + vec![Instr::Call(ctor.clone())].into()
+            }
+            TypedExprNode::GetField(obj, idx) => self.translate_expr(obj) + vec![Instr::GetField(*idx)].into(),
+            TypedExprNode::SetField(obj, idx, value) => {
+                self.translate_expr(obj) + self.translate_value(value) + vec![Instr::SetField(*idx)].into()
+            }
+            TypedExprNode::Index(obj, index) => {
+                let get = if matches!(obj.ty, TypeInfo::Map(..)) { Instr::MapGet } else { Instr::ListGet };
+                self.translate_expr(obj) + self.translate_expr(index) + vec![get].into()
+            }
+            TypedExprNode::SetIndex(obj, index, value) => {
+                let set = if matches!(obj.ty, TypeInfo::Map(..)) { Instr::MapSet } else { Instr::ListSet };
+                self.translate_expr(obj) + self.translate_expr(index) + self.translate_value(value) + vec![set].into()
+            }
+            TypedExprNode::Variant { name, tag, args } => {
+                let layout = Rc::new(VariantLayout { name: name.clone(), tag: *tag, arity: args.len() });
+                let mut instr = Instructions::new();
+                for arg in args {
+                    instr = instr + self.translate_value(arg);
+                }
+                instr + vec![Instr::MakeVariant(layout)].into()
+            }
+            TypedExprNode::VariantTag(value) => self.translate_expr(value) + vec![Instr::VariantTag].into(),
+            TypedExprNode::VariantField(value, idx) => self.translate_expr(value) + vec![Instr::VariantGet(*idx)].into(),
+            TypedExprNode::Match { .. } => self.translate_expr_match(expr),
+        }
+    }
+
+    /// Arms are tried in order, a failed test jumps to the next arm
+    /// and the end of an arm jumps over the arms after it
+    fn translate_expr_match(&mut self, expr: &TypedExpr) -> Instructions {
+        let TypedExprNode::Match { slot, scrutinee, arms } = &expr.node else { unreachable!() };
+        let mut arms_code = Instructions::new();
+        for arm in arms.iter().rev() {
+            let body = self.translate_expr(&arm.body);
+            // value of arms is dropped if the `match` is unit
+            let body = if expr.ty == TypeInfo::Unit && arm.body.ty != TypeInfo::Unit {
+                body + vec![Instr::Pop].into()
+            } else {
+                body
+            };
+            let mut arm_code = body + vec![Instr::Jump(arms_code.len() as i32)].into();
+            for step in arm.steps.iter().rev() {
+                arm_code = match step {
+                    PatternStep::Test(cond) => {
+                        self.translate_expr(cond) + vec![Instr::JumpIfN(arm_code.len() as i32)].into() + arm_code
+                    }
+                    PatternStep::Bind(slot, value) => self.translate_expr(value) + vec![Instr::Store(*slot)].into() + arm_code,
+                };
             }
-            AstExprNode::FnCall(_, _) => self.translate_expr_fncall(expr, cur_module, header),
-            AstExprNode::IfExpr(_, _, _) => self.translate_expr_ifexpr(expr, cur_module, header),
-            AstExprNode::BlockExpr(_) => self.translate_expr_blockexpr(expr, cur_module, header),
-            AstExprNode::AssignExpr(_, _) => self.translate_expr_assign(expr, cur_module, header),
+            arms_code = arm_code + arms_code;
         }
+        self.translate_expr(scrutinee) + vec![Instr::Store(*slot)].into() + arms_code
     }
 }
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use crate::frontend::span::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A span with a short message rendered under it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// Problem found in script source, reported to user instead of panic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub primary: Option<Label>,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            primary: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_primary(mut self, span: &Span, message: impl Into<String>) -> Self {
+        self.primary = Some(Label {
+            span: span.clone(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: &Span, message: impl Into<String>) -> Self {
+        self.secondary.push(Label {
+            span: span.clone(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Render the diagnostic in rustc style, with source snippets taken from `sources`
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut labels: Vec<(&Label, char)> = Vec::new();
+        if let Some(ref primary) = self.primary {
+            labels.push((primary, '^'));
+        }
+        labels.extend(self.secondary.iter().map(|label| (label, '-')));
+        // snippets follow source order, starting with the file of primary label
+        let primary_file = self.primary.as_ref().map(|label| Rc::clone(&label.span.file));
+        labels.sort_by_key(|(label, _)| {
+            (Some(&label.span.file) != primary_file.as_ref(), Rc::clone(&label.span.file), label.span.line, label.span.column)
+        });
+
+        let gutter = labels
+            .iter()
+            .map(|(label, _)| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(gutter);
+
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+        let mut cur_file: Option<&str> = None;
+        let mut cur_line = None;
+        for (label, underline) in labels {
+            let span = &label.span;
+            if cur_file != Some(&span.file) {
+                // location of primary label is shown even if a secondary one comes first
+                let location = match self.primary {
+                    Some(ref primary) if primary.span.file == span.file => &primary.span,
+                    _ => span,
+                };
+                out += &format!("{}--> {}\n", pad, location);
+                cur_file = Some(&span.file);
+                cur_line = None;
+            }
+            match sources.line(&span.file, span.line) {
+                Some(line) => {
+                    // labels on the same line share one snippet
+                    if cur_line != Some(span.line) {
+                        out += &format!("{} |\n", pad);
+                        out += &format!("{:>gutter$} | {}\n", span.line, line, gutter = gutter);
+                        cur_line = Some(span.line);
+                    }
+                    let width = sources.char_width(span).max(1);
+                    out += &format!(
+                        "{} | {}{} {}\n",
+                        pad,
+                        " ".repeat(span.column - 1),
+                        underline.to_string().repeat(width),
+                        label.message
+                    );
+                }
+                None => {
+                    out += &format!("{} = {}\n", pad, label.message);
+                }
+            }
+        }
+        for note in &self.notes {
+            out += &format!("{} = note: {}\n", pad, note);
+        }
+        out
+    }
+}
+
+/// Source text of every loaded file, keyed by the file name used in spans
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: HashMap<Rc<str>, Rc<str>>,
+}
+
+impl SourceMap {
+    pub fn insert(&mut self, file: Rc<str>, source: &str) {
+        self.files.insert(file, Rc::from(source));
+    }
+
+    /// Return content of 1-based `line` in `file`, without line break
+    pub fn line(&self, file: &str, line: usize) -> Option<&str> {
+        self.files
+            .get(file)
+            .and_then(|source| source.lines().nth(line - 1))
+    }
+
+    /// Number of chars covered by `span`, limited to the line it starts
+    fn char_width(&self, span: &Span) -> usize {
+        self.files
+            .get(&span.file)
+            .and_then(|source| source.get(span.start..span.end.min(source.len())))
+            .map(|text| text.chars().take_while(|c| *c != '\n').count())
+            .unwrap_or(0)
+    }
+}
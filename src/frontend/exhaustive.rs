@@ -0,0 +1,64 @@
+use std::iter;
+
+use crate::frontend::ast::basic::TypeInfo;
+
+/// What a pattern covers, as far as exhaustiveness of `match` is concerned
+#[derive(Debug, Clone)]
+pub enum PatShape {
+    /// Wildcard or binding, it covers every value
+    Wild,
+    /// Constructor by its index in `Ctors` of the type, with shapes of its fields
+    Ctor(usize, Vec<PatShape>),
+    /// Literal or range of a type with too many values to list, like `int`
+    Other,
+}
+
+static WILD: PatShape = PatShape::Wild;
+
+/// Constructors of a type with finitely many of them, by name and types of fields
+pub type Ctors = Vec<(String, Vec<TypeInfo>)>;
+
+/// Constructors nested deeper than this are not split, such values only count as covered by wildcards
+const MAX_DEPTH: usize = 64;
+
+/// Find values of types `tys` which are matched by none of `rows`, every row is a pattern for each type.
+/// Return the patterns describing such values, or `None` if the rows are exhaustive.
+/// Types without `Ctors` are only covered by wildcards
+pub fn missing(rows: &[Vec<&PatShape>], tys: &[TypeInfo], ctors_of: &dyn Fn(&TypeInfo) -> Option<Ctors>) -> Option<Vec<String>> {
+    missing_at(rows, tys, ctors_of, 0)
+}
+
+fn missing_at(rows: &[Vec<&PatShape>], tys: &[TypeInfo], ctors_of: &dyn Fn(&TypeInfo) -> Option<Ctors>, depth: usize) -> Option<Vec<String>> {
+    let Some((ty, rest_tys)) = tys.split_first() else {
+        return if rows.is_empty() { Some(Vec::new()) } else { None };
+    };
+    let all_wild = rows.iter().all(|row| matches!(row[0], PatShape::Wild));
+    let ctors = ctors_of(ty).filter(|_| !all_wild && depth < MAX_DEPTH);
+    let Some(ctors) = ctors else {
+        // default matrix, which also keeps recursive types from being expanded forever
+        let rows = rows
+            .iter()
+            .filter(|row| matches!(row[0], PatShape::Wild))
+            .map(|row| row[1..].to_vec())
+            .collect::<Vec<Vec<&PatShape>>>();
+        return missing_at(&rows, rest_tys, ctors_of, depth).map(|witness| iter::once(String::from("_")).chain(witness).collect());
+    };
+    for (tag, (name, fields)) in ctors.iter().enumerate() {
+        // rows matching the constructor, with its fields in place of it
+        let rows = rows
+            .iter()
+            .filter_map(|row| match row[0] {
+                PatShape::Wild => Some(iter::repeat_n(&WILD, fields.len()).chain(row[1..].iter().copied()).collect()),
+                PatShape::Ctor(row_tag, subs) if *row_tag == tag => Some(subs.iter().chain(row[1..].iter().copied()).collect()),
+                _ => None,
+            })
+            .collect::<Vec<Vec<&PatShape>>>();
+        let tys = fields.iter().chain(rest_tys).cloned().collect::<Vec<TypeInfo>>();
+        if let Some(mut witness) = missing_at(&rows, &tys, ctors_of, depth + 1) {
+            let rest = witness.split_off(fields.len());
+            let ctor = if fields.is_empty() { name.clone() } else { format!("{}({})", name, witness.join(", ")) };
+            return Some(iter::once(ctor).chain(rest).collect());
+        }
+    }
+    None
+}
@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+
+use crate::frontend::ast::element::ProgramClassElement;
+use crate::frontend::ast::typed::{PatternStep, TypedExpr, TypedExprNode, TypedStmt, TypedStmtNode};
+use crate::frontend::diagnostic::Diagnostic;
+
+/// Slot of `self` in a constructor
+const SELF_SLOT: usize = 0;
+
+/// Walk the typed body of a constructor in order. A field is definitely assigned after a statement
+/// `self.field = ...` at top level of the body, reading it or using `self` as a whole, like passing it
+/// to a function, is an error before that. Return the fields assigned with the diagnostics found
+pub fn check_ctor_body(class: &ProgramClassElement, body: &[TypedStmt]) -> (HashSet<usize>, Vec<Diagnostic>) {
+    let mut walker = Walker {
+        class,
+        assigned: HashSet::new(),
+        reported: HashSet::new(),
+        self_reported: false,
+        diagnostics: Vec::new(),
+    };
+    for stmt in body {
+        match &stmt.node {
+            TypedStmtNode::Expr(TypedExpr { node: TypedExprNode::SetField(obj, idx, value), .. }) if is_self(obj) => {
+                walker.expr(value);
+                walker.assigned.insert(*idx);
+            }
+            _ => walker.stmt(stmt),
+        }
+    }
+    (walker.assigned, walker.diagnostics)
+}
+
+fn is_self(expr: &TypedExpr) -> bool {
+    matches!(expr.node, TypedExprNode::Load(SELF_SLOT))
+}
+
+struct Walker<'c> {
+    class: &'c ProgramClassElement,
+    assigned: HashSet<usize>,
+    /// Fields whose read is reported, later reads are not reported again
+    reported: HashSet<usize>,
+    self_reported: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Walker<'_> {
+    fn stmts(&mut self, stmts: &[TypedStmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &TypedStmt) {
+        match &stmt.node {
+            TypedStmtNode::Expr(expr) | TypedStmtNode::Var(_, expr) | TypedStmtNode::Throw(expr) => self.expr(expr),
+            TypedStmtNode::TupleVar { tuple, .. } => self.expr(tuple),
+            TypedStmtNode::Return(value, leave) => {
+                self.exprs(value);
+                self.stmts(leave);
+            }
+            TypedStmtNode::While(cond, body) => {
+                self.expr(cond);
+                self.stmts(body);
+            }
+            TypedStmtNode::ForRange { start, end, step, body, .. } => {
+                self.expr(start);
+                self.expr(end);
+                self.exprs(step.as_ref().map(|(_, step)| &**step));
+                self.stmts(body);
+            }
+            TypedStmtNode::ForEach { iterable, body, .. } => {
+                self.expr(iterable);
+                self.stmts(body);
+            }
+            TypedStmtNode::Break(_) | TypedStmtNode::Continue(_) | TypedStmtNode::LeaveTry => {}
+        }
+    }
+
+    fn exprs<'e>(&mut self, exprs: impl IntoIterator<Item = &'e TypedExpr>) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &TypedExpr) {
+        match &expr.node {
+            TypedExprNode::GetField(obj, idx) if is_self(obj) => {
+                if !self.assigned.contains(idx) && self.reported.insert(*idx) {
+                    let field = &self.class.fields[*idx];
+                    self.diagnostics.push(Diagnostic::error(format!("field `{}` is read before it is initialized", field.name))
+                        .with_primary(&expr.span, "read before assignment")
+                        .with_secondary(&field.span, "field declared here")
+                        .with_note(format!("assign it with `self.{} = ...` at top level of constructor before reading it", field.name)));
+                }
+            }
+            // assigning a field inside a branch or loop doesn't make it definitely assigned
+            TypedExprNode::SetField(obj, _, value) if is_self(obj) => self.expr(value),
+            TypedExprNode::Load(SELF_SLOT) => {
+                let missing = self.class.fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| !self.assigned.contains(idx))
+                    .map(|(_, field)| format!("`{}`", field.name))
+                    .collect::<Vec<String>>();
+                if !missing.is_empty() && !self.self_reported {
+                    self.self_reported = true;
+                    self.diagnostics.push(Diagnostic::error("`self` is used before all fields are initialized")
+                        .with_primary(&expr.span, "`self` used here")
+                        .with_note(match missing.len() {
+                            1 => format!("field {} is not initialized yet", missing[0]),
+                            _ => format!("fields {} are not initialized yet", missing.join(", ")),
+                        }));
+                }
+            }
+            TypedExprNode::Integer(_)
+            | TypedExprNode::Float(_)
+            | TypedExprNode::Bool(_)
+            | TypedExprNode::String(_)
+            | TypedExprNode::None
+            | TypedExprNode::Load(_)
+            | TypedExprNode::LoadGlobal(..)
+            | TypedExprNode::LoadCell(_) => {}
+            TypedExprNode::ToOptional(inner)
+            | TypedExprNode::IsNone(inner)
+            | TypedExprNode::TupleGet(inner, _)
+            | TypedExprNode::NewCell(inner)
+            | TypedExprNode::AssignCell(_, inner)
+            | TypedExprNode::UnaryOp(_, inner)
+            | TypedExprNode::IntToFloat(inner)
+            | TypedExprNode::Assign(_, inner)
+            | TypedExprNode::AssignGlobal(_, _, inner)
+            | TypedExprNode::GetField(inner, _)
+            | TypedExprNode::VariantTag(inner)
+            | TypedExprNode::VariantField(inner, _) => self.expr(inner),
+            TypedExprNode::List(items)
+            | TypedExprNode::Tuple(items)
+            | TypedExprNode::Closure(_, items)
+            | TypedExprNode::Call(_, items)
+            | TypedExprNode::CallVirtual(_, items)
+            | TypedExprNode::New { args: items, .. }
+            | TypedExprNode::Variant { args: items, .. } => self.exprs(items),
+            TypedExprNode::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            TypedExprNode::CallClosure(closure, args) => {
+                self.expr(closure);
+                self.exprs(args);
+            }
+            TypedExprNode::Op(lhs, _, rhs)
+            | TypedExprNode::Index(lhs, rhs)
+            | TypedExprNode::SetField(lhs, _, rhs)
+            | TypedExprNode::Try { body: lhs, handler: rhs, .. }
+            | TypedExprNode::Finally { body: lhs, finally: rhs, .. } => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            TypedExprNode::SetIndex(obj, index, value) => {
+                self.expr(obj);
+                self.expr(index);
+                self.expr(value);
+            }
+            TypedExprNode::Block(stmts, tail) => {
+                self.stmts(stmts);
+                self.exprs(tail.as_deref());
+            }
+            TypedExprNode::If(cond, then_branch, else_branch) => {
+                self.expr(cond);
+                self.expr(then_branch);
+                self.exprs(else_branch.as_deref());
+            }
+            TypedExprNode::Match { scrutinee, arms, .. } => {
+                self.expr(scrutinee);
+                for arm in arms {
+                    for step in &arm.steps {
+                        match step {
+                            PatternStep::Test(expr) | PatternStep::Bind(_, expr) => self.expr(expr),
+                        }
+                    }
+                    self.expr(&arm.body);
+                }
+            }
+        }
+    }
+}
@@ -4,51 +4,87 @@ use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 
 use crate::frontend::ast::basic::TypeInfo;
+use crate::frontend::span::Span;
 use crate::vm::const_pool::ConstantPool;
-use crate::vm::instr::Instructions;
 use crate::vm::slot::Slot;
 
-pub struct GenInfo {
-    pub instr: Instructions,
-    pub ty: TypeInfo,
-}
-
-impl GenInfo {
-    pub fn new(instr: Instructions, ty: TypeInfo) -> Self {
-        Self { instr, ty }
-    }
+/// How a variable is introduced, used to explain why it is immutable
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VarOrigin {
+    Local,
+    Param,
+    LoopVar,
+    Pattern,
 }
 
+#[derive(Clone)]
 pub struct VarInfo {
     pub ty: TypeInfo,
     pub binding_slot: usize,
     pub is_mut: bool,
+    pub origin: VarOrigin,
+    /// Declaration of variable, or header of function for parameters
+    pub span: Option<Span>,
+    /// Kept in a cell, as a `var` captured by lambdas
+    pub boxed: bool,
 }
 
 impl VarInfo {
-    pub fn new(ty: TypeInfo, binding_slot: usize, is_mut: bool) -> Self {
+    pub fn new(ty: TypeInfo, binding_slot: usize, is_mut: bool, span: &Span) -> Self {
         Self {
             ty,
             binding_slot,
             is_mut,
+            origin: VarOrigin::Local,
+            span: Some(span.clone()),
+            boxed: false,
         }
     }
-}
 
-pub struct EnvScope {
-    ty_table: HashMap<String, ()>,
-    val_table: HashMap<String, VarInfo>, // 值环境
-}
+    /// Parameters are immutable
+    pub fn param(ty: TypeInfo, binding_slot: usize, header_span: Option<&Span>) -> Self {
+        Self {
+            ty,
+            binding_slot,
+            is_mut: false,
+            origin: VarOrigin::Param,
+            span: header_span.cloned(),
+            boxed: false,
+        }
+    }
 
-impl Default for EnvScope {
-    fn default() -> Self {
+    /// Loop variable of `for` is immutable
+    pub fn loop_var(ty: TypeInfo, binding_slot: usize, span: &Span) -> Self {
         Self {
-            ty_table: HashMap::new(),
-            val_table: HashMap::new(),
+            ty,
+            binding_slot,
+            is_mut: false,
+            origin: VarOrigin::LoopVar,
+            span: Some(span.clone()),
+            boxed: false,
+        }
+    }
+
+    /// Variable bound by pattern of `match` arm is immutable
+    pub fn binding(ty: TypeInfo, binding_slot: usize, span: &Span) -> Self {
+        Self {
+            ty,
+            binding_slot,
+            is_mut: false,
+            origin: VarOrigin::Pattern,
+            span: Some(span.clone()),
+            boxed: false,
         }
     }
 }
 
+#[derive(Default)]
+pub struct EnvScope {
+    val_table: HashMap<String, VarInfo>, // 值环境
+    /// Slots taken by the scope, shadowed variables keep their slots
+    slot_count: usize,
+}
+
 pub struct Env {
     pub stack: Vec<EnvScope>,
     pub max_val_table_size: usize,
@@ -78,12 +114,16 @@ impl Env {
     fn top_mut(&mut self) -> &mut EnvScope {
         self.stack.last_mut().unwrap()
     }
-    fn top(&self) -> &EnvScope {
-        self.stack.last().unwrap()
-    }
     /// Insert a value to value environment, return slot index
     pub fn val_insert(&mut self, name: String, ty: VarInfo) -> usize {
-        self.top_mut().val_table.insert(name, ty);
+        let scope = self.top_mut();
+        scope.val_table.insert(name, ty);
+        scope.slot_count += 1;
+        self.current_val_size() - 1
+    }
+    /// Take a slot for a value that has no name, return slot index
+    pub fn slot_alloc(&mut self) -> usize {
+        self.top_mut().slot_count += 1;
         self.current_val_size() - 1
     }
     pub fn val_lookup(&mut self, name: &str) -> Option<&VarInfo> {
@@ -98,7 +138,7 @@ impl Env {
         let len = self
             .stack
             .iter()
-            .map(|x| x.val_table.len())
+            .map(|x| x.slot_count)
             .reduce(|a, b| a + b)
             .unwrap_or(0);
         self.max_val_table_size = max(self.max_val_table_size, len);
@@ -129,7 +169,7 @@ impl ConstantPoolBuilder {
         let key = Self::hash_key(key);
         
         if self.table.contains_key(&key) {
-            self.table.get(&key).unwrap().clone()
+            *self.table.get(&key).unwrap()
         }else{
             self.pool.push(slot);
             let pos = self.pool.len() - 1;
@@ -141,12 +181,12 @@ impl ConstantPoolBuilder {
 
     pub fn find(&self, key: &impl Hash) -> Option<usize> {
         let key = Self::hash_key(key);
-        self.table.get(&key).map(usize::clone)
+        self.table.get(&key).copied()
     } 
 }
 
-impl Into<ConstantPool> for ConstantPoolBuilder {
-    fn into(self) -> ConstantPool {
-        self.pool.into()
+impl From<ConstantPoolBuilder> for ConstantPool {
+    fn from(builder: ConstantPoolBuilder) -> Self {
+        builder.pool.into()
     }
 }
\ No newline at end of file
@@ -1,18 +1,21 @@
+use std::rc::Rc;
 use std::str::FromStr;
 
-use nom::{AsBytes, IResult};
+use nom::IResult;
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take};
+use nom::bytes::complete::tag;
 use nom::character::complete::{alpha1, alphanumeric1, char, digit1, multispace0};
 use nom::combinator::{map, map_res, opt, recognize};
 use nom::multi::many0;
-use nom::sequence::{delimited, pair, tuple};
+use nom::sequence::{delimited, pair, preceded, tuple};
 
+use crate::frontend::diagnostic::Diagnostic;
+use crate::frontend::span::{LineIndex, Span};
 use crate::frontend::tok::*;
 
 macro_rules! literal_lex {
      ($func_name: ident, $tag_string: literal, $output_token: expr) => {
-         fn $func_name<'a>(s: &'a [u8]) -> IResult<&[u8], Tok> {
+         fn $func_name(s: &[u8]) -> IResult<&[u8], Tok> {
              map(tag($tag_string), |_| $output_token)(s)
          }
      }
@@ -21,6 +24,13 @@ macro_rules! literal_lex {
 literal_lex!(eq_op, "==", Tok::Eq);
 literal_lex!(ne_op, "!=", Tok::Ne);
 literal_lex!(assign_op, "=", Tok::Assign);
+literal_lex!(increment_op, "++", Tok::Increment);
+literal_lex!(decrement_op, "--", Tok::Decrement);
+literal_lex!(add_assign_op, "+=", Tok::AddAssign);
+literal_lex!(sub_assign_op, "-=", Tok::SubAssign);
+literal_lex!(mul_assign_op, "*=", Tok::MulAssign);
+literal_lex!(div_assign_op, "/=", Tok::DivAssign);
+literal_lex!(rem_assign_op, "%=", Tok::RemAssign);
 literal_lex!(plus_op, "+", Tok::Plus);
 literal_lex!(minus_op, "-", Tok::Minus);
 literal_lex!(multiply_op, "*", Tok::Multiply);
@@ -33,12 +43,22 @@ literal_lex!(gt_op, ">", Tok::Gt);
 literal_lex!(lt_op, "<", Tok::Lt);
 literal_lex!(and_op, "&&", Tok::And);
 literal_lex!(or_op, "||", Tok::Or);
+literal_lex!(pipe_op, "|", Tok::Pipe);
 
 fn lex_operator(input: &[u8]) -> IResult<&[u8], Tok> {
     alt((
-        eq_op,
-        ne_op,
-        assign_op,
+        alt((
+            eq_op,
+            ne_op,
+            assign_op,
+            increment_op,
+            decrement_op,
+            add_assign_op,
+            sub_assign_op,
+            mul_assign_op,
+            div_assign_op,
+            rem_assign_op,
+        )),
         plus_op,
         minus_op,
         multiply_op,
@@ -50,7 +70,8 @@ fn lex_operator(input: &[u8]) -> IResult<&[u8], Tok> {
         gt_op,
         lt_op,
         and_op,
-        or_op
+        or_op,
+        pipe_op
     ))(input)
 }
 
@@ -58,11 +79,19 @@ literal_lex!(lparen_punctuation, "(", Tok::LParen);
 literal_lex!(rparen_punctuation, ")", Tok::RParen);
 literal_lex!(lbrace_punctuation, "{", Tok::LBrace);
 literal_lex!(rbrace_punctuation, "}", Tok::RBrace);
+literal_lex!(lbracket_punctuation, "[", Tok::LBracket);
+literal_lex!(rbracket_punctuation, "]", Tok::RBracket);
 literal_lex!(semicolon_punctuation, ";", Tok::Semicolon);
 literal_lex!(colon_punctuation, ":",Tok::Colon);
 literal_lex!(rarrow_punctuation, "->", Tok::RightArrow);
+literal_lex!(fat_arrow_punctuation, "=>", Tok::FatArrow);
 literal_lex!(comma_punctuation, ",", Tok::Comma);
 literal_lex!(dot_puntuation, ".", Tok::Dot);
+literal_lex!(dotdoteq_punctuation, "..=", Tok::DotDotEq);
+literal_lex!(dotdot_punctuation, "..", Tok::DotDot);
+literal_lex!(question_question_punctuation, "??", Tok::QuestionQuestion);
+literal_lex!(question_dot_punctuation, "?.", Tok::QuestionDot);
+literal_lex!(question_punctuation, "?", Tok::Question);
 
 fn lex_punctuations(input: &[u8]) -> IResult<&[u8], Tok> {
     alt((
@@ -70,11 +99,19 @@ fn lex_punctuations(input: &[u8]) -> IResult<&[u8], Tok> {
         rparen_punctuation,
         lbrace_punctuation,
         rbrace_punctuation,
+        lbracket_punctuation,
+        rbracket_punctuation,
         semicolon_punctuation,
         rarrow_punctuation,
+        fat_arrow_punctuation,
         colon_punctuation,
         comma_punctuation,
-        dot_puntuation
+        dotdoteq_punctuation,
+        dotdot_punctuation,
+        dot_puntuation,
+        question_question_punctuation,
+        question_dot_punctuation,
+        question_punctuation
     ))(input)
 }
 
@@ -89,39 +126,6 @@ fn lex_integer(input: &[u8]) -> IResult<&[u8], Tok> {
 }
 
 
-fn concat_slice_vec(c: &[u8], done: Vec<u8>) -> Vec<u8> {
-    let mut new_vec = c.to_vec();
-    new_vec.extend(&done);
-    new_vec
-}
-
-fn string(input: &[u8]) -> IResult<&[u8], String> {
-    fn pis(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
-        use std::result::Result::*;
-
-        let (i1, c1) = take(1usize)(input)?;
-        match c1.as_bytes() {
-            b"\"" => Ok((input, vec![])),
-            b"\\" => {
-                let (i2, c2) = take(1usize)(i1)?;
-                pis(i2).map(|(slice, done)| (slice, concat_slice_vec(c2, done)))
-            }
-            c => pis(i1).map(|(slice, done)| (slice, concat_slice_vec(c, done))),
-        }
-    }
-    delimited(
-        tag("\""),
-        map_res(
-            pis,
-            |v| std::str::from_utf8(v.as_slice()).map(|s| s.to_string())
-        ),
-        tag("\""))(input)
-}
-
-fn lex_string(input: &[u8]) -> IResult<&[u8], Tok> {
-    map(string, Tok::String)(input)
-}
-
 fn lex_float(input: &[u8]) -> IResult<&[u8], Tok> {
     map(
         map_res(
@@ -157,7 +161,20 @@ fn lex_ident_and_keyword(input: &[u8]) -> IResult<&[u8], Tok> {
                 "else" => Tok::KwdElse,
                 "elif" => Tok::KwdElif,
                 "while" => Tok::KwdWhile,
+                "for" => Tok::KwdFor,
+                "in" => Tok::KwdIn,
+                "break" => Tok::KwdBreak,
+                "continue" => Tok::KwdContinue,
                 "class" => Tok::KwdClass,
+                "enum" => Tok::KwdEnum,
+                "match" => Tok::KwdMatch,
+                "none" => Tok::KwdNone,
+                "try" => Tok::KwdTry,
+                "catch" => Tok::KwdCatch,
+                "finally" => Tok::KwdFinally,
+                "throw" => Tok::KwdThrow,
+                "interface" => Tok::KwdInterface,
+                "impl" => Tok::KwdImpl,
                 "and" => Tok::And,
                 "or" => Tok::Or,
                 _ => Tok::Ident(syntax.to_string())
@@ -166,26 +183,330 @@ fn lex_ident_and_keyword(input: &[u8]) -> IResult<&[u8], Tok> {
     )(input)
 }
 
+fn lex_label(input: &[u8]) -> IResult<&[u8], Tok> {
+    map_res(
+        preceded(
+            char('\''),
+            recognize(pair(
+                alt((alpha1, tag("_"))),
+                many0(alt((alphanumeric1, tag("_"))))
+            ))
+        ),
+        |name| std::str::from_utf8(name).map(|name| Tok::Label(name.to_string()))
+    )(input)
+}
+
+/// Function name in backticks used as an infix operator, e.g. `` a `max` b ``
+fn lex_infix_op(input: &[u8]) -> IResult<&[u8], Tok> {
+    map_res(
+        delimited(
+            char('`'),
+            recognize(pair(
+                alt((alpha1, tag("_"))),
+                many0(alt((alphanumeric1, tag("_"))))
+            )),
+            char('`')
+        ),
+        |name| std::str::from_utf8(name).map(|name| Tok::InfixOp(name.to_string()))
+    )(input)
+}
+
 fn lex_token(input: &[u8]) -> IResult<&[u8], Tok> {
     alt((
         lex_punctuations,
         lex_operator,
+        lex_label,
+        lex_infix_op,
         lex_ident_and_keyword,
         lex_float,
-        lex_integer,
-        lex_string
+        lex_integer
     ))(input)
 }
 
-fn lex_tokens(input: &[u8]) -> IResult<&[u8], Vec<Tok>> {
-    many0(delimited(multispace0, lex_token, multispace0))(input)
+/// Length of a `///` doc comment at the start of `input`, `////` starts a normal comment
+fn doc_comment_len(input: &[u8]) -> Option<usize> {
+    if input.starts_with(b"///") && !input.starts_with(b"////") {
+        Some(input.iter().take_while(|b| **b != b'\n').count())
+    } else {
+        None
+    }
+}
+
+/// Skip whitespaces, `//` line comments and nestable `/* */` block comments.
+/// Doc comments are kept since they are tokens.
+/// Return offset of the outermost block comment in `input` if it is never closed
+fn skip_trivia(input: &[u8]) -> Result<&[u8], usize> {
+    let mut rest = input;
+    loop {
+        rest = multispace0::<&[u8], ()>(rest).unwrap().0;
+        if rest.starts_with(b"//") && doc_comment_len(rest).is_none() {
+            let len = rest.iter().take_while(|b| **b != b'\n').count();
+            rest = &rest[len..];
+        } else if rest.starts_with(b"/*") {
+            let start = input.len() - rest.len();
+            let mut depth = 0;
+            loop {
+                if rest.starts_with(b"/*") {
+                    depth += 1;
+                    rest = &rest[2..];
+                } else if rest.starts_with(b"*/") {
+                    depth -= 1;
+                    rest = &rest[2..];
+                    if depth == 0 {
+                        break;
+                    }
+                } else if rest.is_empty() {
+                    return Err(start);
+                } else {
+                    rest = &rest[1..];
+                }
+            }
+        } else {
+            return Ok(rest);
+        }
+    }
+}
+
+/// Offset of the `"` closing the string literal opened at `start`
+fn string_end(input: &[u8], start: usize) -> Option<usize> {
+    let mut pos = start + 1;
+    while pos < input.len() {
+        match input[pos] {
+            b'"' => return Some(pos),
+            b'\\' => pos += 2,
+            b'$' if input.get(pos + 1) == Some(&b'{') => pos = interpolation_end(input, pos + 1)? + 1,
+            _ => pos += 1,
+        }
+    }
+    None
+}
+
+/// Offset of the `}` matching the `{` at `open`, skipping nested braces and string literals
+fn interpolation_end(input: &[u8], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut pos = open;
+    while pos < input.len() {
+        match input[pos] {
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(pos);
+                }
+            }
+            b'"' => pos = string_end(input, pos)?,
+            _ => {}
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// Decode the escape sequence at `pos` (pointing at the backslash) into `buf`,
+/// return offset after the sequence
+fn lex_escape(
+    input: &[u8],
+    pos: usize,
+    buf: &mut Vec<u8>,
+    make_span: &dyn Fn(usize, usize) -> Span,
+    errors: &mut Vec<Diagnostic>,
+) -> usize {
+    let c = match input.get(pos + 1) {
+        Some(b'n') => '\n',
+        Some(b't') => '\t',
+        Some(b'r') => '\r',
+        Some(b'0') => '\0',
+        Some(b'\\') => '\\',
+        Some(b'"') => '"',
+        Some(b'$') => '$',
+        Some(b'u') => {
+            let digits_start = pos + 3;
+            let close = input[pos..].iter().position(|b| *b == b'}' || *b == b'"').map(|i| pos + i);
+            let code = match close {
+                Some(close) if input.get(pos + 2) == Some(&b'{') && input[close] == b'}' => {
+                    std::str::from_utf8(&input[digits_start..close]).ok()
+                        .filter(|digits| !digits.is_empty() && digits.len() <= 6)
+                        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                        .map(|code| (code, close + 1))
+                }
+                _ => None,
+            };
+            return match code {
+                Some((code, end)) => match char::from_u32(code) {
+                    Some(c) => {
+                        let mut utf8 = [0; 4];
+                        buf.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                        end
+                    }
+                    None => {
+                        errors.push(Diagnostic::error("invalid unicode character escape")
+                            .with_primary(&make_span(pos, end), format!("`{:X}` is not a unicode scalar value", code)));
+                        end
+                    }
+                },
+                None => {
+                    errors.push(Diagnostic::error("invalid unicode character escape")
+                        .with_primary(&make_span(pos, pos + 2), "expected `\\u{XXXX}` with 1 to 6 hex digits"));
+                    pos + 2
+                }
+            };
+        }
+        _ => {
+            let c = input.get(pos + 1)
+                .and_then(|_| std::str::from_utf8(&input[pos + 1..]).ok())
+                .and_then(|s| s.chars().next())
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            let end = (pos + 1 + c.len_utf8()).min(input.len());
+            errors.push(Diagnostic::error(format!("unknown character escape `\\{}`", c))
+                .with_primary(&make_span(pos, end), "unknown escape"));
+            return end;
+        }
+    };
+    buf.push(c as u8);
+    pos + 2
+}
+
+/// Lex the string literal opened at `start`, return the token and offset after it.
+/// Return `None` if the literal is never closed
+fn lex_string(
+    input: &[u8],
+    start: usize,
+    make_span: &dyn Fn(usize, usize) -> Span,
+    errors: &mut Vec<Diagnostic>,
+) -> Option<(Tok, usize)> {
+    let end = string_end(input, start)?;
+    let mut parts = Vec::new();
+    let mut buf = Vec::new();
+    let mut pos = start + 1;
+    while pos < end {
+        match input[pos] {
+            b'\\' => pos = lex_escape(input, pos, &mut buf, make_span, errors),
+            b'$' if input[pos + 1] == b'{' => {
+                let close = interpolation_end(input, pos + 1)?;
+                let mut tokens = lex_range(&input[..close], pos + 2, make_span, errors);
+                if tokens.is_empty() {
+                    errors.push(Diagnostic::error("empty string interpolation")
+                        .with_primary(&make_span(pos, close + 1), "expected an expression inside `${}`"));
+                }
+                if !buf.is_empty() {
+                    parts.push(StrPart::Lit(String::from_utf8_lossy(&buf).into_owned()));
+                    buf.clear();
+                }
+                tokens.push(SpannedTok::new(Tok::RBrace, make_span(close, close + 1)));
+                parts.push(StrPart::Expr(tokens));
+                pos = close + 1;
+            }
+            c => {
+                buf.push(c);
+                pos += 1;
+            }
+        }
+    }
+    let text = String::from_utf8_lossy(&buf).into_owned();
+    let tok = if parts.is_empty() {
+        Tok::String(text)
+    } else {
+        if !text.is_empty() {
+            parts.push(StrPart::Lit(text));
+        }
+        Tok::InterpString(parts)
+    };
+    Some((tok, end + 1))
+}
+
+/// Lex `input` from offset `from` to its end, offsets in spans are relative to the start of `input`
+fn lex_range(
+    input: &[u8],
+    from: usize,
+    make_span: &dyn Fn(usize, usize) -> Span,
+    errors: &mut Vec<Diagnostic>,
+) -> Vec<SpannedTok> {
+    let mut tokens = Vec::new();
+    let mut rest = &input[from..];
+    loop {
+        rest = match skip_trivia(rest) {
+            Ok(rest) => rest,
+            Err(start) => {
+                let start = input.len() - rest.len() + start;
+                errors.push(Diagnostic::error("unterminated block comment")
+                    .with_primary(&make_span(start, start + 2), "comment starts here"));
+                break;
+            }
+        };
+        if rest.is_empty() {
+            break;
+        }
+        let start = input.len() - rest.len();
+        if let Some(len) = doc_comment_len(rest) {
+            let text = String::from_utf8_lossy(&rest[3..len]);
+            let text = text.strip_prefix(' ').unwrap_or(&text).trim_end().to_string();
+            tokens.push(SpannedTok::new(Tok::DocComment(text), make_span(start, start + len)));
+            rest = &rest[len..];
+            continue;
+        }
+        if rest[0] == b'"' {
+            match lex_string(input, start, make_span, errors) {
+                Some((tok, end)) => {
+                    tokens.push(SpannedTok::new(tok, make_span(start, end)));
+                    rest = &input[end..];
+                    continue;
+                }
+                None => {
+                    errors.push(Diagnostic::error("unterminated string literal")
+                        .with_primary(&make_span(start, start + 1), "string starts here"));
+                    break;
+                }
+            }
+        }
+        // `t.0.1` accesses elements of nested tuples, so a number after `.` is never a float
+        let lexed = match tokens.last() {
+            Some(SpannedTok { tok: Tok::Dot, .. }) => lex_integer(rest).or_else(|_| lex_token(rest)),
+            _ => lex_token(rest),
+        };
+        match lexed {
+            Ok((i1, tok)) => {
+                let end = input.len() - i1.len();
+                tokens.push(SpannedTok::new(tok, make_span(start, end)));
+                rest = i1;
+            }
+            Err(_) => {
+                let c = std::str::from_utf8(rest).ok()
+                    .and_then(|s| s.chars().next())
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                let (len, diagnostic) = if c.is_ascii_digit() {
+                    let len = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+                    let diagnostic = Diagnostic::error("integer literal is too large")
+                        .with_primary(&make_span(start, start + len), "number out of range of `int`");
+                    (len, diagnostic)
+                } else {
+                    let len = c.len_utf8().min(rest.len());
+                    let diagnostic = Diagnostic::error(format!("unknown character `{}`", c))
+                        .with_primary(&make_span(start, start + len), "unexpected character");
+                    (len, diagnostic)
+                };
+                errors.push(diagnostic);
+                rest = &rest[len..];
+            }
+        }
+    }
+    tokens
 }
 
 pub struct Lexer;
 impl Lexer{
-    pub fn lex_tokens(input: &[u8]) -> Vec<Tok> {
-        let (i1, tok) = lex_tokens(input).unwrap();
-        assert_eq!(i1.len(), 0);
-        tok
+    /// Split source code into tokens, every token is tagged with its location in `file`
+    pub fn lex_tokens(input: &[u8], file: Rc<str>) -> Result<Vec<SpannedTok>, Vec<Diagnostic>> {
+        let line_index = LineIndex::new(input);
+        let make_span = |start: usize, end: usize| {
+            let (line, column) = line_index.line_col(input, start);
+            Span::new(Rc::clone(&file), line, column, start, end)
+        };
+        let mut errors = Vec::new();
+        let tokens = lex_range(input, 0, &make_span, &mut errors);
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
     }
 }
@@ -1,18 +1,22 @@
 use std::{env, fs};
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use crate::frontend::ast::element::ProgramElement;
+use crate::frontend::ast::element::{ProgramClassElement, ProgramElement, ProgramEnumElement, ProgramGlobalElement, ProgramImplElement, ProgramInterfaceElement};
+use crate::frontend::diagnostic::{Diagnostic, SourceMap};
 use crate::frontend::lexer::Lexer;
 use crate::frontend::module_man::ProgramModuleDecl;
 use crate::frontend::parser::Parser;
-use crate::frontend::tok::Tokens;
+use crate::frontend::span::Span;
 
 pub struct ScriptFileLoader {
     load_path: Vec<PathBuf>,
-    file_queue: Vec<PathBuf>,
+    file_queue: Vec<(PathBuf, Option<Span>)>,
     loaded_file_set: HashSet<PathBuf>,
     loaded_module: HashMap<String, Vec<ProgramElement>>,
+    source_map: SourceMap,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl ScriptFileLoader {
@@ -22,67 +26,123 @@ impl ScriptFileLoader {
             file_queue: Vec::new(),
             loaded_file_set: Default::default(),
             loaded_module: HashMap::new(),
+            source_map: SourceMap::default(),
+            diagnostics: Vec::new(),
         }
     }
 
-    fn add_module(&mut self, name: &str, parent: Option<&PathBuf>) -> Result<(), ()> {
+    /// Source code of all loaded files, used to render diagnostics
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    fn add_module(&mut self, name: &str, parent: Option<&Path>, span: &Span) {
         if let Some(parent_file) = parent {
             let file_to_load = parent_file.parent().unwrap().join(name).with_extension("aa");
-            self.file_queue.push(file_to_load);
-            return Ok(())
+            self.file_queue.push((file_to_load, Some(span.clone())));
+            return;
         }
 
         for path in &self.load_path {
             let file_name = path.join(name);
             if file_name.exists() {
-                self.file_queue.push(file_name);
-                return self.load_from_queue();
+                self.file_queue.push((file_name, Some(span.clone())));
+                return;
             }
         }
 
-        Err(())
+        self.diagnostics.push(
+            Diagnostic::error(format!("can't find module `{}`", name))
+                .with_primary(span, "imported here"),
+        );
     }
 
-    fn load_from_queue(&mut self) -> Result<(), ()> {
-        while !self.file_queue.is_empty() {
-            let file = self.file_queue.pop().unwrap();
+    fn load_from_queue(&mut self) {
+        while let Some((file, import_span)) = self.file_queue.pop() {
             if !self.loaded_file_set.contains(&file) {
-                self.add_file(&file)?;
+                self.load_file(&file, import_span.as_ref());
             }
         }
-        Ok(())
     }
 
-    pub fn add_file(&mut self, path: &PathBuf) -> Result<(), ()> {
-        let file = path.canonicalize().unwrap();
-        let name = file.file_stem().unwrap().to_str().unwrap();
+    /// Load `path` and all modules it imports
+    pub fn add_file(&mut self, path: &Path) -> Result<(), Vec<Diagnostic>> {
+        self.load_file(path, None);
+        self.load_from_queue();
+        if self.diagnostics.iter().any(Diagnostic::is_error) {
+            Err(std::mem::take(&mut self.diagnostics))
+        } else {
+            Ok(())
+        }
+    }
 
-        let code = fs::read_to_string(&file).unwrap();
+    fn load_file(&mut self, path: &Path, import_span: Option<&Span>) {
+        let read_result = path.canonicalize()
+            .and_then(|file| fs::read_to_string(&file).map(|code| (file, code)));
+        let (file, code) = match read_result {
+            Ok(result) => result,
+            Err(e) => {
+                let diagnostic = Diagnostic::error(format!("can't read file `{}`: {}", path.display(), e));
+                let diagnostic = match import_span {
+                    Some(span) => diagnostic.with_primary(span, "imported here"),
+                    None => diagnostic,
+                };
+                self.diagnostics.push(diagnostic);
+                return;
+            }
+        };
+        let name = file.file_stem().unwrap().to_str().unwrap().to_string();
         self.loaded_file_set.insert(file.clone());
 
-        let token = Lexer::lex_tokens(code.as_bytes());
-        let programs = Parser::parse(Tokens::new(&token), name)
+        let display_name: Rc<str> = Self::display_name(&file).into();
+        self.source_map.insert(Rc::clone(&display_name), &code);
+        let token = match Lexer::lex_tokens(code.as_bytes(), display_name) {
+            Ok(token) => token,
+            Err(mut diagnostics) => {
+                self.diagnostics.append(&mut diagnostics);
+                return;
+            }
+        };
+        let programs = match Parser::parse(&token, &name) {
+            Ok(programs) => programs,
+            Err(mut diagnostics) => {
+                self.diagnostics.append(&mut diagnostics);
+                return;
+            }
+        };
+        let programs = programs
             .into_iter()
             .filter(|e| {
                 match &e {
-                    ProgramElement::Import(module_name) => {
-                        self.add_module(module_name, Some(&file)).unwrap();
+                    ProgramElement::Import(module_name, span) => {
+                        self.add_module(module_name, Some(&file), span);
                         false
                     }
                     _ => true
                 }
             }).collect();
 
-        self.loaded_module.insert(name.to_string(), programs);
+        self.loaded_module.insert(name, programs);
+    }
 
-        Ok(())
+    /// Path of `file` used in diagnostics, relative to working directory if possible
+    fn display_name(file: &Path) -> String {
+        let relative = env::current_dir()
+            .ok()
+            .and_then(|dir| file.strip_prefix(dir).ok().map(Path::to_path_buf));
+        relative.unwrap_or_else(|| file.to_path_buf()).to_string_lossy().to_string()
     }
-    pub fn unwrap(mut self) -> HashMap<String, ProgramModuleDecl> {
-        let _ = &self.load_from_queue().unwrap();
 
+    pub fn unwrap(self) -> Result<HashMap<String, ProgramModuleDecl>, Vec<Diagnostic>> {
         let mut map: HashMap<String, ProgramModuleDecl> = HashMap::new();
+        let mut diagnostics = Vec::new();
         for (module_name, element_vec) in self.loaded_module {
             let mut functions = HashMap::new();
+            let mut classes: HashMap<String, ProgramClassElement> = HashMap::new();
+            let mut enums: HashMap<String, ProgramEnumElement> = HashMap::new();
+            let mut interfaces: HashMap<String, ProgramInterfaceElement> = HashMap::new();
+            let mut impls: Vec<ProgramImplElement> = Vec::new();
+            let mut globals: Vec<ProgramGlobalElement> = Vec::new();
             for element in element_vec {
                 match element {
                     ProgramElement::Function(f) => {
@@ -91,16 +151,110 @@ impl ScriptFileLoader {
                         }
                         functions.get_mut(&f.header.name).unwrap().push(f);
                     }
-                    _ => todo!("以后会有其他的元素, e.g. {:?}", element)
+                    ProgramElement::Class(class) => {
+                        if let Some(prev) = classes.get(&class.name) {
+                            diagnostics.push(
+                                Diagnostic::error(format!("class `{}` is defined multiple times", class.name))
+                                    .with_primary(&class.span, "redefined here")
+                                    .with_secondary(&prev.span, "previous definition here"),
+                            );
+                            continue;
+                        }
+                        classes.insert(class.name.clone(), class);
+                    }
+                    ProgramElement::Enum(element) => {
+                        if let Some(prev) = enums.get(&element.name) {
+                            diagnostics.push(
+                                Diagnostic::error(format!("enum `{}` is defined multiple times", element.name))
+                                    .with_primary(&element.span, "redefined here")
+                                    .with_secondary(&prev.span, "previous definition here"),
+                            );
+                            continue;
+                        }
+                        enums.insert(element.name.clone(), element);
+                    }
+                    ProgramElement::Interface(element) => {
+                        if let Some(prev) = interfaces.get(&element.name) {
+                            let diagnostic = Diagnostic::error(format!("interface `{}` is defined multiple times", element.name));
+                            let diagnostic = match (&element.span, &prev.span) {
+                                (Some(span), Some(prev_span)) => diagnostic
+                                    .with_primary(span, "redefined here")
+                                    .with_secondary(prev_span, "previous definition here"),
+                                _ => diagnostic,
+                            };
+                            diagnostics.push(diagnostic);
+                            continue;
+                        }
+                        interfaces.insert(element.name.clone(), element);
+                    }
+                    ProgramElement::Impl(element) => impls.push(element),
+                    ProgramElement::Global(element) => {
+                        if let Some(prev) = globals.iter().find(|prev| prev.name == element.name) {
+                            diagnostics.push(
+                                Diagnostic::error(format!("`{}` is defined multiple times in module `{}`", element.name, module_name))
+                                    .with_primary(&element.span, "redefined here")
+                                    .with_secondary(&prev.span, "previous definition here"),
+                            );
+                            continue;
+                        }
+                        globals.push(element);
+                    }
+                    ProgramElement::Import(..) => unreachable!()
+                }
+            }
+            // methods of `impl` blocks join their class, which must be declared in the same module
+            for element in impls {
+                let Some(class) = classes.get_mut(&element.class) else {
+                    diagnostics.push(
+                        Diagnostic::error(format!("cannot find class `{}` in module `{}`", element.class, module_name))
+                            .with_primary(&element.span, "`impl` for unknown class")
+                            .with_note("`impl` must be in the module declaring the class"),
+                    );
+                    continue;
+                };
+                if let Some((_, prev_span)) = class.interfaces.iter().find(|(name, _)| name == &element.interface) {
+                    diagnostics.push(
+                        Diagnostic::error(format!("conflicting implementations of `{}` for `{}`", element.interface, class.name))
+                            .with_primary(&element.span, "implemented again here")
+                            .with_secondary(prev_span, "first implementation here"),
+                    );
+                    continue;
+                }
+                class.interfaces.push((element.interface, element.span));
+                for method in element.methods {
+                    // a call on the class could not choose between methods taking the same arguments
+                    let prev = class.methods_named(class.short_name(&method.header))
+                        .find(|prev| prev.header.param_types() == method.header.param_types());
+                    if let Some(prev) = prev {
+                        let name = class.short_name(&method.header);
+                        let diagnostic = Diagnostic::error(format!("duplicate definitions of method `{}` for `{}`", name, class.name));
+                        let diagnostic = match (&method.header.span, &prev.header.span) {
+                            (Some(span), Some(prev_span)) => diagnostic
+                                .with_primary(span, format!("duplicate definition of `{}`", name))
+                                .with_secondary(prev_span, format!("other definition of `{}`", name)),
+                            _ => diagnostic,
+                        };
+                        diagnostics.push(diagnostic);
+                        continue;
+                    }
+                    class.methods.push(method);
                 }
             }
 
             let module = ProgramModuleDecl {
                 function: functions,
-                vm_function: Default::default()
+                vm_function: Default::default(),
+                class: classes,
+                enums,
+                interfaces,
+                globals,
             };
             map.insert(module_name, module);
         }
-        map
+        if diagnostics.is_empty() {
+            Ok(map)
+        } else {
+            Err(diagnostics)
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,14 @@
 pub mod ast;
 pub mod codegen;
+pub mod typeck;
+pub mod exhaustive;
+pub mod capture;
+pub mod field_init;
 pub mod tok;
 pub mod lexer;
 pub mod parser;
 pub mod loader;
 pub mod gen_info;
-pub mod module_man;
\ No newline at end of file
+pub mod module_man;
+pub mod span;
+pub mod diagnostic;
\ No newline at end of file
@@ -1,31 +1,31 @@
 use std::collections::HashMap;
 
-use crate::frontend::ast::basic::TypeInfo;
-use crate::frontend::ast::element::AstProgramFunctionImplElement;
-use crate::frontend::ast::func::{FunctionBasicInfo, FunctionMatcher};
+use crate::frontend::ast::element::{AstProgramFunctionImplElement, ProgramClassElement, ProgramEnumElement, ProgramGlobalElement, ProgramInterfaceElement};
+use crate::frontend::ast::func::FunctionBasicInfo;
 use crate::vm::builtin::ProgramVmFnElement;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ProgramModuleDecl {
     pub function: HashMap<String, Vec<AstProgramFunctionImplElement>>,
     pub vm_function: HashMap<String, Vec<ProgramVmFnElement>>,
-}
-
-impl Default for ProgramModuleDecl {
-    fn default() -> Self {
-        Self {
-            function: Default::default(),
-            vm_function: Default::default(),
-        }
-    }
+    pub class: HashMap<String, ProgramClassElement>,
+    pub enums: HashMap<String, ProgramEnumElement>,
+    pub interfaces: HashMap<String, ProgramInterfaceElement>,
+    /// Module level variables in order of declaration, which is the index of their storage
+    pub globals: Vec<ProgramGlobalElement>,
 }
 
 impl ProgramModuleDecl {
-    pub fn search_function(&self, name: &str, param: Option<&Vec<TypeInfo>>) -> Option<&FunctionBasicInfo> {
+    /// Name of the function initializing module level variables, user can't declare a function of it
+    pub const INIT: &'static str = "<init>";
+
+    /// First function named `name` whose header is accepted by `accepts`, like by `FunctionMatcher::is_executable_by`
+    /// with argument types of a call
+    pub fn search_function(&self, name: &str, accepts: impl Fn(&FunctionBasicInfo) -> bool) -> Option<&FunctionBasicInfo> {
         if self.vm_function.contains_key(name) {
             let funcs = self.vm_function.get(name).unwrap();
             for item in funcs {
-                if item.is_executable_by(name, param) {
+                if accepts(&item.header) {
                     return Some(&item.header)
                 }
             }
@@ -33,7 +33,7 @@ impl ProgramModuleDecl {
         } else if self.function.contains_key(name) {
             let funcs = self.function.get(name).unwrap();
             for item in funcs {
-                if item.is_executable_by(name, param) {
+                if accepts(&item.header) {
                     return Some(&item.header);
                 }
             }
@@ -42,4 +42,16 @@ impl ProgramModuleDecl {
             None
         }
     }
+
+    /// Module level variable named `name` with its index
+    pub fn global(&self, name: &str) -> Option<(usize, &ProgramGlobalElement)> {
+        self.globals.iter().enumerate().find(|(_, global)| global.name == name)
+    }
+
+    /// All functions named `name`, used to hint user when no overload matches
+    pub fn candidates(&self, name: &str) -> Vec<&FunctionBasicInfo> {
+        let vm_functions = self.vm_function.get(name).into_iter().flatten().map(|f| &f.header);
+        let functions = self.function.get(name).into_iter().flatten().map(|f| &f.header);
+        vm_functions.chain(functions).collect()
+    }
 }
@@ -1,21 +1,97 @@
+use std::cell::RefCell;
+
 use nom::branch::alt;
 use nom::bytes::complete::take;
-use nom::combinator::{map, opt, verify};
+use nom::combinator::{map, not, opt, verify};
 use nom::Err;
-use nom::error::{Error, ErrorKind};
-use nom::IResult;
-use nom::multi::many0;
+use nom::error::ErrorKind;
+use nom::{IResult, Slice};
+use nom::multi::{many0, separated_list0};
 use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 
-use crate::frontend::ast::basic::{AccessedIdent, AstExprNode, AstStmtNode, Op, StmtBlock, TypeInfo, UnaryOp};
-use crate::frontend::ast::element::{AstProgramFunctionImplElement, ProgramClassElement, ProgramElement};
+use crate::frontend::ast::basic::{AccessedIdent, AstExpr, AstExprNode, AstPattern, AstStmt, AstStmtNode, CatchClause, ForIter, MatchArm, Op, PatternNode, Spanned, StmtBlock, TypeInfo, UnaryOp};
+use crate::frontend::ast::element::{AstProgramFunctionImplElement, ClassField, EnumVariant, ProgramClassElement, ProgramElement, ProgramEnumElement, ProgramGlobalElement, ProgramImplElement, ProgramInterfaceElement};
 use crate::frontend::ast::func::FunctionBasicInfo;
-use crate::frontend::tok::{Tok, Tokens};
+use crate::frontend::diagnostic::Diagnostic;
+use crate::frontend::span::Span;
+use crate::frontend::tok::{SpannedTok, StrPart, Tok, Tokens};
+
+/// Parse error keeps the furthest position parser has reached,
+/// which is usually where the source code goes wrong
+#[derive(Debug, PartialEq)]
+pub struct ParseError<'a> {
+    input: Tokens<'a>,
+    /// What parser expected to see at `input`
+    expected: Option<String>,
+}
+
+impl<'a> ParseError<'a> {
+    fn new(input: Tokens<'a>) -> Self {
+        Self { input, expected: None }
+    }
+
+    fn expected(input: Tokens<'a>, expected: &str) -> Self {
+        Self { input, expected: Some(expected.to_string()) }
+    }
+
+    /// Convert to diagnostic, use `context` as message if nothing is known about the error
+    fn into_diagnostic(self, context: &str) -> Diagnostic {
+        let message = self.expected.unwrap_or_else(|| context.to_string());
+        match self.input.tok.first() {
+            Some(tok) => Diagnostic::error(format!("{}, found `{}`", message, tok.tok))
+                .with_primary(&tok.span, "unexpected token"),
+            None => Diagnostic::error(format!("{}, found end of file", message))
+                .with_primary(&self.input.ctx.eof_span, "source ends here"),
+        }
+    }
+}
+
+impl<'a> nom::error::ParseError<Tokens<'a>> for ParseError<'a> {
+    fn from_error_kind(input: Tokens<'a>, _: ErrorKind) -> Self {
+        Self::new(input)
+    }
+
+    fn append(_: Tokens<'a>, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        if other.input.tok.len() < self.input.tok.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// State shared by all tokens of a file while parsing
+#[derive(Debug, PartialEq)]
+pub struct ParseContext {
+    /// Syntax errors parser has recovered from
+    diagnostics: RefCell<Vec<Diagnostic>>,
+    /// Empty span at the end of file, used by errors at end of input
+    eof_span: Span,
+}
+
+type PResult<'a, O> = IResult<Tokens<'a>, O, ParseError<'a>>;
+
+/// Once parser has committed to a syntax, `parser` must succeed.
+/// Otherwise a failure saying `expected` is returned, it will not be backtracked by `alt`
+fn expect<'a, O>(
+    mut parser: impl FnMut(Tokens<'a>) -> PResult<'a, O>,
+    expected: &'static str,
+) -> impl FnMut(Tokens<'a>) -> PResult<'a, O> {
+    move |input: Tokens<'a>| match parser(input) {
+        Err(Err::Error(e)) if e.input.tok.len() < input.tok.len() => Err(Err::Failure(e)),
+        Err(Err::Error(_)) => Err(Err::Failure(ParseError::expected(input, expected))),
+        result => result,
+    }
+}
 
 macro_rules! tag_token (
   ($func_name:ident, $tag:expr) => (
-      fn $func_name (tokens: Tokens) -> IResult<Tokens, Tokens> {
-          verify(take(1usize), |t:&Tokens| t.tok[0] == $tag)(tokens)
+      fn $func_name (tokens: Tokens) -> PResult<Tokens> {
+          verify(take(1usize), |t:&Tokens| t.tok[0].tok == $tag)(tokens)
       }
   )
 );
@@ -33,352 +109,1224 @@ tag_token!(eq_tag, Tok::Eq);
 tag_token!(ne_tag, Tok::Ne);
 tag_token!(and_tag, Tok::And);
 tag_token!(or_tag, Tok::Or);
+tag_token!(pipe_tag, Tok::Pipe);
 
 tag_token!(lparen_tag, Tok::LParen);
 tag_token!(rparen_tag, Tok::RParen);
 tag_token!(lbrace_tag, Tok::LBrace);
 tag_token!(rbrace_tag, Tok::RBrace);
+tag_token!(lbracket_tag, Tok::LBracket);
+tag_token!(rbracket_tag, Tok::RBracket);
 tag_token!(assign_tag, Tok::Assign);
+tag_token!(add_assign_tag, Tok::AddAssign);
+tag_token!(sub_assign_tag, Tok::SubAssign);
+tag_token!(mul_assign_tag, Tok::MulAssign);
+tag_token!(div_assign_tag, Tok::DivAssign);
+tag_token!(rem_assign_tag, Tok::RemAssign);
+tag_token!(increment_tag, Tok::Increment);
+tag_token!(decrement_tag, Tok::Decrement);
 tag_token!(semicolon_tag, Tok::Semicolon);
 tag_token!(colon_tag, Tok::Colon);
 tag_token!(rarrow_tag, Tok::RightArrow);
 tag_token!(comma_tag, Tok::Comma);
 tag_token!(not_tag, Tok::Not);
 tag_token!(dot_tag, Tok::Dot);
+tag_token!(question_tag, Tok::Question);
+tag_token!(question_dot_tag, Tok::QuestionDot);
+tag_token!(question_question_tag, Tok::QuestionQuestion);
 
 tag_token!(fn_kwd_tag, Tok::KwdFn);
 tag_token!(ret_kwd_tag, Tok::KwdRet);
 
-tag_token!(var_kwd_tag, Tok::KwdVal);
-tag_token!(val_kwd_tag, Tok::KwdVar);
+tag_token!(val_kwd_tag, Tok::KwdVal);
+tag_token!(var_kwd_tag, Tok::KwdVar);
 
 tag_token!(if_kwd_tag, Tok::KwdIf);
 tag_token!(elif_kwd_tag, Tok::KwdElif);
 tag_token!(else_kwd_tag, Tok::KwdElse);
 tag_token!(while_kwd_tag, Tok::KwdWhile);
+tag_token!(for_kwd_tag, Tok::KwdFor);
+tag_token!(in_kwd_tag, Tok::KwdIn);
+tag_token!(break_kwd_tag, Tok::KwdBreak);
+tag_token!(continue_kwd_tag, Tok::KwdContinue);
+tag_token!(dotdot_tag, Tok::DotDot);
+tag_token!(dotdoteq_tag, Tok::DotDotEq);
 
 tag_token!(import_kwd_tag, Tok::KwdImport);
 tag_token!(class_kwd_tag, Tok::KwdClass);
+tag_token!(enum_kwd_tag, Tok::KwdEnum);
+tag_token!(match_kwd_tag, Tok::KwdMatch);
+tag_token!(fat_arrow_tag, Tok::FatArrow);
+tag_token!(none_kwd_tag, Tok::KwdNone);
+tag_token!(try_kwd_tag, Tok::KwdTry);
+tag_token!(catch_kwd_tag, Tok::KwdCatch);
+tag_token!(finally_kwd_tag, Tok::KwdFinally);
+tag_token!(throw_kwd_tag, Tok::KwdThrow);
+tag_token!(interface_kwd_tag, Tok::KwdInterface);
+tag_token!(impl_kwd_tag, Tok::KwdImpl);
+
+/// Span covering all tokens consumed from `input` to reach `rest`
+fn consumed_span(input: Tokens, rest: Tokens) -> Span {
+    let consumed = input.tok.len() - rest.tok.len();
+    input.tok[0].span.to(&input.tok[consumed - 1].span)
+}
+
+fn is_item_start(tok: &Tok) -> bool {
+    matches!(tok, Tok::KwdFn | Tok::KwdClass | Tok::KwdEnum | Tok::KwdImport | Tok::KwdInterface | Tok::KwdImpl)
+}
 
-fn parse_ident(input: Tokens) -> IResult<Tokens, String> {
+fn is_stmt_start(tok: &Tok) -> bool {
+    matches!(tok, Tok::KwdVal | Tok::KwdVar | Tok::KwdWhile | Tok::KwdFor | Tok::KwdRet | Tok::KwdIf
+        | Tok::KwdBreak | Tok::KwdContinue | Tok::Label(_))
+}
+
+/// Skip a statement broken at `error`: stop after `;` or a `{ }` block,
+/// or before `}` closing current block and keywords starting a statement or an item.
+/// Nothing before `error` is treated as a boundary, except `}` closing current block
+fn skip_to_stmt_boundary<'a>(input: Tokens<'a>, error: &Tokens) -> Tokens<'a> {
+    let error_idx = input.tok.len().saturating_sub(error.tok.len());
+    let mut depth = 0;
+    for (idx, tok) in input.tok.iter().enumerate() {
+        let after_error = idx >= error_idx;
+        match tok.tok {
+            Tok::LBrace => depth += 1,
+            Tok::RBrace if depth == 0 => return input.slice(idx..),
+            Tok::RBrace => {
+                depth -= 1;
+                if depth == 0 && after_error {
+                    return input.slice(idx + 1..);
+                }
+            }
+            Tok::Semicolon if depth == 0 && after_error => return input.slice(idx + 1..),
+            ref tok if depth == 0 && after_error && idx > 0 && (is_stmt_start(tok) || is_item_start(tok)) => {
+                return input.slice(idx..);
+            }
+            ref tok if depth == 0 && idx > 0 && is_item_start(tok) => return input.slice(idx..),
+            _ => {}
+        }
+    }
+    input.slice(input.tok.len()..)
+}
+
+/// Skip a broken item until next `fn`, `class` or `import`
+fn skip_to_item_boundary(input: Tokens) -> Tokens {
+    let next = input.tok
+        .iter()
+        .skip(1)
+        .position(|tok| is_item_start(&tok.tok))
+        .map(|idx| idx + 1)
+        .unwrap_or(input.tok.len());
+    input.slice(next..)
+}
+
+fn parse_ident(input: Tokens) -> PResult<String> {
     let (i1, t1) = take(1usize)(input)?;
     if t1.tok.is_empty() {
-        Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        Err(Err::Error(ParseError::new(input)))
     } else {
-        match t1.tok[0].clone() {
+        match t1.tok[0].tok.clone() {
             Tok::Ident(name) => Ok((i1, name)),
-            _ => Err(Err::Error(Error::new(input, ErrorKind::Tag))),
+            _ => Err(Err::Error(ParseError::new(input))),
         }
     }
 }
 
-fn parse_accessed_ident(input: Tokens) -> IResult<Tokens, AccessedIdent> {
-    let (i1, (id, mut idents)) = pair(parse_ident, many0(preceded(dot_tag, parse_ident)))(input)?;
+/// `a.b.c`, it stops before `.0` which accesses element of a tuple
+fn parse_accessed_ident(input: Tokens) -> PResult<AccessedIdent> {
+    let (i1, (id, mut idents)) = pair(
+        parse_ident,
+        many0(preceded(
+            pair(dot_tag, not(parse_tuple_index)),
+            expect(parse_ident, "expected identifier after `.`"))))(input)?;
     idents.insert(0, id);
     Ok((i1, idents))
 }
 
-fn parse_ident_expr(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    map(parse_accessed_ident, |tok| Box::new(AstExprNode::Ident(tok)))(input)
+/// Index of tuple element in `t.0`
+fn parse_tuple_index(input: Tokens) -> PResult<String> {
+    let (i1, t1) = take(1usize)(input)?;
+    match t1.tok.first().map(|t| &t.tok) {
+        Some(Tok::Int(idx)) => Ok((i1, idx.to_string())),
+        _ => Err(Err::Error(ParseError::new(input))),
+    }
 }
 
-fn parse_num(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
+fn parse_ident_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, ident) = parse_accessed_ident(input)?;
+    Ok((i1, Box::new(Spanned::new(AstExprNode::Ident(ident), consumed_span(input, i1)))))
+}
+
+fn parse_num(input: Tokens) -> PResult<Box<AstExpr>> {
     let (i1, t1) = take(1usize)(input)?;
     if t1.tok.is_empty() {
-        Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        Err(Err::Error(ParseError::new(input)))
     } else {
-        match t1.tok.first().unwrap() {
-            Tok::Int(num) => Ok((i1, Box::new(AstExprNode::Integer(*num)))),
-            Tok::Float(num) => Ok((i1, Box::new(AstExprNode::Float(*num)))),
-            _ => Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        let t1 = t1.tok.first().unwrap();
+        match t1.tok {
+            Tok::Int(num) => Ok((i1, Box::new(Spanned::new(AstExprNode::Integer(num), t1.span.clone())))),
+            Tok::Float(num) => Ok((i1, Box::new(Spanned::new(AstExprNode::Float(num), t1.span.clone())))),
+            _ => Err(Err::Error(ParseError::new(input)))
         }
     }
 }
 
-fn parse_bool(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
+fn parse_bool(input: Tokens) -> PResult<Box<AstExpr>> {
     let (i1, t1) = take(1usize)(input)?;
     if t1.tok.is_empty() {
-        Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        Err(Err::Error(ParseError::new(input)))
     } else {
-        match t1.tok.first().unwrap() {
-            Tok::Bool(b) => Ok((i1, Box::new(AstExprNode::Bool(*b)))),
-            _ => Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        let t1 = t1.tok.first().unwrap();
+        match t1.tok {
+            Tok::Bool(b) => Ok((i1, Box::new(Spanned::new(AstExprNode::Bool(b), t1.span.clone())))),
+            _ => Err(Err::Error(ParseError::new(input)))
         }
     }
 }
 
+fn parse_none(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, _) = none_kwd_tag(input)?;
+    Ok((i1, Box::new(Spanned::new(AstExprNode::None, consumed_span(input, i1)))))
+}
 
-fn parse_string(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
+fn parse_string(input: Tokens) -> PResult<Box<AstExpr>> {
     let (i1, t1) = take(1usize)(input)?;
     if t1.tok.is_empty() {
-        Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        Err(Err::Error(ParseError::new(input)))
     } else {
-        match t1.tok.first().unwrap() {
-            Tok::String(s) => Ok((i1, Box::new(AstExprNode::String(s.clone())))),
-            _ => Err(Err::Error(Error::new(input, ErrorKind::Tag)))
+        let t1 = t1.tok.first().unwrap();
+        match &t1.tok {
+            Tok::String(s) => Ok((i1, Box::new(Spanned::new(AstExprNode::String(s.clone()), t1.span.clone())))),
+            Tok::InterpString(parts) => Ok((i1, parse_interpolation(parts, &t1.span, input.ctx)?)),
+            _ => Err(Err::Error(ParseError::new(input)))
         }
     }
 }
 
+/// Desugar `"a${x}b"` into `"a" + prelude.to_string(x) + "b"`
+fn parse_interpolation<'a>(parts: &'a [StrPart], span: &Span, ctx: &'a ParseContext) -> Result<Box<AstExpr>, Err<ParseError<'a>>> {
+    let mut result: Option<Box<AstExpr>> = None;
+    for part in parts {
+        let expr = match part {
+            StrPart::Lit(s) => Box::new(Spanned::new(AstExprNode::String(s.clone()), span.clone())),
+            StrPart::Expr(tokens) => {
+                let (_, (expr, _)) = pair(
+                    expect(parse_expr, "expected expression in string interpolation"),
+                    expect(rbrace_tag, "expected `}` to close string interpolation"))(Tokens::new(tokens, ctx))?;
+                let expr_span = expr.span.clone();
+                let to_string = vec![String::from("prelude"), String::from("to_string")];
+                Box::new(Spanned::new(AstExprNode::FnCall(to_string, Some(vec![*expr])), expr_span))
+            }
+        };
+        result = Some(match result {
+            Some(left) => Box::new(Spanned::new(AstExprNode::Op(left, Op::Add, expr), span.clone())),
+            None => expr,
+        });
+    }
+    Ok(result.unwrap_or_else(|| Box::new(Spanned::new(AstExprNode::String(String::new()), span.clone()))))
+}
 
-fn parse_primary(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let fst_match = tuple((lparen_tag, parse_expr, rparen_tag))(input);
-    if fst_match.is_ok() {
-        let (i1, (_, expr, _)) = fst_match.unwrap();
-        Ok((i1, expr))
-    } else {
-        alt((parse_fn_call, parse_assign_expr, parse_num, parse_bool, parse_string, parse_if_expr, parse_ident_expr))(input)
+/// `(expr)` or tuple `(a, b, ...)`
+fn parse_paren_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (_, expr, rest, _)) = tuple((
+        lparen_tag,
+        expect(parse_expr, "expected expression after `(`"),
+        many0(preceded(comma_tag, expect(parse_expr, "expected expression after `,`"))),
+        expect(rparen_tag, "expected `)` to close parenthesized expression")))(input)?;
+    if rest.is_empty() {
+        return Ok((i1, expr));
+    }
+    let rest = std::iter::once(*expr).chain(rest.into_iter().map(|item| *item)).collect();
+    Ok((i1, Box::new(Spanned::new(AstExprNode::Tuple(rest), consumed_span(input, i1)))))
+}
+
+/// `[a, b, c]`, a trailing comma is allowed
+fn parse_list_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (_, items, _, _)) = tuple((
+        lbracket_tag,
+        opt(pair(parse_expr, many0(preceded(comma_tag, parse_expr)))),
+        opt(comma_tag),
+        expect(rbracket_tag, "expected `]` to close list")))(input)?;
+    let items = items
+        .map(|(first, rest)| std::iter::once(*first).chain(rest.into_iter().map(|item| *item)).collect())
+        .unwrap_or_default();
+    let expr = Box::new(Spanned::new(AstExprNode::List(items), consumed_span(input, i1)));
+    Ok((i1, expr))
+}
+
+/// `{key: value, ...}`, a trailing comma is allowed
+fn parse_map_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    fn parse_entry(input: Tokens) -> PResult<(Box<AstExpr>, Box<AstExpr>)> {
+        let (i1, (key, _, value)) = tuple((
+            parse_expr,
+            expect(colon_tag, "expected `:` after map key"),
+            expect(parse_expr, "expected value after `:`")))(input)?;
+        Ok((i1, (key, value)))
     }
+    let (i1, (_, entries, _, _)) = tuple((
+        lbrace_tag,
+        opt(pair(parse_entry, many0(preceded(comma_tag, parse_entry)))),
+        opt(comma_tag),
+        expect(rbrace_tag, "expected `}` to close map")))(input)?;
+    let entries = entries
+        .map(|(first, mut rest)| {
+            rest.insert(0, first);
+            rest
+        })
+        .unwrap_or_default();
+    let expr = Box::new(Spanned::new(AstExprNode::Map(entries), consumed_span(input, i1)));
+    Ok((i1, expr))
 }
 
+fn parse_atom(input: Tokens) -> PResult<Box<AstExpr>> {
+    alt((parse_paren_expr, parse_list_expr, parse_map_expr, parse_fn_call, parse_num, parse_bool, parse_none, parse_string, parse_if_expr, parse_match_expr, parse_try_expr, parse_lambda_expr, parse_ident_expr))(input)
+}
 
-fn parse_unary(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let fst_match = pair(alt((plus_tag, minus_tag, not_tag)), parse_unary)(input);
-    if fst_match.is_ok() {
-        let (i1, (tokens, expr)) = fst_match.unwrap();
-        let op = match tokens.tok.first().unwrap() {
+/// Integer literal of pattern, which may be negative
+fn parse_pattern_int(input: Tokens) -> PResult<i64> {
+    let (i1, (minus, t1)) = pair(opt(minus_tag), take(1usize))(input)?;
+    match t1.tok.first().map(|t| &t.tok) {
+        Some(Tok::Int(v)) if minus.is_some() => Ok((i1, -v)),
+        Some(Tok::Int(v)) => Ok((i1, *v)),
+        _ => Err(Err::Error(ParseError::new(input))),
+    }
+}
+
+fn parse_pattern(input: Tokens) -> PResult<AstPattern> {
+    fn parse_sub_patterns(input: Tokens) -> PResult<Vec<AstPattern>> {
+        let (i1, (_, first, mut rest, _)) = tuple((
+            lparen_tag,
+            expect(parse_pattern, "expected pattern after `(`"),
+            many0(preceded(comma_tag, expect(parse_pattern, "expected pattern after `,`"))),
+            expect(rparen_tag, "expected `)` to close patterns")))(input)?;
+        rest.insert(0, first);
+        Ok((i1, rest))
+    }
+    fn parse_range(input: Tokens) -> PResult<PatternNode> {
+        let (i1, (start, op, end)) = tuple((
+            parse_pattern_int,
+            alt((dotdoteq_tag, dotdot_tag)),
+            expect(parse_pattern_int, "expected integer after range operator")))(input)?;
+        let inclusive = op.tok[0].tok == Tok::DotDotEq;
+        Ok((i1, PatternNode::Range(start, end, inclusive)))
+    }
+    fn parse_literal(input: Tokens) -> PResult<PatternNode> {
+        let (i1, value) = parse_pattern_int(input)?;
+        Ok((i1, PatternNode::Literal(Box::new(Spanned::new(AstExprNode::Integer(value), consumed_span(input, i1))))))
+    }
+    fn parse_name(input: Tokens) -> PResult<PatternNode> {
+        let (i1, (path, subs)) = pair(parse_accessed_ident, opt(parse_sub_patterns))(input)?;
+        let node = match (path.as_slice(), subs) {
+            ([name], None) if name == "_" => PatternNode::Wildcard,
+            ([name], None) => PatternNode::Bind(name.clone()),
+            ([enum_name, variant], subs) => PatternNode::Variant(enum_name.clone(), variant.clone(), subs.unwrap_or_default()),
+            _ => return Err(Err::Failure(ParseError::expected(input, "expected binding or `Enum.Variant` pattern"))),
+        };
+        Ok((i1, node))
+    }
+
+    let (i1, node) = alt((
+        parse_range,
+        parse_literal,
+        map(alt((parse_bool, parse_string)), PatternNode::Literal),
+        map(parse_sub_patterns, |mut subs| match subs.len() {
+            1 => subs.pop().unwrap().node,
+            _ => PatternNode::Tuple(subs),
+        }),
+        parse_name))(input)?;
+    Ok((i1, Spanned::new(node, consumed_span(input, i1))))
+}
+
+/// `pattern if guard => body`, arms are separated by optional commas
+fn parse_match_arm(input: Tokens) -> PResult<MatchArm> {
+    let (i1, (pattern, guard, _, body)) = tuple((
+        parse_pattern,
+        opt(preceded(if_kwd_tag, expect(parse_expr, "expected guard condition after `if`"))),
+        expect(fat_arrow_tag, "expected `=>` after pattern"),
+        expect(alt((parse_block_expr, parse_expr)), "expected expression after `=>`")))(input)?;
+    let (i2, _) = opt(comma_tag)(i1)?;
+    Ok((i2, MatchArm { pattern, guard, body }))
+}
+
+fn parse_match_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (_, scrutinee, _, arms, _)) = tuple((
+        match_kwd_tag,
+        expect(parse_expr, "expected expression after `match`"),
+        expect(lbrace_tag, "expected `{` after match expression"),
+        many0(parse_match_arm),
+        expect(rbrace_tag, "expected pattern or `}` in match body")))(input)?;
+    let expr = Box::new(Spanned::new(AstExprNode::Match(scrutinee, arms), consumed_span(input, i1)));
+    Ok((i1, expr))
+}
+
+/// Atom followed by `.field`, `.method(args)`, `?.field`, `?.method(args)` or `[index]`
+fn parse_primary(input: Tokens) -> PResult<Box<AstExpr>> {
+    enum Postfix {
+        Member(String, Option<Option<Vec<AstExpr>>>),
+        SafeMember(String, Option<Option<Vec<AstExpr>>>),
+        Index(Box<AstExpr>),
+    }
+    fn parse_member(input: Tokens) -> PResult<Postfix> {
+        let (i1, (dot, name, args)) = tuple((
+            alt((dot_tag, question_dot_tag)),
+            expect(alt((parse_ident, parse_tuple_index)), "expected field or method name after `.`"),
+            opt(tuple((lparen_tag, opt(parse_comma_expr), expect(rparen_tag, "expected `)` after call arguments"))))))(input)?;
+        let args = args.map(|(_, args, _)| args);
+        match dot.tok[0].tok {
+            Tok::QuestionDot => Ok((i1, Postfix::SafeMember(name, args))),
+            _ => Ok((i1, Postfix::Member(name, args))),
+        }
+    }
+    fn parse_index(input: Tokens) -> PResult<Postfix> {
+        let (i1, (_, index, _)) = tuple((
+            lbracket_tag,
+            expect(parse_expr, "expected index expression after `[`"),
+            expect(rbracket_tag, "expected `]` after index")))(input)?;
+        Ok((i1, Postfix::Index(index)))
+    }
+
+    let (mut rest, mut expr) = parse_atom(input)?;
+    loop {
+        let (i1, postfix) = match alt((parse_member, parse_index))(rest) {
+            Ok(result) => result,
+            Err(Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        };
+        let node = match postfix {
+            Postfix::Member(name, Some(args)) => AstExprNode::MethodCall(expr, name, args),
+            Postfix::Member(name, None) => AstExprNode::FieldAccess(expr, name),
+            Postfix::SafeMember(name, args) => AstExprNode::SafeAccess(expr, name, args),
+            Postfix::Index(index) => AstExprNode::Index(expr, index),
+        };
+        expr = Box::new(Spanned::new(node, consumed_span(input, i1)));
+        rest = i1;
+    }
+    Ok((rest, expr))
+}
+
+fn parse_unary(input: Tokens) -> PResult<Box<AstExpr>> {
+    fn parse_unary_op(input: Tokens) -> PResult<Box<AstExpr>> {
+        let (i1, (tokens, expr)) = pair(
+            alt((plus_tag, minus_tag, not_tag)),
+            expect(parse_unary, "expected expression after unary operator"))(input)?;
+        let op = match tokens.tok.first().unwrap().tok {
             Tok::Plus => UnaryOp::Plus,
             Tok::Minus => UnaryOp::Minus,
             Tok::Not => UnaryOp::Not,
             _ => unreachable!()
         };
-        Ok((i1, Box::new(AstExprNode::UnaryOp(op, expr))))
-    } else {
-        parse_primary(input)
+        let span = consumed_span(input, i1);
+        Ok((i1, Box::new(Spanned::new(AstExprNode::UnaryOp(op, expr), span))))
     }
+    alt((parse_unary_op, parse_primary))(input)
 }
 
-fn parse_mul(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let (i1, (mut lhs, seq)) = pair(parse_unary, many0(pair(alt((mul_tag, div_tag, rem_tag)), parse_unary)))(input)?;
+/// Build a binary operator node, its span covers both operands
+fn make_op(lhs: Box<AstExpr>, op: Op, rhs: Box<AstExpr>) -> Box<AstExpr> {
+    let span = lhs.span.to(&rhs.span);
+    Box::new(Spanned::new(AstExprNode::Op(lhs, op, rhs), span))
+}
+
+const EXPECT_RHS: &str = "expected expression after binary operator";
+
+fn parse_mul(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (mut lhs, seq)) = pair(parse_unary, many0(pair(alt((mul_tag, div_tag, rem_tag)), expect(parse_unary, EXPECT_RHS))))(input)?;
     for (tokens, rhs) in seq {
-        let op = match tokens.tok.first().unwrap() {
+        let op = match tokens.tok.first().unwrap().tok {
             Tok::Multiply => Op::Mul,
             Tok::Divide => Op::Div,
             Tok::Rem => Op::Rem,
             _ => unreachable!()
         };
-        lhs = Box::new(AstExprNode::Op(lhs, op, rhs))
+        lhs = make_op(lhs, op, rhs)
     }
     Ok((i1, lhs))
 }
 
-fn parse_add(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let (i1, (mut lhs, seq)) = pair(parse_mul, many0(pair(alt((plus_tag, minus_tag)), parse_mul)))(input)?;
+fn parse_add(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (mut lhs, seq)) = pair(parse_mul, many0(pair(alt((plus_tag, minus_tag)), expect(parse_mul, EXPECT_RHS))))(input)?;
     for (tokens, rhs) in seq {
-        let op = match tokens.tok.first().unwrap() {
+        let op = match tokens.tok.first().unwrap().tok {
             Tok::Plus => Op::Add,
             Tok::Minus => Op::Sub,
             _ => unreachable!()
         };
-        lhs = Box::new(AstExprNode::Op(lhs, op, rhs))
+        lhs = make_op(lhs, op, rhs)
     }
     Ok((i1, lhs))
 }
 
-fn parse_relational(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let fst_match = tuple((parse_add, alt((le_tag, ge_tag, lt_tag, gt_tag)), parse_add))(input);
-    if fst_match.is_ok() {
-        let (i1, (lhs, tokens, rhs)) = fst_match.unwrap();
-        let op = match tokens.tok.first().unwrap() {
-            Tok::Lt => Op::Lt,
-            Tok::Le => Op::Le,
-            Tok::Gt => Op::Gt,
-            Tok::Ge => Op::Ge,
-            _ => unreachable!()
-        };
-        Ok((i1, Box::new(AstExprNode::Op(lhs, op, rhs))))
-    } else {
-        parse_add(input)
+fn parse_infix_op(input: Tokens) -> PResult<String> {
+    let (i1, t1) = take(1usize)(input)?;
+    match t1.tok.first().map(|t| &t.tok) {
+        Some(Tok::InfixOp(name)) => Ok((i1, name.clone())),
+        _ => Err(Err::Error(ParseError::new(input))),
     }
 }
 
-fn parse_equality(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let fst_match = tuple((parse_relational, alt((eq_tag, ne_tag)), parse_relational))(input);
-    if fst_match.is_ok() {
-        let (i1, (lhs, tokens, rhs)) = fst_match.unwrap();
-        let op = match tokens.tok.first().unwrap() {
-            Tok::Ne => Op::Ne,
-            Tok::Eq => Op::Eq,
-            _ => unreachable!()
-        };
-        Ok((i1, Box::new(AstExprNode::Op(lhs, op, rhs))))
-    } else {
-        parse_relational(input)
+/// ``a `name` b`` calls the function `name(a, b)`, left associative and below `+` and `-`
+fn parse_infix(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (mut lhs, seq)) = pair(parse_add, many0(pair(parse_infix_op, expect(parse_add, EXPECT_RHS))))(input)?;
+    for (name, rhs) in seq {
+        lhs = make_op(lhs, Op::InfixFn(name), rhs)
+    }
+    Ok((i1, lhs))
+}
+
+/// `maybe ?? default`, right associative so that `a ?? b ?? c` tries `a`, `b` and then `c`
+fn parse_coalesce(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (lhs, rhs)) = pair(parse_infix, opt(preceded(question_question_tag, expect(parse_coalesce, EXPECT_RHS))))(input)?;
+    match rhs {
+        Some(rhs) => {
+            let span = lhs.span.to(&rhs.span);
+            Ok((i1, Box::new(Spanned::new(AstExprNode::Coalesce(lhs, rhs), span))))
+        }
+        None => Ok((i1, lhs)),
+    }
+}
+
+fn parse_relational(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (lhs, rhs)) = pair(parse_coalesce, opt(pair(alt((le_tag, ge_tag, lt_tag, gt_tag)), expect(parse_coalesce, EXPECT_RHS))))(input)?;
+    match rhs {
+        Some((tokens, rhs)) => {
+            let op = match tokens.tok.first().unwrap().tok {
+                Tok::Lt => Op::Lt,
+                Tok::Le => Op::Le,
+                Tok::Gt => Op::Gt,
+                Tok::Ge => Op::Ge,
+                _ => unreachable!()
+            };
+            Ok((i1, make_op(lhs, op, rhs)))
+        }
+        None => Ok((i1, lhs)),
     }
 }
 
-fn parse_logic(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let (i1, (mut lhs, seq)) = pair(parse_equality, many0(pair(alt((and_tag, or_tag)), parse_equality)))(input)?;
+fn parse_equality(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (lhs, rhs)) = pair(parse_relational, opt(pair(alt((eq_tag, ne_tag)), expect(parse_relational, EXPECT_RHS))))(input)?;
+    match rhs {
+        Some((tokens, rhs)) => {
+            let op = match tokens.tok.first().unwrap().tok {
+                Tok::Ne => Op::Ne,
+                Tok::Eq => Op::Eq,
+                _ => unreachable!()
+            };
+            Ok((i1, make_op(lhs, op, rhs)))
+        }
+        None => Ok((i1, lhs)),
+    }
+}
+
+fn parse_logic(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (mut lhs, seq)) = pair(parse_equality, many0(pair(alt((and_tag, or_tag)), expect(parse_equality, EXPECT_RHS))))(input)?;
     for (tokens, rhs) in seq {
-        let op = match tokens.tok.first().unwrap() {
+        let op = match tokens.tok.first().unwrap().tok {
             Tok::And => Op::And,
             Tok::Or => Op::Or,
             _ => unreachable!()
         };
-        lhs = Box::new(AstExprNode::Op(lhs, op, rhs))
+        lhs = make_op(lhs, op, rhs)
     }
     Ok((i1, lhs))
 }
 
-fn parse_comma_expr(input: Tokens) -> IResult<Tokens, Vec<Box<AstExprNode>>> {
-    let (i1, (expr, mut exprs)) = pair(parse_expr, many0(preceded(comma_tag, parse_expr)))(input)?;
-    exprs.insert(0, expr);
+fn parse_comma_expr(input: Tokens) -> PResult<Vec<AstExpr>> {
+    let (i1, (expr, exprs)) = pair(
+        parse_expr,
+        many0(preceded(comma_tag, expect(parse_expr, "expected expression after `,`"))))(input)?;
+    let exprs = std::iter::once(*expr).chain(exprs.into_iter().map(|item| *item)).collect();
     Ok((i1, exprs))
 }
 
-fn parse_fn_call(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let (i1, (fn_name, _, args, _)) = tuple((parse_accessed_ident, lparen_tag, opt(parse_comma_expr), rparen_tag))(input)?;
-    let expr = Box::new(AstExprNode::FnCall(fn_name, args));
+fn parse_fn_call(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (fn_name, _, args, _)) = tuple((
+        parse_accessed_ident,
+        lparen_tag,
+        opt(parse_comma_expr),
+        expect(rparen_tag, "expected `)` after call arguments")))(input)?;
+    let expr = Box::new(Spanned::new(AstExprNode::FnCall(fn_name, args), consumed_span(input, i1)));
     Ok((i1, expr))
 }
 
-fn parse_expr(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    parse_logic(input)
+/// Assignment is the loosest and right associative, its target is checked to be assignable by type checker.
+/// `target++` and `target--` are `target += 1` and `target -= 1`
+fn parse_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, (target, assign)) = pair(parse_logic, opt(alt((
+        map(alt((increment_tag, decrement_tag)), |tokens| (tokens, None)),
+        map(pair(
+            alt((assign_tag, add_assign_tag, sub_assign_tag, mul_assign_tag, div_assign_tag, rem_assign_tag)),
+            expect(parse_expr, "expected expression after assignment operator")), |(tokens, value)| (tokens, Some(value)))))))(input)?;
+    let Some((tokens, value)) = assign else {
+        return Ok((i1, target));
+    };
+    let op_tok = tokens.tok.first().unwrap();
+    let op = match op_tok.tok {
+        Tok::Assign => None,
+        Tok::AddAssign | Tok::Increment => Some(Op::Add),
+        Tok::SubAssign | Tok::Decrement => Some(Op::Sub),
+        Tok::MulAssign => Some(Op::Mul),
+        Tok::DivAssign => Some(Op::Div),
+        Tok::RemAssign => Some(Op::Rem),
+        _ => unreachable!()
+    };
+    let value = value.unwrap_or_else(|| Box::new(Spanned::new(AstExprNode::Integer(1), op_tok.span.clone())));
+    let span = target.span.to(&value.span);
+    Ok((i1, Box::new(Spanned::new(AstExprNode::AssignExpr(target, op, value), span))))
+}
+
+fn parse_block_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let (i1, block) = parse_block_stmt(input)?;
+    Ok((i1, Box::new(Spanned::new(AstExprNode::BlockExpr(block), consumed_span(input, i1)))))
 }
 
-fn parse_if_expr(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    fn parse_else(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-        preceded(
-            else_kwd_tag,
-            map(
-                parse_block_stmt,
-                |x| Box::new(AstExprNode::BlockExpr(x))))(input)
+/// Condition of `if` or `elif`, which may unwrap an optional like `val x = maybe`
+fn parse_if_cond(input: Tokens) -> PResult<Box<AstExpr>> {
+    fn parse_binding(input: Tokens) -> PResult<Box<AstExpr>> {
+        let (i1, (_, name, _, value)) = tuple((
+            val_kwd_tag,
+            expect(parse_ident, "expected variable name after `val`"),
+            expect(assign_tag, "expected `=` after variable name"),
+            expect(parse_expr, "expected optional value after `=`")))(input)?;
+        Ok((i1, Box::new(Spanned::new(AstExprNode::OptionalBinding(name, value), consumed_span(input, i1)))))
     }
-    fn parse_elif(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-        let (i1, (_, cond, code)) = tuple(
+    alt((parse_binding, parse_expr))(input)
+}
+
+fn parse_if_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    fn parse_else(input: Tokens) -> PResult<Box<AstExpr>> {
+        preceded(else_kwd_tag, expect(parse_block_expr, "expected `{` after `else`"))(input)
+    }
+    fn parse_elif(input: Tokens) -> PResult<Box<AstExpr>> {
+        let (i1, (_, cond, code, els)) = tuple(
             (elif_kwd_tag,
-             parse_expr,
-             parse_block_stmt))(input)?;
-        let ret = opt(alt((parse_elif, parse_else)))(i1);
-        if let Ok((tok, els)) = ret {
-            Ok((tok, Box::new(
-                AstExprNode::IfExpr(
-                    cond,
-                    Box::new(AstExprNode::BlockExpr(code)),
-                    els))))
-        } else {
-            Ok((i1, Box::new(
-                AstExprNode::IfExpr(
-                    cond,
-                    Box::new(AstExprNode::BlockExpr(code)),
-                    None))))
-        }
+             expect(parse_if_cond, "expected condition after `elif`"),
+             expect(parse_block_expr, "expected `{` after elif condition"),
+             opt(alt((parse_elif, parse_else)))))(input)?;
+        let span = consumed_span(input, i1);
+        Ok((i1, Box::new(Spanned::new(AstExprNode::IfExpr(cond, code, els), span))))
     }
 
-    let (i1, (_, cond, code, els)) = tuple((if_kwd_tag, parse_expr, parse_block_stmt, opt(alt((parse_elif, parse_else)))))(input)?;
-    let expr = Box::new(AstExprNode::IfExpr(cond, Box::new(AstExprNode::BlockExpr(code)), els));
+    let (i1, (_, cond, code, els)) = tuple((
+        if_kwd_tag,
+        expect(parse_if_cond, "expected condition after `if`"),
+        expect(parse_block_expr, "expected `{` after if condition"),
+        opt(alt((parse_elif, parse_else)))))(input)?;
+    let expr = Box::new(Spanned::new(AstExprNode::IfExpr(cond, code, els), consumed_span(input, i1)));
     Ok((i1, expr))
 }
 
-fn parse_expr_stmt(input: Tokens) -> IResult<Tokens, AstStmtNode> {
-    map(terminated(parse_expr, opt(semicolon_tag)), |expr| {
-        AstStmtNode::ExprStmt(expr)
-    })(input)
+fn parse_try_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    fn parse_catch(input: Tokens) -> PResult<CatchClause> {
+        let (i1, (_, binding, body)) = tuple((
+            catch_kwd_tag,
+            opt(parse_ident),
+            expect(parse_block_expr, "expected `{` after `catch`")))(input)?;
+        Ok((i1, CatchClause { binding, body }))
+    }
+
+    let (i1, (_, body, catch, finally)) = tuple((
+        try_kwd_tag,
+        expect(parse_block_expr, "expected `{` after `try`"),
+        opt(parse_catch),
+        opt(preceded(finally_kwd_tag, expect(parse_block_expr, "expected `{` after `finally`")))))(input)?;
+    if catch.is_none() && finally.is_none() {
+        return Err(Err::Failure(ParseError::expected(i1, "expected `catch` or `finally` after `try` block")));
+    }
+    let expr = Box::new(Spanned::new(AstExprNode::Try(body, catch, finally), consumed_span(input, i1)));
+    Ok((i1, expr))
 }
 
-fn parse_var_stmt(input: Tokens) -> IResult<Tokens, AstStmtNode> {
-    let (i1, (kwd, id, ty, _, expr, _)) = tuple((
-        alt((val_kwd_tag, var_kwd_tag)),
-        parse_ident,
-        opt(preceded(colon_tag, parse_ident)),
-        assign_tag,
-        parse_expr,
-        opt(semicolon_tag)))(input)?;
-    let is_const = kwd.tok.first().unwrap() == &Tok::KwdVal;
-    let stmt = AstStmtNode::VarStmt(id, ty.map(TypeInfo::from), is_const, expr);
-    Ok((i1, stmt))
+/// `|x: int, y: int| -> int { .. }` or `|x: int| expr`, `||` starts a lambda without parameters
+fn parse_lambda_expr(input: Tokens) -> PResult<Box<AstExpr>> {
+    let parse_params = |input| alt((
+        map(or_tag, |_| Vec::new()),
+        delimited(
+            pipe_tag,
+            map(opt(parse_func_params), Option::unwrap_or_default),
+            expect(pipe_tag, "expected `|` after lambda parameters"))))(input);
+    let (i1, (params, ret)) = pair(
+        parse_params,
+        opt(preceded(rarrow_tag, expect(parse_type, "expected return type after `->`"))))(input)?;
+    let (i2, body) = match ret {
+        Some(_) => expect(parse_block_expr, "expected `{` after return type of lambda")(i1)?,
+        // `{` starts a block rather than a map literal
+        None => expect(alt((parse_block_expr, parse_expr)), "expected body of lambda")(i1)?,
+    };
+    let expr = Box::new(Spanned::new(AstExprNode::Lambda(params, ret, body), consumed_span(input, i2)));
+    Ok((i2, expr))
 }
 
-fn parse_assign_expr(input: Tokens) -> IResult<Tokens, Box<AstExprNode>> {
-    let (i1, (id, _, expr)) = tuple((parse_ident, assign_tag, parse_expr))(input)?;
-    let expr = Box::new(AstExprNode::AssignExpr(id, expr));
-    Ok((i1, expr))
+fn parse_expr_stmt(input: Tokens) -> PResult<AstStmt> {
+    let (i1, expr) = parse_expr(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    Ok((i2, Spanned::new(AstStmtNode::ExprStmt(expr), consumed_span(input, i1))))
+}
+
+/// `(A, B, ...)`, `(A)` is just `A`
+fn parse_tuple_type(input: Tokens) -> PResult<TypeInfo> {
+    let (i1, (_, first, mut rest, _)) = tuple((
+        lparen_tag,
+        expect(parse_type, "expected type after `(`"),
+        many0(preceded(comma_tag, expect(parse_type, "expected type after `,`"))),
+        expect(rparen_tag, "expected `)` to close tuple type")))(input)?;
+    if rest.is_empty() {
+        return Ok((i1, first));
+    }
+    rest.insert(0, first);
+    Ok((i1, TypeInfo::Tuple(rest)))
+}
+
+/// `fn(A, B) -> R`, type of lambdas
+fn parse_fn_type(input: Tokens) -> PResult<TypeInfo> {
+    let (i1, (_, _, params, _, ret)) = tuple((
+        fn_kwd_tag,
+        expect(lparen_tag, "expected `(` after `fn`"),
+        separated_list0(comma_tag, parse_type),
+        expect(rparen_tag, "expected `)` to close parameter types"),
+        opt(preceded(rarrow_tag, expect(parse_type, "expected return type after `->`")))))(input)?;
+    Ok((i1, TypeInfo::Function(params, Box::new(ret.unwrap_or(TypeInfo::Unit)))))
+}
+
+/// Type name with optional type arguments, like `int` or `List<String>`, a tuple type or a function type,
+/// followed by `?` if it is optional
+fn parse_type(input: Tokens) -> PResult<TypeInfo> {
+    let (i1, (ty, question)) = pair(parse_plain_type, opt(question_tag))(input)?;
+    match question {
+        Some(_) => Ok((i1, TypeInfo::Optional(Box::new(ty)))),
+        None => Ok((i1, ty)),
+    }
+}
+
+fn parse_plain_type(input: Tokens) -> PResult<TypeInfo> {
+    match alt((parse_tuple_type, parse_fn_type))(input) {
+        Err(Err::Error(_)) => {}
+        result => return result,
+    }
+    let (i1, name) = parse_ident(input)?;
+    let (i2, args) = opt(tuple((
+        lt_tag,
+        expect(parse_type, "expected type argument after `<`"),
+        many0(preceded(comma_tag, expect(parse_type, "expected type argument after `,`"))),
+        expect(gt_tag, "expected `>` to close type arguments"))))(i1)?;
+    let Some((_, first, mut rest, _)) = args else {
+        return Ok((i1, TypeInfo::from(name)));
+    };
+    rest.insert(0, first);
+    match (name.as_str(), rest.len()) {
+        ("List", 1) => Ok((i2, TypeInfo::List(Box::new(rest.remove(0))))),
+        ("List", _) => Err(Err::Failure(ParseError::expected(i1, "`List` takes 1 type argument"))),
+        ("Map", 2) => {
+            let value = rest.pop().unwrap();
+            Ok((i2, TypeInfo::Map(Box::new(rest.pop().unwrap()), Box::new(value))))
+        }
+        ("Map", _) => Err(Err::Failure(ParseError::expected(i1, "`Map` takes 2 type arguments"))),
+        // instance of generic class, the number of type arguments is checked by type checker
+        _ => match TypeInfo::from(name.as_str()) {
+            TypeInfo::TypeSym(name, _) => Ok((i2, TypeInfo::TypeSym(name, rest))),
+            _ => Err(Err::Failure(ParseError::expected(i1, &format!("type `{}` does not take type arguments", name)))),
+        },
+    }
+}
+
+/// Names of variables destructuring a tuple, like `(a, _, c)`
+fn parse_tuple_pattern(input: Tokens) -> PResult<Vec<Option<String>>> {
+    let name = |input| map(expect(parse_ident, "expected variable name"), |name| (name != "_").then_some(name))(input);
+    let (i1, (_, first, mut rest, _)) = tuple((
+        lparen_tag,
+        name,
+        many0(preceded(comma_tag, name)),
+        expect(rparen_tag, "expected `)` to close tuple pattern")))(input)?;
+    rest.insert(0, first);
+    Ok((i1, rest))
+}
+
+fn parse_var_stmt(input: Tokens) -> PResult<AstStmt> {
+    enum Target {
+        Name(String),
+        Tuple(Vec<Option<String>>),
+    }
+    let (i1, (is_val, target, ty, _, expr)) = tuple((
+        alt((map(val_kwd_tag, |_| true), map(var_kwd_tag, |_| false))),
+        alt((
+            map(parse_tuple_pattern, Target::Tuple),
+            map(expect(parse_ident, "expected variable name"), Target::Name))),
+        opt(preceded(colon_tag, expect(parse_type, "expected type after `:`"))),
+        expect(assign_tag, "expected `=` in variable declaration"),
+        expect(parse_expr, "expected expression after `=`")))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    let stmt = match target {
+        Target::Name(id) => AstStmtNode::VarStmt(id, ty, is_val, expr),
+        Target::Tuple(names) => AstStmtNode::TupleVarStmt(names, ty, is_val, expr),
+    };
+    Ok((i2, Spanned::new(stmt, consumed_span(input, i1))))
 }
 
 
-fn parse_ret_stmt(input: Tokens) -> IResult<Tokens, AstStmtNode> {
-    map(delimited(ret_kwd_tag, opt(parse_expr), opt(semicolon_tag)), |expr| {
-        AstStmtNode::RetStmt(expr)
-    })(input)
+fn parse_ret_stmt(input: Tokens) -> PResult<AstStmt> {
+    let (i1, expr) = preceded(ret_kwd_tag, opt(parse_expr))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    Ok((i2, Spanned::new(AstStmtNode::RetStmt(expr), consumed_span(input, i1))))
 }
 
-fn parse_while_stmt(input: Tokens) -> IResult<Tokens, AstStmtNode> {
-    let (i1, (cond, stmt)) = preceded(while_kwd_tag, pair(parse_expr, parse_block_stmt))(input)?;
+fn parse_while_stmt(input: Tokens) -> PResult<AstStmt> {
+    let (i1, (cond, stmt)) = preceded(while_kwd_tag, pair(
+        expect(parse_expr, "expected condition after `while`"),
+        expect(parse_block_stmt, "expected `{` after while condition")))(input)?;
+
+    Ok((i1, Spanned::new(AstStmtNode::WhileStmt(None, cond, stmt), consumed_span(input, i1))))
+}
 
-    Ok((i1, AstStmtNode::WhileStmt(cond, stmt)))
+/// `self` is not a keyword, it only has meaning as the first parameter of a method
+fn self_tag(input: Tokens) -> PResult<Tokens> {
+    verify(take(1usize), |t: &Tokens| t.tok[0].tok == Tok::Ident(String::from("self")))(input)
 }
 
-fn parse_stmt(input: Tokens) -> IResult<Tokens, AstStmtNode> {
+/// `step` is not a keyword, it only has meaning after a range
+fn step_tag(input: Tokens) -> PResult<Tokens> {
+    verify(take(1usize), |t: &Tokens| t.tok[0].tok == Tok::Ident(String::from("step")))(input)
+}
+
+fn parse_for_iter(input: Tokens) -> PResult<ForIter> {
+    let (i1, (start, range)) = pair(
+        expect(parse_expr, "expected expression after `in`"),
+        opt(tuple((
+            alt((map(dotdoteq_tag, |_| true), map(dotdot_tag, |_| false))),
+            expect(parse_expr, "expected end of range"),
+            opt(preceded(step_tag, expect(parse_expr, "expected expression after `step`")))))))(input)?;
+    let iter = match range {
+        Some((inclusive, end, step)) => ForIter::Range { start, end, inclusive, step },
+        None => ForIter::Iterable(start),
+    };
+    Ok((i1, iter))
+}
+
+fn parse_for_stmt(input: Tokens) -> PResult<AstStmt> {
+    let (i1, (_, var, _, iter, block)) = tuple((
+        for_kwd_tag,
+        expect(parse_ident, "expected loop variable after `for`"),
+        expect(in_kwd_tag, "expected `in` after loop variable"),
+        parse_for_iter,
+        expect(parse_block_stmt, "expected `{` after for iterator")))(input)?;
+    Ok((i1, Spanned::new(AstStmtNode::ForStmt(None, var, iter, block), consumed_span(input, i1))))
+}
+
+fn parse_label(input: Tokens) -> PResult<String> {
+    let (i1, t1) = take(1usize)(input)?;
+    match t1.tok.first().map(|t| &t.tok) {
+        Some(Tok::Label(name)) => Ok((i1, name.clone())),
+        _ => Err(Err::Error(ParseError::new(input))),
+    }
+}
+
+/// `'name: while ...` or `'name: for ...`
+fn parse_labeled_loop(input: Tokens) -> PResult<AstStmt> {
+    let (i1, (label, _, mut stmt)) = tuple((
+        parse_label,
+        expect(colon_tag, "expected `:` after loop label"),
+        expect(alt((parse_while_stmt, parse_for_stmt)), "expected `while` or `for` after loop label")))(input)?;
+    match &mut stmt.node {
+        AstStmtNode::WhileStmt(loop_label, ..) | AstStmtNode::ForStmt(loop_label, ..) => *loop_label = Some(label),
+        _ => unreachable!(),
+    }
+    Ok((i1, Spanned::new(stmt.node, consumed_span(input, i1))))
+}
+
+fn parse_break_stmt(input: Tokens) -> PResult<AstStmt> {
+    let (i1, (is_break, label)) = pair(
+        alt((map(break_kwd_tag, |_| true), map(continue_kwd_tag, |_| false))),
+        // a label followed by `:` starts the next statement
+        opt(terminated(parse_label, not(colon_tag))))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    let stmt = if is_break { AstStmtNode::Break(label) } else { AstStmtNode::Continue(label) };
+    Ok((i2, Spanned::new(stmt, consumed_span(input, i1))))
+}
+
+fn parse_throw_stmt(input: Tokens) -> PResult<AstStmt> {
+    let (i1, expr) = preceded(throw_kwd_tag, expect(parse_expr, "expected error after `throw`"))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    Ok((i2, Spanned::new(AstStmtNode::Throw(expr), consumed_span(input, i1))))
+}
+
+fn parse_stmt(input: Tokens) -> PResult<AstStmt> {
     alt((
         parse_ret_stmt,
+        parse_throw_stmt,
+        parse_break_stmt,
+        parse_labeled_loop,
         parse_var_stmt,
         parse_while_stmt,
+        parse_for_stmt,
         parse_expr_stmt, ))(input)
 }
 
-fn parse_block_stmt(input: Tokens) -> IResult<Tokens, StmtBlock> {
-    delimited(lbrace_tag, many0(parse_stmt), rbrace_tag)(input)
+/// Parse statements until `}`, a broken statement is reported and skipped
+fn parse_block_stmt(input: Tokens) -> PResult<StmtBlock> {
+    let (mut rest, _) = lbrace_tag(input)?;
+    let mut block = Vec::new();
+    loop {
+        if let Ok((i1, _)) = rbrace_tag(rest) {
+            return Ok((i1, block));
+        }
+        if rest.tok.first().map(|t| is_item_start(&t.tok)).unwrap_or(true) {
+            return Err(Err::Failure(ParseError::expected(rest, "expected `}` to close block")));
+        }
+        match parse_stmt(rest) {
+            Ok((i1, stmt)) => {
+                block.push(stmt);
+                rest = i1;
+            }
+            Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                rest = skip_to_stmt_boundary(rest, &e.input);
+                let diagnostic = e.into_diagnostic("expected statement");
+                rest.ctx.diagnostics.borrow_mut().push(diagnostic);
+            }
+            Err(Err::Incomplete(_)) => unreachable!(),
+        }
+    }
 }
 
-fn parse_func_params(input: Tokens) -> IResult<Tokens, Vec<(String, TypeInfo)>> {
-    fn parse_func_param_item(input: Tokens) -> IResult<Tokens, (String, TypeInfo)> {
-        map(tuple((parse_ident, colon_tag, parse_ident)), |item| (item.0, TypeInfo::from(item.2.as_str())))(input)
+fn parse_func_params(input: Tokens) -> PResult<Vec<(String, TypeInfo)>> {
+    fn parse_func_param_item(input: Tokens) -> PResult<(String, TypeInfo)> {
+        map(tuple((
+            parse_ident,
+            expect(colon_tag, "expected `:` after parameter name"),
+            expect(parse_type, "expected parameter type"))),
+            |item| (item.0, item.2))(input)
     }
-    let (i1, (param, mut params)) = pair(parse_func_param_item, many0(preceded(comma_tag, parse_func_param_item)))(input)?;
+    let (i1, (param, mut params)) = pair(
+        parse_func_param_item,
+        many0(preceded(comma_tag, expect(parse_func_param_item, "expected parameter after `,`"))))(input)?;
     params.insert(0, param);
     Ok((i1, params))
 }
 
-fn parse_func(input: Tokens) -> IResult<Tokens, ProgramElement> {
-    let (i1, (_, id, _, params, _, ret_value, block)) = tuple((
-        fn_kwd_tag,
-        parse_ident,
-        lparen_tag,
-        opt(parse_func_params),
-        rparen_tag,
-        opt(pair(rarrow_tag, parse_ident)),
-        parse_block_stmt))(input)?;
-    let func = ProgramElement::Function(AstProgramFunctionImplElement {
-        header: FunctionBasicInfo {
+/// Join consecutive `///` comments into one doc string
+fn parse_doc_comment(input: Tokens) -> PResult<Option<String>> {
+    let mut lines = Vec::new();
+    let mut rest = input;
+    while let Some(SpannedTok { tok: Tok::DocComment(text), .. }) = rest.tok.first() {
+        lines.push(text.as_str());
+        rest = rest.slice(1..);
+    }
+    let doc = if lines.is_empty() { None } else { Some(lines.join("\n")) };
+    Ok((rest, doc))
+}
+
+/// `<T, U>` after name of generic function or class
+fn parse_type_params(input: Tokens) -> PResult<Vec<String>> {
+    let (i1, (_, first, mut rest, _)) = tuple((
+        lt_tag,
+        expect(parse_ident, "expected type parameter after `<`"),
+        many0(preceded(comma_tag, expect(parse_ident, "expected type parameter after `,`"))),
+        expect(gt_tag, "expected `>` to close type parameters")))(input)?;
+    rest.insert(0, first);
+    Ok((i1, rest))
+}
+
+/// `self` may start parameters of a method, it has type `self_ty`
+fn parse_func_header<'a>(self_ty: Option<TypeInfo>) -> impl FnMut(Tokens<'a>) -> PResult<'a, FunctionBasicInfo> {
+    move |input: Tokens<'a>| {
+        let parse_params = |input: Tokens<'a>| match &self_ty {
+            Some(ty) => match self_tag(input) {
+                Ok((i1, _)) => {
+                    let (i2, params) = opt(preceded(comma_tag, expect(parse_func_params, "expected parameter after `,`")))(i1)?;
+                    let mut params = params.unwrap_or_default();
+                    params.insert(0, (String::from("self"), ty.clone()));
+                    Ok((i2, Some(params)))
+                }
+                Err(_) => opt(parse_func_params)(input),
+            },
+            None => opt(parse_func_params)(input),
+        };
+        let (i1, (_, id, type_params, _, params, _, ret_value)) = tuple((
+            fn_kwd_tag,
+            expect(parse_ident, "expected function name after `fn`"),
+            opt(parse_type_params),
+            expect(lparen_tag, "expected `(` after function name"),
+            parse_params,
+            expect(rparen_tag, "expected `)` after function parameters"),
+            opt(preceded(rarrow_tag, expect(parse_type, "expected return type after `->`")))))(input)?;
+        let header = FunctionBasicInfo {
             name: id,
             param: params,
             module: None,
-            ret: match ret_value {
-                None => None,
-                Some((_, id)) => Some(TypeInfo::from(id.as_str())),
-            },
-        },
-        block,
-    });
-    Ok((i1, func))
+            ret: ret_value,
+            span: Some(consumed_span(input, i1)),
+        };
+        Ok((i1, header.resolve_params(&type_params.unwrap_or_default())))
+    }
 }
 
-fn parse_class(input: Tokens) -> IResult<Tokens, ProgramElement>{
-    let (i1, (name, _, _)) = preceded(class_kwd_tag, tuple((parse_ident, lbrace_tag, rbrace_tag)))(input)?;
+fn parse_func_impl<'a>(self_ty: Option<TypeInfo>) -> impl FnMut(Tokens<'a>) -> PResult<'a, AstProgramFunctionImplElement> {
+    move |input: Tokens<'a>| {
+        let (i1, header) = parse_func_header(self_ty.clone())(input)?;
+        let (i2, block) = expect(parse_block_stmt, "expected `{` to start function body")(i1)?;
+        Ok((i2, AstProgramFunctionImplElement { doc: None, header, block }))
+    }
+}
+
+fn parse_func(input: Tokens) -> PResult<ProgramElement> {
+    map(parse_func_impl(None), ProgramElement::Function)(input)
+}
+
+/// `var name: type = default`, the default value is optional
+fn parse_class_field(input: Tokens) -> PResult<ClassField> {
+    let (i1, (is_val, name, _, ty, default)) = tuple((
+        alt((map(val_kwd_tag, |_| true), map(var_kwd_tag, |_| false))),
+        expect(parse_ident, "expected field name"),
+        expect(colon_tag, "expected `:` after field name"),
+        expect(parse_type, "expected field type"),
+        opt(preceded(assign_tag, expect(parse_expr, "expected expression after `=`")))))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    Ok((i2, ClassField { name, ty, is_val, default, span: consumed_span(input, i1) }))
+}
+
+fn parse_class(input: Tokens) -> PResult<ProgramElement>{
+    let (mut rest, (_, name, type_params, _)) = tuple((
+        class_kwd_tag,
+        expect(parse_ident, "expected class name after `class`"),
+        opt(parse_type_params),
+        expect(lbrace_tag, "expected `{` after class name")))(input)?;
+    let type_params = type_params.unwrap_or_default();
+    let self_ty = TypeInfo::TypeSym(name.clone(), type_params.iter().cloned().map(TypeInfo::Param).collect());
+    let mut fields = Vec::new();
+    let mut methods = Vec::new();
+    loop {
+        if let Ok((i1, _)) = rbrace_tag(rest) {
+            rest = i1;
+            break;
+        }
+        if let Ok((i1, mut field)) = parse_class_field(rest) {
+            field.ty = field.ty.resolve_params(&type_params);
+            fields.push(field);
+            rest = i1;
+            continue;
+        }
+        let (i1, doc) = parse_doc_comment(rest)?;
+        let (i2, mut method) = expect(parse_func_impl(Some(self_ty.clone())), "expected field, method or `}` in class body")(i1)?;
+        method.doc = doc;
+        method.header.name = format!("{}.{}", name, method.header.name);
+        method.header = method.header.resolve_params(&type_params);
+        methods.push(method);
+        rest = i2;
+    }
     let class = ProgramClassElement{
+        doc: None,
+        name,
+        type_params,
+        module: String::from(""),
+        fields,
+        methods,
+        interfaces: Vec::new(),
+        span: consumed_span(input, rest),
+    };
+    Ok((rest, ProgramElement::Class(class)))
+}
+
+/// `Variant` or `Variant(types of payload)`
+fn parse_enum_variant(input: Tokens) -> PResult<EnumVariant> {
+    let (i1, (name, fields)) = pair(
+        parse_ident,
+        opt(tuple((
+            lparen_tag,
+            expect(parse_type, "expected payload type after `(`"),
+            many0(preceded(comma_tag, expect(parse_type, "expected payload type after `,`"))),
+            expect(rparen_tag, "expected `)` after payload types")))))(input)?;
+    let fields = fields
+        .map(|(_, first, mut rest, _)| {
+            rest.insert(0, first);
+            rest
+        })
+        .unwrap_or_default();
+    Ok((i1, EnumVariant { name, fields, span: consumed_span(input, i1) }))
+}
+
+/// `enum Name { A, B(int), ... }`, a trailing comma is allowed
+fn parse_enum(input: Tokens) -> PResult<ProgramElement> {
+    let (i1, (_, name, _, variants, _, _)) = tuple((
+        enum_kwd_tag,
+        expect(parse_ident, "expected enum name after `enum`"),
+        expect(lbrace_tag, "expected `{` after enum name"),
+        opt(pair(parse_enum_variant, many0(preceded(comma_tag, parse_enum_variant)))),
+        opt(comma_tag),
+        expect(rbrace_tag, "expected variant or `}` in enum body")))(input)?;
+    let variants = variants
+        .map(|(first, mut rest)| {
+            rest.insert(0, first);
+            rest
+        })
+        .unwrap_or_default();
+    let element = ProgramEnumElement {
+        doc: None,
         name,
-        module: String::from("")
+        module: String::new(),
+        variants,
+        span: consumed_span(input, i1),
     };
-    Ok((i1, ProgramElement::Class(class)))
+    Ok((i1, ProgramElement::Enum(element)))
 }
 
-fn parse_import(input: Tokens) -> IResult<Tokens, ProgramElement> {
-    let (i1, (_, module_name, _)) = tuple((import_kwd_tag, parse_ident, opt(semicolon_tag)))(input)?;
-    Ok((i1, ProgramElement::Import(module_name)))
+/// `interface Name { fn method(self, params) -> ret; ... }`, methods have no body
+fn parse_interface(input: Tokens) -> PResult<ProgramElement> {
+    let (mut rest, (_, name, _)) = tuple((
+        interface_kwd_tag,
+        expect(parse_ident, "expected interface name after `interface`"),
+        expect(lbrace_tag, "expected `{` after interface name")))(input)?;
+    let self_ty = TypeInfo::TypeSym(name.clone(), Vec::new());
+    let mut methods = Vec::new();
+    loop {
+        if let Ok((i1, _)) = rbrace_tag(rest) {
+            rest = i1;
+            break;
+        }
+        let (i1, _) = parse_doc_comment(rest)?;
+        let (i2, mut header) = expect(parse_func_header(Some(self_ty.clone())), "expected method or `}` in interface body")(i1)?;
+        let (i3, _) = opt(semicolon_tag)(i2)?;
+        header.name = format!("{}.{}", name, header.name);
+        methods.push(header);
+        rest = i3;
+    }
+    let element = ProgramInterfaceElement {
+        doc: None,
+        name,
+        module: String::new(),
+        methods,
+        span: Some(consumed_span(input, rest)),
+    };
+    Ok((rest, ProgramElement::Interface(element)))
 }
 
-fn parse_program(input: Tokens) -> IResult<Tokens, ProgramElement> {
-    alt((parse_func, parse_import, parse_class))(input)
+/// `impl Interface for Class { methods }`, a generic class names its type parameters like `Class<T>`
+fn parse_impl(input: Tokens) -> PResult<ProgramElement> {
+    let (mut rest, (_, interface, _, class, type_params, _)) = tuple((
+        impl_kwd_tag,
+        expect(parse_ident, "expected interface name after `impl`"),
+        expect(for_kwd_tag, "expected `for` after interface name"),
+        expect(parse_ident, "expected class name after `for`"),
+        opt(parse_type_params),
+        expect(lbrace_tag, "expected `{` after class name")))(input)?;
+    let type_params = type_params.unwrap_or_default();
+    let self_ty = TypeInfo::TypeSym(class.clone(), type_params.iter().cloned().map(TypeInfo::Param).collect());
+    let mut methods = Vec::new();
+    loop {
+        if let Ok((i1, _)) = rbrace_tag(rest) {
+            rest = i1;
+            break;
+        }
+        let (i1, doc) = parse_doc_comment(rest)?;
+        let (i2, mut method) = expect(parse_func_impl(Some(self_ty.clone())), "expected method or `}` in impl body")(i1)?;
+        method.doc = doc;
+        method.header.name = format!("{}.{}.{}", class, interface, method.header.name);
+        method.header = method.header.resolve_params(&type_params);
+        methods.push(method);
+        rest = i2;
+    }
+    let element = ProgramImplElement { interface, class, methods, span: consumed_span(input, rest) };
+    Ok((rest, ProgramElement::Impl(element)))
+}
+
+fn parse_import(input: Tokens) -> PResult<ProgramElement> {
+    let (i1, (_, module_name)) = tuple((import_kwd_tag, expect(parse_ident, "expected module name after `import`")))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    Ok((i2, ProgramElement::Import(module_name, consumed_span(input, i1))))
+}
+
+/// Module level `val NAME: T = value` or `var`, a tuple can only be destructured in functions
+fn parse_global(input: Tokens) -> PResult<ProgramElement> {
+    let (i1, (is_val, name, ty, _, value)) = tuple((
+        alt((map(val_kwd_tag, |_| true), map(var_kwd_tag, |_| false))),
+        expect(parse_ident, "expected variable name"),
+        opt(preceded(colon_tag, expect(parse_type, "expected type after `:`"))),
+        expect(assign_tag, "expected `=` in variable declaration"),
+        expect(parse_expr, "expected expression after `=`")))(input)?;
+    let (i2, _) = opt(semicolon_tag)(i1)?;
+    let element = ProgramGlobalElement {
+        name,
+        module: String::new(),
+        ty,
+        is_val,
+        value,
+        span: consumed_span(input, i1),
+    };
+    Ok((i2, ProgramElement::Global(element)))
+}
+
+fn parse_program(input: Tokens) -> PResult<ProgramElement> {
+    let (i1, doc) = parse_doc_comment(input)?;
+    if doc.is_none() {
+        return alt((parse_func, parse_import, parse_class, parse_enum, parse_interface, parse_impl, parse_global))(input);
+    }
+    let (i2, element) = expect(
+        alt((parse_func, parse_class, parse_enum, parse_interface)),
+        "expected `fn`, `class`, `enum` or `interface` after doc comment")(i1)?;
+    let element = match element {
+        ProgramElement::Function(mut f) => {
+            f.doc = doc;
+            ProgramElement::Function(f)
+        }
+        ProgramElement::Class(mut c) => {
+            c.doc = doc;
+            ProgramElement::Class(c)
+        }
+        ProgramElement::Enum(mut e) => {
+            e.doc = doc;
+            ProgramElement::Enum(e)
+        }
+        ProgramElement::Interface(mut e) => {
+            e.doc = doc;
+            ProgramElement::Interface(e)
+        }
+        ProgramElement::Import(..) | ProgramElement::Impl(_) | ProgramElement::Global(_) => unreachable!(),
+    };
+    Ok((i2, element))
 }
 
 pub struct Parser;
 
 impl Parser {
-    pub fn parse(tokens: Tokens, module_name: &str) -> Vec<ProgramElement> {
-        let (i1, program) = many0(parse_program)(tokens).unwrap();
-        assert!(i1.tok.is_empty());
-        program.into_iter()
-            .map(|e| e.set_module(module_name.to_string()))
-            .collect()
-    }
-}
\ No newline at end of file
+    /// Parse a file, all syntax errors are collected instead of stopping at the first one
+    pub fn parse(tokens: &[SpannedTok], module_name: &str) -> Result<Vec<ProgramElement>, Vec<Diagnostic>> {
+        let Some(last) = tokens.last() else {
+            return Ok(Vec::new());
+        };
+        // doc comments only document items, elsewhere they are ordinary comments
+        let tokens: Vec<SpannedTok> = tokens
+            .iter()
+            .enumerate()
+            .filter(|(idx, tok)| match tok.tok {
+                Tok::DocComment(_) => tokens[idx + 1..]
+                    .iter()
+                    .find(|next| !matches!(next.tok, Tok::DocComment(_)))
+                    .map(|next| matches!(next.tok, Tok::KwdFn | Tok::KwdClass | Tok::KwdEnum | Tok::KwdInterface))
+                    .unwrap_or(false),
+                _ => true,
+            })
+            .map(|(_, tok)| tok.clone())
+            .collect();
+        let ctx = ParseContext {
+            diagnostics: RefCell::new(Vec::new()),
+            eof_span: last.span.shrink_to_end(),
+        };
+        let mut program = Vec::new();
+        let mut rest = Tokens::new(&tokens, &ctx);
+        while !rest.tok.is_empty() {
+            match parse_program(rest) {
+                Ok((i1, element)) => {
+                    program.push(element.set_module(module_name.to_string()));
+                    rest = i1;
+                }
+                Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                    let diagnostic = e.into_diagnostic("expected `fn`, `class`, `enum`, `interface`, `impl`, `import`, `val` or `var`");
+                    ctx.diagnostics.borrow_mut().push(diagnostic);
+                    rest = skip_to_item_boundary(rest);
+                }
+                Err(Err::Incomplete(_)) => unreachable!(),
+            }
+        }
+        let diagnostics = ctx.diagnostics.take();
+        if diagnostics.is_empty() {
+            Ok(program)
+        } else {
+            Err(diagnostics)
+        }
+    }
+}
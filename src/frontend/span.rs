@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+/// Location of a piece of source code.
+/// `line` and `column` are 1-based and point at the first character,
+/// `start` and `end` are the byte range in the source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: Rc<str>, line: usize, column: usize, start: usize, end: usize) -> Self {
+        Self {
+            file,
+            line,
+            column,
+            start,
+            end,
+        }
+    }
+
+    /// Span covering from the start of `self` to the end of `other`
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            file: Rc::clone(&self.file),
+            line: self.line,
+            column: self.column,
+            start: self.start,
+            end: other.end.max(self.end),
+        }
+    }
+
+    /// Empty span located right after `self`
+    pub fn shrink_to_end(&self) -> Span {
+        Span {
+            file: Rc::clone(&self.file),
+            line: self.line,
+            column: self.column + (self.end - self.start),
+            start: self.end,
+            end: self.end,
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Convert byte offset to line and column
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &[u8]) -> Self {
+        let mut line_starts = vec![0];
+        for (idx, c) in source.iter().enumerate() {
+            if *c == b'\n' {
+                line_starts.push(idx + 1);
+            }
+        }
+        Self { line_starts }
+    }
+
+    /// Return 1-based (line, column) of `offset`, column is counted in chars
+    pub fn line_col(&self, source: &[u8], offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let line_start = self.line_starts[line];
+        let column = String::from_utf8_lossy(&source[line_start..offset]).chars().count();
+        (line + 1, column + 1)
+    }
+}
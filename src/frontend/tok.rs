@@ -1,8 +1,12 @@
+use std::fmt::{Display, Formatter};
 use std::iter::Enumerate;
 use std::ops::{Range, RangeFrom, RangeFull, RangeTo};
 
 use nom::{InputIter, InputLength, InputTake, Needed, Slice};
 
+use crate::frontend::parser::ParseContext;
+use crate::frontend::span::Span;
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum Tok {
     Int(i64),
@@ -10,16 +14,26 @@ pub enum Tok {
     Bool(bool),
     Ident(String),
     String(String),
+    /// String literal containing `${expr}` interpolations
+    InterpString(Vec<StrPart>),
     // punctuations
     LParen,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     Semicolon,
     Colon,
     RightArrow,
+    FatArrow,
     Comma,
     Dot,
+    DotDot,
+    DotDotEq,
+    Question,
+    QuestionDot,
+    QuestionQuestion,
     // keyword
     KwdFn,
     KwdRet,
@@ -30,7 +44,20 @@ pub enum Tok {
     KwdElse,
     KwdElif,
     KwdWhile,
+    KwdFor,
+    KwdIn,
+    KwdBreak,
+    KwdContinue,
     KwdClass,
+    KwdEnum,
+    KwdMatch,
+    KwdNone,
+    KwdTry,
+    KwdCatch,
+    KwdFinally,
+    KwdThrow,
+    KwdInterface,
+    KwdImpl,
 
 
     // operator
@@ -40,9 +67,20 @@ pub enum Tok {
     Divide,
     Rem,
     Assign,
+    AddAssign,
+    SubAssign,
+    MulAssign,
+    DivAssign,
+    RemAssign,
+    /// `++` after an assignment target
+    Increment,
+    /// `--` after an assignment target
+    Decrement,
 
     And,
     Or,
+    /// `|` around parameters of lambda
+    Pipe,
 
     Eq,
     Ne,
@@ -53,21 +91,146 @@ pub enum Tok {
     Not,
     // special
     InfixOp(String),
+    /// Text of a `///` comment, without the slashes
+    DocComment(String),
+    /// Loop label `'name`, without the quote
+    Label(String),
+}
+
+impl Display for Tok {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tok::Int(v) => write!(f, "{}", v),
+            Tok::Float(v) => write!(f, "{}", v),
+            Tok::Bool(v) => write!(f, "{}", v),
+            Tok::Ident(name) => write!(f, "{}", name),
+            Tok::String(s) => write!(f, "\"{}\"", s),
+            Tok::InterpString(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        StrPart::Lit(s) => write!(f, "{}", s)?,
+                        StrPart::Expr(tokens) => {
+                            write!(f, "${{")?;
+                            for (i, tok) in tokens.iter().enumerate() {
+                                if i > 0 {
+                                    write!(f, " ")?;
+                                }
+                                write!(f, "{}", tok.tok)?;
+                            }
+                        }
+                    }
+                }
+                write!(f, "\"")
+            }
+            Tok::LParen => write!(f, "("),
+            Tok::RParen => write!(f, ")"),
+            Tok::LBrace => write!(f, "{{"),
+            Tok::RBrace => write!(f, "}}"),
+            Tok::LBracket => write!(f, "["),
+            Tok::RBracket => write!(f, "]"),
+            Tok::Semicolon => write!(f, ";"),
+            Tok::Colon => write!(f, ":"),
+            Tok::RightArrow => write!(f, "->"),
+            Tok::FatArrow => write!(f, "=>"),
+            Tok::Comma => write!(f, ","),
+            Tok::Dot => write!(f, "."),
+            Tok::DotDot => write!(f, ".."),
+            Tok::DotDotEq => write!(f, "..="),
+            Tok::Question => write!(f, "?"),
+            Tok::QuestionDot => write!(f, "?."),
+            Tok::QuestionQuestion => write!(f, "??"),
+            Tok::KwdFn => write!(f, "fn"),
+            Tok::KwdRet => write!(f, "return"),
+            Tok::KwdVar => write!(f, "var"),
+            Tok::KwdVal => write!(f, "val"),
+            Tok::KwdImport => write!(f, "import"),
+            Tok::KwdIf => write!(f, "if"),
+            Tok::KwdElse => write!(f, "else"),
+            Tok::KwdElif => write!(f, "elif"),
+            Tok::KwdWhile => write!(f, "while"),
+            Tok::KwdFor => write!(f, "for"),
+            Tok::KwdIn => write!(f, "in"),
+            Tok::KwdBreak => write!(f, "break"),
+            Tok::KwdContinue => write!(f, "continue"),
+            Tok::KwdClass => write!(f, "class"),
+            Tok::KwdEnum => write!(f, "enum"),
+            Tok::KwdMatch => write!(f, "match"),
+            Tok::KwdNone => write!(f, "none"),
+            Tok::KwdTry => write!(f, "try"),
+            Tok::KwdCatch => write!(f, "catch"),
+            Tok::KwdFinally => write!(f, "finally"),
+            Tok::KwdThrow => write!(f, "throw"),
+            Tok::KwdInterface => write!(f, "interface"),
+            Tok::KwdImpl => write!(f, "impl"),
+            Tok::Plus => write!(f, "+"),
+            Tok::Minus => write!(f, "-"),
+            Tok::Multiply => write!(f, "*"),
+            Tok::Divide => write!(f, "/"),
+            Tok::Rem => write!(f, "%"),
+            Tok::Assign => write!(f, "="),
+            Tok::AddAssign => write!(f, "+="),
+            Tok::SubAssign => write!(f, "-="),
+            Tok::MulAssign => write!(f, "*="),
+            Tok::DivAssign => write!(f, "/="),
+            Tok::RemAssign => write!(f, "%="),
+            Tok::Increment => write!(f, "++"),
+            Tok::Decrement => write!(f, "--"),
+            Tok::And => write!(f, "&&"),
+            Tok::Or => write!(f, "||"),
+            Tok::Pipe => write!(f, "|"),
+            Tok::Eq => write!(f, "=="),
+            Tok::Ne => write!(f, "!="),
+            Tok::Lt => write!(f, "<"),
+            Tok::Le => write!(f, "<="),
+            Tok::Gt => write!(f, ">"),
+            Tok::Ge => write!(f, ">="),
+            Tok::Not => write!(f, "!"),
+            Tok::InfixOp(name) => write!(f, "`{}`", name),
+            Tok::DocComment(text) => write!(f, "/// {}", text),
+            Tok::Label(name) => write!(f, "'{}", name),
+        }
+    }
+}
+
+/// Piece of an interpolated string literal
+#[derive(PartialEq, Debug, Clone)]
+pub enum StrPart {
+    /// Text with escapes already decoded
+    Lit(String),
+    /// Tokens after `${`, including the closing `}`
+    Expr(Vec<SpannedTok>),
+}
+
+/// Token with its location in source file
+#[derive(PartialEq, Debug, Clone)]
+pub struct SpannedTok {
+    pub tok: Tok,
+    pub span: Span,
+}
+
+impl SpannedTok {
+    pub fn new(tok: Tok, span: Span) -> Self {
+        Self { tok, span }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Tokens<'a> {
-    pub tok: &'a [Tok],
+    pub tok: &'a [SpannedTok],
     pub start: usize,
     pub end: usize,
+    /// State shared by the whole token stream during parsing
+    pub ctx: &'a ParseContext,
 }
 
 impl<'a> Tokens<'a> {
-    pub fn new(vec: &'a [Tok]) -> Self {
+    pub fn new(vec: &'a [SpannedTok], ctx: &'a ParseContext) -> Self {
         Tokens {
             tok: vec,
             start: 0,
             end: vec.len(),
+            ctx,
         }
     }
 }
@@ -86,18 +249,19 @@ impl<'a> InputTake for Tokens<'a> {
             tok: &self.tok[0..count],
             start: 0,
             end: count,
+            ctx: self.ctx,
         }
     }
     #[inline]
     fn take_split(&self, count: usize) -> (Self, Self) {
         let (prefix, suffix) = self.tok.split_at(count);
-        let first = Tokens::new(prefix);
-        let second = Tokens::new(suffix);
+        let first = Tokens::new(prefix, self.ctx);
+        let second = Tokens::new(suffix, self.ctx);
         (second, first)
     }
 }
 
-impl InputLength for Tok {
+impl InputLength for SpannedTok {
     #[inline]
     fn input_len(&self) -> usize {
         1
@@ -111,6 +275,7 @@ impl<'a> Slice<Range<usize>> for Tokens<'a> {
             tok: self.tok.slice(range.clone()),
             start: self.start + range.start,
             end: self.start + range.end,
+            ctx: self.ctx,
         }
     }
 }
@@ -136,14 +301,15 @@ impl<'a> Slice<RangeFull> for Tokens<'a> {
             tok: self.tok,
             start: self.start,
             end: self.end,
+            ctx: self.ctx,
         }
     }
 }
 
 impl<'a> InputIter for Tokens<'a> {
-    type Item = &'a Tok;
-    type Iter = Enumerate<::std::slice::Iter<'a, Tok>>;
-    type IterElem = ::std::slice::Iter<'a, Tok>;
+    type Item = &'a SpannedTok;
+    type Iter = Enumerate<::std::slice::Iter<'a, SpannedTok>>;
+    type IterElem = ::std::slice::Iter<'a, SpannedTok>;
 
     #[inline]
     fn iter_indices(&self) -> Self::Iter {
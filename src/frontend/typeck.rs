@@ -0,0 +1,2705 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use crate::frontend::ast::basic::{AccessedIdent, AstExpr, AstExprNode, AstPattern, AstStmt, AstStmtNode, CatchClause, ForIter, Op, PatternNode, TypeInfo, UnaryOp};
+use crate::frontend::ast::element::{AstProgramFunctionImplElement, ProgramClassElement, ProgramEnumElement, ProgramGlobalElement, ProgramInterfaceElement};
+use crate::frontend::ast::func::{FunctionBasicInfo, FunctionMatcher};
+use crate::frontend::ast::typed::{PatternStep, TypedExpr, TypedExprNode, TypedFunction, TypedMatchArm, TypedModule, TypedStmt, TypedStmtNode};
+use crate::frontend::capture;
+use crate::frontend::diagnostic::Diagnostic;
+use crate::frontend::exhaustive::{self, Ctors, PatShape};
+use crate::frontend::field_init;
+use crate::frontend::gen_info::{Env, VarInfo, VarOrigin};
+use crate::frontend::module_man::ProgramModuleDecl;
+use crate::frontend::span::Span;
+
+/// Signature of the initializer of a module with functions of the initializer and of lambdas in it
+type Initializer = (String, Vec<TypedFunction>);
+
+/// `try` block which `return`, `break` and `continue` have to leave
+struct TryScope {
+    /// Number of loops enclosing the `try` block
+    loops: usize,
+    /// Copied to the places jumping out of the block
+    finally: Option<TypedExpr>,
+}
+
+/// Check types of all functions and resolve names to local slots and function signatures.
+/// Errors are collected, checking goes on with the next statement.
+/// Check functions return `None` after the error is reported
+pub struct TypeChecker<'a> {
+    modules: &'a HashMap<String, ProgramModuleDecl>,
+    env: Env,
+    /// Variables whose declaration is broken, using them reports nothing
+    poisoned: HashSet<String>,
+    /// Labels of loops enclosing current statement, innermost last
+    loops: Vec<Option<String>>,
+    /// `try` blocks enclosing current statement, innermost last
+    trys: Vec<TryScope>,
+    /// Classes of all modules by name
+    classes: HashMap<String, &'a ProgramClassElement>,
+    /// Enums of all modules by name, they share names with classes
+    enums: HashMap<String, &'a ProgramEnumElement>,
+    /// Interfaces of all modules by name, they share names with classes and enums
+    interfaces: HashMap<String, &'a ProgramInterfaceElement>,
+    /// Class whose constructor is being checked, its `val` fields may be assigned through `self`
+    ctor_of: Option<String>,
+    /// Names used by lambdas in the function being checked, `var`s of these names are kept in cells
+    captured: HashSet<String>,
+    /// Functions of lambdas checked in current module
+    lambdas: Vec<TypedFunction>,
+    /// Lambdas checked so far, numbering names of their functions
+    lambda_count: usize,
+    /// Type parameters of the function being checked, they may be used in type annotations of its body
+    type_params: Vec<String>,
+    /// Types of module level variables by module and name, known once their initializers are checked
+    global_types: HashMap<(String, String), TypeInfo>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub fn new(modules: &'a HashMap<String, ProgramModuleDecl>) -> Self {
+        let mut names = modules.keys().collect::<Vec<&String>>();
+        names.sort();
+        let mut classes: HashMap<String, &'a ProgramClassElement> = HashMap::new();
+        let mut diagnostics = Vec::new();
+        for name in &names {
+            let mut module_classes = modules[*name].class.values().collect::<Vec<&ProgramClassElement>>();
+            module_classes.sort_by(|a, b| a.name.cmp(&b.name));
+            for class in module_classes {
+                match classes.get(&class.name) {
+                    Some(prev) => diagnostics.push(
+                        Diagnostic::error(format!("class `{}` is defined in both module `{}` and `{}`", class.name, prev.module, class.module))
+                            .with_primary(&class.span, "redefined here")
+                            .with_secondary(&prev.span, "previous definition here")),
+                    None => {
+                        classes.insert(class.name.clone(), class);
+                    }
+                }
+            }
+        }
+        let mut enums: HashMap<String, &'a ProgramEnumElement> = HashMap::new();
+        for name in &names {
+            let mut module_enums = modules[*name].enums.values().collect::<Vec<&ProgramEnumElement>>();
+            module_enums.sort_by(|a, b| a.name.cmp(&b.name));
+            for element in module_enums {
+                let prev_span = match (classes.get(&element.name), enums.get(&element.name)) {
+                    (Some(prev), _) => Some(&prev.span),
+                    (_, Some(prev)) => Some(&prev.span),
+                    _ => None,
+                };
+                match prev_span {
+                    Some(prev_span) => diagnostics.push(
+                        Diagnostic::error(format!("the name `{}` is defined multiple times", element.name))
+                            .with_primary(&element.span, "redefined here")
+                            .with_secondary(prev_span, "previous definition here")),
+                    None => {
+                        enums.insert(element.name.clone(), element);
+                    }
+                }
+            }
+        }
+        let mut interfaces: HashMap<String, &'a ProgramInterfaceElement> = HashMap::new();
+        for name in &names {
+            let mut module_interfaces = modules[*name].interfaces.values().collect::<Vec<&ProgramInterfaceElement>>();
+            module_interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+            for element in module_interfaces {
+                let prev_span = match (classes.get(&element.name), enums.get(&element.name), interfaces.get(&element.name)) {
+                    (Some(prev), _, _) => Some(Some(&prev.span)),
+                    (_, Some(prev), _) => Some(Some(&prev.span)),
+                    (_, _, Some(prev)) => Some(prev.span.as_ref()),
+                    _ => None,
+                };
+                let Some(prev_span) = prev_span else {
+                    interfaces.insert(element.name.clone(), element);
+                    continue;
+                };
+                let mut diagnostic = Diagnostic::error(format!("the name `{}` is defined multiple times", element.name));
+                if let Some(span) = &element.span {
+                    diagnostic = diagnostic.with_primary(span, "redefined here");
+                }
+                if let Some(prev_span) = prev_span {
+                    diagnostic = diagnostic.with_secondary(prev_span, "previous definition here");
+                }
+                diagnostics.push(diagnostic);
+            }
+        }
+        Self {
+            modules,
+            env: Env::default(),
+            poisoned: HashSet::new(),
+            loops: Vec::new(),
+            trys: Vec::new(),
+            classes,
+            enums,
+            interfaces,
+            ctor_of: None,
+            captured: HashSet::new(),
+            lambdas: Vec::new(),
+            lambda_count: 0,
+            type_params: Vec::new(),
+            global_types: HashMap::new(),
+            diagnostics,
+        }
+    }
+
+    fn report<T>(&mut self, diagnostic: Diagnostic) -> Option<T> {
+        self.diagnostics.push(diagnostic);
+        None
+    }
+
+    /// Modules are returned in the order their module level variables are initialized
+    pub fn check_modules(mut self) -> Result<Vec<TypedModule>, Vec<Diagnostic>> {
+        let mut names = self.modules.keys().cloned().collect::<Vec<String>>();
+        // keep diagnostics in a stable order
+        names.sort();
+        let (order, mut inits) = self.check_globals(&names);
+        let mut typed_modules = Vec::new();
+        for name in names {
+            let module = self.modules.get(&name).unwrap();
+            let (init, mut functions) = match inits.remove(&name) {
+                Some((signature, functions)) => (Some(signature), functions),
+                None => (None, Vec::new()),
+            };
+            let mut module_functions = module.function.values().flatten().collect::<Vec<&AstProgramFunctionImplElement>>();
+            // in source order, so diagnostics and lambda numbering don't depend on hash order
+            module_functions.sort_by_key(|func| func.header.span.as_ref().map(|span| span.start));
+            for func in module_functions {
+                if let Some(typed) = self.check_function(func, &name) {
+                    functions.push(typed);
+                }
+            }
+            let mut classes = module.class.values().collect::<Vec<&ProgramClassElement>>();
+            classes.sort_by(|a, b| a.name.cmp(&b.name));
+            for class in classes {
+                functions.extend(self.check_class(class, &name));
+            }
+            let mut enums = module.enums.values().collect::<Vec<&ProgramEnumElement>>();
+            enums.sort_by(|a, b| a.name.cmp(&b.name));
+            for element in enums {
+                self.check_enum(element);
+            }
+            let mut interfaces = module.interfaces.values().collect::<Vec<&ProgramInterfaceElement>>();
+            interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+            for element in interfaces {
+                self.check_interface(element);
+            }
+            functions.append(&mut self.lambdas);
+            let vm_functions = module.vm_function.values().flatten().cloned().collect();
+            let globals = module.globals.iter().map(|global| global.name.clone()).collect();
+            typed_modules.push(TypedModule { name, functions, vm_functions, globals, init });
+        }
+        typed_modules.sort_by_key(|module| order.iter().position(|name| name == &module.name));
+        if self.diagnostics.is_empty() {
+            Ok(typed_modules)
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+
+    /// Check initializers of module level variables, modules are ordered so that their initializers
+    /// only use variables of modules before them. Return the order with initializers of modules having variables
+    fn check_globals(&mut self, names: &[String]) -> (Vec<String>, HashMap<String, Initializer>) {
+        let mut inits = HashMap::new();
+        let module_deps = |name: &String| {
+            let mut deps = self.modules[name].globals.iter()
+                .flat_map(|global| self.global_deps(global))
+                .map(|(module, _)| module)
+                .filter(|module| module != name)
+                .collect::<Vec<String>>();
+            deps.sort();
+            deps.dedup();
+            deps
+        };
+        let order = match topo_sort(names, module_deps) {
+            Ok(order) => order,
+            Err(cycle) => {
+                let modules = cycle.iter().map(|name| format!("`{}`", name)).collect::<Vec<String>>().join(", ");
+                self.diagnostics.push(Diagnostic::error(format!("initializers of modules {} depend on each other", modules))
+                    .with_note("variables of a module are initialized together, move the variables using each other into one module"));
+                return (names.to_vec(), inits);
+            }
+        };
+        for name in &order {
+            let module = &self.modules[name];
+            if module.globals.is_empty() {
+                continue;
+            }
+            let indices = (0..module.globals.len()).collect::<Vec<usize>>();
+            let sorted = topo_sort(&indices, |idx| {
+                self.global_deps(&module.globals[*idx])
+                    .into_iter()
+                    .filter(|(module, _)| module == name)
+                    .map(|(_, idx)| idx)
+                    .collect()
+            });
+            match sorted {
+                Ok(sorted) => {
+                    let init = self.check_initializers(name, &sorted);
+                    inits.insert(name.clone(), init);
+                }
+                Err(cycle) => {
+                    let first = &module.globals[cycle[0]];
+                    let mut diagnostic = Diagnostic::error(format!("cycle detected when initializing `{}`", first.name))
+                        .with_primary(&first.span, format!("`{}` is used by its own initializer", first.name));
+                    for idx in &cycle[1..] {
+                        let global = &module.globals[*idx];
+                        diagnostic = diagnostic.with_secondary(&global.span, format!("`{}` is part of the cycle", global.name));
+                    }
+                    self.diagnostics.push(diagnostic);
+                }
+            }
+        }
+        (order, inits)
+    }
+
+    /// Module level variables used by initializer of `global`, with their modules.
+    /// Functions called by it are followed, so are the functions they call
+    fn global_deps(&self, global: &ProgramGlobalElement) -> Vec<(String, usize)> {
+        let mut deps = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![(global.module.clone(), capture::paths_used_by(&global.value))];
+        while let Some((module, paths)) = pending.pop() {
+            for path in &paths {
+                if let Some((idx, dep, _)) = self.global_of(path, &module) {
+                    deps.push((dep.module.clone(), idx));
+                    continue;
+                }
+                for (key, callee_module, callee_paths) in self.callee_paths(path, &module) {
+                    if visited.insert(key) {
+                        pending.push((callee_module, callee_paths));
+                    }
+                }
+            }
+        }
+        deps
+    }
+
+    /// Paths used by bodies of functions which `path` may call, with a key of each body and its module.
+    /// Free functions, constructors with field defaults and static methods are found,
+    /// methods called on instances are not
+    fn callee_paths(&self, path: &[String], cur_module: &str) -> Vec<(String, String, Vec<AccessedIdent>)> {
+        let (name, prefix) = path.split_last().unwrap();
+        let is_module = |name: &String| self.modules.contains_key(name) && !self.modules[cur_module].class.contains_key(name);
+        let (module_name, class_name) = match prefix {
+            [] => (cur_module, None),
+            [module] if is_module(module) => (module.as_str(), None),
+            [class] => (cur_module, Some(class)),
+            _ => return Vec::new(),
+        };
+        let Some(module) = self.modules.get(module_name) else {
+            return Vec::new();
+        };
+        let body = |func: &AstProgramFunctionImplElement| {
+            (func.header.signature(), module_name.to_string(), capture::paths_used_in(&func.block))
+        };
+        let mut bodies = module.function.get(name).into_iter().flatten().map(body).collect::<Vec<_>>();
+        // `Class(args)` calls the constructor, `Class.method(args)` a static method
+        let (class, method) = match class_name {
+            Some(class) => (module.class.get(class), name.as_str()),
+            None => (module.class.get(name), ProgramClassElement::CTOR),
+        };
+        let Some(class) = class else {
+            return bodies;
+        };
+        if method == ProgramClassElement::CTOR {
+            let defaults = class.fields.iter().filter_map(|field| field.default.as_deref()).flat_map(capture::paths_used_by);
+            bodies.push((format!("{}.{}", module_name, class.name), module_name.to_string(), defaults.collect()));
+        }
+        bodies.extend(class.methods_named(method).filter(|func| method == ProgramClassElement::CTOR || !func.header.takes_self()).map(body));
+        bodies
+    }
+
+    /// Module level variable named by the start of `path` with its index and the number of names taken,
+    /// it is `NAME` of current module or `module.NAME`. Local variables shadow it
+    fn global_of(&self, path: &[String], cur_module: &str) -> Option<(usize, &'a ProgramGlobalElement, usize)> {
+        if let Some((idx, global)) = self.modules.get(cur_module).and_then(|module| module.global(&path[0])) {
+            return Some((idx, global, 1));
+        }
+        let (idx, global) = self.modules.get(&path[0])?.global(path.get(1)?)?;
+        Some((idx, global, 2))
+    }
+
+    /// Initializer of module assigns its variables in `order`
+    fn check_initializers(&mut self, module_name: &str, order: &[usize]) -> Initializer {
+        let module = &self.modules[module_name];
+        let header = FunctionBasicInfo {
+            name: String::from(ProgramModuleDecl::INIT),
+            module: Some(module_name.to_string()),
+            param: Some(Vec::new()),
+            ret: None,
+            span: None,
+        };
+        let error_count = self.begin_function(&header);
+        let mut body = Vec::new();
+        for idx in order {
+            let global = &module.globals[*idx];
+            self.captured = capture::used_in_lambdas_of(&global.value);
+            if let Some(value) = self.check_initializer(global, &header) {
+                let node = TypedExprNode::AssignGlobal(module_name.to_string(), *idx, Box::new(value));
+                let assign = TypedExpr::new(node, TypeInfo::Unit, global.span.clone());
+                body.push(TypedStmt::new(TypedStmtNode::Expr(assign), global.span.clone()));
+            }
+        }
+        let mut functions = self.finish_function(&header, body, error_count).into_iter().collect::<Vec<TypedFunction>>();
+        functions.append(&mut self.lambdas);
+        (header.signature(), functions)
+    }
+
+    /// Type of module level variable is inferred from the initializer if it is not given
+    fn check_initializer(&mut self, global: &ProgramGlobalElement, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let key = (global.module.clone(), global.name.clone());
+        if let Some(ty) = &global.ty {
+            self.check_type(ty, &global.span)?;
+            self.global_types.insert(key.clone(), ty.clone());
+        }
+        let typed = self.check_expr(&global.value, &global.module, header)?;
+        let typed = match &global.ty {
+            Some(ty) => self.coerce(typed, ty)?,
+            None => typed,
+        };
+        if global.ty.is_none() && typed.ty.has_unknown_elem() {
+            return self.report(Self::annotations_needed(&global.name, &typed.ty, &typed.span));
+        }
+        self.global_types.insert(key, typed.ty.clone());
+        Some(typed)
+    }
+
+    /// Value of module level variable, nothing is reported if its initializer is broken
+    fn load_global(&self, idx: usize, global: &ProgramGlobalElement, span: &Span) -> Option<TypedExpr> {
+        let ty = self.global_types.get(&(global.module.clone(), global.name.clone()))?.clone();
+        Some(TypedExpr::new(TypedExprNode::LoadGlobal(global.module.clone(), idx), ty, span.clone()))
+    }
+
+    fn find_function(
+        &self,
+        name: &str,
+        cur_module: &str,
+        access_module: Option<&str>,
+        param: Option<&Vec<TypeInfo>>,
+        span: &Span,
+    ) -> Result<&'a FunctionBasicInfo, Box<Diagnostic>> {
+        let module_name = access_module.unwrap_or(cur_module);
+        let module = self.modules.get(module_name).ok_or_else(|| {
+            Box::new(Diagnostic::error(format!("can't find module `{}`", module_name))
+                .with_primary(span, "module not imported"))
+        })?;
+        let prelude = self.modules.get("prelude").unwrap();
+        if let Some(header) = module.search_function(name, |header| self.accepts(header, param)) {
+            return Ok(header);
+        } else if let Some(header) = prelude.search_function(name, |header| self.accepts(header, param)) {
+            return Ok(header);
+        }
+
+        let arg_types = param
+            .map(|types| types.iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", "))
+            .unwrap_or_default();
+        let mut diagnostic = Diagnostic::error(format!("can't find function `{}({})` in module `{}`", name, arg_types, module_name))
+            .with_primary(span, "no matching function");
+        for candidate in module.candidates(name).into_iter().chain(prelude.candidates(name)) {
+            let params = candidate.param.as_ref()
+                .map(|params| params.iter().map(|(_, ty)| ty.display_name()).collect::<Vec<String>>().join(", "))
+                .unwrap_or_default();
+            diagnostic = match candidate.span {
+                Some(ref candidate_span) => diagnostic.with_secondary(candidate_span, "candidate function defined here"),
+                None => diagnostic.with_note(format!("candidate function: builtin `{}({})`", name, params)),
+            };
+        }
+        Err(Box::new(diagnostic))
+    }
+
+    fn check_function(&mut self, func: &AstProgramFunctionImplElement, cur_module: &str) -> Option<TypedFunction> {
+        let error_count = self.begin_function(&func.header);
+        self.captured = capture::used_in_lambdas(&func.block);
+        let body = self.check_block(&func.block, cur_module, &func.header);
+        self.finish_function(&func.header, body, error_count)
+    }
+
+    /// Reset environment and declare parameters, return number of errors reported before the function
+    fn begin_function(&mut self, header: &FunctionBasicInfo) -> usize {
+        self.env = Env::default();
+        self.poisoned.clear();
+        self.loops.clear();
+        self.trys.clear();
+        self.type_params = header.type_params();
+        let error_count = self.diagnostics.len();
+        for (name, ty) in header.param.iter().flatten() {
+            if let Some(ref span) = header.span {
+                self.check_type(ty, span);
+            }
+            let slot_id = self.env.current_val_size();
+            self.env.val_insert(name.clone(), VarInfo::param(ty.clone(), slot_id, header.span.as_ref()));
+        }
+        if let (Some(ret), Some(span)) = (&header.ret, &header.span) {
+            self.check_type(ret, span);
+        }
+        error_count
+    }
+
+    fn finish_function(&mut self, header: &FunctionBasicInfo, body: Vec<TypedStmt>, error_count: usize) -> Option<TypedFunction> {
+        let ret_ty = header.ret.as_ref().unwrap_or(&TypeInfo::Unit);
+        // statements with errors are dropped from the body, a `return` among them would be missed
+        if self.diagnostics.len() != error_count {
+            return None;
+        }
+        if ret_ty != &TypeInfo::Unit && !stmts_return(&body) {
+            let mut diagnostic = Diagnostic::error(format!("function `{}` may not return a value on all paths", header.name))
+                .with_note("add a `return` statement at the end of the function");
+            if let Some(ref span) = header.span {
+                diagnostic = diagnostic.with_primary(span, format!("expected `{}` because of return type", ret_ty.display_name()));
+            }
+            self.diagnostics.push(diagnostic);
+        }
+        if self.diagnostics.len() != error_count {
+            return None;
+        }
+        Some(TypedFunction {
+            header: header.clone(),
+            local_var_size: self.env.max_val_table_size,
+            body,
+        })
+    }
+
+    /// Types named by user must be declared
+    fn check_type(&mut self, ty: &TypeInfo, span: &Span) -> Option<()> {
+        match ty {
+            TypeInfo::TypeSym(name, _)
+                if !self.classes.contains_key(name) && !self.enums.contains_key(name) && !self.interfaces.contains_key(name) => {
+                self.report(Diagnostic::error(format!("cannot find type `{}` in this scope", name))
+                    .with_primary(span, "not found in this scope"))
+            }
+            TypeInfo::TypeSym(name, args) => {
+                let expected = self.classes.get(name).map(|class| class.type_params.len()).unwrap_or(0);
+                if args.len() != expected {
+                    let diagnostic = match expected {
+                        0 => Diagnostic::error(format!("type `{}` does not take type arguments", name)),
+                        _ => Diagnostic::error(format!(
+                            "class `{}` takes {} but {} supplied",
+                            name, count(expected, "type argument"), count(args.len(), "was"))),
+                    };
+                    return self.report(diagnostic.with_primary(span, "wrong number of type arguments"));
+                }
+                args.iter()
+                    .map(|arg| self.check_type(arg, span))
+                    .collect::<Vec<Option<()>>>()
+                    .into_iter()
+                    .collect()
+            }
+            TypeInfo::List(elem) => self.check_type(elem, span),
+            TypeInfo::Optional(inner) if matches!(**inner, TypeInfo::Optional(_)) => {
+                self.report(Diagnostic::error(format!("optional of optional type `{}` is not supported", ty.display_name()))
+                    .with_primary(span, "nested optional type")
+                    .with_note(format!("use `{}` instead", inner.display_name())))
+            }
+            TypeInfo::Optional(inner) => self.check_type(inner, span),
+            TypeInfo::Tuple(items) => items
+                .iter()
+                .map(|item| self.check_type(item, span))
+                .collect::<Vec<Option<()>>>()
+                .into_iter()
+                .collect(),
+            TypeInfo::Function(params, ret) => params
+                .iter()
+                .chain([&**ret])
+                .map(|ty| self.check_type(ty, span))
+                .collect::<Vec<Option<()>>>()
+                .into_iter()
+                .collect(),
+            TypeInfo::Map(key, value) => {
+                let key_known = self.check_type(key, span);
+                let value_known = self.check_type(value, span);
+                if key_known.is_some() && !key.is_hashable() {
+                    return self.report(Diagnostic::error(format!("`{}` cannot be a map key", key.display_name()))
+                        .with_primary(span, "unsupported key type")
+                        .with_note("keys of a map can be `int`, `bool` or `String`"));
+                }
+                key_known.and(value_known)
+            }
+            _ => Some(()),
+        }
+    }
+
+    /// Variant names are unique and payload types are declared
+    fn check_enum(&mut self, element: &ProgramEnumElement) {
+        for (idx, variant) in element.variants.iter().enumerate() {
+            if let Some(prev) = element.variants[..idx].iter().find(|prev| prev.name == variant.name) {
+                self.diagnostics.push(Diagnostic::error(format!("variant `{}` is defined multiple times", element.variant_path(idx)))
+                    .with_primary(&variant.span, "redefined here")
+                    .with_secondary(&prev.span, "previous definition here"));
+            }
+            for ty in &variant.fields {
+                self.check_type(ty, &variant.span);
+            }
+        }
+    }
+
+    fn class_of(&self, ty: &TypeInfo) -> Option<&'a ProgramClassElement> {
+        match ty {
+            TypeInfo::TypeSym(name, _) => self.classes.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// Constructor declared by user, or the one taking fields without default value in order
+    fn ctor_header(class: &ProgramClassElement) -> FunctionBasicInfo {
+        if let Some(ctor) = class.methods_named(ProgramClassElement::CTOR).next() {
+            return ctor.header.clone();
+        }
+        let mut param = vec![(String::from("self"), class.self_type())];
+        param.extend(class.fields.iter()
+            .filter(|field| field.default.is_none())
+            .map(|field| (field.name.clone(), field.ty.clone())));
+        FunctionBasicInfo {
+            name: class.method_name(ProgramClassElement::CTOR),
+            module: Some(class.module.clone()),
+            param: Some(param),
+            ret: None,
+            span: Some(class.span.clone()),
+        }
+    }
+
+    fn check_class(&mut self, class: &ProgramClassElement, cur_module: &str) -> Vec<TypedFunction> {
+        for (idx, field) in class.fields.iter().enumerate() {
+            self.check_type(&field.ty, &field.span);
+            if let Some(prev) = class.fields[..idx].iter().find(|prev| prev.name == field.name) {
+                self.diagnostics.push(Diagnostic::error(format!("field `{}` is already declared", field.name))
+                    .with_primary(&field.span, "field redeclared")
+                    .with_secondary(&prev.span, "first declared here"));
+            }
+        }
+        self.check_impls(class);
+        let ctor_name = class.method_name(ProgramClassElement::CTOR);
+        let mut functions: Vec<TypedFunction> = class.methods
+            .iter()
+            .filter(|method| method.header.name != ctor_name)
+            .filter_map(|method| self.check_function(method, cur_module))
+            .collect();
+        if let Some(ctor) = self.check_ctor(class, cur_module) {
+            functions.push(ctor);
+        }
+        functions
+    }
+
+    /// Each interface implemented by the class must exist and have all its methods implemented with the same types
+    fn check_impls(&mut self, class: &ProgramClassElement) {
+        for (name, span) in &class.interfaces {
+            let Some(interface) = self.interfaces.get(name).copied() else {
+                self.diagnostics.push(Diagnostic::error(format!("cannot find interface `{}` in this scope", name))
+                    .with_primary(span, "not found in this scope"));
+                continue;
+            };
+            for (idx, method) in interface.methods.iter().enumerate() {
+                // broken methods are reported with the interface
+                let broken = !method.takes_self() || interface.methods[..idx].iter().any(|prev| prev.name == method.name);
+                if broken || Self::implementation(class, interface, method).is_some() {
+                    continue;
+                }
+                let mut diagnostic = Diagnostic::error(format!(
+                    "method `{}` of interface `{}` is not implemented for `{}`",
+                    Self::short_name(interface, method), name, class.name))
+                    .with_primary(span, "missing method")
+                    .with_note(format!("expected `{}`", Self::describe_method(interface, method)));
+                if let Some(ref method_span) = method.span {
+                    diagnostic = diagnostic.with_secondary(method_span, "declared in interface here");
+                }
+                self.diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    /// Methods of interface take `self` and have distinct names
+    fn check_interface(&mut self, element: &ProgramInterfaceElement) {
+        for (idx, method) in element.methods.iter().enumerate() {
+            let span = method.span.as_ref().or(element.span.as_ref()).cloned();
+            let Some(span) = span else {
+                continue;
+            };
+            for ty in method.param.iter().flatten().skip(1).map(|(_, ty)| ty).chain(&method.ret) {
+                self.check_type(ty, &span);
+            }
+            if !method.takes_self() {
+                self.diagnostics.push(Diagnostic::error(format!(
+                    "method `{}` of interface `{}` does not take `self`", Self::short_name(element, method), element.name))
+                    .with_primary(&span, "`self` is missing")
+                    .with_note("methods of interfaces are called on instances, add `self` as the first parameter"));
+            }
+            if let Some(prev) = element.methods[..idx].iter().find(|prev| prev.name == method.name) {
+                let mut diagnostic = Diagnostic::error(format!(
+                    "method `{}` is defined multiple times in interface `{}`", Self::short_name(element, method), element.name))
+                    .with_primary(&span, "redefined here");
+                if let Some(ref prev_span) = prev.span {
+                    diagnostic = diagnostic.with_secondary(prev_span, "previous definition here");
+                }
+                self.diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    /// Method of class implementing `method` of interface, it takes the same parameters after `self` and returns the same type
+    fn implementation<'b>(
+        class: &'b ProgramClassElement,
+        interface: &ProgramInterfaceElement,
+        method: &FunctionBasicInfo,
+    ) -> Option<&'b FunctionBasicInfo> {
+        let rest = |header: &FunctionBasicInfo| header.param.iter().flatten().skip(1).map(|(_, ty)| ty.clone()).collect::<Vec<TypeInfo>>();
+        // the method of `impl` block for the interface goes before a method of the class body
+        let own = format!("{}.{}", class.name, method.name);
+        class.methods_named(Self::short_name(interface, method))
+            .map(|candidate| &candidate.header)
+            .filter(|header| header.takes_self() && rest(header) == rest(method) && header.ret == method.ret)
+            .min_by_key(|header| header.name != own)
+    }
+
+    /// `show` of `Printable.show`
+    fn short_name<'b>(interface: &ProgramInterfaceElement, method: &'b FunctionBasicInfo) -> &'b str {
+        &method.name[interface.name.len() + 1..]
+    }
+
+    /// Method of interface as declared, like `fn show(self) -> String`
+    fn describe_method(interface: &ProgramInterfaceElement, method: &FunctionBasicInfo) -> String {
+        let params = method.param.iter().flatten()
+            .map(|(name, ty)| if name == "self" { name.clone() } else { format!("{}: {}", name, ty.display_name()) })
+            .collect::<Vec<String>>()
+            .join(", ");
+        let ret = method.ret.as_ref().map(|ret| format!(" -> {}", ret.display_name())).unwrap_or_default();
+        format!("fn {}({}){}", Self::short_name(interface, method), params, ret)
+    }
+
+    /// Signature of the method implementing each method of interfaces implemented by class
+    fn vtable(&self, class: &ProgramClassElement) -> Vec<(String, String)> {
+        class.interfaces
+            .iter()
+            .filter_map(|(name, _)| self.interfaces.get(name))
+            .flat_map(|interface| interface.methods.iter().filter_map(|method| {
+                Self::implementation(class, interface, method).map(|header| (method.name.clone(), header.signature()))
+            }))
+            .collect()
+    }
+
+    /// Whether `ty` is a class implementing interface `target`, so its instances can be used as the interface
+    fn implements(&self, ty: &TypeInfo, target: &TypeInfo) -> bool {
+        match (self.class_of(ty), target) {
+            (Some(class), TypeInfo::TypeSym(name, _)) => {
+                self.interfaces.contains_key(name) && class.interfaces.iter().any(|(interface, _)| interface == name)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether function of `header` can be called with arguments of `types`,
+    /// instances of classes are passed as interfaces they implement
+    fn accepts(&self, header: &FunctionBasicInfo, types: Option<&Vec<TypeInfo>>) -> bool {
+        let types = types.map(|types| {
+            let mut types = types.clone();
+            for (ty, (_, param)) in types.iter_mut().zip(header.param.iter().flatten()) {
+                if self.implements(ty, param) {
+                    *ty = param.clone();
+                }
+            }
+            types
+        });
+        header.is_executable_by(&header.name, types.as_ref())
+    }
+
+    /// Constructor first sets fields with default value, then runs its body.
+    /// Other fields must be assigned by the body
+    fn check_ctor(&mut self, class: &ProgramClassElement, cur_module: &str) -> Option<TypedFunction> {
+        let mut ctors = class.methods_named(ProgramClassElement::CTOR);
+        let user_ctor = ctors.next();
+        if let Some(extra) = ctors.next() {
+            let diagnostic = Diagnostic::error(format!("class `{}` has multiple constructors", class.name));
+            let diagnostic = match (&extra.header.span, &user_ctor.unwrap().header.span) {
+                (Some(extra_span), Some(first_span)) => diagnostic
+                    .with_primary(extra_span, "redefined here")
+                    .with_secondary(first_span, "first constructor here"),
+                _ => diagnostic,
+            };
+            return self.report(diagnostic);
+        }
+        let header = Self::ctor_header(class);
+        let header_span = header.span.clone().unwrap_or_else(|| class.span.clone());
+        if user_ctor.is_some() && (!header.takes_self() || header.ret.is_some()) {
+            return self.report(Diagnostic::error(format!("constructor of `{}` has a wrong signature", class.name))
+                .with_primary(&header_span, "declared here")
+                .with_note(format!("declare it as `fn {}(self, ...)` without return type", ProgramClassElement::CTOR)));
+        }
+
+        let error_count = self.begin_function(&header);
+        let self_ty = class.self_type();
+        let load_self = |span: &Span| Box::new(TypedExpr::new(TypedExprNode::Load(0), self_ty.clone(), span.clone()));
+        let set_field = |idx: usize, value: TypedExpr, span: &Span| {
+            let node = TypedExprNode::SetField(load_self(span), idx, Box::new(value));
+            TypedStmt::new(TypedStmtNode::Expr(TypedExpr::new(node, TypeInfo::Unit, span.clone())), span.clone())
+        };
+        let mut body = Vec::new();
+        for (idx, field) in class.fields.iter().enumerate() {
+            let Some(ref default) = field.default else { continue };
+            let typed = self.check_expr(default, cur_module, &header)
+                .and_then(|typed| self.coerce(typed, &field.ty));
+            if let Some(typed) = typed {
+                body.push(set_field(idx, typed, &field.span));
+            }
+        }
+        match user_ctor {
+            Some(ctor) => {
+                self.captured = capture::used_in_lambdas(&ctor.block);
+                self.ctor_of = Some(class.name.clone());
+                body.extend(self.check_block(&ctor.block, cur_module, &header));
+                self.ctor_of = None;
+                self.check_fields_initialized(class, &body, &header_span);
+            }
+            None => {
+                for (idx, field) in class.fields.iter().enumerate().filter(|(_, field)| field.default.is_none()) {
+                    let info = self.env.val_lookup(&field.name).unwrap().clone();
+                    let value = TypedExpr::new(TypedExprNode::Load(info.binding_slot), info.ty, field.span.clone());
+                    body.push(set_field(idx, value, &field.span));
+                }
+            }
+        }
+        self.finish_function(&header, body, error_count)
+    }
+
+    /// Fields without default value must be assigned by a statement at top level of constructor,
+    /// and neither read nor `self` used as a whole before that
+    fn check_fields_initialized(&mut self, class: &ProgramClassElement, body: &[TypedStmt], ctor_span: &Span) {
+        let (assigned, diagnostics) = field_init::check_ctor_body(class, body);
+        self.diagnostics.extend(diagnostics);
+        for (idx, field) in class.fields.iter().enumerate() {
+            if !assigned.contains(&idx) {
+                self.diagnostics.push(Diagnostic::error(format!("field `{}` is not initialized by constructor", field.name))
+                    .with_primary(ctor_span, format!("constructor of `{}`", class.name))
+                    .with_secondary(&field.span, "field declared here")
+                    .with_note(format!("assign it with `self.{} = ...` or give it a default value", field.name)));
+            }
+        }
+    }
+
+    /// Check statements in a new scope, broken statements are dropped
+    fn check_block(&mut self, block: &[AstStmt], cur_module: &str, header: &FunctionBasicInfo) -> Vec<TypedStmt> {
+        self.env.push_scope();
+        let stmts = block
+            .iter()
+            .filter_map(|stmt| self.check_stmt(stmt, cur_module, header))
+            .collect();
+        self.env.pop_scope();
+        stmts
+    }
+
+    fn check_stmt(&mut self, stmt: &AstStmt, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedStmt> {
+        let ret_ty = header.ret.clone().unwrap_or(TypeInfo::Unit);
+        let node = match &stmt.node {
+            AstStmtNode::ExprStmt(expr) => {
+                TypedStmtNode::Expr(self.check_expr_with(expr, cur_module, header, true)?)
+            }
+            AstStmtNode::RetStmt(Some(expr)) => {
+                let typed = self.check_expr(expr, cur_module, header)?;
+                TypedStmtNode::Return(Some(self.coerce(typed, &ret_ty)?), self.leave_trys(self.trys.len(), &stmt.span))
+            }
+            AstStmtNode::RetStmt(None) => {
+                if ret_ty != TypeInfo::Unit {
+                    return self.report(Diagnostic::error("mismatched types")
+                        .with_primary(&stmt.span, format!("expected `{}`, found `unit`", ret_ty.display_name())));
+                }
+                TypedStmtNode::Return(None, self.leave_trys(self.trys.len(), &stmt.span))
+            }
+            AstStmtNode::VarStmt(name, ty_expect, is_val, expr) => {
+                let ty_expect = &ty_expect.as_ref().map(|ty| ty.resolve_params(&self.type_params));
+                if let Some(ty) = ty_expect {
+                    if self.check_type(ty, &stmt.span).is_none() {
+                        self.poisoned.insert(name.clone());
+                        return None;
+                    }
+                }
+                let typed = self.check_expr(expr, cur_module, header)
+                    .and_then(|typed| match ty_expect {
+                        Some(ty) => self.coerce(typed, ty),
+                        None => Some(typed),
+                    });
+                if let (None, Some(typed)) = (ty_expect, &typed) {
+                    if typed.ty.has_unknown_elem() {
+                        self.poisoned.insert(name.clone());
+                        return self.report(Self::annotations_needed(name, &typed.ty, &typed.span));
+                    }
+                }
+                let ty = ty_expect.clone().or_else(|| typed.as_ref().map(|typed| typed.ty.clone()));
+                let Some(ty) = ty else {
+                    self.poisoned.insert(name.clone());
+                    return None;
+                };
+                let slot_index = self.env.current_val_size();
+                let boxed = !*is_val && self.captured.contains(name);
+                self.env.val_insert(name.clone(), VarInfo { boxed, ..VarInfo::new(ty, slot_index, !*is_val, &stmt.span) });
+                let typed = typed?;
+                if boxed {
+                    let span = typed.span.clone();
+                    let ty = typed.ty.clone();
+                    TypedStmtNode::Var(slot_index, TypedExpr::new(TypedExprNode::NewCell(Box::new(typed)), ty, span))
+                } else {
+                    TypedStmtNode::Var(slot_index, typed)
+                }
+            }
+            AstStmtNode::TupleVarStmt(names, ty_expect, is_val, expr) => {
+                let ty_expect = ty_expect.as_ref().map(|ty| ty.resolve_params(&self.type_params));
+                let typed = self.check_tuple_var(names, ty_expect.as_ref(), expr, cur_module, header);
+                if typed.is_none() {
+                    self.poisoned.extend(names.iter().flatten().cloned());
+                }
+                let typed = typed?;
+                let TypeInfo::Tuple(items) = &typed.ty else { unreachable!() };
+                let tuple_slot = self.env.slot_alloc();
+                let mut boxed = Vec::new();
+                let slots = names
+                    .iter()
+                    .zip(items)
+                    .map(|(name, ty)| {
+                        let name = name.as_ref()?;
+                        let slot = self.env.current_val_size();
+                        let info = VarInfo { boxed: !*is_val && self.captured.contains(name), ..VarInfo::new(ty.clone(), slot, !*is_val, &stmt.span) };
+                        if info.boxed {
+                            boxed.push((slot, ty.clone()));
+                        }
+                        Some(self.env.val_insert(name.clone(), info))
+                    })
+                    .collect();
+                let node = TypedStmtNode::TupleVar { tuple_slot, slots, tuple: typed };
+                if boxed.is_empty() {
+                    node
+                } else {
+                    // elements are put in cells after destructuring
+                    let span = &stmt.span;
+                    let mut stmts = vec![TypedStmt::new(node, span.clone())];
+                    for (slot, ty) in boxed {
+                        let value = TypedExpr::new(TypedExprNode::Load(slot), ty.clone(), span.clone());
+                        let cell = TypedExpr::new(TypedExprNode::NewCell(Box::new(value)), ty, span.clone());
+                        stmts.push(TypedStmt::new(TypedStmtNode::Var(slot, cell), span.clone()));
+                    }
+                    TypedStmtNode::Expr(TypedExpr::new(TypedExprNode::Block(stmts, None), TypeInfo::Unit, span.clone()))
+                }
+            }
+            AstStmtNode::WhileStmt(label, cond, block) => {
+                self.loops.push(label.clone());
+                let cond = self.check_cond(cond, cur_module, header);
+                let block = self.check_block(block, cur_module, header);
+                self.loops.pop();
+                TypedStmtNode::While(cond?, block)
+            }
+            AstStmtNode::ForStmt(label, var, ForIter::Range { start, end, inclusive, step }, block) => {
+                let start = self.check_int(start, cur_module, header);
+                let end = self.check_int(end, cur_module, header);
+                let step = step.as_ref().map(|step| {
+                    let typed = self.check_int(step, cur_module, header);
+                    if is_zero_literal(step) {
+                        self.diagnostics.push(Diagnostic::error("step of range is zero")
+                            .with_primary(&step.span, "the loop would never reach its end")
+                            .with_note("use a positive step to count up or a negative one to count down"));
+                    }
+                    typed
+                });
+                self.env.push_scope();
+                let var_slot = self.env.current_val_size();
+                self.env.val_insert(var.clone(), VarInfo::loop_var(TypeInfo::Int, var_slot, &stmt.span));
+                let end_slot = self.env.slot_alloc();
+                let step_slot = step.as_ref().map(|_| self.env.slot_alloc());
+                self.loops.push(label.clone());
+                let body = self.check_block(block, cur_module, header);
+                self.loops.pop();
+                self.env.pop_scope();
+                let step = match (step_slot, step) {
+                    (Some(slot), Some(step)) => Some((slot, Box::new(step?))),
+                    _ => None,
+                };
+                TypedStmtNode::ForRange {
+                    var_slot,
+                    start: Box::new(start?),
+                    end_slot,
+                    end: Box::new(end?),
+                    inclusive: *inclusive,
+                    step,
+                    body,
+                }
+            }
+            AstStmtNode::ForStmt(label, var, ForIter::Iterable(iterable), block) => {
+                let typed = self.check_expr(iterable, cur_module, header);
+                // a map is iterated over its keys in insertion order
+                let typed = match typed {
+                    Some(map) if matches!(map.ty, TypeInfo::Map(..)) => self.check_builtin_method_call(map, "keys", vec![], &iterable.span),
+                    typed => typed,
+                };
+                let elem = match typed.as_ref().map(|typed| &typed.ty) {
+                    Some(TypeInfo::List(elem)) => Some((**elem).clone()),
+                    Some(ty) => self.report(Diagnostic::error(format!("`{}` is not iterable", ty.display_name()))
+                        .with_primary(&iterable.span, format!("cannot iterate over `{}`", ty.display_name()))
+                        .with_note("iterate over a `List` or a `Map`, or use a range such as `0..10` to count")),
+                    None => None,
+                };
+                self.env.push_scope();
+                let var_slot = self.env.current_val_size();
+                match elem {
+                    Some(ref elem) => {
+                        self.env.val_insert(var.clone(), VarInfo::loop_var(elem.clone(), var_slot, &stmt.span));
+                    }
+                    // body is still checked, uses of the variable report nothing
+                    None => {
+                        self.poisoned.insert(var.clone());
+                        self.env.slot_alloc();
+                    }
+                }
+                let list_slot = self.env.slot_alloc();
+                let index_slot = self.env.slot_alloc();
+                self.loops.push(label.clone());
+                let body = self.check_block(block, cur_module, header);
+                self.loops.pop();
+                self.env.pop_scope();
+                elem?;
+                TypedStmtNode::ForEach {
+                    var_slot,
+                    list_slot,
+                    index_slot,
+                    iterable: Box::new(typed?),
+                    body,
+                }
+            }
+            AstStmtNode::Break(label) => {
+                let depth = self.loop_depth("break", label.as_deref(), &stmt.span)?;
+                self.leave_trys_then(TypedStmtNode::Break(depth), depth, &stmt.span)
+            }
+            AstStmtNode::Continue(label) => {
+                let depth = self.loop_depth("continue", label.as_deref(), &stmt.span)?;
+                self.leave_trys_then(TypedStmtNode::Continue(depth), depth, &stmt.span)
+            }
+            AstStmtNode::Throw(error) => {
+                let typed = self.check_expr(error, cur_module, header)?;
+                if typed.ty != TypeInfo::Error {
+                    return self.report(Self::mismatch(&TypeInfo::Error, &typed.ty, &error.span)
+                        .with_note("create an error to throw with `Error(message)`"));
+                }
+                TypedStmtNode::Throw(typed)
+            }
+        };
+        Some(TypedStmt::new(node, stmt.span.clone()))
+    }
+
+    /// Count loops between `break` or `continue` and the loop it refers to
+    /// Initializer of `val (a, b) = tuple`, it must be a tuple of as many elements as names
+    fn check_tuple_var(
+        &mut self,
+        names: &[Option<String>],
+        ty_expect: Option<&TypeInfo>,
+        expr: &AstExpr,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+    ) -> Option<TypedExpr> {
+        if let Some(ty) = ty_expect {
+            self.check_type(ty, &expr.span)?;
+        }
+        let typed = self.check_expr(expr, cur_module, header)?;
+        let typed = match ty_expect {
+            Some(ty) => self.coerce(typed, ty)?,
+            None => typed,
+        };
+        let TypeInfo::Tuple(items) = &typed.ty else {
+            return self.report(Diagnostic::error("mismatched types")
+                .with_primary(&typed.span, format!("expected a tuple, found `{}`", typed.ty.display_name()))
+                .with_note(format!("the pattern destructures a tuple of {} elements", names.len())));
+        };
+        if items.len() != names.len() {
+            return self.report(Diagnostic::error("mismatched types")
+                .with_primary(&typed.span, format!("expected a tuple of {} elements, found `{}`", names.len(), typed.ty.display_name())));
+        }
+        for (name, ty) in names.iter().zip(items) {
+            if let Some(name) = name.as_ref().filter(|_| ty_expect.is_none() && ty.has_unknown_elem()) {
+                return self.report(Self::annotations_needed(name, ty, &typed.span));
+            }
+        }
+        Some(typed)
+    }
+
+    /// Variable initialized by `[]`, `{}` or `none` without type annotation
+    fn annotations_needed(name: &str, ty: &TypeInfo, span: &Span) -> Diagnostic {
+        let (what, label, example) = match ty {
+            TypeInfo::Map(..) => ("empty map", "element type of this map is unknown", String::from("Map<String, int>")),
+            TypeInfo::Optional(..) => ("`none`", "value type of this optional is unknown", String::from("int?")),
+            TypeInfo::TypeSym(class, args) => {
+                let example = format!("{}<{}>", class, vec!["int"; args.len()].join(", "));
+                ("instance of generic class", "type arguments of this instance are unknown", example)
+            }
+            _ => ("empty list", "element type of this list is unknown", String::from("List<int>")),
+        };
+        Diagnostic::error(format!("type annotations needed for {}", what))
+            .with_primary(span, label)
+            .with_note(format!("give `{}` a type, like `{}: {}`", name, name, example))
+    }
+
+    /// Statements leaving the innermost `count` of `try` blocks: each of them stops catching errors
+    /// and runs its `finally` block, which is outside of the blocks left
+    fn leave_trys(&self, count: usize, span: &Span) -> Vec<TypedStmt> {
+        let mut stmts = Vec::new();
+        for scope in self.trys.iter().rev().take(count) {
+            stmts.push(TypedStmt::new(TypedStmtNode::LeaveTry, span.clone()));
+            if let Some(finally) = &scope.finally {
+                stmts.push(TypedStmt::new(TypedStmtNode::Expr(finally.clone()), span.clone()));
+            }
+        }
+        stmts
+    }
+
+    /// `break` or `continue` of loop at `depth`, after leaving `try` blocks inside the loop
+    fn leave_trys_then(&self, jump: TypedStmtNode, depth: usize, span: &Span) -> TypedStmtNode {
+        let loops = self.loops.len() - depth;
+        let count = self.trys.iter().rev().take_while(|scope| scope.loops >= loops).count();
+        if count == 0 {
+            return jump;
+        }
+        let mut stmts = self.leave_trys(count, span);
+        stmts.push(TypedStmt::new(jump, span.clone()));
+        TypedStmtNode::Expr(TypedExpr::new(TypedExprNode::Block(stmts, None), TypeInfo::Unit, span.clone()))
+    }
+
+    fn loop_depth(&mut self, keyword: &str, label: Option<&str>, span: &Span) -> Option<usize> {
+        let found = match label {
+            None => self.loops.len().checked_sub(1),
+            Some(label) => self.loops.iter().rposition(|name| name.as_deref() == Some(label)),
+        };
+        match (found, label) {
+            (Some(idx), _) => Some(self.loops.len() - 1 - idx),
+            (None, Some(label)) => self.report(Diagnostic::error(format!("use of undeclared label `'{}`", label))
+                .with_primary(span, "no enclosing loop has this label")),
+            (None, None) => self.report(Diagnostic::error(format!("`{}` outside of a loop", keyword))
+                .with_primary(span, format!("cannot `{}` outside of a loop", keyword))),
+        }
+    }
+
+    /// Condition of `if` and `while` must be `bool`
+    fn check_cond(&mut self, cond: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let typed = self.check_expr(cond, cur_module, header)?;
+        if typed.ty != TypeInfo::Bool {
+            return self.report(Self::mismatch(&TypeInfo::Bool, &typed.ty, &cond.span));
+        }
+        Some(typed)
+    }
+
+    fn check_int(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let typed = self.check_expr(expr, cur_module, header)?;
+        self.coerce(typed, &TypeInfo::Int)
+    }
+
+    fn mismatch(expected: &TypeInfo, found: &TypeInfo, span: &Span) -> Diagnostic {
+        let diagnostic = Diagnostic::error("mismatched types")
+            .with_primary(span, format!("expected `{}`, found `{}`", expected.display_name(), found.display_name()));
+        match found {
+            TypeInfo::Optional(inner) if inner.is_can_convert_to(expected) => diagnostic
+                .with_note("the value may be `none`, unwrap it with `if val x = value { .. }` or give a default with `??`"),
+            _ => diagnostic,
+        }
+    }
+
+    /// Convert `expr` to `target` type, only `int` can be widened to `float` implicitly
+    /// and a value of `T` can be used as `T?`.
+    /// An empty list or map takes element types of the target
+    fn coerce(&mut self, expr: TypedExpr, target: &TypeInfo) -> Option<TypedExpr> {
+        if &expr.ty == target || target == &TypeInfo::Any {
+            Some(expr)
+        } else if expr.ty.fills_unknown_elem(target) {
+            self.coerce_elements(expr, target)
+        } else if matches!(target, TypeInfo::Optional(_)) && !matches!(expr.ty, TypeInfo::Optional(_)) {
+            let TypeInfo::Optional(inner) = target else { unreachable!() };
+            let value = self.coerce(expr, inner)?;
+            let span = value.span.clone();
+            Some(TypedExpr::new(TypedExprNode::ToOptional(Box::new(value)), target.clone(), span))
+        } else if expr.ty == TypeInfo::Int && target == &TypeInfo::Float {
+            let span = expr.span.clone();
+            Some(TypedExpr::new(TypedExprNode::IntToFloat(Box::new(expr)), TypeInfo::Float, span))
+        } else if matches!((&expr.node, target), (TypedExprNode::Tuple(items), TypeInfo::Tuple(targets)) if items.len() == targets.len()) {
+            // elements of tuple literal are converted one by one, like `(1, 2)` to `(float, float)`
+            self.coerce_elements(expr, target)
+        } else if self.implements(&expr.ty, target) {
+            // the instance itself is the value of interface, its class is found at runtime
+            Some(TypedExpr { ty: target.clone(), ..expr })
+        } else {
+            self.report(Self::mismatch(target, &expr.ty, &expr.span))
+        }
+    }
+
+    /// Give a literal with empty collections inside, or a tuple literal, the element types of `target`
+    fn coerce_elements(&mut self, expr: TypedExpr, target: &TypeInfo) -> Option<TypedExpr> {
+        let TypedExpr { node, span, .. } = expr;
+        let node = match (node, target) {
+            (TypedExprNode::List(items), TypeInfo::List(elem)) => TypedExprNode::List(items
+                .into_iter()
+                .map(|item| self.coerce(item, elem))
+                .collect::<Option<Vec<TypedExpr>>>()?),
+            (TypedExprNode::Map(entries), TypeInfo::Map(key_ty, value_ty)) => TypedExprNode::Map(entries
+                .into_iter()
+                .map(|(key, value)| Some((self.coerce(key, key_ty)?, self.coerce(value, value_ty)?)))
+                .collect::<Option<Vec<(TypedExpr, TypedExpr)>>>()?),
+            (TypedExprNode::Tuple(items), TypeInfo::Tuple(targets)) => TypedExprNode::Tuple(items
+                .into_iter()
+                .zip(targets)
+                .map(|(item, target)| self.coerce(item, target))
+                .collect::<Vec<Option<TypedExpr>>>()
+                .into_iter()
+                .collect::<Option<Vec<TypedExpr>>>()?),
+            // not a literal, its collections are empty
+            (node, _) => node,
+        };
+        Some(TypedExpr::new(node, target.clone(), span))
+    }
+
+    fn assign_immutable(name: &str, info: &VarInfo, span: &Span) -> Diagnostic {
+        let (diagnostic, label, note) = match info.origin {
+            VarOrigin::Param => (
+                Diagnostic::error(format!("cannot assign to parameter `{}`", name)),
+                format!("`{}` is a parameter of this function", name),
+                "parameters are immutable, copy it into a `var` to modify it",
+            ),
+            VarOrigin::LoopVar => (
+                Diagnostic::error(format!("cannot assign to loop variable `{}`", name)),
+                format!("`{}` is the variable of this loop", name),
+                "loop variables are immutable, copy it into a `var` to modify it",
+            ),
+            VarOrigin::Pattern => (
+                Diagnostic::error(format!("cannot assign to pattern binding `{}`", name)),
+                format!("`{}` is bound by this pattern", name),
+                "bindings of patterns are immutable, copy it into a `var` to modify it",
+            ),
+            VarOrigin::Local => (
+                Diagnostic::error(format!("cannot assign twice to immutable variable `{}`", name)),
+                format!("`{}` is declared with `val` here", name),
+                "declare it with `var` to make it mutable",
+            ),
+        };
+        let diagnostic = diagnostic.with_primary(span, "cannot assign to immutable variable");
+        let diagnostic = match info.span {
+            Some(ref decl_span) => diagnostic.with_secondary(decl_span, label),
+            None => diagnostic,
+        };
+        diagnostic.with_note(note)
+    }
+
+    fn unknown_value(&mut self, name: &str, span: &Span) -> Option<VarInfo> {
+        if self.poisoned.contains(name) {
+            return None;
+        }
+        self.report(Diagnostic::error(format!("can't find value `{}` in this scope", name))
+            .with_primary(span, "not found in this scope"))
+    }
+
+    fn lookup(&mut self, name: &str, span: &Span) -> Option<VarInfo> {
+        match self.env.val_lookup(name) {
+            Some(info) => Some(info.clone()),
+            None => self.unknown_value(name, span),
+        }
+    }
+
+    fn check_expr(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        self.check_expr_with(expr, cur_module, header, false)
+    }
+
+    /// Value of the expression is dropped if `discard` is set,
+    /// branches of `if` may have different types then
+    fn check_expr_with(
+        &mut self,
+        expr: &AstExpr,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+        discard: bool,
+    ) -> Option<TypedExpr> {
+        let span = expr.span.clone();
+        match &expr.node {
+            AstExprNode::Integer(v) => Some(TypedExpr::new(TypedExprNode::Integer(*v), TypeInfo::Int, span)),
+            AstExprNode::Float(v) => Some(TypedExpr::new(TypedExprNode::Float(*v), TypeInfo::Float, span)),
+            AstExprNode::Bool(v) => Some(TypedExpr::new(TypedExprNode::Bool(*v), TypeInfo::Bool, span)),
+            AstExprNode::String(s) => {
+                Some(TypedExpr::new(TypedExprNode::String(s.clone()), TypeInfo::String, span))
+            }
+            AstExprNode::None => Some(TypedExpr::new(TypedExprNode::None, TypeInfo::Optional(Box::new(TypeInfo::Any)), span)),
+            AstExprNode::List(items) => self.check_expr_list(items, &span, cur_module, header),
+            AstExprNode::Map(entries) => self.check_expr_map(entries, &span, cur_module, header),
+            AstExprNode::Tuple(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| self.check_expr(item, cur_module, header))
+                    .collect::<Vec<Option<TypedExpr>>>()
+                    .into_iter()
+                    .collect::<Option<Vec<TypedExpr>>>()?;
+                let ty = TypeInfo::Tuple(items.iter().map(|item| item.ty.clone()).collect());
+                Some(TypedExpr::new(TypedExprNode::Tuple(items), ty, span))
+            }
+            AstExprNode::Ident(id) => self.check_field_path(id, &span, cur_module),
+            AstExprNode::Op(..) => self.check_expr_op(expr, cur_module, header),
+            AstExprNode::UnaryOp(..) => self.check_expr_unary(expr, cur_module, header),
+            AstExprNode::FnCall(..) => self.check_expr_fncall(expr, cur_module, header),
+            AstExprNode::FieldAccess(obj, name) => {
+                let obj = self.check_expr(obj, cur_module, header)?;
+                self.check_field_access(obj, name, &span)
+            }
+            AstExprNode::MethodCall(receiver, name, args) => {
+                let receiver = self.check_expr(receiver, cur_module, header);
+                let args = self.check_args(args.as_ref(), cur_module, header);
+                self.check_method_call(receiver?, name, args?, &span)
+            }
+            AstExprNode::Index(obj, index) => self.check_expr_index(obj, index, &span, cur_module, header),
+            AstExprNode::IfExpr(..) => self.check_expr_if(expr, cur_module, header, discard),
+            AstExprNode::Match(..) => self.check_expr_match(expr, cur_module, header, discard),
+            AstExprNode::OptionalBinding(..) => unreachable!("`val` binding is only parsed as condition of `if`"),
+            AstExprNode::SafeAccess(..) => self.check_expr_safe_access(expr, cur_module, header),
+            AstExprNode::Coalesce(..) => self.check_expr_coalesce(expr, cur_module, header),
+            AstExprNode::Try(..) => self.check_expr_try(expr, cur_module, header, discard),
+            AstExprNode::Lambda(..) => self.check_expr_lambda(expr, cur_module, header),
+            AstExprNode::BlockExpr(block) => {
+                self.env.push_scope();
+                let typed = self.check_block_expr(block, &span, cur_module, header, discard);
+                self.env.pop_scope();
+                typed
+            }
+            AstExprNode::AssignExpr(..) => self.check_expr_assign(expr, cur_module, header),
+        }
+    }
+
+    /// Element type of `[]` is unknown, it is decided by the type it is converted to
+    fn check_expr_list(
+        &mut self,
+        items: &[AstExpr],
+        span: &Span,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+    ) -> Option<TypedExpr> {
+        let items: Vec<&AstExpr> = items.iter().collect();
+        let (items, elem) = self.check_elements(&items, cur_module, header)?;
+        Some(TypedExpr::new(TypedExprNode::List(items), TypeInfo::List(Box::new(elem)), span.clone()))
+    }
+
+    /// Elements take the first type known, `int` elements are widened if there is a `float` one.
+    /// Return converted elements and their type, which is `any` if there is no element
+    fn check_elements(
+        &mut self,
+        items: &[&AstExpr],
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+    ) -> Option<(Vec<TypedExpr>, TypeInfo)> {
+        let items = items
+            .iter()
+            .map(|item| self.check_expr(item, cur_module, header))
+            .collect::<Vec<Option<TypedExpr>>>()
+            .into_iter()
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        let mut elem = items.iter()
+            .map(|item| &item.ty)
+            .find(|ty| !ty.is_empty_literal())
+            .or(items.first().map(|item| &item.ty))
+            .cloned()
+            .unwrap_or(TypeInfo::Any);
+        if elem == TypeInfo::Int && items.iter().any(|item| item.ty == TypeInfo::Float) {
+            elem = TypeInfo::Float;
+        }
+        // `[1, none]` is `List<int?>`
+        for item in &items {
+            if let Some(joined) = elem.join(&item.ty) {
+                elem = joined;
+            }
+        }
+        let items = items
+            .into_iter()
+            .map(|item| self.coerce(item, &elem))
+            .collect::<Vec<Option<TypedExpr>>>()
+            .into_iter()
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        Some((items, elem))
+    }
+
+    /// `list[index]` reads element of `int` index, `map[key]` reads value of a key which must be present
+    fn check_expr_index(
+        &mut self,
+        obj: &AstExpr,
+        index: &AstExpr,
+        span: &Span,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+    ) -> Option<TypedExpr> {
+        let obj = self.check_expr(obj, cur_module, header);
+        let index = self.check_expr(index, cur_module, header);
+        self.index_of(obj?, index, span, cur_module)
+    }
+
+    /// Element of list, value of map, char of string, or the result of `get(obj, index)` for user types
+    fn index_of(&mut self, obj: TypedExpr, index: Option<TypedExpr>, span: &Span, cur_module: &str) -> Option<TypedExpr> {
+        match &obj.ty {
+            TypeInfo::String => {
+                let index = self.coerce(index?, &TypeInfo::Int)?;
+                self.check_builtin_method_call(obj, "char_at", vec![index], span)
+            }
+            TypeInfo::List(elem) => {
+                let ty = (**elem).clone();
+                let index = self.coerce(index?, &TypeInfo::Int)?;
+                Some(TypedExpr::new(TypedExprNode::Index(Box::new(obj), Box::new(index)), ty, span.clone()))
+            }
+            TypeInfo::Map(key, value) => {
+                let ty = (**value).clone();
+                let index = self.coerce(index?, key)?;
+                Some(TypedExpr::new(TypedExprNode::Index(Box::new(obj), Box::new(index)), ty, span.clone()))
+            }
+            ty if self.is_user_type(ty) => {
+                let index = index?;
+                let types = vec![obj.ty.clone(), index.ty.clone()];
+                let Some(fn_header) = self.find_overload("get", &types, cur_module, span) else {
+                    return self.report(Diagnostic::error(format!("cannot index into a value of type `{}`", ty.display_name()))
+                        .with_primary(&obj.span, "cannot be indexed")
+                        .with_note(format!("define `fn get(a: {}, index: {})` to overload indexing", ty.display_name(), index.ty.display_name())));
+                };
+                self.finish_call(fn_header, vec![obj, index], span)
+            }
+            ty => self.report(Diagnostic::error(format!("cannot index into a value of type `{}`", ty.display_name()))
+                .with_primary(&obj.span, "cannot be indexed")),
+        }
+    }
+
+    /// Keys and values take the first type known like elements of list,
+    /// `{}` has unknown types until it is converted
+    fn check_expr_map(
+        &mut self,
+        entries: &[(Box<AstExpr>, Box<AstExpr>)],
+        span: &Span,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+    ) -> Option<TypedExpr> {
+        let keys: Vec<&AstExpr> = entries.iter().map(|(key, _)| &**key).collect();
+        let values: Vec<&AstExpr> = entries.iter().map(|(_, value)| &**value).collect();
+        let keys = self.check_elements(&keys, cur_module, header);
+        let values = self.check_elements(&values, cur_module, header);
+        let ((keys, key_ty), (values, value_ty)) = (keys?, values?);
+        if !entries.is_empty() && !key_ty.is_hashable() {
+            return self.report(Diagnostic::error(format!("`{}` cannot be a map key", key_ty.display_name()))
+                .with_primary(&entries[0].0.span, "unsupported key type")
+                .with_note("keys of a map can be `int`, `bool` or `String`"));
+        }
+        let entries = keys.into_iter().zip(values).collect();
+        Some(TypedExpr::new(TypedExprNode::Map(entries), TypeInfo::Map(Box::new(key_ty), Box::new(value_ty)), span.clone()))
+    }
+
+    /// Variable followed by names of fields, like `a.b.c`
+    fn check_field_path(&mut self, path: &[String], span: &Span, cur_module: &str) -> Option<TypedExpr> {
+        // `Enum.Variant` without payload, unless a variable has the name of the enum
+        if let (2, Some(element)) = (path.len(), self.enums.get(&path[0]).copied()) {
+            if self.env.val_lookup(&path[0]).is_none() {
+                return self.check_variant(element, &path[1], Vec::new(), span);
+            }
+        }
+        let (mut typed, fields) = match self.global_of(path, cur_module).filter(|_| self.env.val_lookup(&path[0]).is_none()) {
+            Some((idx, global, len)) => (self.load_global(idx, global, span)?, &path[len..]),
+            None => {
+                let info = self.lookup(&path[0], span)?;
+                let node = if info.boxed { TypedExprNode::LoadCell(info.binding_slot) } else { TypedExprNode::Load(info.binding_slot) };
+                (TypedExpr::new(node, info.ty, span.clone()), &path[1..])
+            }
+        };
+        for name in fields {
+            let (_, idx, ty) = self.field_of(&typed.ty, name, span)?;
+            typed = TypedExpr::new(TypedExprNode::GetField(Box::new(typed), idx), ty, span.clone());
+        }
+        Some(typed)
+    }
+
+    /// `obj.name` reads a field of instance or an element of tuple
+    fn check_field_access(&mut self, obj: TypedExpr, name: &str, span: &Span) -> Option<TypedExpr> {
+        if let TypeInfo::Tuple(items) = &obj.ty {
+            let Some(idx) = name.parse::<usize>().ok().filter(|idx| *idx < items.len()) else {
+                return self.report(Diagnostic::error(format!("no field `{}` on type `{}`", name, obj.ty.display_name()))
+                    .with_primary(span, "unknown field")
+                    .with_note(format!("elements of the tuple are `0` to `{}`", items.len() - 1)));
+            };
+            let ty = items[idx].clone();
+            return Some(TypedExpr::new(TypedExprNode::TupleGet(Box::new(obj), idx), ty, span.clone()));
+        }
+        let (_, idx, ty) = self.field_of(&obj.ty, name, span)?;
+        Some(TypedExpr::new(TypedExprNode::GetField(Box::new(obj), idx), ty, span.clone()))
+    }
+
+    /// Class of `ty` with index and type of its field, type arguments of `ty` are in place of type parameters
+    fn field_of(&mut self, ty: &TypeInfo, name: &str, span: &Span) -> Option<(&'a ProgramClassElement, usize, TypeInfo)> {
+        let Some(class) = self.class_of(ty) else {
+            return self.report(Diagnostic::error(format!("`{}` has no fields", ty.display_name()))
+                .with_primary(span, format!("cannot access field `{}`", name)));
+        };
+        match class.field_index(name) {
+            Some(idx) => Some((class, idx, class.fields[idx].ty.substitute(&class.bindings(ty)))),
+            None => {
+                let fields = class.fields.iter().map(|field| format!("`{}`", field.name)).collect::<Vec<String>>();
+                let diagnostic = Diagnostic::error(format!("no field `{}` on type `{}`", name, class.name))
+                    .with_primary(span, "unknown field");
+                let diagnostic = if fields.is_empty() {
+                    diagnostic
+                } else {
+                    diagnostic.with_note(format!("available fields are: {}", fields.join(", ")))
+                };
+                self.report(diagnostic)
+            }
+        }
+    }
+
+    /// `a.b = value`, a `val` field is only assigned by constructor through `self`
+    /// `target = value` or `target op= value`, where target is a variable, a field or an element.
+    /// Object and index of the target are evaluated once, before the value
+    fn check_expr_assign(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::AssignExpr(target, op, value) = &expr.node else { unreachable!() };
+        let span = &expr.span;
+        match &target.node {
+            AstExprNode::Ident(path) if self.env.val_lookup(&path[0]).is_none()
+                && self.global_of(path, cur_module).is_some_and(|(_, _, len)| len == path.len()) => {
+                let value = self.check_expr(value, cur_module, header);
+                let (idx, global, _) = self.global_of(path, cur_module).unwrap();
+                if global.is_val {
+                    return self.report(Diagnostic::error(format!("cannot assign to immutable module variable `{}`", global.name))
+                        .with_primary(span, "cannot assign to immutable variable")
+                        .with_secondary(&global.span, format!("`{}` is declared with `val` here", global.name))
+                        .with_note("declare it with `var` to make it mutable"));
+                }
+                let current = self.load_global(idx, global, &target.span)?;
+                let value = match op {
+                    Some(op) => self.check_binary(op, current.clone(), value?, span, cur_module)?,
+                    None => value?,
+                };
+                let value = self.coerce(value, &current.ty)?;
+                let node = TypedExprNode::AssignGlobal(global.module.clone(), idx, Box::new(value));
+                Some(TypedExpr::new(node, current.ty, span.clone()))
+            }
+            AstExprNode::Ident(path) if path.len() == 1 => {
+                let id = &path[0];
+                let value = self.check_expr(value, cur_module, header);
+                let info = self.lookup(id, &target.span)?;
+                if !info.is_mut {
+                    return self.report(Self::assign_immutable(id, &info, span));
+                }
+                let value = match op {
+                    Some(op) => {
+                        let current = self.check_field_path(path, &target.span, cur_module)?;
+                        self.check_binary(op, current, value?, span, cur_module)?
+                    }
+                    None => value?,
+                };
+                let value = Box::new(self.coerce(value, &info.ty)?);
+                let node = if info.boxed {
+                    TypedExprNode::AssignCell(info.binding_slot, value)
+                } else {
+                    TypedExprNode::Assign(info.binding_slot, value)
+                };
+                Some(TypedExpr::new(node, info.ty, span.clone()))
+            }
+            AstExprNode::Ident(path) => {
+                let obj = self.check_field_path(&path[..path.len() - 1], &target.span, cur_module);
+                let value = self.check_expr(value, cur_module, header);
+                self.check_assign_field(obj?, target, op.as_ref(), value?, span, cur_module)
+            }
+            AstExprNode::FieldAccess(obj, _) => {
+                let obj = self.check_expr(obj, cur_module, header);
+                let value = self.check_expr(value, cur_module, header);
+                self.check_assign_field(obj?, target, op.as_ref(), value?, span, cur_module)
+            }
+            AstExprNode::Index(obj, index) => {
+                let obj = self.check_expr(obj, cur_module, header);
+                let index = self.check_expr(index, cur_module, header);
+                let value = self.check_expr(value, cur_module, header);
+                self.check_assign_index(obj?, index?, op.as_ref(), value?, span, cur_module)
+            }
+            _ => self.report(Diagnostic::error("invalid left-hand side of assignment")
+                .with_primary(&target.span, "cannot assign to this expression")
+                .with_note("assign to a variable, a field like `a.b` or an element like `xs[i]`")),
+        }
+    }
+
+    /// Keep the value in a hidden slot unless reading it again gives the same value,
+    /// so that target of compound assignment is evaluated once
+    fn pin(&mut self, expr: TypedExpr, stmts: &mut Vec<TypedStmt>) -> TypedExpr {
+        if matches!(expr.node, TypedExprNode::Load(_) | TypedExprNode::Integer(_)) {
+            return expr;
+        }
+        let slot = self.env.slot_alloc();
+        let load = TypedExpr::new(TypedExprNode::Load(slot), expr.ty.clone(), expr.span.clone());
+        stmts.push(TypedStmt::new(TypedStmtNode::Var(slot, expr), load.span.clone()));
+        load
+    }
+
+    /// Run the statements keeping parts of assignment target before the assignment
+    fn with_pinned(stmts: Vec<TypedStmt>, expr: TypedExpr) -> TypedExpr {
+        if stmts.is_empty() {
+            return expr;
+        }
+        let (ty, span) = (expr.ty.clone(), expr.span.clone());
+        TypedExpr::new(TypedExprNode::Block(stmts, Some(Box::new(expr))), ty, span)
+    }
+
+    /// `obj.name = value`, a `val` field can only be assigned through `self` in constructor
+    fn check_assign_field(
+        &mut self,
+        obj: TypedExpr,
+        target: &AstExpr,
+        op: Option<&Op>,
+        value: TypedExpr,
+        span: &Span,
+        cur_module: &str,
+    ) -> Option<TypedExpr> {
+        let (name, on_self) = match &target.node {
+            AstExprNode::Ident(path) => (path.last().unwrap(), path.len() == 2 && path[0] == "self"),
+            AstExprNode::FieldAccess(_, name) => (name, false),
+            _ => unreachable!(),
+        };
+        if matches!(obj.ty, TypeInfo::Tuple(_)) {
+            return self.report(Diagnostic::error(format!("cannot assign to element `{}` of tuple", name))
+                .with_primary(span, "cannot assign to tuple element")
+                .with_note("tuples are immutable, build a new tuple instead"));
+        }
+        let (class, idx, ty) = self.field_of(&obj.ty, name, span)?;
+        let field = &class.fields[idx];
+        let in_ctor = self.ctor_of.as_deref() == Some(class.name.as_str()) && on_self;
+        if field.is_val && !in_ctor {
+            return self.report(Diagnostic::error(format!("cannot assign to immutable field `{}`", name))
+                .with_primary(span, "cannot assign to immutable field")
+                .with_secondary(&field.span, format!("`{}` is declared with `val` here", name))
+                .with_note("`val` fields can only be assigned in constructor, declare it with `var` to make it mutable"));
+        }
+        let mut stmts = Vec::new();
+        let (obj, value) = match op {
+            Some(op) => {
+                let obj = self.pin(obj, &mut stmts);
+                let current = TypedExpr::new(TypedExprNode::GetField(Box::new(obj.clone()), idx), ty.clone(), span.clone());
+                (obj, self.check_binary(op, current, value, span, cur_module)?)
+            }
+            None => (obj, value),
+        };
+        let value = self.coerce(value, &ty)?;
+        let node = TypedExprNode::SetField(Box::new(obj), idx, Box::new(value));
+        Some(Self::with_pinned(stmts, TypedExpr::new(node, TypeInfo::Unit, span.clone())))
+    }
+
+    /// `obj[index] = value` sets element of list or value of map, user types call `set(obj, index, value)`
+    fn check_assign_index(
+        &mut self,
+        obj: TypedExpr,
+        index: TypedExpr,
+        op: Option<&Op>,
+        value: TypedExpr,
+        span: &Span,
+        cur_module: &str,
+    ) -> Option<TypedExpr> {
+        let mut stmts = Vec::new();
+        let (obj, index, value) = match op {
+            Some(op) => {
+                let obj = self.pin(obj, &mut stmts);
+                let index = self.pin(index, &mut stmts);
+                let current = self.index_of(obj.clone(), Some(index.clone()), span, cur_module)?;
+                (obj, index, self.check_binary(op, current, value, span, cur_module)?)
+            }
+            None => (obj, index, value),
+        };
+        let typed = match &obj.ty {
+            TypeInfo::List(elem) => {
+                let elem = (**elem).clone();
+                let index = self.coerce(index, &TypeInfo::Int)?;
+                let value = self.coerce(value, &elem)?;
+                TypedExpr::new(TypedExprNode::SetIndex(Box::new(obj), Box::new(index), Box::new(value)), TypeInfo::Unit, span.clone())
+            }
+            TypeInfo::Map(key, elem) => {
+                let (key, elem) = ((**key).clone(), (**elem).clone());
+                let index = self.coerce(index, &key)?;
+                let value = self.coerce(value, &elem)?;
+                TypedExpr::new(TypedExprNode::SetIndex(Box::new(obj), Box::new(index), Box::new(value)), TypeInfo::Unit, span.clone())
+            }
+            ty if self.is_user_type(ty) => {
+                let types = vec![obj.ty.clone(), index.ty.clone(), value.ty.clone()];
+                let Some(fn_header) = self.find_overload("set", &types, cur_module, span) else {
+                    return self.report(Diagnostic::error(format!("cannot assign to an element of `{}`", ty.display_name()))
+                        .with_primary(span, "cannot assign to element")
+                        .with_note(format!("define `fn set(a: {}, index: {}, value: {})` to overload assignment to an element",
+                            ty.display_name(), index.ty.display_name(), value.ty.display_name())));
+                };
+                self.finish_call(fn_header, vec![obj, index, value], span)?
+            }
+            TypeInfo::String => return self.report(Diagnostic::error("cannot assign to a char of `String`")
+                .with_primary(span, "strings can't be changed in place")
+                .with_note("build a new string, like `s.substring(0, i) + c + s.substring(i + 1, s.len())`")),
+            ty => return self.report(Diagnostic::error(format!("cannot index into a value of type `{}`", ty.display_name()))
+                .with_primary(&obj.span, "cannot be indexed")),
+        };
+        Some(Self::with_pinned(stmts, typed))
+    }
+
+    /// Statements of block are checked in current scope,
+    /// the last expression statement gives the value unless it is discarded
+    fn check_block_expr(
+        &mut self,
+        block: &[AstStmt],
+        span: &Span,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+        discard: bool,
+    ) -> Option<TypedExpr> {
+        let (stmts, tail) = match block.split_last() {
+            Some((last, head)) if !discard => match &last.node {
+                AstStmtNode::ExprStmt(expr) => (head, Some(expr)),
+                _ => (block, None),
+            },
+            _ => (block, None),
+        };
+        let error_count = self.diagnostics.len();
+        let stmts: Vec<TypedStmt> = stmts
+            .iter()
+            .filter_map(|stmt| self.check_stmt(stmt, cur_module, header))
+            .collect();
+        let tail = match tail {
+            Some(expr) => Some(Box::new(self.check_expr(expr, cur_module, header)?)),
+            None => None,
+        };
+        if self.diagnostics.len() != error_count {
+            return None;
+        }
+        let ty = tail.as_ref().map(|tail| tail.ty.clone()).unwrap_or(TypeInfo::Unit);
+        Some(TypedExpr::new(TypedExprNode::Block(stmts, tail), ty, span.clone()))
+    }
+
+    /// `if val x = maybe` keeps the optional in the slot of `x`, which is only visible in `then` branch
+    fn check_expr_if(
+        &mut self,
+        expr: &AstExpr,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+        discard: bool,
+    ) -> Option<TypedExpr> {
+        let AstExprNode::IfExpr(cond, then_branch, else_branch) = &expr.node else { unreachable!() };
+        let (cond, binding, then_typed) = match &cond.node {
+            AstExprNode::OptionalBinding(name, value) => {
+                let span = cond.span.clone();
+                let value = self.check_optional(value, cur_module, header);
+                self.env.push_scope();
+                let slot = match &value {
+                    Some(value) => {
+                        let TypeInfo::Optional(inner) = &value.ty else { unreachable!() };
+                        let slot = self.env.current_val_size();
+                        self.env.val_insert(name.clone(), VarInfo::binding((**inner).clone(), slot, &cond.span))
+                    }
+                    None => {
+                        self.poisoned.insert(name.clone());
+                        self.env.slot_alloc()
+                    }
+                };
+                let then_typed = self.check_expr_with(then_branch, cur_module, header, discard);
+                self.env.pop_scope();
+                let cond = value.as_ref().map(|value| Self::has_value(TypedExpr::new(TypedExprNode::Load(slot), value.ty.clone(), span.clone())));
+                let binding = value.map(|value| TypedStmt::new(TypedStmtNode::Var(slot, value), span));
+                (cond, binding, then_typed)
+            }
+            _ => {
+                let cond = self.check_cond(cond, cur_module, header);
+                (cond, None, self.check_expr_with(then_branch, cur_module, header, discard))
+            }
+        };
+        let else_typed = match else_branch {
+            Some(e) => Some(self.check_expr_with(e, cur_module, header, discard)?),
+            None => None,
+        };
+        let (cond, then_typed) = (cond?, then_typed?);
+        // `if c { 1 } else { none }` is `int?`, `if c { xs } else { [] }` is the type of `xs`
+        let (then_typed, else_typed) = match (then_typed, else_typed) {
+            (then_typed, Some(else_typed)) if !discard => match then_typed.ty.join(&else_typed.ty) {
+                Some(ty) => (self.coerce(then_typed, &ty)?, Some(self.coerce(else_typed, &ty)?)),
+                None => (then_typed, Some(else_typed)),
+            },
+            branches => branches,
+        };
+
+        let ty = match else_typed {
+            _ if discard => TypeInfo::Unit,
+            None if then_typed.ty != TypeInfo::Unit => {
+                return self.report(Diagnostic::error("`if` may be missing an `else` clause")
+                    .with_primary(&expr.span, format!("expected `{}`, found `unit`", then_typed.ty.display_name()))
+                    .with_note("`if` without `else` has type `unit`"));
+            }
+            None => TypeInfo::Unit,
+            Some(ref else_typed) if else_typed.ty != then_typed.ty => {
+                return self.report(Diagnostic::error("`if` and `else` have incompatible types")
+                    .with_primary(&else_typed.span, format!("expected `{}`, found `{}`", then_typed.ty.display_name(), else_typed.ty.display_name()))
+                    .with_secondary(&then_typed.span, format!("this is `{}`", then_typed.ty.display_name())));
+            }
+            Some(_) => then_typed.ty.clone(),
+        };
+        let node = TypedExprNode::If(Box::new(cond), Box::new(then_typed), else_typed.map(Box::new));
+        let typed = TypedExpr::new(node, ty, expr.span.clone());
+        match binding {
+            Some(binding) => Some(Self::with_stmt(binding, typed)),
+            None => Some(typed),
+        }
+    }
+
+    /// `try` with `catch` has the type of both blocks like `if`, `finally` is always `unit`.
+    /// `finally` is checked first, so its slots are not used by the blocks it is copied into
+    fn check_expr_try(
+        &mut self,
+        expr: &AstExpr,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+        discard: bool,
+    ) -> Option<TypedExpr> {
+        let AstExprNode::Try(body, catch, finally) = &expr.node else { unreachable!() };
+        let pending_slot = finally.as_ref().map(|_| self.env.slot_alloc());
+        let finally = finally.as_ref().map(|finally| self.check_expr_with(finally, cur_module, header, true));
+        if let Some(finally) = &finally {
+            self.trys.push(TryScope { loops: self.loops.len(), finally: finally.clone() });
+        }
+        if catch.is_some() {
+            self.trys.push(TryScope { loops: self.loops.len(), finally: None });
+        }
+        let body_typed = self.check_expr_with(body, cur_module, header, discard);
+        if catch.is_some() {
+            self.trys.pop();
+        }
+        let handler = catch.as_ref().map(|catch| self.check_catch(catch, cur_module, header, discard));
+        if finally.is_some() {
+            self.trys.pop();
+        }
+
+        let mut typed = body_typed?;
+        if let Some((slot, handler)) = handler {
+            let mut handler = handler?;
+            if !discard {
+                if let Some(ty) = typed.ty.join(&handler.ty) {
+                    typed = self.coerce(typed, &ty)?;
+                    handler = self.coerce(handler, &ty)?;
+                } else if handler.ty != typed.ty {
+                    return self.report(Diagnostic::error("`try` and `catch` have incompatible types")
+                        .with_primary(&handler.span, format!("expected `{}`, found `{}`", typed.ty.display_name(), handler.ty.display_name()))
+                        .with_secondary(&typed.span, format!("this is `{}`", typed.ty.display_name())));
+                }
+            }
+            let ty = if discard { TypeInfo::Unit } else { typed.ty.clone() };
+            let node = TypedExprNode::Try { body: Box::new(typed), slot, handler: Box::new(handler) };
+            typed = TypedExpr::new(node, ty, expr.span.clone());
+        }
+        if let (Some(finally), Some(slot)) = (finally, pending_slot) {
+            let ty = typed.ty.clone();
+            let node = TypedExprNode::Finally { body: Box::new(typed), slot, finally: Box::new(finally?) };
+            typed = TypedExpr::new(node, ty, expr.span.clone());
+        }
+        Some(typed)
+    }
+
+    /// Body of `catch` with the caught `Error` in the returned slot, it is named by the binding
+    fn check_catch(&mut self, catch: &CatchClause, cur_module: &str, header: &FunctionBasicInfo, discard: bool) -> (usize, Option<TypedExpr>) {
+        self.env.push_scope();
+        let slot = match &catch.binding {
+            Some(name) => {
+                let slot = self.env.current_val_size();
+                self.env.val_insert(name.clone(), VarInfo::binding(TypeInfo::Error, slot, &catch.body.span))
+            }
+            None => self.env.slot_alloc(),
+        };
+        let handler = self.check_expr_with(&catch.body, cur_module, header, discard);
+        self.env.pop_scope();
+        (slot, handler)
+    }
+
+    /// Block running `stmt` before evaluating `expr`
+    fn with_stmt(stmt: TypedStmt, expr: TypedExpr) -> TypedExpr {
+        let (ty, span) = (expr.ty.clone(), expr.span.clone());
+        TypedExpr::new(TypedExprNode::Block(vec![stmt], Some(Box::new(expr))), ty, span)
+    }
+
+    /// Whether the optional has a value
+    fn has_value(optional: TypedExpr) -> TypedExpr {
+        let span = optional.span.clone();
+        let is_none = TypedExpr::new(TypedExprNode::IsNone(Box::new(optional)), TypeInfo::Bool, span.clone());
+        TypedExpr::new(TypedExprNode::UnaryOp(UnaryOp::Not, Box::new(is_none)), TypeInfo::Bool, span)
+    }
+
+    /// Operand of `?.`, `??` and `if val` must be optional
+    fn check_optional(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let typed = self.check_expr(expr, cur_module, header)?;
+        if !matches!(typed.ty, TypeInfo::Optional(_)) {
+            return self.report(Diagnostic::error("mismatched types")
+                .with_primary(&expr.span, format!("expected optional, found `{}`", typed.ty.display_name()))
+                .with_note("only optional values of `T?` types can be unwrapped"));
+        }
+        Some(typed)
+    }
+
+    /// `maybe?.member` keeps the optional in a hidden slot, accesses the member if it has a value
+    /// and is `none` otherwise. The result is optional unless the member is `unit`
+    fn check_expr_safe_access(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::SafeAccess(obj, name, args) = &expr.node else { unreachable!() };
+        let obj = self.check_optional(obj, cur_module, header);
+        let args = args.as_ref().map(|args| self.check_args(args.as_ref(), cur_module, header));
+        let obj = obj?;
+        let TypeInfo::Optional(inner) = obj.ty.clone() else { unreachable!() };
+        let slot = self.env.slot_alloc();
+        let load = |ty: TypeInfo| TypedExpr::new(TypedExprNode::Load(slot), ty, obj.span.clone());
+        let (cond, value) = (Self::has_value(load(obj.ty.clone())), load(*inner));
+        let access = match args {
+            Some(args) => self.check_method_call(value, name, args?, &expr.span)?,
+            None => self.check_field_access(value, name, &expr.span)?,
+        };
+        let typed = if access.ty == TypeInfo::Unit {
+            TypedExpr::new(TypedExprNode::If(Box::new(cond), Box::new(access), None), TypeInfo::Unit, expr.span.clone())
+        } else {
+            let ty = access.ty.clone().optional();
+            let access = self.coerce(access, &ty)?;
+            let none = TypedExpr::new(TypedExprNode::None, ty.clone(), expr.span.clone());
+            TypedExpr::new(TypedExprNode::If(Box::new(cond), Box::new(access), Some(Box::new(none))), ty, expr.span.clone())
+        };
+        Some(Self::with_stmt(TypedStmt::new(TypedStmtNode::Var(slot, obj), expr.span.clone()), typed))
+    }
+
+    /// `maybe ?? default` is the value of the optional if it has one, `default` is evaluated otherwise.
+    /// It is optional only if `default` is
+    fn check_expr_coalesce(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::Coalesce(optional, default) = &expr.node else { unreachable!() };
+        let optional = self.check_optional(optional, cur_module, header);
+        let default = self.check_expr(default, cur_module, header);
+        let (optional, default) = (optional?, default?);
+        let TypeInfo::Optional(inner) = &optional.ty else { unreachable!() };
+        let ty = if optional.ty.is_none_literal() {
+            default.ty.clone()
+        } else if matches!(default.ty, TypeInfo::Optional(_)) {
+            optional.ty.clone()
+        } else {
+            (**inner).clone()
+        };
+        let optional = self.coerce(optional, &ty.clone().optional())?;
+        let default = self.coerce(default, &ty)?;
+        let slot = self.env.slot_alloc();
+        let cond = Self::has_value(TypedExpr::new(TypedExprNode::Load(slot), optional.ty.clone(), optional.span.clone()));
+        let value = TypedExpr::new(TypedExprNode::Load(slot), ty.clone(), optional.span.clone());
+        let node = TypedExprNode::If(Box::new(cond), Box::new(value), Some(Box::new(default)));
+        let span = optional.span.clone();
+        Some(Self::with_stmt(TypedStmt::new(TypedStmtNode::Var(slot, optional), span), TypedExpr::new(node, ty, expr.span.clone())))
+    }
+
+    /// Value of `Enum.Variant(payload)`
+    fn check_variant(&mut self, element: &'a ProgramEnumElement, name: &str, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        let Some(tag) = element.variant_index(name) else {
+            return self.report(Self::no_variant(element, name, span));
+        };
+        let variant = &element.variants[tag];
+        if args.len() != variant.fields.len() {
+            return self.report(Diagnostic::error(format!(
+                "variant `{}` takes {} but {} supplied",
+                element.variant_path(tag), count(variant.fields.len(), "payload value"), count(args.len(), "was")))
+                .with_primary(span, "wrong number of payload values")
+                .with_secondary(&variant.span, "variant defined here"));
+        }
+        let args = args
+            .into_iter()
+            .zip(&variant.fields)
+            .map(|(arg, ty)| self.coerce(arg, ty))
+            .collect::<Vec<Option<TypedExpr>>>()
+            .into_iter()
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        let node = TypedExprNode::Variant { name: variant.name.clone(), tag, args };
+        Some(TypedExpr::new(node, TypeInfo::TypeSym(element.name.clone(), Vec::new()), span.clone()))
+    }
+
+    fn no_variant(element: &ProgramEnumElement, name: &str, span: &Span) -> Diagnostic {
+        let variants = element.variants.iter().map(|variant| format!("`{}`", variant.name)).collect::<Vec<String>>();
+        Diagnostic::error(format!("no variant `{}` in enum `{}`", name, element.name))
+            .with_primary(span, "variant not found")
+            .with_note(format!("variants are: {}", variants.join(", ")))
+    }
+
+    /// Arms are checked in their own scopes, bindings of pattern are visible to guard and body.
+    /// Arms without guard must cover every value of the scrutinee
+    fn check_expr_match(
+        &mut self,
+        expr: &AstExpr,
+        cur_module: &str,
+        header: &FunctionBasicInfo,
+        discard: bool,
+    ) -> Option<TypedExpr> {
+        let AstExprNode::Match(scrutinee, arms) = &expr.node else { unreachable!() };
+        let scrutinee = self.check_expr(scrutinee, cur_module, header)?;
+        let slot = self.env.slot_alloc();
+        let value = TypedExpr::new(TypedExprNode::Load(slot), scrutinee.ty.clone(), scrutinee.span.clone());
+        let mut typed_arms = Vec::new();
+        let mut shapes = Vec::new();
+        let mut broken = false;
+        for arm in arms {
+            self.env.push_scope();
+            let mut steps = Vec::new();
+            let shape = self.check_pattern(&arm.pattern, value.clone(), &mut steps);
+            // bindings of a broken pattern are missing, nothing more is checked
+            let checked = shape.and_then(|shape| {
+                let guard = arm.guard.as_ref().map(|guard| self.check_cond(guard, cur_module, header));
+                let body = self.check_expr_with(&arm.body, cur_module, header, discard);
+                match guard {
+                    Some(guard) => steps.push(PatternStep::Test(guard?)),
+                    None => shapes.push(shape),
+                }
+                Some(TypedMatchArm { steps, body: body? })
+            });
+            self.env.pop_scope();
+            match checked {
+                Some(arm) => typed_arms.push(arm),
+                None => broken = true,
+            }
+        }
+        if broken {
+            return None;
+        }
+
+        // arms are joined like branches of `if`
+        let mut ty = match typed_arms.first() {
+            Some(first) if !discard => first.body.ty.clone(),
+            _ => TypeInfo::Unit,
+        };
+        for arm in typed_arms.iter().filter(|_| !discard) {
+            if let Some(joined) = ty.join(&arm.body.ty) {
+                ty = joined;
+            }
+        }
+        let joins = |arm: &TypedMatchArm| arm.body.ty == ty || arm.body.ty.join(&ty).as_ref() == Some(&ty);
+        if let Some(arm) = typed_arms.iter().find(|arm| !discard && !joins(arm)) {
+            let first = &typed_arms[0].body;
+            return self.report(Diagnostic::error("`match` arms have incompatible types")
+                .with_primary(&arm.body.span, format!("expected `{}`, found `{}`", ty.display_name(), arm.body.ty.display_name()))
+                .with_secondary(&first.span, format!("this is `{}`", ty.display_name())));
+        }
+        let rows = shapes.iter().map(|shape| vec![shape]).collect::<Vec<Vec<&PatShape>>>();
+        if let Some(witness) = exhaustive::missing(&rows, std::slice::from_ref(&scrutinee.ty), &|ty| self.ctors_of(ty)) {
+            return self.report(Diagnostic::error(format!("non-exhaustive patterns: `{}` not covered", witness[0]))
+                .with_primary(&scrutinee.span, format!("pattern `{}` not covered", witness[0]))
+                .with_note("add an arm for it, or a wildcard pattern `_` to match anything else"));
+        }
+        let typed_arms = if discard {
+            typed_arms
+        } else {
+            typed_arms
+                .into_iter()
+                .map(|arm| Some(TypedMatchArm { body: self.coerce(arm.body, &ty)?, ..arm }))
+                .collect::<Option<Vec<TypedMatchArm>>>()?
+        };
+        let node = TypedExprNode::Match { slot, scrutinee: Box::new(scrutinee), arms: typed_arms };
+        Some(TypedExpr::new(node, ty, expr.span.clone()))
+    }
+
+    /// Constructors of a type with finitely many of them, for exhaustiveness checking
+    fn ctors_of(&self, ty: &TypeInfo) -> Option<Ctors> {
+        match ty {
+            TypeInfo::Bool => Some(vec![(String::from("true"), Vec::new()), (String::from("false"), Vec::new())]),
+            TypeInfo::Tuple(items) => Some(vec![(String::new(), items.clone())]),
+            TypeInfo::TypeSym(name, _) => self.enums.get(name).map(|element| {
+                element.variants
+                    .iter()
+                    .enumerate()
+                    .map(|(tag, variant)| (element.variant_path(tag), variant.fields.clone()))
+                    .collect()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Lower pattern to steps testing `value` and binding parts of it,
+    /// return what it covers for exhaustiveness checking
+    fn check_pattern(&mut self, pattern: &AstPattern, value: TypedExpr, steps: &mut Vec<PatternStep>) -> Option<PatShape> {
+        let span = &pattern.span;
+        let test = |left: TypedExpr, op: Op, right: TypedExpr| {
+            PatternStep::Test(TypedExpr::new(TypedExprNode::Op(Box::new(left), op, Box::new(right)), TypeInfo::Bool, span.clone()))
+        };
+        match &pattern.node {
+            PatternNode::Wildcard => Some(PatShape::Wild),
+            PatternNode::Bind(name) => {
+                let slot = self.env.current_val_size();
+                let slot = self.env.val_insert(name.clone(), VarInfo::binding(value.ty.clone(), slot, span));
+                steps.push(PatternStep::Bind(slot, value));
+                Some(PatShape::Wild)
+            }
+            PatternNode::Literal(literal) => {
+                let (node, ty, shape) = match &literal.node {
+                    AstExprNode::Integer(v) => (TypedExprNode::Integer(*v), TypeInfo::Int, PatShape::Other),
+                    AstExprNode::String(s) => (TypedExprNode::String(s.clone()), TypeInfo::String, PatShape::Other),
+                    // `true` is the first constructor of `bool`
+                    AstExprNode::Bool(b) => (TypedExprNode::Bool(*b), TypeInfo::Bool, PatShape::Ctor(usize::from(!*b), Vec::new())),
+                    _ => {
+                        return self.report(Diagnostic::error("string interpolation can't be used in patterns")
+                            .with_primary(span, "not a literal"));
+                    }
+                };
+                if ty != value.ty {
+                    return self.report(Self::mismatch(&value.ty, &ty, span));
+                }
+                steps.push(test(value, Op::Eq, TypedExpr::new(node, ty, span.clone())));
+                Some(shape)
+            }
+            PatternNode::Range(start, end, inclusive) => {
+                if value.ty != TypeInfo::Int {
+                    return self.report(Self::mismatch(&value.ty, &TypeInfo::Int, span));
+                }
+                if start > end || (start == end && !inclusive) {
+                    return self.report(Diagnostic::error("range pattern is empty")
+                        .with_primary(span, "no value is in this range")
+                        .with_note("lower bound must be less than upper bound of `..`, or not greater than that of `..=`"));
+                }
+                let int = |v: i64| TypedExpr::new(TypedExprNode::Integer(v), TypeInfo::Int, span.clone());
+                steps.push(test(value.clone(), Op::Ge, int(*start)));
+                steps.push(test(value, if *inclusive { Op::Le } else { Op::Lt }, int(*end)));
+                Some(PatShape::Other)
+            }
+            PatternNode::Variant(enum_name, name, subs) => {
+                let Some(element) = self.enums.get(enum_name).copied() else {
+                    return self.report(Diagnostic::error(format!("cannot find enum `{}` in this scope", enum_name))
+                        .with_primary(span, "not found in this scope"));
+                };
+                let enum_ty = TypeInfo::TypeSym(element.name.clone(), Vec::new());
+                if value.ty != enum_ty {
+                    return self.report(Self::mismatch(&value.ty, &enum_ty, span));
+                }
+                let Some(tag) = element.variant_index(name) else {
+                    return self.report(Self::no_variant(element, name, span));
+                };
+                let variant = &element.variants[tag];
+                if subs.len() != variant.fields.len() {
+                    return self.report(Diagnostic::error(format!(
+                        "this pattern has {}, but variant `{}` has {}",
+                        count(subs.len(), "field"), element.variant_path(tag), variant.fields.len()))
+                        .with_primary(span, format!("expected {}", count(variant.fields.len(), "field")))
+                        .with_secondary(&variant.span, "variant defined here"));
+                }
+                let tag_value = TypedExpr::new(TypedExprNode::VariantTag(Box::new(value.clone())), TypeInfo::Int, span.clone());
+                steps.push(test(tag_value, Op::Eq, TypedExpr::new(TypedExprNode::Integer(tag as i64), TypeInfo::Int, span.clone())));
+                let subs = subs
+                    .iter()
+                    .zip(&variant.fields)
+                    .enumerate()
+                    .map(|(idx, (sub, ty))| {
+                        let field = TypedExprNode::VariantField(Box::new(value.clone()), idx);
+                        self.check_pattern(sub, TypedExpr::new(field, ty.clone(), sub.span.clone()), steps)
+                    })
+                    .collect::<Vec<Option<PatShape>>>()
+                    .into_iter()
+                    .collect::<Option<Vec<PatShape>>>()?;
+                Some(PatShape::Ctor(tag, subs))
+            }
+            PatternNode::Tuple(subs) => {
+                let items = match &value.ty {
+                    TypeInfo::Tuple(items) if items.len() == subs.len() => items.clone(),
+                    ty => {
+                        return self.report(Diagnostic::error("mismatched types")
+                            .with_primary(span, format!("expected `{}`, found a tuple of {} elements", ty.display_name(), subs.len())));
+                    }
+                };
+                let subs = subs
+                    .iter()
+                    .zip(items)
+                    .enumerate()
+                    .map(|(idx, (sub, ty))| {
+                        let item = TypedExprNode::TupleGet(Box::new(value.clone()), idx);
+                        self.check_pattern(sub, TypedExpr::new(item, ty, sub.span.clone()), steps)
+                    })
+                    .collect::<Vec<Option<PatShape>>>()
+                    .into_iter()
+                    .collect::<Option<Vec<PatShape>>>()?;
+                Some(PatShape::Ctor(0, subs))
+            }
+        }
+    }
+
+    fn check_expr_op(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::Op(left, op, right) = &expr.node else { unreachable!() };
+        let left_typed = self.check_expr(left, cur_module, header);
+        let right_typed = self.check_expr(right, cur_module, header);
+        self.check_binary(op, left_typed?, right_typed?, &expr.span, cur_module)
+    }
+
+    /// Operator applied to typed operands, also used by compound assignment like `a += b`
+    fn check_binary(&mut self, op: &Op, left_typed: TypedExpr, right_typed: TypedExpr, span: &Span, cur_module: &str) -> Option<TypedExpr> {
+        let is_none_test = matches!(op, Op::Eq | Op::Ne)
+            && matches!((&left_typed.ty, &right_typed.ty), (TypeInfo::Optional(_), TypeInfo::Optional(_)))
+            && (left_typed.ty.is_none_literal() || right_typed.ty.is_none_literal());
+        if is_none_test {
+            let value = if right_typed.ty.is_none_literal() { left_typed } else { right_typed };
+            let is_none = TypedExpr::new(TypedExprNode::IsNone(Box::new(value)), TypeInfo::Bool, span.clone());
+            return match op {
+                Op::Ne => Some(TypedExpr::new(TypedExprNode::UnaryOp(UnaryOp::Not, Box::new(is_none)), TypeInfo::Bool, span.clone())),
+                _ => Some(is_none),
+            };
+        }
+        if let Op::InfixFn(name) = op {
+            let types = vec![left_typed.ty.clone(), right_typed.ty.clone()];
+            let fn_header = match self.find_function(name, cur_module, None, Some(&types), span) {
+                Ok(fn_header) => fn_header,
+                Err(diagnostic) => return self.report(*diagnostic),
+            };
+            return self.finish_call(fn_header, vec![left_typed, right_typed], span);
+        }
+        if self.is_user_type(&left_typed.ty) || self.is_user_type(&right_typed.ty) {
+            return self.check_overloaded_op(op, left_typed, right_typed, span, cur_module);
+        }
+        let is_cmp = matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::Eq | Op::Ne);
+        let is_arith = matches!(op, Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem);
+        let (left_typed, right_typed, ty) = match (&left_typed.ty, &right_typed.ty) {
+            (TypeInfo::Int, TypeInfo::Int) if is_arith => (left_typed, right_typed, TypeInfo::Int),
+            (TypeInfo::Int, TypeInfo::Int) if is_cmp => (left_typed, right_typed, TypeInfo::Bool),
+            (TypeInfo::Int | TypeInfo::Float, TypeInfo::Int | TypeInfo::Float) if is_arith || is_cmp => {
+                let ty = if is_cmp { TypeInfo::Bool } else { TypeInfo::Float };
+                let left_typed = self.coerce(left_typed, &TypeInfo::Float)?;
+                let right_typed = self.coerce(right_typed, &TypeInfo::Float)?;
+                (left_typed, right_typed, ty)
+            }
+            (TypeInfo::Bool, TypeInfo::Bool) if matches!(op, Op::And | Op::Or | Op::Eq | Op::Ne) => {
+                (left_typed, right_typed, TypeInfo::Bool)
+            }
+            (TypeInfo::String, TypeInfo::String) if is_cmp => (left_typed, right_typed, TypeInfo::Bool),
+            (TypeInfo::String, TypeInfo::String) if matches!(op, Op::Add) => (left_typed, right_typed, TypeInfo::String),
+            (left_ty, right_ty) => {
+                let diagnostic = Diagnostic::error(format!("cannot apply operator `{}` to `{}` and `{}`", op, left_ty.display_name(), right_ty.display_name()))
+                    .with_primary(span, "unsupported operand types")
+                    .with_secondary(&left_typed.span, left_ty.display_name())
+                    .with_secondary(&right_typed.span, right_ty.display_name());
+                if matches!(left_ty, TypeInfo::Optional(_)) || matches!(right_ty, TypeInfo::Optional(_)) {
+                    return self.report(diagnostic.with_note("optional values may be `none`, unwrap them with `if val` or `??`, or compare them with `none`"));
+                }
+                return self.report(diagnostic);
+            }
+        };
+        let node = TypedExprNode::Op(Box::new(left_typed), op.clone(), Box::new(right_typed));
+        Some(TypedExpr::new(node, ty, span.clone()))
+    }
+
+    /// Name of the function overloading `op` for user types
+    fn overload_name(op: &Op) -> Option<&'static str> {
+        match op {
+            Op::Add => Some("plus"),
+            Op::Sub => Some("minus"),
+            Op::Mul => Some("times"),
+            Op::Div => Some("div"),
+            Op::Rem => Some("rem"),
+            Op::Eq | Op::Ne => Some("equals"),
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => Some("compare"),
+            Op::And | Op::Or | Op::InfixFn(_) => None,
+        }
+    }
+
+    fn is_user_type(&self, ty: &TypeInfo) -> bool {
+        match ty {
+            TypeInfo::TypeSym(name, _) => self.classes.contains_key(name) || self.interfaces.contains_key(name),
+            _ => false,
+        }
+    }
+
+    /// Function overloading an operator, searched in current module and then in module declaring the class of an operand
+    fn find_overload(&self, name: &str, types: &Vec<TypeInfo>, cur_module: &str, span: &Span) -> Option<&'a FunctionBasicInfo> {
+        if let Ok(fn_header) = self.find_function(name, cur_module, None, Some(types), span) {
+            return Some(fn_header);
+        }
+        types.iter()
+            .filter_map(|ty| self.class_of(ty))
+            .filter(|class| class.module != cur_module)
+            .find_map(|class| self.find_function(name, cur_module, Some(&class.module), Some(types), span).ok())
+    }
+
+    /// Operator applied to a class or interface calls the function overloading it: `a + b` is `plus(a, b)`,
+    /// `a == b` is `equals(a, b)` and `a < b` is `compare(a, b) < 0`
+    fn check_overloaded_op(&mut self, op: &Op, left_typed: TypedExpr, right_typed: TypedExpr, span: &Span, cur_module: &str) -> Option<TypedExpr> {
+        let (left_ty, right_ty) = (left_typed.ty.clone(), right_typed.ty.clone());
+        let types = vec![left_ty.clone(), right_ty.clone()];
+        let fn_header = Self::overload_name(op)
+            .and_then(|name| self.find_overload(name, &types, cur_module, span).map(|fn_header| (name, fn_header)));
+        let Some((name, fn_header)) = fn_header else {
+            let diagnostic = Diagnostic::error(format!("cannot apply operator `{}` to `{}` and `{}`", op, left_ty.display_name(), right_ty.display_name()))
+                .with_primary(span, "unsupported operand types")
+                .with_secondary(&left_typed.span, left_ty.display_name())
+                .with_secondary(&right_typed.span, right_ty.display_name());
+            return match Self::overload_name(op) {
+                Some(name) => self.report(diagnostic.with_note(format!(
+                    "define `fn {}(a: {}, b: {})` to overload `{}`", name, left_ty.display_name(), right_ty.display_name(), op))),
+                None => self.report(diagnostic),
+            };
+        };
+        let expected = match op {
+            Op::Eq | Op::Ne => Some(TypeInfo::Bool),
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => Some(TypeInfo::Int),
+            _ => None,
+        };
+        if let Some(expected) = expected.filter(|expected| fn_header.ret.as_ref() != Some(expected)) {
+            let diagnostic = Diagnostic::error(format!("`{}` must return `{}` to overload `{}`", name, expected.display_name(), op))
+                .with_primary(span, format!("`{}` overloaded here", op));
+            let diagnostic = match fn_header.span {
+                Some(ref fn_span) => diagnostic.with_secondary(fn_span, "function defined here"),
+                None => diagnostic,
+            };
+            return self.report(diagnostic);
+        }
+        let call = self.finish_call(fn_header, vec![left_typed, right_typed], span)?;
+        let span = span.clone();
+        match op {
+            Op::Ne => Some(TypedExpr::new(TypedExprNode::UnaryOp(UnaryOp::Not, Box::new(call)), TypeInfo::Bool, span)),
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                let zero = TypedExpr::new(TypedExprNode::Integer(0), TypeInfo::Int, span.clone());
+                Some(TypedExpr::new(TypedExprNode::Op(Box::new(call), op.clone(), Box::new(zero)), TypeInfo::Bool, span))
+            }
+            _ => Some(call),
+        }
+    }
+
+    fn check_expr_unary(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::UnaryOp(op, sub) = &expr.node else { unreachable!() };
+        let sub_typed = self.check_expr(sub, cur_module, header)?;
+        match (op, &sub_typed.ty) {
+            (UnaryOp::Plus | UnaryOp::Minus, TypeInfo::Int | TypeInfo::Float) | (UnaryOp::Not, TypeInfo::Bool) => {
+                let ty = sub_typed.ty.clone();
+                Some(TypedExpr::new(TypedExprNode::UnaryOp(op.clone(), Box::new(sub_typed)), ty, expr.span.clone()))
+            }
+            (op, ty) => self.report(Diagnostic::error(format!("cannot apply unary operator `{}` to type `{}`", op, ty.display_name()))
+                .with_primary(&expr.span, "unsupported operand type")),
+        }
+    }
+
+    fn check_expr_fncall(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::FnCall(fn_id, param) = &expr.node else { unreachable!() };
+        let args = self.check_args(param.as_ref(), cur_module, header)?;
+        let types = param
+            .as_ref()
+            .map(|_| args.iter().map(|e| e.ty.clone()).collect::<Vec<TypeInfo>>());
+
+        let (fn_name, path) = fn_id.split_last().unwrap();
+        if path.is_empty() {
+            // a variable holding a lambda shadows functions of the name
+            if let Some(info) = self.env.val_lookup(fn_name).filter(|info| matches!(info.ty, TypeInfo::Function(..))).cloned() {
+                let callee = self.check_field_path(fn_id, &expr.span, cur_module)?;
+                return self.check_closure_call(callee, fn_name, info.span.as_ref(), args, &expr.span);
+            }
+            // the variable failed to check and is reported already
+            if self.poisoned.contains(fn_name) {
+                return None;
+            }
+            if let Some((idx, global, _)) = self.global_of(fn_id, cur_module) {
+                let decl_span = global.span.clone();
+                let callee = self.load_global(idx, global, &expr.span)?;
+                if matches!(callee.ty, TypeInfo::Function(..)) {
+                    return self.check_closure_call(callee, fn_name, Some(&decl_span), args, &expr.span);
+                }
+            }
+            if let Some(class) = self.classes.get(fn_name).copied() {
+                return self.check_new(class, args, &expr.span);
+            }
+        } else if self.poisoned.contains(&path[0]) {
+            return None;
+        } else if self.env.val_lookup(&path[0]).is_some() || self.global_of(path, cur_module).is_some() {
+            let receiver = self.check_field_path(path, &expr.span, cur_module)?;
+            return self.check_method_call(receiver, fn_name, args, &expr.span);
+        } else if let (true, Some(element)) = (path.len() == 1, self.enums.get(&path[0]).copied()) {
+            return self.check_variant(element, fn_name, args, &expr.span);
+        } else if let (true, Some(class)) = (path.len() == 1, self.classes.get(&path[0]).copied()) {
+            if fn_name == ProgramClassElement::CTOR {
+                return self.check_new(class, args, &expr.span);
+            }
+            let method = class.methods_named(fn_name)
+                .map(|method| &method.header)
+                .find(|header| !header.takes_self() && self.accepts(header, types.as_ref()));
+            let Some(method) = method else {
+                return self.report(Self::no_method(class, fn_name, types.as_deref().unwrap_or_default(), false, &expr.span));
+            };
+            return self.finish_call(method, args, &expr.span);
+        } else if let Some(class) = self.modules.get(&path.join(".")).and_then(|module| module.class.get(fn_name)) {
+            // `module.Class(args)` constructs the class like `Class(args)`
+            return self.check_new(class, args, &expr.span);
+        }
+        let access_module = path
+            .iter()
+            .map(|x| x.to_string())
+            .reduce(|a, b| format!("{}.{}", a, b));
+
+        let fn_header = match self.find_function(fn_name, cur_module, access_module.as_deref(), types.as_ref(), &expr.span) {
+            Ok(fn_header) => fn_header,
+            Err(diagnostic) => return self.report(*diagnostic),
+        };
+        self.finish_call(fn_header, args, &expr.span)
+    }
+
+    /// `f(args)` where `f` is a variable holding a lambda
+    fn check_closure_call(&mut self, callee: TypedExpr, name: &str, decl_span: Option<&Span>, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        let TypeInfo::Function(params, ret) = &callee.ty else { unreachable!() };
+        if args.len() != params.len() {
+            let diagnostic = Diagnostic::error(format!(
+                "`{}` takes {} but {} supplied",
+                name, count(params.len(), "argument"), count(args.len(), "was")))
+                .with_primary(span, "wrong number of arguments");
+            let diagnostic = match decl_span {
+                Some(decl_span) => diagnostic.with_secondary(decl_span, format!("`{}` has type `{}`", name, callee.ty.display_name())),
+                None => diagnostic,
+            };
+            return self.report(diagnostic);
+        }
+        let ret = (**ret).clone();
+        let args = args
+            .into_iter()
+            .zip(params)
+            .map(|(arg, param)| self.coerce(arg, param))
+            .collect::<Vec<Option<TypedExpr>>>()
+            .into_iter()
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        Some(TypedExpr::new(TypedExprNode::CallClosure(Box::new(callee), args), ret, span.clone()))
+    }
+
+    /// Lambda is checked as a function of current module, taking values of captured variables after its parameters.
+    /// A captured `var` is shared through its cell, other variables are copied
+    fn check_expr_lambda(&mut self, expr: &AstExpr, cur_module: &str, header: &FunctionBasicInfo) -> Option<TypedExpr> {
+        let AstExprNode::Lambda(params, ret, body) = &expr.node else { unreachable!() };
+        let params = &params.iter()
+            .map(|(name, ty)| (name.clone(), ty.resolve_params(&self.type_params)))
+            .collect::<Vec<(String, TypeInfo)>>();
+        let ret = &ret.as_ref().map(|ty| ty.resolve_params(&self.type_params));
+        let span = &expr.span;
+        let mut types_known = true;
+        for ty in params.iter().map(|(_, ty)| ty).chain(ret) {
+            types_known &= self.check_type(ty, span).is_some();
+        }
+        if !types_known {
+            return None;
+        }
+        let mut names = capture::used_by(body)
+            .into_iter()
+            .filter(|name| params.iter().all(|(param, _)| param != name))
+            .collect::<Vec<String>>();
+        names.sort();
+        let captures = names
+            .into_iter()
+            .filter_map(|name| self.env.val_lookup(&name).cloned().map(|info| (name, info)))
+            .collect::<Vec<(String, VarInfo)>>();
+        self.lambda_count += 1;
+        let mut lambda_header = FunctionBasicInfo {
+            name: format!("{}.lambda{}", header.name, self.lambda_count),
+            module: Some(cur_module.to_string()),
+            param: Some(params.clone()),
+            ret: ret.clone(),
+            span: Some(span.clone()),
+        };
+
+        // state of enclosing function is restored after the body
+        let env = std::mem::take(&mut self.env);
+        let loops = std::mem::take(&mut self.loops);
+        let trys = std::mem::take(&mut self.trys);
+        let ctor_of = self.ctor_of.take();
+        let captured = std::mem::replace(&mut self.captured, capture::used_in_lambdas_of(body));
+        for (name, ty) in params {
+            let slot = self.env.current_val_size();
+            self.env.val_insert(name.clone(), VarInfo::param(ty.clone(), slot, Some(span)));
+        }
+        for (name, info) in &captures {
+            let binding_slot = self.env.current_val_size();
+            self.env.val_insert(name.clone(), VarInfo { binding_slot, ..info.clone() });
+        }
+        let typed = self.check_expr(body, cur_module, &lambda_header);
+        let typed = match (typed, ret) {
+            (Some(typed), Some(ret)) if ret != &TypeInfo::Unit && !expr_returns(&typed) => self.coerce(typed, ret),
+            (typed, _) => typed,
+        };
+        let local_var_size = self.env.max_val_table_size;
+        self.env = env;
+        self.loops = loops;
+        self.trys = trys;
+        self.ctor_of = ctor_of;
+        self.captured = captured;
+        let typed = typed?;
+
+        let ret_ty = ret.clone().unwrap_or_else(|| typed.ty.clone());
+        if ret_ty.has_unknown_elem() {
+            return self.report(Diagnostic::error("type annotations needed for return type of lambda")
+                .with_primary(&typed.span, "type of this value is not fully known")
+                .with_note(format!("give the lambda a return type, like `-> {}`", match ret_ty {
+                    TypeInfo::Map(..) => "Map<String, int>",
+                    TypeInfo::Optional(..) => "int?",
+                    _ => "List<int>",
+                })));
+        }
+        let fn_ty = TypeInfo::Function(params.iter().map(|(_, ty)| ty.clone()).collect(), Box::new(ret_ty.clone()));
+        let body_span = typed.span.clone();
+        let body = if ret_ty == TypeInfo::Unit {
+            vec![
+                TypedStmt::new(TypedStmtNode::Expr(typed), body_span.clone()),
+                TypedStmt::new(TypedStmtNode::Return(None, Vec::new()), body_span),
+            ]
+        } else {
+            vec![TypedStmt::new(TypedStmtNode::Return(Some(typed), Vec::new()), body_span)]
+        };
+        lambda_header.ret = Some(ret_ty).filter(|ty| ty != &TypeInfo::Unit);
+        let signature = lambda_header.signature();
+        self.lambdas.push(TypedFunction { header: lambda_header, local_var_size, body });
+        let captures = captures
+            .into_iter()
+            .map(|(_, info)| TypedExpr::new(TypedExprNode::Load(info.binding_slot), info.ty, span.clone()))
+            .collect();
+        Some(TypedExpr::new(TypedExprNode::Closure(signature, captures), fn_ty, span.clone()))
+    }
+
+    /// All arguments are checked even if some of them are broken
+    fn check_args(&mut self, args: Option<&Vec<AstExpr>>, cur_module: &str, header: &FunctionBasicInfo) -> Option<Vec<TypedExpr>> {
+        let args: Vec<Option<TypedExpr>> = args
+            .into_iter()
+            .flatten()
+            .map(|e| self.check_expr(e, cur_module, header))
+            .collect();
+        args.into_iter().collect()
+    }
+
+    /// Convert arguments to parameter types of `fn_header`, which accepts them
+    fn finish_call(&mut self, fn_header: &FunctionBasicInfo, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        self.finish_call_as(fn_header, fn_header.signature(), args, span)
+    }
+
+    /// Call function of `signature`, whose parameter and return types are given by `fn_header`.
+    /// Type parameters of a generic function are inferred from the arguments, the code is shared by all instances
+    fn finish_call_as(&mut self, fn_header: &FunctionBasicInfo, signature: String, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        let types = args.iter().map(|e| e.ty.clone()).collect::<Vec<TypeInfo>>();
+        let bindings = fn_header.infer(&types);
+        let unknown = fn_header.type_params()
+            .into_iter()
+            .find(|name| matches!(bindings.get(name), None | Some(TypeInfo::Any)));
+        if let Some(name) = unknown {
+            return self.report(Diagnostic::error(format!("can't infer type parameter `{}` of `{}`", name, fn_header.name))
+                .with_primary(span, "type of this call is unknown")
+                .with_note("annotate the type of empty literals passed to the call"));
+        }
+        let fn_header = &fn_header.instantiate(&bindings);
+        let require_types = fn_header.param.iter().flatten().map(|x| &x.1);
+        let args = args
+            .into_iter()
+            .zip(require_types)
+            .map(|(arg, require)| self.coerce(arg, require))
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        let ty = fn_header.ret.clone().unwrap_or(TypeInfo::Unit);
+        Some(TypedExpr::new(TypedExprNode::Call(signature, args), ty, span.clone()))
+    }
+
+    fn no_method(class: &ProgramClassElement, name: &str, types: &[TypeInfo], on_instance: bool, span: &Span) -> Diagnostic {
+        let arg_types = types.iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", ");
+        let kind = if on_instance { "method" } else { "static method" };
+        let mut diagnostic = Diagnostic::error(format!("no {} `{}({})` in class `{}`", kind, name, arg_types, class.name))
+            .with_primary(span, format!("no matching {}", kind));
+        for candidate in class.methods_named(name) {
+            if let Some(ref candidate_span) = candidate.header.span {
+                diagnostic = diagnostic.with_secondary(candidate_span, "candidate method defined here");
+            }
+        }
+        diagnostic
+    }
+
+    /// `receiver.name(args)`, receiver is passed as `self`
+    fn check_method_call(&mut self, receiver: TypedExpr, name: &str, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        if let TypeInfo::TypeSym(interface, _) = &receiver.ty {
+            if let Some(interface) = self.interfaces.get(interface).copied() {
+                return self.check_interface_call(interface, receiver, name, args, span);
+            }
+        }
+        let Some(class) = self.class_of(&receiver.ty) else {
+            return self.check_builtin_method_call(receiver, name, args, span);
+        };
+        let types = args.iter().map(|e| e.ty.clone()).collect::<Vec<TypeInfo>>();
+        let mut self_types = vec![receiver.ty.clone()];
+        self_types.extend(types.iter().cloned());
+        // type parameters of a generic class take type arguments of the receiver
+        let bindings = class.bindings(&receiver.ty);
+        let method = class.methods_named(name)
+            .map(|method| (&method.header, method.header.instantiate(&bindings)))
+            .filter(|_| name != ProgramClassElement::CTOR)
+            .find(|(_, instance)| instance.takes_self() && self.accepts(instance, Some(&self_types)));
+        let Some((method, instance)) = method else {
+            return self.report(Self::no_method(class, name, &types, true, span));
+        };
+        let mut all_args = vec![receiver];
+        all_args.extend(args);
+        self.finish_call_as(&instance, method.signature(), all_args, span)
+    }
+
+    /// `receiver.name(args)` on a value of interface type, the method is found in vtable of its class at runtime
+    fn check_interface_call(
+        &mut self,
+        interface: &ProgramInterfaceElement,
+        receiver: TypedExpr,
+        name: &str,
+        args: Vec<TypedExpr>,
+        span: &Span,
+    ) -> Option<TypedExpr> {
+        let mut self_types = vec![receiver.ty.clone()];
+        self_types.extend(args.iter().map(|e| e.ty.clone()));
+        let Some(method) = interface.method(name).filter(|method| self.accepts(method, Some(&self_types))) else {
+            let arg_types = self_types[1..].iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", ");
+            let mut diagnostic = Diagnostic::error(format!("no method `{}({})` in interface `{}`", name, arg_types, interface.name))
+                .with_primary(span, "no matching method");
+            if let Some(candidate_span) = interface.method(name).and_then(|method| method.span.as_ref()) {
+                diagnostic = diagnostic.with_secondary(candidate_span, "candidate method declared here");
+            }
+            return self.report(diagnostic);
+        };
+        let mut all_args = vec![receiver];
+        all_args.extend(args);
+        let require_types = method.param.iter().flatten().map(|x| &x.1);
+        let args = all_args
+            .into_iter()
+            .zip(require_types)
+            .map(|(arg, require)| self.coerce(arg, require))
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        let ty = method.ret.clone().unwrap_or(TypeInfo::Unit);
+        Some(TypedExpr::new(TypedExprNode::CallVirtual(method.name.clone(), args), ty, span.clone()))
+    }
+
+    /// Methods of built-in types are vm functions of prelude named `Type.method`.
+    /// Methods of `List<T>` and `Map<K, V>` are generic, type parameters are bound to element types of receiver
+    fn check_builtin_method_call(&mut self, receiver: TypedExpr, name: &str, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        let mut self_types = vec![receiver.ty.clone()];
+        self_types.extend(args.iter().map(|e| e.ty.clone()));
+        let prelude = self.modules.get("prelude").unwrap();
+        let (owner, bindings) = match &receiver.ty {
+            TypeInfo::List(elem) => (String::from("List"), HashMap::from([(String::from("T"), (**elem).clone())])),
+            TypeInfo::Map(key, value) => (String::from("Map"), HashMap::from([
+                (String::from("K"), (**key).clone()),
+                (String::from("V"), (**value).clone()),
+            ])),
+            ty => (ty.display_name(), HashMap::new()),
+        };
+        let method_name = format!("{}.{}", owner, name);
+        let candidates = prelude.candidates(&method_name);
+        let method = candidates
+            .iter()
+            .map(|candidate| (*candidate, candidate.instantiate(&bindings)))
+            .find(|(_, instance)| self.accepts(instance, Some(&self_types)));
+        let Some((method, instance)) = method else {
+            let arg_types = self_types[1..].iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", ");
+            let mut diagnostic = Diagnostic::error(format!("no method `{}({})` on type `{}`", name, arg_types, receiver.ty.display_name()))
+                .with_primary(span, "no matching method");
+            for candidate in candidates {
+                let params = candidate.instantiate(&bindings).param.unwrap_or_default();
+                let self_ty = params.first().map(|(_, ty)| ty.display_name()).unwrap_or_default();
+                let params = params.iter().skip(1)
+                    .map(|(_, ty)| ty.display_name())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                diagnostic = diagnostic.with_note(format!("candidate method: builtin `{}.{}({})`", self_ty, name, params));
+            }
+            return self.report(diagnostic);
+        };
+        let mut all_args = vec![receiver];
+        all_args.extend(args);
+        self.finish_call_as(&instance, method.signature(), all_args, span)
+    }
+
+    /// `Class(args)` allocates an instance and runs constructor on it
+    fn check_new(&mut self, class: &ProgramClassElement, args: Vec<TypedExpr>, span: &Span) -> Option<TypedExpr> {
+        let generic = Self::ctor_header(class);
+        // type arguments of a generic class come from constructor arguments, those left unknown from the context,
+        // like `Stack<int>` of `val s: Stack<int> = Stack()`
+        let mut types = vec![TypeInfo::TypeSym(class.name.clone(), vec![TypeInfo::Any; class.type_params.len()])];
+        types.extend(args.iter().map(|e| e.ty.clone()));
+        let bindings = generic.infer(&types);
+        let type_args = class.type_params.iter()
+            .map(|name| bindings.get(name).cloned().unwrap_or(TypeInfo::Any))
+            .collect();
+        let self_ty = TypeInfo::TypeSym(class.name.clone(), type_args);
+        types[0] = self_ty.clone();
+        let header = generic.instantiate(&class.bindings(&self_ty));
+        if !self.accepts(&header, Some(&types)) {
+            let params = header.param.iter().flatten().skip(1)
+                .map(|(_, ty)| ty.display_name())
+                .collect::<Vec<String>>()
+                .join(", ");
+            let found = types[1..].iter().map(TypeInfo::display_name).collect::<Vec<String>>().join(", ");
+            let diagnostic = Diagnostic::error(format!("constructor of `{}` takes `({})`, found `({})`", class.name, params, found))
+                .with_primary(span, "wrong arguments");
+            let diagnostic = match header.span {
+                Some(ref ctor_span) => diagnostic.with_secondary(ctor_span, "constructor declared here"),
+                None => diagnostic,
+            };
+            return self.report(diagnostic);
+        }
+        let require_types = header.param.iter().flatten().skip(1).map(|x| &x.1);
+        let args = args
+            .into_iter()
+            .zip(require_types)
+            .map(|(arg, require)| self.coerce(arg, require))
+            .collect::<Option<Vec<TypedExpr>>>()?;
+        let node = TypedExprNode::New {
+            class: class.name.clone(),
+            fields: class.fields.iter().map(|field| field.name.clone()).collect(),
+            ctor: generic.signature(),
+            args,
+            vtable: self.vtable(class),
+        };
+        Some(TypedExpr::new(node, self_ty, span.clone()))
+    }
+}
+
+/// Nodes ordered after the nodes they depend on, visiting `nodes` in order.
+/// A cycle is returned as the path from a node to the one depending on it
+fn topo_sort<K: Clone + Eq + Hash>(nodes: &[K], deps: impl Fn(&K) -> Vec<K>) -> Result<Vec<K>, Vec<K>> {
+    fn visit<K: Clone + Eq + Hash>(
+        node: &K,
+        deps: &impl Fn(&K) -> Vec<K>,
+        path: &mut Vec<K>,
+        done: &mut HashSet<K>,
+        order: &mut Vec<K>,
+    ) -> Result<(), Vec<K>> {
+        if done.contains(node) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|visiting| visiting == node) {
+            return Err(path[start..].to_vec());
+        }
+        path.push(node.clone());
+        for dep in deps(node) {
+            visit(&dep, deps, path, done, order)?;
+        }
+        path.pop();
+        done.insert(node.clone());
+        order.push(node.clone());
+        Ok(())
+    }
+    let (mut path, mut done, mut order) = (Vec::new(), HashSet::new(), Vec::new());
+    for node in nodes {
+        visit(node, &deps, &mut path, &mut done, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// `1 field` or `2 fields`, `was` becomes `were`
+fn count(n: usize, noun: &str) -> String {
+    match (n, noun) {
+        (1, _) => format!("1 {}", noun),
+        (n, "was") => format!("{} were", n),
+        (n, noun) => format!("{} {}s", n, noun),
+    }
+}
+
+/// Whether every path through statements reaches a `return`
+fn stmts_return(stmts: &[TypedStmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.node {
+        TypedStmtNode::Return(..) | TypedStmtNode::Throw(_) => true,
+        TypedStmtNode::Break(_) | TypedStmtNode::Continue(_) | TypedStmtNode::LeaveTry => false,
+        TypedStmtNode::Expr(expr) | TypedStmtNode::Var(_, expr) => expr_returns(expr),
+        TypedStmtNode::TupleVar { tuple, .. } => expr_returns(tuple),
+        TypedStmtNode::While(cond, _) => expr_returns(cond),
+        TypedStmtNode::ForRange { start, end, step, .. } => {
+            expr_returns(start) || expr_returns(end) || step.as_ref().map(|(_, step)| expr_returns(step)).unwrap_or(false)
+        }
+        TypedStmtNode::ForEach { iterable, .. } => expr_returns(iterable),
+    })
+}
+
+/// `0` or `-0`, a step which is known to be zero without running the loop
+fn is_zero_literal(expr: &AstExpr) -> bool {
+    match &expr.node {
+        AstExprNode::Integer(value) => *value == 0,
+        AstExprNode::UnaryOp(_, inner) => is_zero_literal(inner),
+        _ => false,
+    }
+}
+
+fn expr_returns(expr: &TypedExpr) -> bool {
+    match &expr.node {
+        TypedExprNode::Block(stmts, tail) => stmts_return(stmts) || tail.as_deref().map(expr_returns).unwrap_or(false),
+        TypedExprNode::If(cond, then_branch, Some(else_branch)) => {
+            expr_returns(cond) || (expr_returns(then_branch) && expr_returns(else_branch))
+        }
+        TypedExprNode::If(cond, _, None) => expr_returns(cond),
+        TypedExprNode::Try { body, handler, .. } => expr_returns(body) && expr_returns(handler),
+        TypedExprNode::Finally { body, finally, .. } => expr_returns(body) || expr_returns(finally),
+        // arms cover every value, but there may be none if the scrutinee has no value
+        TypedExprNode::Match { scrutinee, arms, .. } => {
+            expr_returns(scrutinee) || (!arms.is_empty() && arms.iter().all(|arm| expr_returns(&arm.body)))
+        }
+        _ => false,
+    }
+}
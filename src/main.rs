@@ -1,11 +1,14 @@
 extern crate core;
 
 use std::path::PathBuf;
+use std::process;
 
 use clap::Parser;
 
 use crate::frontend::codegen::CodeGen;
+use crate::frontend::diagnostic::{Diagnostic, SourceMap};
 use crate::frontend::loader::ScriptFileLoader;
+use crate::frontend::typeck::TypeChecker;
 use crate::vm::builtin::VMBuiltinRegister;
 use crate::vm::vm::AutoScriptVM;
 
@@ -24,6 +27,16 @@ pub struct VmArgs{
     pub instr: bool
 }
 
+/// Print diagnostics to stderr and exit
+fn abort_with_diagnostics(diagnostics: &[Diagnostic], sources: &SourceMap) -> ! {
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic.render(sources));
+    }
+    let error_count = diagnostics.iter().filter(|d| d.is_error()).count();
+    eprintln!("error: aborting due to {} previous error{}", error_count, if error_count > 1 { "s" } else { "" });
+    process::exit(1);
+}
+
 fn main() {
     let vm_args = VmArgs::parse();
 
@@ -31,22 +44,36 @@ fn main() {
     let file = PathBuf::from(vm_args.file.as_str());
 
     // load all file into modules obj
-    loader.add_file(&file).unwrap();
-    let mut modules = loader.unwrap();
+    if let Err(diagnostics) = loader.add_file(&file) {
+        abort_with_diagnostics(&diagnostics, loader.source_map());
+    }
+    let sources = loader.source_map().clone();
+    let mut modules = loader.unwrap().unwrap_or_else(|diagnostics| {
+        abort_with_diagnostics(&diagnostics, &sources)
+    });
 
     VMBuiltinRegister::register_prelude(&mut modules);
 
-    let codegen = CodeGen::new(modules);
-    let modules_prototype = codegen.translate_modules();
+    let typed_modules = TypeChecker::new(&modules).check_modules().unwrap_or_else(|diagnostics| {
+        abort_with_diagnostics(&diagnostics, &sources)
+    });
+    let modules_prototype = CodeGen::new(typed_modules).translate_modules();
     let main_module_name = file.file_stem().unwrap().to_str().unwrap();
+    let main_function_name = format!("V@{}.main(V", main_module_name);
+    if modules_prototype.get_function_prototype(&main_function_name).is_none() {
+        let diagnostic = Diagnostic::error(format!("`main` function not found in module `{}`", main_module_name))
+            .with_note("declare entry function as `fn main() { }`");
+        abort_with_diagnostics(&[diagnostic], &sources);
+    }
 
     let mut vm = AutoScriptVM::new(modules_prototype, vm_args);
 
-    let main_function_name = format!("V@{}.main(V", main_module_name);
-
     let start_time = std::time::SystemTime::now();
-    vm.start(&main_function_name);
+    if let Err(e) = vm.start(&main_function_name) {
+        eprint!("{}", e.render(&sources));
+        process::exit(1);
+    }
     let end_time = std::time::SystemTime::now();
     let cost_time = end_time.duration_since(start_time).unwrap().as_millis();
     println!("Finished in {}ms", cost_time);
-}
\ No newline at end of file
+}
@@ -1,37 +1,481 @@
-use crate::vm::mem::ObjCore;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use crate::vm::error::{VmError, VmResult};
+use crate::vm::mem::{Mem, Obj, ObjCore};
+use crate::vm::slot::Slot;
+use crate::vm::vm::AutoScriptFunction;
 
 #[derive(Debug)]
-pub struct ObjI32(pub i32);
-impl ObjI32 {
-    pub const NAME: &'static str = "prelude.i32";
+pub struct ObjStr(pub String);
+impl ObjStr {
+    pub const NAME: &'static str = "prelude.str";
+
+    /// Run `f` on content of the string object in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&str) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjStr>() {
+            Some(s) => Ok(f(&s.0)),
+            None => Err(VmError::TypeMismatch { expected: "String", found: "ref" }),
+        }
+    }
+
+    pub fn alloc(mem: &Mem, s: String) -> Slot {
+        Slot::Ref(mem.mutator().make(ObjStr(s)))
+    }
 }
 
-impl ToString for ObjI32{
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl Display for ObjStr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
-unsafe impl ObjCore for ObjI32 {
+unsafe impl ObjCore for ObjStr {
     fn name(&self) -> &str {
         Self::NAME
     }
 }
 
+/// Value of the built-in `Error` type, thrown by script or made of an error raised by the vm
 #[derive(Debug)]
-pub struct ObjStr(pub String);
-impl ObjStr {
-    pub const NAME: &'static str = "prelude.str";
+pub struct ObjError(pub String);
+impl ObjError {
+    pub const NAME: &'static str = "prelude.Error";
+
+    /// Run `f` on message of the error object in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&str) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjError>() {
+            Some(error) => Ok(f(&error.0)),
+            None => Err(VmError::TypeMismatch { expected: "Error", found: "ref" }),
+        }
+    }
+
+    pub fn alloc(mem: &Mem, message: String) -> Slot {
+        Slot::Ref(mem.mutator().make(ObjError(message)))
+    }
 }
 
-impl ToString for ObjStr{
-    fn to_string(&self) -> String {
-        self.0.clone()
+impl Display for ObjError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
-unsafe impl ObjCore for ObjStr {
+unsafe impl ObjCore for ObjError {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+}
+
+/// Name and field names of a class declared by script, shared by its instances
+#[derive(Debug)]
+pub struct ClassLayout {
+    pub name: String,
+    pub fields: Vec<String>,
+    /// Signature of the method implementing each method of interfaces, like `Printable.show`
+    pub vtable: HashMap<String, String>,
+}
+
+/// Instance of a class declared by script, fields are in declaration order
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub layout: Rc<ClassLayout>,
+    pub fields: Vec<Slot>,
+}
+
+impl ObjInstance {
+    /// Fields hold `unit` until they are initialized
+    pub fn new(layout: Rc<ClassLayout>) -> Self {
+        let fields = vec![Slot::Unit; layout.fields.len()];
+        Self { layout, fields }
+    }
+
+    /// Run `f` on the instance in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&ObjInstance) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjInstance>() {
+            Some(instance) => Ok(f(instance)),
+            None => Err(VmError::TypeMismatch { expected: "object", found: "ref" }),
+        }
+    }
+}
+
+impl Display for ObjInstance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fields = self.layout.fields
+            .iter()
+            .zip(&self.fields)
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(f, "{} {{ {} }}", self.layout.name, fields)
+    }
+}
+
+unsafe impl ObjCore for ObjInstance {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        for field in &self.fields {
+            if let Slot::Ref(obj) = field {
+                mark(*obj);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.layout.name
+    }
+}
+
+/// Name, tag and payload size of an enum variant declared by script, shared by its values
+#[derive(Debug)]
+pub struct VariantLayout {
+    pub name: String,
+    pub tag: usize,
+    pub arity: usize,
+}
+
+/// Value of an enum declared by script
+#[derive(Debug)]
+pub struct ObjVariant {
+    pub layout: Rc<VariantLayout>,
+    pub fields: Vec<Slot>,
+}
+
+impl ObjVariant {
+    /// Run `f` on the enum value in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&ObjVariant) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjVariant>() {
+            Some(variant) => Ok(f(variant)),
+            None => Err(VmError::TypeMismatch { expected: "enum", found: "ref" }),
+        }
+    }
+}
+
+impl Display for ObjVariant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.fields.is_empty() {
+            return f.write_str(&self.layout.name);
+        }
+        let fields = self.fields.iter().map(Slot::to_string).collect::<Vec<String>>().join(", ");
+        write!(f, "{}({})", self.layout.name, fields)
+    }
+}
+
+unsafe impl ObjCore for ObjVariant {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        for field in &self.fields {
+            if let Slot::Ref(obj) = field {
+                mark(*obj);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.layout.name
+    }
+}
+
+/// Elements of a `List<T>`
+#[derive(Debug)]
+pub struct ObjList(pub Vec<Slot>);
+impl ObjList {
+    pub const NAME: &'static str = "prelude.List";
+
+    /// Run `f` on elements of the list object in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&Vec<Slot>) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjList>() {
+            Some(list) => Ok(f(&list.0)),
+            None => Err(VmError::TypeMismatch { expected: "List", found: "ref" }),
+        }
+    }
+
+    /// Run `f` on elements of the list object in `slot`, which may change them
+    pub fn with_mut<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&mut Vec<Slot>) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let mut writer = unsafe { mutator.write(obj) };
+        match (*writer).any_mut().downcast_mut::<ObjList>() {
+            Some(list) => Ok(f(&mut list.0)),
+            None => Err(VmError::TypeMismatch { expected: "List", found: "ref" }),
+        }
+    }
+
+    pub fn alloc(mem: &Mem, items: Vec<Slot>) -> Slot {
+        Slot::Ref(mem.mutator().make(ObjList(items)))
+    }
+}
+
+impl Display for ObjList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let items = self.0.iter().map(Slot::to_string).collect::<Vec<String>>().join(", ");
+        write!(f, "[{}]", items)
+    }
+}
+
+unsafe impl ObjCore for ObjList {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        for item in &self.0 {
+            if let Slot::Ref(obj) = item {
+                mark(*obj);
+            }
+        }
+    }
+
     fn name(&self) -> &str {
         Self::NAME
     }
-}
\ No newline at end of file
+}
+
+/// Elements of a tuple, their number is fixed by its type
+#[derive(Debug)]
+pub struct ObjTuple(pub Vec<Slot>);
+impl ObjTuple {
+    pub const NAME: &'static str = "prelude.Tuple";
+
+    /// Run `f` on elements of the tuple object in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&Vec<Slot>) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjTuple>() {
+            Some(tuple) => Ok(f(&tuple.0)),
+            None => Err(VmError::TypeMismatch { expected: "tuple", found: "ref" }),
+        }
+    }
+
+    pub fn alloc(mem: &Mem, items: Vec<Slot>) -> Slot {
+        Slot::Ref(mem.mutator().make(ObjTuple(items)))
+    }
+}
+
+impl Display for ObjTuple {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let items = self.0.iter().map(Slot::to_string).collect::<Vec<String>>().join(", ");
+        write!(f, "({})", items)
+    }
+}
+
+unsafe impl ObjCore for ObjTuple {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        for item in &self.0 {
+            if let Slot::Ref(obj) = item {
+                mark(*obj);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+}
+
+/// Variable shared by a function and the closures capturing it, assignments by any of them are seen by all
+#[derive(Debug)]
+pub struct ObjCell(pub Slot);
+impl ObjCell {
+    pub const NAME: &'static str = "prelude.Cell";
+
+    /// Run `f` on value of the cell object in `slot`
+    pub fn with_mut<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&mut Slot) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let mut writer = unsafe { mutator.write(obj) };
+        match (*writer).any_mut().downcast_mut::<ObjCell>() {
+            Some(cell) => Ok(f(&mut cell.0)),
+            None => Err(VmError::TypeMismatch { expected: "cell", found: "ref" }),
+        }
+    }
+
+    pub fn alloc(mem: &Mem, value: Slot) -> Slot {
+        Slot::Ref(mem.mutator().make(ObjCell(value)))
+    }
+}
+
+impl Display for ObjCell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+unsafe impl ObjCore for ObjCell {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        if let Slot::Ref(obj) = &self.0 {
+            mark(*obj);
+        }
+    }
+
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+}
+
+/// Lambda with values of variables it captures, which follow its arguments in local variables
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: Rc<AutoScriptFunction>,
+    pub captures: Vec<Slot>,
+}
+
+impl ObjClosure {
+    pub const NAME: &'static str = "prelude.Closure";
+
+    /// Run `f` on the closure object in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&ObjClosure) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjClosure>() {
+            Some(closure) => Ok(f(closure)),
+            None => Err(VmError::TypeMismatch { expected: "function", found: "ref" }),
+        }
+    }
+}
+
+impl Display for ObjClosure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<fn {}>", self.function.name)
+    }
+}
+
+unsafe impl ObjCore for ObjClosure {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        for capture in &self.captures {
+            if let Slot::Ref(obj) = capture {
+                mark(*obj);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+}
+
+/// Key of a map entry, strings are keyed by content
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Int(i64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+}
+
+impl MapKey {
+    pub fn of(slot: &Slot, mem: &Mem) -> VmResult<MapKey> {
+        match slot {
+            Slot::Int(v) => Ok(MapKey::Int(*v)),
+            Slot::Bool(b) => Ok(MapKey::Bool(*b)),
+            Slot::Char(c) => Ok(MapKey::Char(*c)),
+            Slot::Ref(_) => ObjStr::with(slot, mem, |s| MapKey::Str(s.to_string())),
+            other => Err(VmError::TypeMismatch { expected: "map key", found: other.type_name() }),
+        }
+    }
+}
+
+/// Entries of a `Map<K, V>` in insertion order
+#[derive(Debug, Default)]
+pub struct ObjMap {
+    entries: Vec<(Slot, Slot)>,
+    /// Position of entry in `entries` by key
+    index: HashMap<MapKey, usize>,
+}
+
+impl ObjMap {
+    pub const NAME: &'static str = "prelude.Map";
+
+    /// Run `f` on the map object in `slot`
+    pub fn with<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&ObjMap) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let reader = unsafe { mutator.read(obj) };
+        match (*reader).any_ref().downcast_ref::<ObjMap>() {
+            Some(map) => Ok(f(map)),
+            None => Err(VmError::TypeMismatch { expected: "Map", found: "ref" }),
+        }
+    }
+
+    /// Run `f` on the map object in `slot`, which may change it
+    pub fn with_mut<R>(slot: &Slot, mem: &Mem, f: impl FnOnce(&mut ObjMap) -> R) -> VmResult<R> {
+        let obj = slot.get_ref()?;
+        let mutator = mem.mutator();
+        let mut writer = unsafe { mutator.write(obj) };
+        match (*writer).any_mut().downcast_mut::<ObjMap>() {
+            Some(map) => Ok(f(map)),
+            None => Err(VmError::TypeMismatch { expected: "Map", found: "ref" }),
+        }
+    }
+
+    pub fn alloc(mem: &Mem, map: ObjMap) -> Slot {
+        Slot::Ref(mem.mutator().make(map))
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Slot> {
+        self.index.get(key).map(|idx| &self.entries[*idx].1)
+    }
+
+    /// Value of an existing key is replaced in place, a new key goes last
+    pub fn insert(&mut self, key: MapKey, key_slot: Slot, value: Slot) {
+        match self.index.get(&key) {
+            Some(idx) => self.entries[*idx].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key_slot, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Slot> {
+        let idx = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(idx);
+        for pos in self.index.values_mut().filter(|pos| **pos > idx) {
+            *pos -= 1;
+        }
+        Some(value)
+    }
+
+    pub fn entries(&self) -> &[(Slot, Slot)] {
+        &self.entries
+    }
+}
+
+impl Display for ObjMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = self.entries
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(f, "{{{}}}", entries)
+    }
+}
+
+unsafe impl ObjCore for ObjMap {
+    fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {
+        for (key, value) in &self.entries {
+            for slot in [key, value] {
+                if let Slot::Ref(obj) = slot {
+                    mark(*obj);
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+}
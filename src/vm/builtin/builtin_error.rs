@@ -0,0 +1,26 @@
+use crate::frontend::ast::basic::TypeInfo;
+use crate::vm::builtin::builtin_class::{ObjError, ObjStr};
+use crate::vm::builtin::NativeFn;
+use crate::vm::error::VmResult;
+use crate::vm::slot::Slot;
+use crate::vm::thread::Thread;
+
+/// Constructor and methods of `Error`, the type of values thrown and caught by scripts
+pub(crate) fn error_functions() -> Vec<NativeFn> {
+    vec![
+        NativeFn { name: "Error", args: vec![("message", TypeInfo::String)], ret: TypeInfo::Error, func: new },
+        NativeFn { name: "Error.message", args: vec![("self", TypeInfo::Error)], ret: TypeInfo::String, func: message },
+    ]
+}
+
+fn new(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let message = ObjStr::with(frame.local_vars.get(0), &frame.vm().mem, str::to_string)?;
+    Ok(ObjError::alloc(&frame.vm().mem, message))
+}
+
+fn message(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let message = ObjError::with(frame.local_vars.get(0), &frame.vm().mem, str::to_string)?;
+    Ok(ObjStr::alloc(&frame.vm().mem, message))
+}
@@ -1,31 +1,151 @@
 use std::fmt::Debug;
+use std::rc::Rc;
 
 use crate::frontend::ast::basic::TypeInfo;
-use crate::vm::builtin::AutoScriptRustVMFunctionBinding;
+use crate::frontend::ast::element::ProgramInterfaceElement;
+use crate::frontend::ast::func::FunctionBasicInfo;
+use crate::vm::builtin::{AutoScriptRustVMFunctionBinding, NativeFn};
+use crate::vm::builtin::builtin_class::{ClassLayout, ObjInstance, ObjList, ObjMap, ObjStr, ObjTuple, ObjVariant};
+use crate::vm::error::{VmError, VmResult};
 use crate::vm::slot::Slot;
-use crate::vm::thread::Frame;
+use crate::vm::thread::Thread;
 
-#[derive(Debug, Clone)]
-pub(crate) struct FnPrint;
+/// `print` and `to_string` take a value of any type, `T` is bound to the type of argument by type checker
+pub(crate) fn value_functions() -> Vec<NativeFn> {
+    let value = || TypeInfo::Param(String::from("T"));
+    vec![
+        NativeFn { name: "print", args: vec![("msg", value())], ret: TypeInfo::Unit, func: print },
+        NativeFn { name: "to_string", args: vec![("value", value())], ret: TypeInfo::String, func: to_string },
+    ]
+}
 
+/// Method of `ToString` in vtables of classes implementing it
+pub(crate) const TO_STRING: &str = "ToString.to_string";
 
-impl AutoScriptRustVMFunctionBinding for FnPrint{
-    fn get_name(&self) -> &'static str {
-        "print"
+/// `interface ToString { fn to_string(self) -> String }`, instances of classes implementing it are shown by the method
+pub(crate) fn to_string_interface() -> ProgramInterfaceElement {
+    let method = FunctionBasicInfo {
+        name: String::from(TO_STRING),
+        module: Some(String::from("prelude")),
+        param: Some(vec![(String::from("self"), TypeInfo::from("ToString"))]),
+        ret: Some(TypeInfo::String),
+        span: None,
+    };
+    ProgramInterfaceElement {
+        doc: None,
+        name: String::from("ToString"),
+        module: String::from("prelude"),
+        methods: vec![method],
+        span: None,
     }
+}
 
-    fn get_args(&self) -> &'static [(&'static str, TypeInfo)] {
-        &[("msg", TypeInfo::Any)]
-    }
+fn print(thread: &mut Thread) -> VmResult<Slot> {
+    let value = thread.current_frame().local_vars.get(0).clone();
+    println!("{}", display(thread, &value)?);
+    Ok(Slot::Unit)
+}
 
-    fn get_ret_type(&self) -> TypeInfo {
-        TypeInfo::Unit
+/// Text of any value as `print` shows it, string interpolation is lowered to calls of it
+fn to_string(thread: &mut Thread) -> VmResult<Slot> {
+    let value = thread.current_frame().local_vars.get(0).clone();
+    let text = display(thread, &value)?;
+    Ok(ObjStr::alloc(&thread.vm().mem, text))
+}
+
+/// Parts of a value shown by `display`, copied out so that no object is being read while `to_string` of an instance runs
+enum Shown {
+    Text(String),
+    Instance(Rc<ClassLayout>, Vec<Slot>),
+    List(Vec<Slot>),
+    Tuple(Vec<Slot>),
+    Map(Vec<(Slot, Slot)>),
+    Variant(String, Vec<Slot>),
+}
+
+/// Text of an object reached again while it is being shown
+const CYCLE: &str = "<cycle>";
+
+/// Objects nested deeper than this are not shown, which stops `to_string` calling itself without end
+const MAX_SHOW_DEPTH: usize = 256;
+
+/// Text of a value, instances of classes implementing `ToString` are shown by calling their `to_string`,
+/// also as elements of collections and fields of other instances.
+/// An object reached again inside itself is shown as `<cycle>`, unless it would be shown by its `to_string` again
+fn display(thread: &mut Thread, value: &Slot) -> VmResult<String> {
+    let Slot::Ref(obj) = value else {
+        return Ok(value.to_string());
+    };
+    if thread.showing.len() >= MAX_SHOW_DEPTH {
+        return Err(VmError::ShowTooDeep);
     }
+    let repeated = thread.showing.contains(obj);
+    thread.showing.push(*obj);
+    let text = display_obj(thread, value, repeated);
+    thread.showing.pop();
+    text
+}
 
-    fn execute(&self, frame: &mut Frame, _: &mut Option<Slot>) {
-        let value = frame.local_vars.get(0);
-        println!("{}", value.to_string());
+fn display_obj(thread: &mut Thread, value: &Slot, repeated: bool) -> VmResult<String> {
+    let shown = {
+        let mutator = thread.vm().mem.mutator();
+        let reader = unsafe { mutator.read(value.get_ref()?) };
+        let any = (*reader).any_ref();
+        if let Some(instance) = any.downcast_ref::<ObjInstance>() {
+            Shown::Instance(Rc::clone(&instance.layout), instance.fields.clone())
+        } else if let Some(list) = any.downcast_ref::<ObjList>() {
+            Shown::List(list.0.clone())
+        } else if let Some(tuple) = any.downcast_ref::<ObjTuple>() {
+            Shown::Tuple(tuple.0.clone())
+        } else if let Some(map) = any.downcast_ref::<ObjMap>() {
+            Shown::Map(map.entries().to_vec())
+        } else if let Some(variant) = any.downcast_ref::<ObjVariant>() {
+            Shown::Variant(variant.layout.name.clone(), variant.fields.clone())
+        } else {
+            Shown::Text(reader.to_string())
+        }
+    };
+    if repeated {
+        return match shown {
+            Shown::Instance(layout, _) if layout.vtable.contains_key(TO_STRING) => Err(VmError::RecursiveToString(layout.name.clone())),
+            _ => Ok(String::from(CYCLE)),
+        };
     }
+    let mut join = |items: &[Slot]| items
+        .iter()
+        .map(|item| display(thread, item))
+        .collect::<VmResult<Vec<String>>>()
+        .map(|items| items.join(", "));
+    Ok(match shown {
+        Shown::Text(text) => text,
+        Shown::Instance(layout, fields) => match layout.vtable.get(TO_STRING) {
+            Some(signature) => {
+                let text = thread.call_function(signature, vec![value.clone()])?;
+                ObjStr::with(&text, &thread.vm().mem, str::to_string)?
+            }
+            None => {
+                let fields = layout.fields
+                    .iter()
+                    .zip(&fields)
+                    .map(|(name, value)| Ok(format!("{}: {}", name, display(thread, value)?)))
+                    .collect::<VmResult<Vec<String>>>()?
+                    .join(", ");
+                format!("{} {{ {} }}", layout.name, fields)
+            }
+        },
+        Shown::List(items) => format!("[{}]", join(&items)?),
+        Shown::Tuple(items) => format!("({})", join(&items)?),
+        Shown::Map(entries) => {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| Ok(format!("{}: {}", display(thread, key)?, display(thread, value)?)))
+                .collect::<VmResult<Vec<String>>>()?
+                .join(", ");
+            format!("{{{}}}", entries)
+        }
+        Shown::Variant(name, fields) if fields.is_empty() => name,
+        Shown::Variant(name, fields) => format!("{}({})", name, join(&fields)?),
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -37,7 +157,7 @@ impl AutoScriptRustVMFunctionBinding for FnAssert {
         "assert"
     }
 
-    fn get_args(&self) -> &'static [(&'static str, TypeInfo)] {
+    fn get_args(&self) -> &[(&'static str, TypeInfo)] {
         &[("expr", TypeInfo::Bool)]
     }
 
@@ -45,7 +165,11 @@ impl AutoScriptRustVMFunctionBinding for FnAssert {
         TypeInfo::Unit
     }
 
-    fn execute(&self, frame: &mut Frame, _: &mut Option<Slot>) {
-        assert!(frame.local_vars.get(0).get_bool())
+    fn execute(&self, thread: &mut Thread, _: &mut Option<Slot>) -> VmResult<()> {
+        if thread.current_frame().local_vars.get(0).get_bool()? {
+            Ok(())
+        } else {
+            Err(VmError::AssertionFailed)
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,22 @@
+use std::fs;
+
+use crate::frontend::ast::basic::TypeInfo;
+use crate::vm::builtin::builtin_class::ObjStr;
+use crate::vm::builtin::NativeFn;
+use crate::vm::error::{VmError, VmResult};
+use crate::vm::slot::Slot;
+use crate::vm::thread::Thread;
+
+/// Functions touching the system, their failures can be caught by scripts
+pub(crate) fn io_functions() -> Vec<NativeFn> {
+    vec![
+        NativeFn { name: "read_file", args: vec![("path", TypeInfo::String)], ret: TypeInfo::String, func: read_file },
+    ]
+}
+
+fn read_file(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let path = ObjStr::with(frame.local_vars.get(0), &frame.vm().mem, str::to_string)?;
+    let content = fs::read_to_string(&path).map_err(|error| VmError::Io(format!("cannot read `{}`: {}", path, error)))?;
+    Ok(ObjStr::alloc(&frame.vm().mem, content))
+}
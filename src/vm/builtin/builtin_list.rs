@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+
+use crate::frontend::ast::basic::TypeInfo;
+use crate::vm::builtin::builtin_class::{ObjList, ObjStr};
+use crate::vm::builtin::NativeFn;
+use crate::vm::error::{VmError, VmResult};
+use crate::vm::slot::Slot;
+use crate::vm::thread::{Frame, Thread};
+
+/// Methods of `List<T>`, `T` is bound to element type of the receiver by type checker,
+/// `U` of `map` is bound to return type of the lambda
+pub(crate) fn list_methods() -> Vec<NativeFn> {
+    let elem = || TypeInfo::Param(String::from("T"));
+    let mapped = || TypeInfo::Param(String::from("U"));
+    let list = || TypeInfo::List(Box::new(elem()));
+    let this = || ("self", list());
+    let mut methods = vec![
+        NativeFn { name: "List.len", args: vec![this()], ret: TypeInfo::Int, func: len },
+        NativeFn { name: "List.push", args: vec![this(), ("value", elem())], ret: TypeInfo::Unit, func: push },
+        NativeFn { name: "List.pop", args: vec![this()], ret: elem(), func: pop },
+        NativeFn {
+            name: "List.insert",
+            args: vec![this(), ("index", TypeInfo::Int), ("value", elem())],
+            ret: TypeInfo::Unit,
+            func: insert,
+        },
+        NativeFn { name: "List.remove", args: vec![this(), ("index", TypeInfo::Int)], ret: elem(), func: remove },
+        NativeFn {
+            name: "List.slice",
+            args: vec![this(), ("start", TypeInfo::Int), ("end", TypeInfo::Int)],
+            ret: list(),
+            func: slice,
+        },
+        NativeFn {
+            name: "List.map",
+            args: vec![this(), ("f", TypeInfo::Function(vec![elem()], Box::new(mapped())))],
+            ret: TypeInfo::List(Box::new(mapped())),
+            func: map,
+        },
+        NativeFn {
+            name: "List.filter",
+            args: vec![this(), ("f", TypeInfo::Function(vec![elem()], Box::new(TypeInfo::Bool)))],
+            ret: list(),
+            func: filter,
+        },
+        NativeFn {
+            name: "List.sort_by",
+            args: vec![this(), ("compare", TypeInfo::Function(vec![elem(), elem()], Box::new(TypeInfo::Int)))],
+            ret: TypeInfo::Unit,
+            func: sort_by,
+        },
+    ];
+    // only lists of ordered types can be sorted
+    for ty in [TypeInfo::Int, TypeInfo::Float, TypeInfo::String] {
+        let func = if ty == TypeInfo::String { sort_str } else { sort_num };
+        methods.push(NativeFn { name: "List.sort", args: vec![("self", TypeInfo::List(Box::new(ty)))], ret: TypeInfo::Unit, func });
+    }
+    // only elements supporting `==` can be searched, lists and instances have no equality to search by
+    for ty in [TypeInfo::Int, TypeInfo::Float, TypeInfo::Bool, TypeInfo::String] {
+        let args = vec![("self", TypeInfo::List(Box::new(ty.clone()))), ("value", ty)];
+        methods.push(NativeFn { name: "List.contains", args, ret: TypeInfo::Bool, func: contains });
+    }
+    methods
+}
+
+/// Copy elements of list argument at `idx`
+fn list_arg(frame: &Frame, idx: usize) -> VmResult<Vec<Slot>> {
+    ObjList::with(frame.local_vars.get(idx), &frame.vm().mem, Vec::clone)
+}
+
+fn with_self<R>(frame: &Frame, f: impl FnOnce(&mut Vec<Slot>) -> R) -> VmResult<R> {
+    ObjList::with_mut(frame.local_vars.get(0), &frame.vm().mem, f)
+}
+
+/// Elements are equal by value, strings by content
+fn same_value(frame: &Frame, v1: &Slot, v2: &Slot) -> bool {
+    let mem = &frame.vm().mem;
+    match (v1, v2) {
+        (Slot::Ref(_), Slot::Ref(_)) => ObjStr::with(v1, mem, |s1| ObjStr::with(v2, mem, |s2| s1 == s2))
+            .and_then(|eq| eq)
+            .unwrap_or(v1 == v2),
+        _ => v1 == v2,
+    }
+}
+
+fn len(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    Ok(Slot::Int(with_self(frame, |items| items.len())? as i64))
+}
+
+fn push(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let value = frame.local_vars.get(1).clone();
+    with_self(frame, |items| items.push(value))?;
+    Ok(Slot::Unit)
+}
+
+fn pop(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    with_self(frame, Vec::pop)?.ok_or(VmError::EmptyList)
+}
+
+fn insert(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let index = frame.local_vars.get(1).get_int()?;
+    let value = frame.local_vars.get(2).clone();
+    with_self(frame, |items| match usize::try_from(index) {
+        Ok(idx) if idx <= items.len() => {
+            items.insert(idx, value);
+            Ok(Slot::Unit)
+        }
+        _ => Err(VmError::IndexOutOfBounds { index, len: items.len() }),
+    })?
+}
+
+fn remove(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let index = frame.local_vars.get(1).get_int()?;
+    with_self(frame, |items| match usize::try_from(index) {
+        Ok(idx) if idx < items.len() => Ok(items.remove(idx)),
+        _ => Err(VmError::IndexOutOfBounds { index, len: items.len() }),
+    })?
+}
+
+fn slice(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let items = list_arg(frame, 0)?;
+    let start = frame.local_vars.get(1).get_int()?;
+    let end = frame.local_vars.get(2).get_int()?;
+    if start < 0 || start > end || end as usize > items.len() {
+        return Err(VmError::InvalidRange { start, end, len: items.len() });
+    }
+    Ok(ObjList::alloc(&frame.vm().mem, items[start as usize..end as usize].to_vec()))
+}
+
+fn contains(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let items = list_arg(frame, 0)?;
+    let value = frame.local_vars.get(1);
+    Ok(Slot::Bool(items.iter().any(|item| same_value(frame, item, value))))
+}
+
+/// Floats are ordered by `total_cmp`, `NaN` goes before or after all numbers depending on its sign
+fn sort_num(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    with_self(frame, |items| items.sort_by(|v1, v2| match (v1, v2) {
+        (Slot::Int(i1), Slot::Int(i2)) => i1.cmp(i2),
+        (Slot::Float(f1), Slot::Float(f2)) => f1.total_cmp(f2),
+        _ => Ordering::Equal,
+    }))?;
+    Ok(Slot::Unit)
+}
+
+fn sort_str(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let mem = &frame.vm().mem;
+    let mut keyed = list_arg(frame, 0)?
+        .into_iter()
+        .map(|item| ObjStr::with(&item, mem, str::to_string).map(|key| (key, item)))
+        .collect::<VmResult<Vec<(String, Slot)>>>()?;
+    keyed.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+    with_self(frame, |items| *items = keyed.into_iter().map(|(_, item)| item).collect())?;
+    Ok(Slot::Unit)
+}
+
+fn map(thread: &mut Thread) -> VmResult<Slot> {
+    let f = thread.current_frame().local_vars.get(1).clone();
+    let mapped = list_arg(thread.current_frame(), 0)?
+        .into_iter()
+        .map(|item| thread.call_closure(&f, vec![item]))
+        .collect::<VmResult<Vec<Slot>>>()?;
+    Ok(ObjList::alloc(&thread.vm().mem, mapped))
+}
+
+fn filter(thread: &mut Thread) -> VmResult<Slot> {
+    let f = thread.current_frame().local_vars.get(1).clone();
+    let mut kept = Vec::new();
+    for item in list_arg(thread.current_frame(), 0)? {
+        if thread.call_closure(&f, vec![item.clone()])?.get_bool()? {
+            kept.push(item);
+        }
+    }
+    Ok(ObjList::alloc(&thread.vm().mem, kept))
+}
+
+/// Stable sort by comparator returning a negative number, zero or a positive number.
+/// The list is sorted on a copy, the comparator may not see it half sorted
+fn sort_by(thread: &mut Thread) -> VmResult<Slot> {
+    let compare = thread.current_frame().local_vars.get(1).clone();
+    let items = list_arg(thread.current_frame(), 0)?;
+    let sorted = merge_sort(items, &mut |v1, v2| {
+        Ok(thread.call_closure(&compare, vec![v1.clone(), v2.clone()])?.get_int()? > 0)
+    })?;
+    with_self(thread.current_frame(), |list| *list = sorted)?;
+    Ok(Slot::Unit)
+}
+
+/// Merge sort with a comparator telling whether the first value goes after the second one.
+/// Unlike `slice::sort_by` it accepts inconsistent comparators, the result is then in some order
+fn merge_sort(mut items: Vec<Slot>, after: &mut dyn FnMut(&Slot, &Slot) -> VmResult<bool>) -> VmResult<Vec<Slot>> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, after)?;
+    let right = merge_sort(right, after)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(v1), Some(v2)) = (left.peek(), right.peek()) {
+        // equal values are taken from the left to keep the sort stable
+        let next = if after(v1, v2)? { right.next() } else { left.next() };
+        merged.extend(next);
+    }
+    merged.extend(left.chain(right));
+    Ok(merged)
+}
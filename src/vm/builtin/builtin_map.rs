@@ -0,0 +1,101 @@
+use crate::frontend::ast::basic::TypeInfo;
+use crate::vm::builtin::builtin_class::{MapKey, ObjList, ObjMap};
+use crate::vm::builtin::NativeFn;
+use crate::vm::error::VmResult;
+use crate::vm::slot::Slot;
+use crate::vm::thread::{Frame, Thread};
+
+/// Methods of `Map<K, V>`, `K` and `V` are bound to key and value types of the receiver by type checker
+pub(crate) fn map_methods() -> Vec<NativeFn> {
+    let key = || TypeInfo::Param(String::from("K"));
+    let value = || TypeInfo::Param(String::from("V"));
+    let this = || ("self", TypeInfo::Map(Box::new(key()), Box::new(value())));
+    vec![
+        NativeFn { name: "Map.len", args: vec![this()], ret: TypeInfo::Int, func: len },
+        NativeFn {
+            name: "Map.get",
+            args: vec![this(), ("key", key())],
+            ret: TypeInfo::Optional(Box::new(value())),
+            func: get,
+        },
+        NativeFn {
+            name: "Map.get_or",
+            args: vec![this(), ("key", key()), ("default", value())],
+            ret: value(),
+            func: get_or,
+        },
+        NativeFn {
+            name: "Map.set",
+            args: vec![this(), ("key", key()), ("value", value())],
+            ret: TypeInfo::Unit,
+            func: set,
+        },
+        NativeFn { name: "Map.remove", args: vec![this(), ("key", key())], ret: TypeInfo::Bool, func: remove },
+        NativeFn { name: "Map.contains_key", args: vec![this(), ("key", key())], ret: TypeInfo::Bool, func: contains_key },
+        NativeFn { name: "Map.keys", args: vec![this()], ret: TypeInfo::List(Box::new(key())), func: keys },
+        NativeFn { name: "Map.values", args: vec![this()], ret: TypeInfo::List(Box::new(value())), func: values },
+    ]
+}
+
+fn key_arg(frame: &Frame, idx: usize) -> VmResult<MapKey> {
+    MapKey::of(frame.local_vars.get(idx), &frame.vm().mem)
+}
+
+fn with_self<R>(frame: &Frame, f: impl FnOnce(&ObjMap) -> R) -> VmResult<R> {
+    ObjMap::with(frame.local_vars.get(0), &frame.vm().mem, f)
+}
+
+fn with_self_mut<R>(frame: &Frame, f: impl FnOnce(&mut ObjMap) -> R) -> VmResult<R> {
+    ObjMap::with_mut(frame.local_vars.get(0), &frame.vm().mem, f)
+}
+
+fn len(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    Ok(Slot::Int(with_self(frame, |map| map.entries().len())? as i64))
+}
+
+fn get(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let key = key_arg(frame, 1)?;
+    Ok(with_self(frame, |map| map.get(&key).cloned())?.unwrap_or(Slot::None))
+}
+
+fn get_or(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let key = key_arg(frame, 1)?;
+    let value = with_self(frame, |map| map.get(&key).cloned())?;
+    Ok(value.unwrap_or_else(|| frame.local_vars.get(2).clone()))
+}
+
+fn set(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let key = key_arg(frame, 1)?;
+    let key_slot = frame.local_vars.get(1).clone();
+    let value = frame.local_vars.get(2).clone();
+    with_self_mut(frame, |map| map.insert(key, key_slot, value))?;
+    Ok(Slot::Unit)
+}
+
+fn remove(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let key = key_arg(frame, 1)?;
+    Ok(Slot::Bool(with_self_mut(frame, |map| map.remove(&key))?.is_some()))
+}
+
+fn contains_key(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let key = key_arg(frame, 1)?;
+    Ok(Slot::Bool(with_self(frame, |map| map.get(&key).is_some())?))
+}
+
+fn keys(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let keys = with_self(frame, |map| map.entries().iter().map(|(key, _)| key.clone()).collect())?;
+    Ok(ObjList::alloc(&frame.vm().mem, keys))
+}
+
+fn values(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let values = with_self(frame, |map| map.entries().iter().map(|(_, value)| value.clone()).collect())?;
+    Ok(ObjList::alloc(&frame.vm().mem, values))
+}
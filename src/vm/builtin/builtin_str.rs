@@ -0,0 +1,144 @@
+use crate::frontend::ast::basic::TypeInfo;
+use crate::vm::builtin::builtin_class::{ObjList, ObjStr};
+use crate::vm::builtin::NativeFn;
+use crate::vm::error::{VmError, VmResult};
+use crate::vm::slot::Slot;
+use crate::vm::thread::{Frame, Thread};
+
+/// Methods of `String`, indices count chars rather than bytes
+pub(crate) fn string_methods() -> Vec<NativeFn> {
+    const SELF: (&str, TypeInfo) = ("self", TypeInfo::String);
+    vec![
+        NativeFn { name: "String.len", args: vec![SELF], ret: TypeInfo::Int, func: len },
+        NativeFn {
+            name: "String.substring",
+            args: vec![SELF, ("start", TypeInfo::Int), ("end", TypeInfo::Int)],
+            ret: TypeInfo::String,
+            func: substring,
+        },
+        NativeFn { name: "String.char_at", args: vec![SELF, ("index", TypeInfo::Int)], ret: TypeInfo::String, func: char_at },
+        NativeFn { name: "String.trim", args: vec![SELF], ret: TypeInfo::String, func: trim },
+        NativeFn {
+            name: "String.replace",
+            args: vec![SELF, ("from", TypeInfo::String), ("to", TypeInfo::String)],
+            ret: TypeInfo::String,
+            func: replace,
+        },
+        NativeFn { name: "String.contains", args: vec![SELF, ("pat", TypeInfo::String)], ret: TypeInfo::Bool, func: contains },
+        NativeFn { name: "String.starts_with", args: vec![SELF, ("pat", TypeInfo::String)], ret: TypeInfo::Bool, func: starts_with },
+        NativeFn { name: "String.ends_with", args: vec![SELF, ("pat", TypeInfo::String)], ret: TypeInfo::Bool, func: ends_with },
+        NativeFn { name: "String.to_upper", args: vec![SELF], ret: TypeInfo::String, func: to_upper },
+        NativeFn { name: "String.to_lower", args: vec![SELF], ret: TypeInfo::String, func: to_lower },
+        NativeFn {
+            name: "String.split",
+            args: vec![SELF, ("sep", TypeInfo::String)],
+            ret: TypeInfo::List(Box::new(TypeInfo::String)),
+            func: split,
+        },
+        NativeFn { name: "String.parse_int", args: vec![SELF], ret: TypeInfo::Int, func: parse_int },
+        NativeFn { name: "String.parse_float", args: vec![SELF], ret: TypeInfo::Float, func: parse_float },
+    ]
+}
+
+/// Copy string argument at `idx`
+fn str_arg(frame: &Frame, idx: usize) -> VmResult<String> {
+    ObjStr::with(frame.local_vars.get(idx), &frame.vm().mem, str::to_string)
+}
+
+fn make_str(frame: &Frame, s: String) -> Slot {
+    ObjStr::alloc(&frame.vm().mem, s)
+}
+
+fn len(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    Ok(Slot::Int(str_arg(frame, 0)?.chars().count() as i64))
+}
+
+fn substring(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    let start = frame.local_vars.get(1).get_int()?;
+    let end = frame.local_vars.get(2).get_int()?;
+    let len = s.chars().count();
+    if start < 0 || start > end || end as usize > len {
+        return Err(VmError::InvalidRange { start, end, len });
+    }
+    let sub = s.chars().skip(start as usize).take((end - start) as usize).collect();
+    Ok(make_str(frame, sub))
+}
+
+/// Char at `index` as a string of its own, `s[index]` is lowered to it
+fn char_at(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    let index = frame.local_vars.get(1).get_int()?;
+    let len = s.chars().count();
+    let c = usize::try_from(index).ok()
+        .and_then(|idx| s.chars().nth(idx))
+        .ok_or(VmError::IndexOutOfBounds { index, len })?;
+    Ok(make_str(frame, c.to_string()))
+}
+
+fn trim(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    Ok(make_str(frame, s.trim().to_string()))
+}
+
+fn replace(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    let replaced = s.replace(&str_arg(frame, 1)?, &str_arg(frame, 2)?);
+    Ok(make_str(frame, replaced))
+}
+
+fn contains(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    Ok(Slot::Bool(str_arg(frame, 0)?.contains(&str_arg(frame, 1)?)))
+}
+
+fn starts_with(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    Ok(Slot::Bool(str_arg(frame, 0)?.starts_with(&str_arg(frame, 1)?)))
+}
+
+fn ends_with(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    Ok(Slot::Bool(str_arg(frame, 0)?.ends_with(&str_arg(frame, 1)?)))
+}
+
+fn to_upper(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    Ok(make_str(frame, s.to_uppercase()))
+}
+
+fn to_lower(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    Ok(make_str(frame, s.to_lowercase()))
+}
+
+fn parse_int(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    s.parse().map(Slot::Int).map_err(|_| VmError::InvalidNumber { text: s, ty: "int" })
+}
+
+fn parse_float(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    s.parse().map(Slot::Float).map_err(|_| VmError::InvalidNumber { text: s, ty: "float" })
+}
+
+fn split(thread: &mut Thread) -> VmResult<Slot> {
+    let frame = thread.current_frame();
+    let s = str_arg(frame, 0)?;
+    let sep = str_arg(frame, 1)?;
+    let parts = if sep.is_empty() {
+        s.chars().map(|c| make_str(frame, c.to_string())).collect()
+    } else {
+        s.split(sep.as_str()).map(|part| make_str(frame, part.to_string())).collect()
+    };
+    Ok(ObjList::alloc(&frame.vm().mem, parts))
+}
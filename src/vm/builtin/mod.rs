@@ -5,19 +5,63 @@ use std::rc::Rc;
 use crate::frontend::ast::basic::TypeInfo;
 use crate::frontend::ast::func::{FunctionBasicInfo, FunctionMatcher};
 use crate::frontend::module_man::ProgramModuleDecl;
-use crate::vm::builtin::builtin_func::{FnAssert, FnPrint};
+use crate::vm::builtin::builtin_error::error_functions;
+use crate::vm::builtin::builtin_func::{to_string_interface, value_functions, FnAssert};
+use crate::vm::builtin::builtin_io::io_functions;
+use crate::vm::builtin::builtin_list::list_methods;
+use crate::vm::builtin::builtin_map::map_methods;
+use crate::vm::builtin::builtin_str::string_methods;
+use crate::vm::error::VmResult;
 use crate::vm::slot::Slot;
-use crate::vm::thread::Frame;
+use crate::vm::thread::Thread;
 
 pub mod builtin_class;
+pub mod builtin_error;
 pub mod builtin_func;
+pub mod builtin_io;
+pub mod builtin_list;
+pub mod builtin_map;
+pub mod builtin_str;
 
 pub trait AutoScriptRustVMFunctionBinding: Debug {
     fn get_name(&self) -> &'static str;
-    fn get_args(&self) -> &'static [(&'static str, TypeInfo)];
+    fn get_args(&self) -> &[(&'static str, TypeInfo)];
     fn get_ret_type(&self) -> TypeInfo;
 
-    fn execute(&self, frame: &mut Frame, ret: &mut Option<Slot>);
+    /// Arguments are in the current frame of `thread`, the binding may call closures through it
+    fn execute(&self, thread: &mut Thread, ret: &mut Option<Slot>) -> VmResult<()>;
+}
+
+/// Binding implemented by a plain function, which returns the value of call.
+/// Methods of built-in types are bound this way, with `self` as first argument
+#[derive(Debug, Clone)]
+pub struct NativeFn {
+    pub name: &'static str,
+    pub args: Vec<(&'static str, TypeInfo)>,
+    pub ret: TypeInfo,
+    pub func: fn(&mut Thread) -> VmResult<Slot>,
+}
+
+impl AutoScriptRustVMFunctionBinding for NativeFn {
+    fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn get_args(&self) -> &[(&'static str, TypeInfo)] {
+        &self.args
+    }
+
+    fn get_ret_type(&self) -> TypeInfo {
+        self.ret.clone()
+    }
+
+    fn execute(&self, thread: &mut Thread, ret: &mut Option<Slot>) -> VmResult<()> {
+        let value = (self.func)(thread)?;
+        if self.ret != TypeInfo::Unit {
+            *ret = Some(value);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -38,23 +82,28 @@ pub struct VMBuiltinRegister;
 impl VMBuiltinRegister{
     pub fn register_prelude(map: &mut HashMap<String, ProgramModuleDecl>) {
         let mut module = ProgramModuleDecl::default();
-        register_fn(&mut module.vm_function, Box::new(FnAssert));
-        register_fn(&mut module.vm_function, Box::new(FnPrint));
+        register_fn(&mut module.vm_function, FnAssert);
+        for method in value_functions().into_iter().chain(string_methods()).chain(list_methods()).chain(map_methods()).chain(error_functions()).chain(io_functions()) {
+            register_fn(&mut module.vm_function, method);
+        }
+        let interface = to_string_interface();
+        module.interfaces.insert(interface.name.clone(), interface);
         map.insert(String::from("prelude"), module);
     }
 }
 
-fn register_fn<T>(fn_map: &mut HashMap<String, Vec<ProgramVmFnElement>>, fn_code: Box<T>) where T:Sized + AutoScriptRustVMFunctionBinding + 'static {
-    let name = (&fn_code.get_name()).to_string();
+fn register_fn<T>(fn_map: &mut HashMap<String, Vec<ProgramVmFnElement>>, fn_code: T) where T:Sized + AutoScriptRustVMFunctionBinding + 'static {
+    let name = fn_code.get_name().to_string();
     let fn_prototype = ProgramVmFnElement {
         header: FunctionBasicInfo {
             name: fn_code.get_name().to_string(),
             module: Some(String::from("prelude")),
-            param: Some(fn_code.get_args().into_iter().map(|(fst, snd)| (fst.to_string(), snd.clone())).collect()),
-            ret: None,
+            param: Some(fn_code.get_args().iter().map(|(fst, snd)| (fst.to_string(), snd.clone())).collect()),
+            ret: Some(fn_code.get_ret_type()).filter(|ty| ty != &TypeInfo::Unit),
+            span: None,
         },
-        block: Rc::from(*fn_code),
+        block: Rc::new(fn_code),
     };
-    fn_map.insert(name, vec![fn_prototype]);
+    fn_map.entry(name).or_default().push(fn_prototype);
 }
 
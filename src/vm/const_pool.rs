@@ -6,7 +6,7 @@ pub struct ConstantPool(Vec<Slot>);
 impl ConstantPool {
 
     pub fn get(&self, idx: usize) -> Option<Slot>{
-        self.0.get(idx).map(Clone::clone)
+        self.0.get(idx).cloned()
     }
 }
 
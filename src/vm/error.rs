@@ -0,0 +1,136 @@
+use std::fmt::{Display, Formatter};
+
+use crate::frontend::diagnostic::SourceMap;
+use crate::frontend::span::Span;
+use crate::vm::slot::Slot;
+
+/// Error raised by script while running, it aborts the thread unless a `try` block catches it
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    DivideByZero,
+    IntegerOverflow,
+    /// `step` of a range loop is zero, so the loop would never reach its end
+    ZeroStep,
+    /// Slot holds a different kind of value than the instruction requires
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// No prototype has the signature
+    FunctionNotFound(String),
+    AssertionFailed,
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    InvalidRange {
+        start: i64,
+        end: i64,
+        len: usize,
+    },
+    /// Element is taken from an empty list
+    EmptyList,
+    /// Map has no entry of the key, which is shown as text
+    KeyNotFound(String),
+    /// Text can't be parsed as number of the type
+    InvalidNumber {
+        text: String,
+        ty: &'static str,
+    },
+    /// Operation of the system failed, like reading a file
+    Io(String),
+    /// `to_string` of an instance of the class shows the instance again
+    RecursiveToString(String),
+    /// Values are nested too deeply to be shown, like by a `to_string` making new instances to show
+    ShowTooDeep,
+    /// Module level variable is read by a function called before its initializer ran, like `module.NAME`
+    UninitializedGlobal(String),
+    /// Calls are nested deeper than the thread allows, like by a recursion without end
+    StackOverflow,
+    /// `Error` value raised by `throw`
+    Thrown(Slot),
+}
+
+impl VmError {
+    /// Errors of the script can be caught, while the others mean the vm or an `assert` is broken
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, VmError::TypeMismatch { .. } | VmError::FunctionNotFound(_) | VmError::AssertionFailed)
+    }
+}
+
+pub type VmResult<T> = Result<T, VmError>;
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::DivideByZero => write!(f, "attempt to divide by zero"),
+            VmError::IntegerOverflow => write!(f, "integer overflow"),
+            VmError::ZeroStep => write!(f, "step of range must not be zero"),
+            VmError::TypeMismatch { expected, found } => {
+                write!(f, "expected `{}` value, found `{}`", expected, found)
+            }
+            VmError::FunctionNotFound(signature) => write!(f, "can't find function `{}`", signature),
+            VmError::AssertionFailed => write!(f, "assertion failed"),
+            VmError::IndexOutOfBounds { index, len } => {
+                write!(f, "index out of bounds: the len is {} but the index is {}", len, index)
+            }
+            VmError::InvalidRange { start, end, len } => {
+                write!(f, "range `{}..{}` is out of bounds for length {}", start, end, len)
+            }
+            VmError::EmptyList => write!(f, "list is empty"),
+            VmError::KeyNotFound(key) => write!(f, "key `{}` is not found in map", key),
+            VmError::InvalidNumber { text, ty } => write!(f, "cannot parse `{}` as `{}`", text, ty),
+            VmError::Io(reason) => write!(f, "{}", reason),
+            VmError::RecursiveToString(class) => write!(f, "`to_string` of `{}` shows the instance itself", class),
+            VmError::ShowTooDeep => write!(f, "value is nested too deeply to be converted to string"),
+            VmError::UninitializedGlobal(name) => write!(f, "`{}` is used before it is initialized", name),
+            VmError::StackOverflow => write!(f, "stack overflow, calls are nested too deeply"),
+            VmError::Thrown(error) => write!(f, "uncaught error: {}", error),
+        }
+    }
+}
+
+/// One frame of the script call stack when error happens
+#[derive(Debug, Clone)]
+pub struct BacktraceFrame {
+    pub declaration: String,
+    /// Source of the instruction being executed, `None` for vm builtin functions
+    pub span: Option<Span>,
+}
+
+/// A `VmError` with the script call stack it unwound, innermost frame first
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub error: VmError,
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+impl RuntimeError {
+    /// Render error and backtrace, with source lines taken from `sources`.
+    /// Only the innermost and outermost frames of a long backtrace are shown, like one left by a stack overflow
+    pub fn render(&self, sources: &SourceMap) -> String {
+        const INNERMOST: usize = 20;
+        const OUTERMOST: usize = 10;
+        let mut out = format!("runtime error: {}\nstack backtrace:\n", self.error);
+        let omitted = self.backtrace.len().saturating_sub(INNERMOST + OUTERMOST);
+        for (idx, frame) in self.backtrace.iter().enumerate() {
+            if omitted > 0 && idx >= INNERMOST && idx < INNERMOST + omitted {
+                if idx == INNERMOST {
+                    out += &format!("      ... {} frames omitted\n", omitted);
+                }
+                continue;
+            }
+            out += &format!("{:>4}: {}\n", idx, frame.declaration);
+            match frame.span {
+                Some(ref span) => {
+                    out += &format!("            at {}\n", span);
+                    if let Some(line) = sources.line(&span.file, span.line) {
+                        out += &format!("            {} | {}\n", span.line, line.trim());
+                    }
+                }
+                None => out += "            at <builtin>\n",
+            }
+        }
+        out
+    }
+}
@@ -2,8 +2,11 @@ use std::fmt::{Display, Formatter};
 use std::ops;
 use std::rc::Rc;
 
+use crate::frontend::span::Span;
+use crate::vm::builtin::builtin_class::{ClassLayout, MapKey, ObjCell, ObjClosure, ObjInstance, ObjList, ObjMap, ObjStr, ObjTuple, ObjVariant, VariantLayout};
+use crate::vm::error::{VmError, VmResult};
 use crate::vm::slot::Slot;
-use crate::vm::thread::{Frame, Thread};
+use crate::vm::thread::{Frame, Handler, Thread};
 
 #[derive(Clone, Debug)]
 pub enum Instr {
@@ -14,6 +17,8 @@ pub enum Instr {
     IDiv,
     INeg,
     IRem,
+    /// Raise error if the int on top is zero, it is left on stack
+    CheckStep,
 
     I2F,
     F2I,
@@ -43,25 +48,89 @@ pub enum Instr {
     Jump(i32),
 
     NPush,
+    /// Push `none`
+    NonePush,
 
     Call(String),
+    /// Call method of interface like `Printable.show` with the number of arguments, `self` first.
+    /// The function is looked up in vtable of the class of `self`
+    CallVirtual(String, usize),
+    /// Pop the number of captured values, push closure of the lambda with the signature capturing them
+    MakeClosure(String, usize),
+    /// Pop the number of arguments and the closure below them, call the closure
+    CallClosure(usize),
+    /// Pop value, push a cell holding it
+    MakeCell,
+    /// Pop cell, push its value
+    CellGet,
+    /// Pop cell and then value, set value of the cell
+    CellSet,
 
     Dup,
     Store(usize),
     Load(usize),
+    /// Push module level variable of the module by index
+    GLoad(String, usize),
+    /// Pop value into module level variable of the module by index
+    GStore(String, usize),
     Pop,
     ReturnValue,
     // pop a value from top frame, and then pop a frame, push the value to next
     Return,
+
+    /// Catch errors raised until the matching `TryEnd`, by jumping to the handler with the `Error` pushed.
+    /// Handler is at the offset like `Jump`, values pushed after this instruction are dropped
+    TryBegin(i32),
+    /// Stop catching errors by the innermost handler of frame
+    TryEnd,
+    /// Pop `Error` and raise it
+    Throw,
     // pop a stack frame, do thing
     Nop, // do nothing
 
     CPush(usize), // push from constant pool
+
+    /// Pop two strings, push their concatenation
+    SConcat,
+    /// Pop two strings, push `-1`, `0` or `1` as the first is less than, equal to or greater than the second
+    SCmp,
+
+    /// Allocate an instance with all fields `unit`
+    New(Rc<ClassLayout>),
+    /// Pop instance, push its field
+    GetField(usize),
+    /// Pop value and instance, set field of instance
+    SetField(usize),
+
+    /// Pop the number of values, push a list of them in order
+    MakeList(usize),
+    /// Pop index and list, push the element
+    ListGet,
+    /// Pop value, index and list, replace the element
+    ListSet,
+    /// Pop list, push its length
+    ListLen,
+    /// Pop key and map, push the value
+    MapGet,
+    /// Pop value, key and map, insert the value or replace the one at key
+    MapSet,
+    /// Pop the number of key and value pairs, push a map of them
+    MakeMap(usize),
+    /// Pop the number of values, push a tuple of them in order
+    MakeTuple(usize),
+    /// Pop tuple, push its element
+    TupleGet(usize),
+    /// Pop payload of the variant, push enum value of it
+    MakeVariant(Rc<VariantLayout>),
+    /// Pop enum value, push its tag
+    VariantTag,
+    /// Pop enum value, push field of its payload
+    VariantGet(usize),
 }
 
 impl Instr {
     /// Execute a instr
-    pub fn execute(&self, frame: &mut Frame) -> bool {
+    pub fn execute(&self, frame: &mut Frame) -> VmResult<bool> {
         unsafe {
             if frame.thread.as_ref().unwrap().vm.as_ref().unwrap().args.instr {
                 let signature = &frame.function.signature;
@@ -74,81 +143,92 @@ impl Instr {
 
         match self {
             Instr::IPush(value) => {
-                let slot = Slot::Int(*value as i64);
+                let slot = Slot::Int(*value);
                 frame.operand_stack.push(slot);
             }
             Instr::IAdd => {
-                let v2 = frame.operand_stack.pop().unwrap().get_int();
-                let v1 = frame.operand_stack.pop().unwrap().get_int();
-                frame.operand_stack.push(Slot::Int(v2 + v1));
+                let v2 = frame.operand_stack.pop().unwrap().get_int()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_int()?;
+                frame.operand_stack.push(Slot::Int(v1.checked_add(v2).ok_or(VmError::IntegerOverflow)?));
             }
             Instr::ISub => {
-                let v2 = frame.operand_stack.pop().unwrap().get_int();
-                let v1 = frame.operand_stack.pop().unwrap().get_int();
-                frame.operand_stack.push(Slot::Int(v1 - v2));
+                let v2 = frame.operand_stack.pop().unwrap().get_int()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_int()?;
+                frame.operand_stack.push(Slot::Int(v1.checked_sub(v2).ok_or(VmError::IntegerOverflow)?));
             }
             Instr::IMul => {
-                let v2 = frame.operand_stack.pop().unwrap().get_int();
-                let v1 = frame.operand_stack.pop().unwrap().get_int();
-                frame.operand_stack.push(Slot::Int(v1 * v2));
+                let v2 = frame.operand_stack.pop().unwrap().get_int()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_int()?;
+                frame.operand_stack.push(Slot::Int(v1.checked_mul(v2).ok_or(VmError::IntegerOverflow)?));
             }
             Instr::IDiv => {
-                let v2 = frame.operand_stack.pop().unwrap().get_int();
-                let v1 = frame.operand_stack.pop().unwrap().get_int();
-                frame.operand_stack.push(Slot::Int(v1 / v2));
+                let v2 = frame.operand_stack.pop().unwrap().get_int()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_int()?;
+                if v2 == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                frame.operand_stack.push(Slot::Int(v1.checked_div(v2).ok_or(VmError::IntegerOverflow)?));
             }
             Instr::INeg => {
-                let v = frame.operand_stack.pop().unwrap().get_int();
-                frame.operand_stack.push(Slot::Int(-v));
+                let v = frame.operand_stack.pop().unwrap().get_int()?;
+                frame.operand_stack.push(Slot::Int(v.checked_neg().ok_or(VmError::IntegerOverflow)?));
             }
             Instr::IRem => {
-                let v2 = frame.operand_stack.pop().unwrap().get_int();
-                let v1 = frame.operand_stack.pop().unwrap().get_int();
-                frame.operand_stack.push(Slot::Int(v1 % v2));
+                let v2 = frame.operand_stack.pop().unwrap().get_int()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_int()?;
+                if v2 == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                frame.operand_stack.push(Slot::Int(v1.checked_rem(v2).ok_or(VmError::IntegerOverflow)?));
+            }
+            Instr::CheckStep => {
+                if frame.operand_stack.last().unwrap().get_int()? == 0 {
+                    return Err(VmError::ZeroStep);
+                }
             }
 
             Instr::I2F => {
-                let v = frame.operand_stack.pop().unwrap().get_int();
+                let v = frame.operand_stack.pop().unwrap().get_int()?;
                 frame.operand_stack.push(Slot::Float(v as f64));
             }
             Instr::F2I => {
-                let v = frame.operand_stack.pop().unwrap().get_float();
+                let v = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Int(v as i64));
             }
             Instr::FPush(value) => {
                 frame.operand_stack.push(Slot::Float(*value));
             }
             Instr::FAdd => {
-                let v2 = frame.operand_stack.pop().unwrap().get_float();
-                let v1 = frame.operand_stack.pop().unwrap().get_float();
+                let v2 = frame.operand_stack.pop().unwrap().get_float()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Float(v1 + v2));
             }
             Instr::FSub => {
-                let v2 = frame.operand_stack.pop().unwrap().get_float();
-                let v1 = frame.operand_stack.pop().unwrap().get_float();
+                let v2 = frame.operand_stack.pop().unwrap().get_float()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Float(v1 - v2));
             }
             Instr::FMul => {
-                let v2 = frame.operand_stack.pop().unwrap().get_float();
-                let v1 = frame.operand_stack.pop().unwrap().get_float();
+                let v2 = frame.operand_stack.pop().unwrap().get_float()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Float(v1 * v2));
             }
             Instr::FDiv => {
-                let v2 = frame.operand_stack.pop().unwrap().get_float();
-                let v1 = frame.operand_stack.pop().unwrap().get_float();
+                let v2 = frame.operand_stack.pop().unwrap().get_float()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Float(v1 / v2));
             }
             Instr::FNeg => {
-                let v = frame.operand_stack.pop().unwrap().get_float();
+                let v = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Float(-v));
             }
             Instr::FRem => {
-                let v2 = frame.operand_stack.pop().unwrap().get_float();
-                let v1 = frame.operand_stack.pop().unwrap().get_float();
+                let v2 = frame.operand_stack.pop().unwrap().get_float()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_float()?;
                 frame.operand_stack.push(Slot::Float(v1 % v2));
             }
             Instr::BNeg => {
-                let v = frame.operand_stack.pop().unwrap().get_bool();
+                let v = frame.operand_stack.pop().unwrap().get_bool()?;
                 frame.operand_stack.push(Slot::Bool(!v));
             }
             Instr::Store(idx) => {
@@ -159,22 +239,51 @@ impl Instr {
                 let slot = frame.local_vars.get(*idx).clone();
                 frame.operand_stack.push(slot);
             }
-            Instr::Call(fn_signature) => {
+            Instr::GLoad(module, idx) => {
+                let slot = frame.vm().prototypes.get_global(module, *idx)?;
+                frame.operand_stack.push(slot);
+            }
+            Instr::GStore(module, idx) => {
+                let slot = frame.operand_stack.pop().unwrap();
+                frame.vm().prototypes.set_global(module, *idx, slot);
+            }
+            Instr::Call(fn_signature) => Self::call(frame, fn_signature)?,
+            Instr::CallVirtual(method, argc) => {
+                let receiver = &frame.operand_stack[frame.operand_stack.len() - argc];
+                let fn_signature = ObjInstance::with(receiver, &frame.vm().mem, |instance| instance.layout.vtable.get(method).cloned())?
+                    .ok_or_else(|| VmError::FunctionNotFound(method.clone()))?;
+                Self::call(frame, &fn_signature)?;
+            }
+            Instr::MakeClosure(fn_signature, len) => {
+                let function = frame.vm().prototypes.get_function_prototype(fn_signature)
+                    .ok_or_else(|| VmError::FunctionNotFound(fn_signature.clone()))?;
+                let captures = frame.operand_stack.split_off(frame.operand_stack.len() - len);
+                let closure = ObjClosure { function, captures };
+                let closure = Slot::Ref(frame.vm().mem.mutator().make(closure));
+                frame.operand_stack.push(closure);
+            }
+            Instr::CallClosure(argc) => {
+                let args = frame.operand_stack.split_off(frame.operand_stack.len() - argc);
+                let closure = frame.operand_stack.pop().unwrap();
                 let thread = unsafe {
                     frame.thread.as_mut()
                 }.unwrap();
-
-                let fn_prototype = unsafe {
-                    thread.vm.as_ref().unwrap().prototypes.get_function_prototype(fn_signature)
-                }.unwrap();
-
-                let new_frame: &mut Frame = thread.push_new_frame(fn_prototype.local_var_size, Rc::clone(&fn_prototype));
-
-                for i in 0..fn_prototype.arg_num {
-                    let idx = fn_prototype.arg_num - i - 1;
-                    let slot = frame.operand_stack.pop().unwrap();
-                    new_frame.local_vars.set(idx, slot)
-                }
+                thread.push_closure_frame(&closure, args)?;
+            }
+            Instr::MakeCell => {
+                let value = frame.operand_stack.pop().unwrap();
+                let cell = ObjCell::alloc(&frame.vm().mem, value);
+                frame.operand_stack.push(cell);
+            }
+            Instr::CellGet => {
+                let cell = frame.operand_stack.pop().unwrap();
+                let value = ObjCell::with_mut(&cell, &frame.vm().mem, |value| value.clone())?;
+                frame.operand_stack.push(value);
+            }
+            Instr::CellSet => {
+                let cell = frame.operand_stack.pop().unwrap();
+                let value = frame.operand_stack.pop().unwrap();
+                ObjCell::with_mut(&cell, &frame.vm().mem, |slot| *slot = value)?;
             }
             Instr::ReturnValue => {
                 let thread = unsafe {
@@ -193,17 +302,28 @@ impl Instr {
                     let _ = thread.pop_frame().unwrap();
                 }
             }
+            Instr::TryBegin(offset) => {
+                let handler = Handler { pc: frame.next_pc + offset, stack_len: frame.operand_stack.len() };
+                frame.handlers.push(handler);
+            }
+            Instr::TryEnd => {
+                frame.handlers.pop().unwrap();
+            }
+            Instr::Throw => {
+                let error = frame.operand_stack.pop().unwrap();
+                return Err(VmError::Thrown(error));
+            }
             Instr::BPush(b) => {
                 frame.operand_stack.push(Slot::Bool(*b))
             }
             Instr::BAnd => {
-                let v2 = frame.operand_stack.pop().unwrap().get_bool();
-                let v1 = frame.operand_stack.pop().unwrap().get_bool();
+                let v2 = frame.operand_stack.pop().unwrap().get_bool()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_bool()?;
                 frame.operand_stack.push(Slot::Bool(v2 && v1));
             }
             Instr::BOr => {
-                let v2 = frame.operand_stack.pop().unwrap().get_bool();
-                let v1 = frame.operand_stack.pop().unwrap().get_bool();
+                let v2 = frame.operand_stack.pop().unwrap().get_bool()?;
+                let v1 = frame.operand_stack.pop().unwrap().get_bool()?;
                 frame.operand_stack.push(Slot::Bool(v2 || v1));
             }
             Instr::CmpGt => {
@@ -246,13 +366,13 @@ impl Instr {
                 frame.next_pc += offset
             }
             Instr::JumpIf(offset) => {
-                let cond = frame.operand_stack.pop().unwrap().get_bool();
+                let cond = frame.operand_stack.pop().unwrap().get_bool()?;
                 if cond {
                     frame.next_pc += offset
                 }
             }
             Instr::JumpIfN(offset) => {
-                let cond = frame.operand_stack.pop().unwrap().get_bool();
+                let cond = frame.operand_stack.pop().unwrap().get_bool()?;
                 if !cond {
                     frame.next_pc += offset
                 }
@@ -263,6 +383,9 @@ impl Instr {
             Instr::NPush => {
                 frame.operand_stack.push(Slot::Unit);
             }
+            Instr::NonePush => {
+                frame.operand_stack.push(Slot::None);
+            }
             Instr::Dup => {
                 let slot = frame.operand_stack.last().unwrap().clone();
                 frame.operand_stack.push(slot);
@@ -275,6 +398,136 @@ impl Instr {
                 let slot = vm.prototypes.get_constant(*idx).unwrap();
                 frame.operand_stack.push(slot)
             }
+            Instr::SConcat => {
+                let v2 = frame.operand_stack.pop().unwrap();
+                let v1 = frame.operand_stack.pop().unwrap();
+                let mem = &frame.vm().mem;
+                let result = ObjStr::with(&v1, mem, |s1| ObjStr::with(&v2, mem, |s2| format!("{}{}", s1, s2)))??;
+                let slot = ObjStr::alloc(mem, result);
+                frame.operand_stack.push(slot);
+            }
+            Instr::SCmp => {
+                let v2 = frame.operand_stack.pop().unwrap();
+                let v1 = frame.operand_stack.pop().unwrap();
+                let mem = &frame.vm().mem;
+                let ordering = ObjStr::with(&v1, mem, |s1| ObjStr::with(&v2, mem, |s2| s1.cmp(s2)))??;
+                frame.operand_stack.push(Slot::Int(ordering as i64));
+            }
+            Instr::New(layout) => {
+                let vm = unsafe {
+                    frame.thread.as_ref().unwrap().vm.as_ref().unwrap()
+                };
+                let obj = vm.mem.mutator().make(ObjInstance::new(Rc::clone(layout)));
+                frame.operand_stack.push(Slot::Ref(obj));
+            }
+            Instr::GetField(idx) => {
+                let obj = frame.operand_stack.pop().unwrap().get_ref()?;
+                let vm = unsafe {
+                    frame.thread.as_ref().unwrap().vm.as_ref().unwrap()
+                };
+                let mutator = vm.mem.mutator();
+                let reader = unsafe { mutator.read(obj) };
+                let instance = (*reader).any_ref().downcast_ref::<ObjInstance>()
+                    .ok_or(VmError::TypeMismatch { expected: "object", found: "ref" })?;
+                frame.operand_stack.push(instance.fields[*idx].clone());
+            }
+            Instr::SetField(idx) => {
+                let value = frame.operand_stack.pop().unwrap();
+                let obj = frame.operand_stack.pop().unwrap().get_ref()?;
+                let vm = unsafe {
+                    frame.thread.as_ref().unwrap().vm.as_ref().unwrap()
+                };
+                let mutator = vm.mem.mutator();
+                let mut writer = unsafe { mutator.write(obj) };
+                let instance = (*writer).any_mut().downcast_mut::<ObjInstance>()
+                    .ok_or(VmError::TypeMismatch { expected: "object", found: "ref" })?;
+                instance.fields[*idx] = value;
+            }
+            Instr::MakeList(len) => {
+                let items = frame.operand_stack.split_off(frame.operand_stack.len() - len);
+                let list = ObjList::alloc(&frame.vm().mem, items);
+                frame.operand_stack.push(list);
+            }
+            Instr::ListGet => {
+                let index = frame.operand_stack.pop().unwrap().get_int()?;
+                let list = frame.operand_stack.pop().unwrap();
+                let item = ObjList::with(&list, &frame.vm().mem, |items| {
+                    usize::try_from(index).ok()
+                        .and_then(|idx| items.get(idx).cloned())
+                        .ok_or(VmError::IndexOutOfBounds { index, len: items.len() })
+                })??;
+                frame.operand_stack.push(item);
+            }
+            Instr::ListSet => {
+                let value = frame.operand_stack.pop().unwrap();
+                let index = frame.operand_stack.pop().unwrap().get_int()?;
+                let list = frame.operand_stack.pop().unwrap();
+                ObjList::with_mut(&list, &frame.vm().mem, |items| {
+                    let len = items.len();
+                    usize::try_from(index).ok()
+                        .and_then(|idx| items.get_mut(idx))
+                        .map(|item| *item = value)
+                        .ok_or(VmError::IndexOutOfBounds { index, len })
+                })??;
+            }
+            Instr::ListLen => {
+                let list = frame.operand_stack.pop().unwrap();
+                let len = ObjList::with(&list, &frame.vm().mem, Vec::len)?;
+                frame.operand_stack.push(Slot::Int(len as i64));
+            }
+            Instr::MapGet => {
+                let key = frame.operand_stack.pop().unwrap();
+                let map = frame.operand_stack.pop().unwrap();
+                let mem = &frame.vm().mem;
+                let map_key = MapKey::of(&key, mem)?;
+                let value = ObjMap::with(&map, mem, |map| map.get(&map_key).cloned())?
+                    .ok_or_else(|| VmError::KeyNotFound(key.to_string()))?;
+                frame.operand_stack.push(value);
+            }
+            Instr::MapSet => {
+                let value = frame.operand_stack.pop().unwrap();
+                let key = frame.operand_stack.pop().unwrap();
+                let map = frame.operand_stack.pop().unwrap();
+                let mem = &frame.vm().mem;
+                let map_key = MapKey::of(&key, mem)?;
+                ObjMap::with_mut(&map, mem, |map| map.insert(map_key, key, value))?;
+            }
+            Instr::MakeMap(len) => {
+                let slots = frame.operand_stack.split_off(frame.operand_stack.len() - 2 * len);
+                let mem = &frame.vm().mem;
+                let mut map = ObjMap::default();
+                for pair in slots.chunks(2) {
+                    map.insert(MapKey::of(&pair[0], mem)?, pair[0].clone(), pair[1].clone());
+                }
+                let map = ObjMap::alloc(mem, map);
+                frame.operand_stack.push(map);
+            }
+            Instr::MakeTuple(len) => {
+                let items = frame.operand_stack.split_off(frame.operand_stack.len() - len);
+                let tuple = ObjTuple::alloc(&frame.vm().mem, items);
+                frame.operand_stack.push(tuple);
+            }
+            Instr::TupleGet(idx) => {
+                let tuple = frame.operand_stack.pop().unwrap();
+                let item = ObjTuple::with(&tuple, &frame.vm().mem, |items| items[*idx].clone())?;
+                frame.operand_stack.push(item);
+            }
+            Instr::MakeVariant(layout) => {
+                let fields = frame.operand_stack.split_off(frame.operand_stack.len() - layout.arity);
+                let variant = ObjVariant { layout: Rc::clone(layout), fields };
+                let variant = Slot::Ref(frame.vm().mem.mutator().make(variant));
+                frame.operand_stack.push(variant);
+            }
+            Instr::VariantTag => {
+                let variant = frame.operand_stack.pop().unwrap();
+                let tag = ObjVariant::with(&variant, &frame.vm().mem, |variant| variant.layout.tag)?;
+                frame.operand_stack.push(Slot::Int(tag as i64));
+            }
+            Instr::VariantGet(idx) => {
+                let variant = frame.operand_stack.pop().unwrap();
+                let field = ObjVariant::with(&variant, &frame.vm().mem, |variant| variant.fields[*idx].clone())?;
+                frame.operand_stack.push(field);
+            }
         };
 
         // unsafe{
@@ -284,37 +537,131 @@ impl Instr {
         //         eprintln!("- {:?}", i as *const Frame);
         //     }
         // }
-        match self {
-            Instr::Return | Instr::ReturnValue => false,
-            _ => true
+        Ok(!matches!(self, Instr::Return | Instr::ReturnValue))
+    }
+
+    /// Push frame of function with the signature, its arguments are popped from `frame`
+    fn call(frame: &mut Frame, fn_signature: &str) -> VmResult<()> {
+        let thread = unsafe {
+            frame.thread.as_mut()
+        }.unwrap();
+
+        let fn_prototype = unsafe {
+            thread.vm.as_ref().unwrap().prototypes.get_function_prototype(fn_signature)
+        }.ok_or_else(|| VmError::FunctionNotFound(fn_signature.to_string()))?;
+
+        // arguments are taken before pushing the new frame, `frame` is reached through
+        // the same thread pointer and must not be used while `new_frame` is borrowed
+        let args = frame.operand_stack.split_off(frame.operand_stack.len() - fn_prototype.arg_num);
+
+        let new_frame: &mut Frame = thread.push_new_frame(fn_prototype.local_var_size, Rc::clone(&fn_prototype))?;
+
+        for (idx, slot) in args.into_iter().enumerate() {
+            new_frame.local_vars.set(idx, slot)
         }
+        Ok(())
     }
 }
 
+/// `break` or `continue` whose target is unknown until the enclosing loop is generated.
+/// Depth counts loops between the jump and its target, `0` is the innermost loop
+#[derive(Debug, Clone, Copy)]
+pub enum LoopJump {
+    Break(usize),
+    Continue(usize),
+}
+
+/// Instructions with the source span each one is generated from
 #[derive(Debug, Clone)]
-pub struct Instructions(Vec<Instr>);
+pub struct Instructions {
+    instr: Vec<Instr>,
+    spans: Vec<Option<Span>>,
+    /// Pcs of jumps waiting for their loop, patched by `resolve_loop_jumps`
+    loop_jumps: Vec<(usize, LoopJump)>,
+}
 
 impl Instructions {
     pub fn new() -> Self {
-        Instructions(Vec::new())
+        Instructions {
+            instr: Vec::new(),
+            spans: Vec::new(),
+            loop_jumps: Vec::new(),
+        }
+    }
+
+    /// A jump to be patched once the loop it leaves is generated
+    pub fn loop_jump(jump: LoopJump) -> Self {
+        Instructions {
+            instr: vec![Instr::Jump(0)],
+            spans: vec![None],
+            loop_jumps: vec![(0, jump)],
+        }
+    }
+
+    /// Patch jumps of the innermost loop, which is all of `self`.
+    /// `break` goes to the end, `continue` goes to `continue_pc`.
+    /// Jumps to outer loops are kept for the next loop
+    pub fn resolve_loop_jumps(mut self, continue_pc: usize) -> Self {
+        let end_pc = self.instr.len();
+        let mut outer = Vec::new();
+        for (pc, jump) in std::mem::take(&mut self.loop_jumps) {
+            let target = match jump {
+                LoopJump::Break(0) => end_pc,
+                LoopJump::Continue(0) => continue_pc,
+                LoopJump::Break(depth) => {
+                    outer.push((pc, LoopJump::Break(depth - 1)));
+                    continue;
+                }
+                LoopJump::Continue(depth) => {
+                    outer.push((pc, LoopJump::Continue(depth - 1)));
+                    continue;
+                }
+            };
+            self.instr[pc] = Instr::Jump(target as i32 - pc as i32 - 1);
+        }
+        self.loop_jumps = outer;
+        self
     }
     pub fn get_instr(&self, index: i32) -> Option<Instr> {
-        self.0.get(index as usize).cloned()
+        self.instr.get(index as usize).cloned()
     }
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.instr.len()
+    }
+
+    /// Attribute instructions without a source span to `span`.
+    /// Codegen calls it from inner node to outer node, so every instruction keeps the innermost span
+    pub fn with_span(mut self, span: &Span) -> Self {
+        for item in self.spans.iter_mut().filter(|item| item.is_none()) {
+            *item = Some(span.clone());
+        }
+        self
+    }
+
+    /// Build pc-to-line table from spans of instructions
+    pub fn line_table(&self) -> LineTable {
+        let mut entries: Vec<(i32, Span)> = Vec::new();
+        for (pc, span) in self.spans.iter().enumerate() {
+            if let Some(span) = span {
+                if entries.last().map(|(_, last)| last != span).unwrap_or(true) {
+                    entries.push((pc as i32, span.clone()));
+                }
+            }
+        }
+        LineTable(entries)
     }
 }
 
 impl From<Vec<Instr>> for Instructions {
     fn from(s: Vec<Instr>) -> Self {
-        Self(s)
+        let spans = vec![None; s.len()];
+        Self { instr: s, spans, loop_jumps: Vec::new() }
     }
 }
 
-impl Into<Vec<Instr>> for Instructions {
-    fn into(self) -> Vec<Instr> {
-        self.0
+impl From<Instructions> for Vec<Instr> {
+    fn from(instr: Instructions) -> Self {
+        instr.instr
     }
 }
 
@@ -322,13 +669,28 @@ impl ops::Add for Instructions {
     type Output = Instructions;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let mut instrs = self.0;
-        let mut other = rhs.0;
-        instrs.append(&mut other);
-        Instructions(instrs)
+        let offset = self.instr.len();
+        let mut instr = self.instr;
+        let mut spans = self.spans;
+        let mut loop_jumps = self.loop_jumps;
+        instr.extend(rhs.instr);
+        spans.extend(rhs.spans);
+        loop_jumps.extend(rhs.loop_jumps.into_iter().map(|(pc, jump)| (pc + offset, jump)));
+        Instructions { instr, spans, loop_jumps }
     }
 }
 
+/// Maps pc of a function to the source span its instruction comes from.
+/// Each entry covers pcs from its start until the next entry
+#[derive(Debug, Clone, Default)]
+pub struct LineTable(Vec<(i32, Span)>);
+
+impl LineTable {
+    pub fn lookup(&self, pc: i32) -> Option<&Span> {
+        let idx = self.0.partition_point(|(start, _)| *start <= pc);
+        idx.checked_sub(1).map(|idx| &self.0[idx].1)
+    }
+}
 
 impl Display for Instr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -339,8 +701,32 @@ impl Display for Instr {
             Instr::IMul => write!(f, "imul"),
             Instr::IDiv => write!(f, "idiv"),
             Instr::INeg => write!(f, "ineg"),
+            Instr::CheckStep => write!(f, "check_step"),
             Instr::IRem => write!(f, "irem"),
             Instr::Call(refer) => write!(f, "call {}", refer),
+            Instr::CallVirtual(method, argc) => write!(f, "call_virtual {} {}", method, argc),
+            Instr::MakeClosure(refer, len) => write!(f, "make_closure {} {}", refer, len),
+            Instr::CallClosure(argc) => write!(f, "call_closure {}", argc),
+            Instr::MakeCell => write!(f, "make_cell"),
+            Instr::CellGet => write!(f, "cell_get"),
+            Instr::CellSet => write!(f, "cell_set"),
+            Instr::SConcat => write!(f, "sconcat"),
+            Instr::SCmp => write!(f, "scmp"),
+            Instr::New(layout) => write!(f, "new {}", layout.name),
+            Instr::GetField(idx) => write!(f, "get_field {}", idx),
+            Instr::SetField(idx) => write!(f, "set_field {}", idx),
+            Instr::MakeList(len) => write!(f, "make_list {}", len),
+            Instr::ListGet => write!(f, "list_get"),
+            Instr::ListSet => write!(f, "list_set"),
+            Instr::ListLen => write!(f, "list_len"),
+            Instr::MapGet => write!(f, "map_get"),
+            Instr::MapSet => write!(f, "map_set"),
+            Instr::MakeMap(len) => write!(f, "make_map {}", len),
+            Instr::MakeTuple(len) => write!(f, "make_tuple {}", len),
+            Instr::TupleGet(idx) => write!(f, "tuple_get {}", idx),
+            Instr::MakeVariant(layout) => write!(f, "make_variant {}", layout.name),
+            Instr::VariantTag => write!(f, "variant_tag"),
+            Instr::VariantGet(idx) => write!(f, "variant_get {}", idx),
             Instr::I2F => write!(f, "i2f"),
             Instr::F2I => write!(f, "f2i"),
             Instr::FPush(value) => write!(f, "fpush {}", value),
@@ -362,14 +748,20 @@ impl Display for Instr {
             Instr::CmpLe => write!(f, "cmp_le"),
             Instr::ReturnValue => write!(f, "retv"),
             Instr::Return => write!(f, "ret"),
+            Instr::TryBegin(offset) => write!(f, "try_begin {}", offset),
+            Instr::TryEnd => write!(f, "try_end"),
+            Instr::Throw => write!(f, "throw"),
             Instr::Jump(offset) => write!(f, "jump {}", offset),
             Instr::JumpIf(offset) => write!(f, "jump_if {}", offset),
             Instr::JumpIfN(offset) => write!(f, "jump_ifn {}", offset),
             Instr::Nop => write!(f, "nop"),
             Instr::Store(idx) => write!(f, "store {}", idx),
             Instr::Load(idx) => write!(f, "load {}", idx),
+            Instr::GLoad(module, idx) => write!(f, "gload {} {}", module, idx),
+            Instr::GStore(module, idx) => write!(f, "gstore {} {}", module, idx),
             Instr::Pop => write!(f, "pop"),
             Instr::NPush => write!(f, "npush"),
+            Instr::NonePush => write!(f, "none_push"),
             Instr::Dup => write!(f, "dup"),
             Instr::CPush(i) => write!(f, "const_push {}", i)
         }
@@ -378,8 +770,8 @@ impl Display for Instr {
 
 impl Display for Instructions {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        for instr in &self.0 {
-            write!(f, "{}\n", instr)?
+        for instr in &self.instr {
+            writeln!(f, "{}", instr)?
         }
         Ok(())
     }
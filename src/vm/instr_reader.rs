@@ -4,7 +4,6 @@ use crate::vm::instr::{Instr, Instructions};
 
 pub trait AutoScriptInstrReader{
     fn read_instr(&mut self) -> Instr;
-    fn set_pc(&mut self, pc:i32);
     fn pc(&self) -> i32;
 }
 
@@ -19,9 +18,6 @@ impl AutoScriptInstrReader for InstrReader{
         self.pc+=1;
         instr.unwrap()
     }
-    fn set_pc(&mut self, pc: i32) {
-        self.pc = pc;
-    }
 
     fn pc(&self) -> i32 {
         self.pc
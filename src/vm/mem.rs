@@ -1,6 +1,6 @@
 use std::alloc::{GlobalAlloc, System};
 use std::any::Any;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::ptr::{NonNull, null_mut};
@@ -23,7 +23,12 @@ impl<T: Any> AsAny for T {
     }
 }
 
-pub unsafe trait ObjCore: Debug + Any + ToString {
+/// Payload of a heap object managed by `Mem`
+///
+/// # Safety
+///
+/// `trace` must report every `Obj` the value refers to, or the collector frees objects which are still reachable
+pub unsafe trait ObjCore: Debug + Any + AsAny + Display {
     #[allow(unused_variables)]
     fn trace(&self, mark: &mut dyn FnMut(*mut Obj)) {}
 
@@ -37,9 +42,9 @@ pub struct Obj {
     prev: *mut Obj,
 }
 
-impl ToString for Obj{
-    fn to_string(&self) -> String {
-        self.core.to_string()
+impl Display for Obj {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.core)
     }
 }
 
@@ -90,7 +95,7 @@ impl ObjReader<'_> {
     unsafe fn new(obj: *mut Obj) -> Self {
         while {
             let rw = (*obj).rw.load(SeqCst);
-            assert_eq!(rw, Obj::MUTATOR_WRITE);
+            assert_ne!(rw, Obj::MUTATOR_WRITE);
             (rw & Obj::COLLECTOR_READ_MASK != 0)
                 || (*obj).rw
                 .compare_exchange_weak(rw, rw + 1, SeqCst, SeqCst)
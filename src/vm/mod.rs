@@ -1,8 +1,10 @@
 pub mod instr;
 pub mod builtin;
 pub mod mem;
+#[allow(clippy::module_inception)]
 pub mod vm;
 pub mod thread;
 pub mod instr_reader;
 pub mod slot;
 pub mod const_pool;
+pub mod error;
@@ -1,13 +1,16 @@
 use std::cmp::Ordering;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 
 use num_cmp::NumCmp;
 
+use crate::vm::error::{VmError, VmResult};
 use crate::vm::mem::Obj;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Slot {
     Unit,
+    /// Value of an optional without value
+    None,
     Int(i64),
     Float(f64),
     Char(char),
@@ -15,22 +18,44 @@ pub enum Slot {
     Ref(*mut Obj),
 }
 
-impl ToString for Slot {
-    fn to_string(&self) -> String {
+impl Display for Slot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Slot::Unit => String::from("unit"),
-            Slot::Int(v) => v.to_string(),
-            Slot::Float(f) => f.to_string(),
-            Slot::Char(c) => c.to_string(),
-            Slot::Bool(b) => b.to_string(),
-            Slot::Ref(obj) => unsafe {
-                obj.as_ref()
-            }.map(ToString::to_string).unwrap_or(String::from("nullptr")),
+            Slot::Unit => write!(f, "unit"),
+            Slot::None => write!(f, "none"),
+            Slot::Int(v) => write!(f, "{}", v),
+            Slot::Float(v) => write!(f, "{}", v),
+            Slot::Char(c) => write!(f, "{}", c),
+            Slot::Bool(b) => write!(f, "{}", b),
+            Slot::Ref(obj) => match unsafe { obj.as_ref() } {
+                Some(obj) => write!(f, "{}", obj),
+                None => write!(f, "nullptr"),
+            },
         }
     }
 }
 
 impl Slot {
+    /// Name of the kind of value, used in runtime errors
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Slot::Unit => "unit",
+            Slot::None => "none",
+            Slot::Int(_) => "int",
+            Slot::Float(_) => "float",
+            Slot::Char(_) => "char",
+            Slot::Bool(_) => "bool",
+            Slot::Ref(_) => "ref",
+        }
+    }
+
+    fn mismatch(&self, expected: &'static str) -> VmError {
+        VmError::TypeMismatch {
+            expected,
+            found: self.type_name(),
+        }
+    }
+
     #[inline]
     pub fn set_int(&mut self, val: i64) {
         match self {
@@ -43,11 +68,11 @@ impl Slot {
     }
 
     #[inline]
-    pub fn get_int(&self) -> i64 {
+    pub fn get_int(&self) -> VmResult<i64> {
         if let Slot::Int(value) = self {
-            *value
+            Ok(*value)
         } else {
-            panic!()
+            Err(self.mismatch("int"))
         }
     }
 
@@ -63,11 +88,19 @@ impl Slot {
     }
 
     #[inline]
-    pub fn get_float(&self) -> f64 {
+    pub fn get_float(&self) -> VmResult<f64> {
         if let Slot::Float(value) = self {
-            *value
+            Ok(*value)
         } else {
-            panic!()
+            Err(self.mismatch("float"))
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> VmResult<*mut Obj> {
+        match self {
+            Slot::Ref(obj) if !obj.is_null() => Ok(*obj),
+            _ => Err(self.mismatch("ref")),
         }
     }
 
@@ -83,11 +116,11 @@ impl Slot {
     }
 
     #[inline]
-    pub fn get_bool(&self) -> bool {
+    pub fn get_bool(&self) -> VmResult<bool> {
         if let Slot::Bool(value) = self {
-            *value
+            Ok(*value)
         } else {
-            panic!()
+            Err(self.mismatch("bool"))
         }
     }
 }
@@ -1,15 +1,31 @@
 use std::ptr::null_mut;
 use std::rc::Rc;
 
+use crate::vm::builtin::builtin_class::{ObjClosure, ObjError};
+use crate::vm::error::{BacktraceFrame, RuntimeError, VmError, VmResult};
 use crate::vm::instr_reader::{AutoScriptInstrReader, InstrReader};
+use crate::vm::mem::Obj;
 use crate::vm::slot::Slot;
 use crate::vm::vm::{AutoScriptFunction, AutoScriptFunctionCode, AutoScriptVM};
 
+/// Frames a thread may hold, calling one more function raises `StackOverflow`
+const MAX_FRAMES: usize = 10_000;
+/// Calls from natives a thread may nest, each of them runs on the Rust stack, so the limit is far below `MAX_FRAMES`
+const MAX_NATIVE_CALLS: usize = 200;
+
 #[derive(Debug)]
 pub struct Thread {
     name: String,
     pc: i32,
-    pub frame_stack: Vec<Frame>,
+    /// Frames are boxed to stay in place while natives call closures, which pushes more frames
+    #[allow(clippy::vec_box)]
+    pub frame_stack: Vec<Box<Frame>>,
+    /// Frames of calls from natives which failed, innermost first, kept for backtrace until the error is caught
+    unwound: Vec<BacktraceFrame>,
+    /// Objects being shown as text by `print` or interpolation, innermost last
+    pub showing: Vec<*mut Obj>,
+    /// Calls from natives being run, like closures called by `List.map`
+    native_calls: usize,
     pub vm: *mut AutoScriptVM,
 }
 
@@ -22,6 +38,9 @@ impl Thread {
             name: String::from("unnamed_thread"),
             pc: 0,
             frame_stack: Vec::new(),
+            unwound: Vec::new(),
+            showing: Vec::new(),
+            native_calls: 0,
             vm: interp_ptr,
         }
     }
@@ -34,6 +53,9 @@ impl Thread {
             name: String::from("unnamed_thread"),
             pc: 0,
             frame_stack: Vec::new(),
+            unwound: Vec::new(),
+            showing: Vec::new(),
+            native_calls: 0,
             vm: null_mut(),
         }
     }
@@ -41,18 +63,21 @@ impl Thread {
     pub fn switch_interp(&mut self, interp: *mut AutoScriptVM) {
         self.vm = interp;
     }
-    pub fn pop_frame(&mut self) -> Option<Frame> {
+    pub fn pop_frame(&mut self) -> Option<Box<Frame>> {
         self.frame_stack.pop()
     }
 
     pub fn push_frame(&mut self, frame: Frame) -> &Frame {
-        self.frame_stack.push(frame);
+        self.frame_stack.push(Box::new(frame));
         self.frame_stack.last().unwrap()
     }
-    pub fn push_new_frame(&mut self, slot_size: usize, instr: Rc<AutoScriptFunction>) -> &mut Frame {
+    pub fn push_new_frame(&mut self, slot_size: usize, instr: Rc<AutoScriptFunction>) -> VmResult<&mut Frame> {
+        if self.frame_stack.len() >= MAX_FRAMES {
+            return Err(VmError::StackOverflow);
+        }
         let frame = Frame::new(slot_size, instr, self);
         self.push_frame(frame);
-        self.frame_stack.last_mut().unwrap()
+        Ok(self.frame_stack.last_mut().unwrap())
     }
 
     pub fn current_frame_mut(&mut self) -> &mut Frame {
@@ -62,8 +87,63 @@ impl Thread {
         self.frame_stack.last().unwrap()
     }
 
+    pub fn vm(&self) -> &AutoScriptVM {
+        unsafe { self.vm.as_ref().unwrap() }
+    }
+
+    /// Push frame of closure in `closure`, its local variables are `args` followed by its captures
+    pub fn push_closure_frame(&mut self, closure: &Slot, args: Vec<Slot>) -> VmResult<()> {
+        let mem = unsafe { &self.vm.as_ref().unwrap().mem };
+        let (function, captures) = ObjClosure::with(closure, mem, |closure| (Rc::clone(&closure.function), closure.captures.clone()))?;
+        let frame = self.push_new_frame(function.local_var_size, function)?;
+        for (idx, slot) in args.into_iter().chain(captures).enumerate() {
+            frame.local_vars.set(idx, slot)
+        }
+        Ok(())
+    }
+
+    /// Call closure from a native function and run it until it returns.
+    /// Errors not caught by the closure are returned with its frames dropped, they are still in the backtrace
+    pub fn call_closure(&mut self, closure: &Slot, args: Vec<Slot>) -> VmResult<Slot> {
+        let base = self.frame_stack.len();
+        self.push_closure_frame(closure, args)?;
+        self.run_call(base)
+    }
+
+    /// Call function of the signature from a native function and run it until it returns, like `call_closure`
+    pub fn call_function(&mut self, signature: &str, args: Vec<Slot>) -> VmResult<Slot> {
+        let vm = unsafe { self.vm.as_ref().unwrap() };
+        let function = vm.prototypes.get_function_prototype(signature)
+            .ok_or_else(|| VmError::FunctionNotFound(signature.to_string()))?;
+        let base = self.frame_stack.len();
+        let frame = self.push_new_frame(function.local_var_size, Rc::clone(&function))?;
+        for (idx, slot) in args.into_iter().enumerate() {
+            frame.local_vars.set(idx, slot)
+        }
+        self.run_call(base)
+    }
 
-    fn interpret(&mut self){
+    /// Run the frame pushed above `base` by a native function, its value is pushed to the frame of the native function
+    fn run_call(&mut self, base: usize) -> VmResult<Slot> {
+        let result = if self.native_calls >= MAX_NATIVE_CALLS {
+            Err(VmError::StackOverflow)
+        } else {
+            self.native_calls += 1;
+            let result = self.run(base);
+            self.native_calls -= 1;
+            result
+        };
+        if let Err(error) = result {
+            let frames = self.frame_stack.split_off(base);
+            self.unwound.extend(frames.iter().rev().map(|frame| frame.trace()));
+            return Err(error);
+        }
+        // return value is pushed to the frame of native function, nothing is pushed for unit
+        Ok(self.current_frame_mut().operand_stack.pop().unwrap_or(Slot::Unit))
+    }
+
+    /// Run until frames above `base` have all returned
+    fn run(&mut self, base: usize) -> VmResult<()> {
         let mut instr_reader = if let AutoScriptFunctionCode::Instr(instr) = &self.current_frame().function.code {
             InstrReader::new(Rc::clone(instr))
         }else{
@@ -75,14 +155,14 @@ impl Thread {
             let pc = frame.next_pc;
             self.set_pc(pc);
 
-            let mut frame = self.current_frame_mut();
             // decode
-            let function = Rc::clone(&frame.function);
+            let function = Rc::clone(&self.current_frame().function);
             match &function.code {
                 AutoScriptFunctionCode::Binding(binding) => {
                     let mut return_value: Option<Slot> = None;
-                    binding.execute(frame, &mut return_value);
-                    if let Some(value) = return_value {
+                    if let Err(error) = binding.execute(self, &mut return_value) {
+                        self.catch(error, base)?;
+                    } else if let Some(value) = return_value {
                         self.pop_frame();
                         self.current_frame_mut().operand_stack.push(value);
                     }else{
@@ -90,27 +170,63 @@ impl Thread {
                     }
                 },
                 AutoScriptFunctionCode::Instr(instr) => {
+                    let frame = self.current_frame_mut();
                     instr_reader.reset(Rc::clone(instr), pc);
                     let instr = instr_reader.read_instr();
                     frame.next_pc = instr_reader.pc();
-                    instr.execute(frame);
+                    if let Err(error) = instr.execute(frame) {
+                        self.catch(error, base)?;
+                    }
                 }
             }
 
-            if self.frame_stack.is_empty() {
-                break;
+            if self.frame_stack.len() <= base {
+                return Ok(());
             }
         }
     }
 
-    pub fn start(&mut self, function_signature: &str) {
-        let vm: &mut AutoScriptVM = unsafe { &mut *self.vm };
-        let function = vm.prototypes.get_function_prototype(function_signature).unwrap();
-        self.push_new_frame(function.local_var_size, Rc::clone(&function));
-
+    /// Unwind to the innermost frame with a handler and continue at the handler with the error pushed.
+    /// Only frames above `base` are searched.
+    /// The error is returned if nothing catches it, frames are kept for backtrace then
+    fn catch(&mut self, error: VmError, base: usize) -> VmResult<()> {
+        let depth = self.frame_stack[base..].iter().rposition(|frame| !frame.handlers.is_empty()).map(|depth| depth + base);
+        let Some(depth) = depth.filter(|_| error.is_catchable()) else {
+            return Err(error);
+        };
+        self.frame_stack.truncate(depth + 1);
+        self.unwound.clear();
+        let frame = self.current_frame_mut();
+        let handler = frame.handlers.pop().unwrap();
+        let error = match error {
+            VmError::Thrown(error) => error,
+            error => ObjError::alloc(&frame.vm().mem, error.to_string()),
+        };
+        frame.operand_stack.truncate(handler.stack_len);
+        frame.operand_stack.push(error);
+        frame.next_pc = handler.pc;
+        Ok(())
+    }
 
+    /// Pop all frames, recording where each of them stopped, after frames dropped by natives
+    fn unwind(&mut self) -> Vec<BacktraceFrame> {
+        let mut backtrace = std::mem::take(&mut self.unwound);
+        while let Some(frame) = self.pop_frame() {
+            backtrace.push(frame.trace());
+        }
+        backtrace
+    }
 
-        self.interpret();
+    pub fn start(&mut self, function_signature: &str) -> Result<(), RuntimeError> {
+        let vm: &mut AutoScriptVM = unsafe { &mut *self.vm };
+        let function = vm.prototypes.get_function_prototype(function_signature).unwrap();
+        let result = self.push_new_frame(function.local_var_size, Rc::clone(&function))
+            .map(|_| ())
+            .and_then(|_| self.run(0));
+        result.map_err(|error| RuntimeError {
+            error,
+            backtrace: self.unwind(),
+        })
     }
 
     pub fn pc(&self) -> i32 {
@@ -125,16 +241,41 @@ pub struct Frame {
     pub local_vars: LocalVars,
     pub operand_stack: Vec<Slot>,
     pub next_pc: i32,
+    /// Handlers of `try` blocks being run, innermost last
+    pub handlers: Vec<Handler>,
     pub function: Rc<AutoScriptFunction>,
     pub thread: *mut Thread,
 }
 
+/// Where a `try` block of frame catches errors
+#[derive(Debug)]
+pub struct Handler {
+    pub pc: i32,
+    /// Height of operand stack when the `try` block began
+    pub stack_len: usize,
+}
+
 impl Frame {
+    pub fn vm(&self) -> &AutoScriptVM {
+        unsafe { self.thread.as_ref().unwrap().vm.as_ref().unwrap() }
+    }
+
+    /// Where the frame stopped
+    fn trace(&self) -> BacktraceFrame {
+        // `next_pc` has moved past the instruction being executed
+        let span = self.function.line_table.lookup(self.next_pc - 1).cloned();
+        BacktraceFrame {
+            declaration: self.function.declaration.clone(),
+            span,
+        }
+    }
+
     fn new(size: usize, instr: Rc<AutoScriptFunction>, ptr: &mut Thread) -> Self {
         Self {
             local_vars: LocalVars::with_cap(size),
             operand_stack: Vec::new(),
             next_pc: 0,
+            handlers: Vec::new(),
             function: instr,
             thread: ptr as *mut Thread,
         }
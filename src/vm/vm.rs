@@ -1,10 +1,12 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::vm::builtin::AutoScriptRustVMFunctionBinding;
-use crate::vm::instr::Instructions;
+use crate::vm::error::{RuntimeError, VmError, VmResult};
+use crate::vm::instr::{Instructions, LineTable};
 use crate::vm::mem::Mem;
 use crate::vm::slot::Slot;
 use crate::vm::thread::Thread;
@@ -13,18 +15,28 @@ use crate::VmArgs;
 use super::const_pool::ConstantPool;
 
 pub type FnSignature = String;
+
+/// Name of module level variable with its value, `None` until initialized
+type Global = (String, Option<Slot>);
+
 #[derive(Debug)]
 pub struct AutoScriptPrototype {
     // temporary implementations
     functions: HashMap<FnSignature, Rc<AutoScriptFunction>>,
-    constant_pool: ConstantPool
+    constant_pool: ConstantPool,
+    /// Module level variables of each module by index
+    globals: HashMap<String, RefCell<Vec<Global>>>,
+    /// Functions initializing module level variables, in the order to run them
+    initializers: Vec<FnSignature>,
 }
 
 impl AutoScriptPrototype {
     pub fn new() -> Self {
         Self {
             functions: HashMap::new(),
-            constant_pool: vec![].into()
+            constant_pool: vec![].into(),
+            globals: HashMap::new(),
+            initializers: Vec::new(),
         }
     }
     pub fn insert_function_prototype(&mut self, signature: FnSignature, prototype: AutoScriptFunction) {
@@ -40,6 +52,25 @@ impl AutoScriptPrototype {
     pub fn get_constant(&self, idx:usize) -> Option<Slot> {
         self.constant_pool.get(idx)
     }
+
+    pub fn insert_globals(&mut self, module: String, names: Vec<String>) {
+        self.globals.insert(module, RefCell::new(names.into_iter().map(|name| (name, None)).collect()));
+    }
+    /// Value of module level variable, it may be read by a function called before its initializer ran
+    pub fn get_global(&self, module: &str, idx: usize) -> VmResult<Slot> {
+        let (name, value) = &self.globals[module].borrow()[idx];
+        value.clone().ok_or_else(|| VmError::UninitializedGlobal(format!("{}.{}", module, name)))
+    }
+    pub fn set_global(&self, module: &str, idx: usize, slot: Slot) {
+        self.globals[module].borrow_mut()[idx].1 = Some(slot);
+    }
+
+    pub fn push_initializer(&mut self, signature: FnSignature) {
+        self.initializers.push(signature);
+    }
+    pub fn initializers(&self) -> &[FnSignature] {
+        &self.initializers
+    }
 }
 
 #[derive(Debug)]
@@ -53,9 +84,13 @@ pub enum AutoScriptFunctionCode{
 pub struct AutoScriptFunction {
     pub name: String,
     pub signature: String,
+    /// Readable form of the function for backtrace, like `fn inner(x: int) -> int`
+    pub declaration: String,
     pub local_var_size: usize,
     pub arg_num: usize,
     pub code: AutoScriptFunctionCode,
+    /// Source location of instructions, empty for vm builtin functions
+    pub line_table: LineTable,
 }
 
 
@@ -67,25 +102,26 @@ pub struct AutoScriptVM {
 }
 
 impl AutoScriptVM {
-    pub fn new(prototypes: AutoScriptPrototype, args: VmArgs) -> Self {
+    /// VM is boxed so that the pointer held by its threads stays valid
+    pub fn new(prototypes: AutoScriptPrototype, args: VmArgs) -> Box<Self> {
         let mut interp = unsafe {
-            Self {
+            Box::new(Self {
                 prototypes,
                 main_thread: Thread::new_dangle(),
                 mem: Arc::new(Mem::new()),
                 args,
-            }
+            })
         };
-        let interp_ptr: *mut AutoScriptVM = (&mut interp) as *mut AutoScriptVM;
+        let interp_ptr: *mut AutoScriptVM = &mut *interp as *mut AutoScriptVM;
         interp.main_thread.switch_interp(interp_ptr);
         interp
     }
 
-    pub fn start(&mut self, function_signature: &str) {
+    /// Initialize module level variables of all modules, then run the function
+    pub fn start(&mut self, function_signature: &str) -> Result<(), RuntimeError> {
+        for signature in self.prototypes.initializers().to_vec() {
+            self.main_thread.start(&signature)?;
+        }
         self.main_thread.start(function_signature)
     }
-
-    fn new_thread(&mut self) -> Thread {
-        Thread::new(self)
-    }
 }
\ No newline at end of file